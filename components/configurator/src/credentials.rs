@@ -0,0 +1,43 @@
+// Accounts as the SASL mechanisms see them
+use eemail_lib_sasl::{CredentialStore, ScramCredentials};
+use log::{debug, error};
+use yescrypt::{PasswordHash, PasswordVerifier, Yescrypt};
+
+use crate::Configuration;
+
+impl CredentialStore for Configuration {
+    fn verify_password(&self, username: &str, password: &str) -> bool {
+        let Some(account) = self.clone().get_user_from_alias(username) else {
+            debug!("User not found for email: {username}");
+            return false;
+        };
+        let Some(hash) = account.hashed_password else {
+            debug!("User doesn't have a password: {username}");
+            return false;
+        };
+
+        match PasswordHash::new(&hash) {
+            Ok(parsed_hash) => match Yescrypt.verify_password(password.as_bytes(), &parsed_hash) {
+                Ok(_) => true,
+                Err(_) => {
+                    debug!("Password verification failed for user: {username}");
+                    false
+                }
+            },
+            Err(e) => {
+                error!("Failed to parse password hash for user {username}: {e}");
+                false
+            }
+        }
+    }
+
+    fn scram_sha256(&self, username: &str) -> Option<ScramCredentials> {
+        let account = self.clone().get_user_from_alias(username)?;
+        let stored = account.scram_sha256?;
+        let credentials = ScramCredentials::parse(&stored);
+        if credentials.is_none() {
+            error!("Failed to parse SCRAM-SHA-256 credentials for user {username}");
+        }
+        credentials
+    }
+}
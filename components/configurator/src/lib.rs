@@ -1,8 +1,12 @@
-use std::fs;
+use std::{fs, net::IpAddr};
 
+use eemail_lib_shared::{BareLfPolicy, FilterAction, VrfyPolicy};
+use ipnet::IpNet;
 use log::debug;
 use serde::Deserialize;
 
+mod credentials;
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Configuration {
     pub enable_smtp: Option<bool>,
@@ -15,6 +19,93 @@ pub struct Configuration {
     pub domains: Vec<String>,
 
     pub accounts: Vec<Account>,
+    // Any address of the account that gets mail for postmaster at every local domain, the first account if unset
+    pub postmaster: Option<String>,
+
+    pub smtp: Option<SmtpConfiguration>,
+    pub queue: Option<QueueConfiguration>,
+    pub dkim: Option<DkimConfiguration>,
+    pub spf: Option<SpfConfiguration>,
+    pub dmarc: Option<DmarcConfiguration>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SmtpConfiguration {
+    pub transfer: Option<SmtpListenerConfiguration>,
+    pub submission: Option<SmtpListenerConfiguration>,
+    // Implicit TLS submission (RFC 8314), only started when this section exists
+    pub submissions: Option<SmtpListenerConfiguration>,
+
+    // Clients in these networks may relay without authenticating, e.g. ["127.0.0.0/8", "::1/128"]
+    pub relay_networks: Option<Vec<IpNet>>,
+}
+
+// Every field is optional, the SMTP component fills in sane defaults for anything left unset
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SmtpListenerConfiguration {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub vrfy: Option<VrfyPolicy>,
+    pub max_message_size: Option<usize>,
+    pub bare_lf: Option<BareLfPolicy>,
+    // Both default to on for the submission listeners and off for transfer
+    pub require_tls: Option<bool>,
+    pub require_auth: Option<bool>,
+    // Also hold the From: header of authenticated mail to the sender rules, not just MAIL FROM
+    pub check_header_from: Option<bool>,
+}
+
+// Outbound mail that couldn't be delivered straight away, times are in hours
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct QueueConfiguration {
+    // How long to keep retrying before giving up and telling the sender, 5 days if unset
+    pub lifetime_hours: Option<u64>,
+    // How long before the sender is warned their mail is delayed, 4 hours if unset
+    pub delay_warning_hours: Option<u64>,
+    // How many delivery attempts can run at once, 20 if unset. Anything else that's due waits its turn
+    pub max_concurrent_deliveries: Option<usize>,
+}
+
+// DKIM signing (RFC 6376) for mail our accounts send, picked by the domain of the From: header
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct DkimConfiguration {
+    // Header fields to sign, a sensible list is used if unset
+    pub headers: Option<Vec<String>>,
+    pub keys: Vec<DkimKeyConfiguration>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DkimKeyConfiguration {
+    // One of `domains`
+    pub domain: String,
+    pub selector: String,
+    // Path to a PEM private key, RSA or Ed25519. `eemail dkim-keygen` makes one
+    pub private_key: String,
+}
+
+// What filtering listeners do for each SPF result (RFC 7208 8), a pass is always accepted
+// Unset results reject on fail, tag on softfail and accept anything else
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SpfConfiguration {
+    pub none: Option<FilterAction>,
+    pub neutral: Option<FilterAction>,
+    pub softfail: Option<FilterAction>,
+    pub fail: Option<FilterAction>,
+    pub temperror: Option<FilterAction>,
+    pub permerror: Option<FilterAction>,
+}
+
+// DMARC (RFC 7489) on filtering listeners, what local policy makes of each domain's policy
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct DmarcConfiguration {
+    // p=reject rejects if unset
+    pub reject: Option<FilterAction>,
+    // p=quarantine tags if unset
+    pub quarantine: Option<FilterAction>,
+    // Send the aggregate reports domains ask for with rua=, on if unset
+    pub reports: Option<bool>,
+    // Where reports say they're from, postmaster@<sending_fqdn> if unset
+    pub report_address: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -23,41 +114,85 @@ pub struct Account {
     pub user: String,
     pub aliases: Option<Vec<String>>,
     pub hashed_password: Option<String>,
+    // RFC 5803 format, SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>, as printed by `eemail scram-password`.
+    // Without it the account can't use SCRAM-SHA-256
+    pub scram_sha256: Option<String>,
+    // Other accounts (by any of their addresses) or single addresses this account may also send as
+    pub send_as: Option<Vec<String>>,
 }
 
 impl Configuration {
     pub fn parse_from_file(path: &'static str) -> anyhow::Result<Self> {
         debug!("Starting Configuration Load from file");
         let file = fs::read_to_string(path)?;
-        return Ok(toml::from_str::<Self>(file.as_str())?);
+        Ok(toml::from_str::<Self>(file.as_str())?)
     }
 
     pub fn parse_from_string(string: String) -> anyhow::Result<Self> {
         debug!("Starting Configuration Load from string");
-        return Ok(toml::from_str::<Self>(string.as_str())?);
+        Ok(toml::from_str::<Self>(string.as_str())?)
     }
 
     pub fn get_accounts(self) -> Vec<Account> {
         self.accounts.clone()
     }
 
-    pub fn get_user_from_alias(self, alias: &String) -> Option<Account> {
-        for account in self.accounts {
-            let all_addresses = account.clone().get_all_addresses();
-            if all_addresses.contains(alias) {
-                return Some(account);
+    pub fn get_user_from_alias(self, alias: &str) -> Option<Account> {
+        self.accounts
+            .into_iter()
+            .find(|account| account.has_address(alias))
+    }
+
+    // The account whose mailbox mail for `address` goes in. RFC 5321 4.5.1 wants a postmaster at every local domain,
+    // and plain `postmaster` with no domain at all (4.1.1.3), so those always have one
+    pub fn get_recipient(&self, address: &str) -> Option<Account> {
+        if let Some(account) = self.clone().get_user_from_alias(address) {
+            return Some(account);
+        }
+        let postmaster = match address.rsplit_once('@') {
+            Some((local, domain)) => {
+                local.eq_ignore_ascii_case("postmaster") && self.is_local_domain(domain)
             }
+            None => address.eq_ignore_ascii_case("postmaster"),
+        };
+        if !postmaster {
+            return None;
+        }
+        match &self.postmaster {
+            Some(postmaster) => self.clone().get_user_from_alias(postmaster),
+            None => self.accounts.first().cloned(),
         }
-        None
+    }
+
+    // Whether `account` may use `address` as a sender, either its own or through a send_as grant
+    pub fn may_send_as(&self, account: &Account, address: &str) -> bool {
+        account.has_address(address)
+            || account.send_as.iter().flatten().any(|grant| {
+                match self.clone().get_user_from_alias(grant) {
+                    Some(granted) => granted.has_address(address),
+                    None => normalize_address(grant) == normalize_address(address),
+                }
+            })
+    }
+
+    pub fn is_trusted_relay(&self, ip: IpAddr) -> bool {
+        self.smtp
+            .iter()
+            .flat_map(|smtp| smtp.relay_networks.iter().flatten())
+            .any(|network| network.contains(&ip))
+    }
+
+    pub fn is_local_domain(&self, domain: &str) -> bool {
+        let domain = normalize_domain(domain);
+        self.domains
+            .iter()
+            .any(|local| normalize_domain(local) == domain)
     }
 }
 
 impl Account {
     pub fn get_all_addresses(self) -> Vec<String> {
-        let mut aliases: Vec<String> = match self.aliases {
-            Some(aliases) => aliases,
-            None => Vec::new(),
-        };
+        let mut aliases: Vec<String> = self.aliases.unwrap_or_default();
 
         let primary = format!("{}@{}", self.user, self.domain);
         aliases.push(primary);
@@ -68,6 +203,28 @@ impl Account {
     pub fn get_primary_address(self) -> String {
         format!("{}@{}", self.user, self.domain).to_string()
     }
+
+    // Compares against every address of the account, ignoring how the domain was written
+    pub fn has_address(&self, address: &str) -> bool {
+        let address = normalize_address(address);
+        self.clone()
+            .get_all_addresses()
+            .iter()
+            .any(|own| normalize_address(own) == address)
+    }
+}
+
+// Domains are compared as lowercase A-labels (RFC 5890), so "bücher.example" and "xn--bcher-kva.example" are the same
+pub fn normalize_domain(domain: &str) -> String {
+    idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_lowercase())
+}
+
+// Only the domain is normalized, the local part is left as is since it belongs to the receiving server (RFC 5321 2.4)
+pub fn normalize_address(address: &str) -> String {
+    match address.rsplit_once('@') {
+        Some((local, domain)) => format!("{}@{}", local, normalize_domain(domain)),
+        None => address.to_string(),
+    }
 }
 
 #[cfg(test)]
@@ -80,7 +237,7 @@ mod tests {
         fqdn = "mail.example.com"
         sending_fqdn = "example.com"
 
-        domains = ["example.com", "example.net"]
+        domains = ["example.com", "example.net", "bücher.example"]
 
         enable_smtp = true
         enable_pop3 = false
@@ -93,6 +250,43 @@ mod tests {
         [[accounts]]
         domain = "example.com"
         user = "test"
+        aliases = ["jörg@bücher.example"]
+        send_as = ["hi@example.com", "postmaster@example.org"]
+
+        [smtp.transfer]
+        max_connections = 50
+        max_connections_per_ip = 5
+        vrfy = "ambiguous"
+        max_message_size = 1048576
+        bare_lf = "reject"
+
+        [smtp]
+        relay_networks = ["192.0.2.0/24", "2001:db8::/32"]
+
+        [smtp.submissions]
+        max_connections = 20
+        require_tls = true
+        require_auth = false
+
+        [queue]
+        lifetime_hours = 72
+        max_concurrent_deliveries = 5
+
+        [dkim]
+        headers = ["From", "To", "Subject"]
+
+        [[dkim.keys]]
+        domain = "example.com"
+        selector = "eemail"
+        private_key = "keys/example.com.pem"
+
+        [spf]
+        softfail = "reject"
+        temperror = "tag"
+
+        [dmarc]
+        reject = "tag"
+        reports = false
         "#
         .to_string()
     }
@@ -113,6 +307,63 @@ mod tests {
         Configuration::parse_from_file(path).unwrap();
     }
 
+    #[test]
+    fn config_parses_smtp_listener_limits() {
+        let config = Configuration::parse_from_string(config()).unwrap();
+        let smtp = config.smtp.unwrap();
+        let transfer = smtp.transfer.unwrap();
+        assert_eq!(transfer.max_connections, Some(50));
+        assert_eq!(transfer.max_connections_per_ip, Some(5));
+        assert_eq!(transfer.vrfy, Some(VrfyPolicy::Ambiguous));
+        assert_eq!(transfer.max_message_size, Some(1024 * 1024));
+        assert_eq!(transfer.bare_lf, Some(BareLfPolicy::Reject));
+        assert!(smtp.submission.is_none());
+        let submissions = smtp.submissions.unwrap();
+        assert_eq!(submissions.max_connections, Some(20));
+        assert_eq!(submissions.require_tls, Some(true));
+        assert_eq!(submissions.require_auth, Some(false));
+        assert_eq!(transfer.require_tls, None);
+    }
+
+    #[test]
+    fn config_parses_queue_settings() {
+        let config = Configuration::parse_from_string(config()).unwrap();
+        let queue = config.queue.unwrap();
+        assert_eq!(queue.lifetime_hours, Some(72));
+        assert_eq!(queue.delay_warning_hours, None);
+        assert_eq!(queue.max_concurrent_deliveries, Some(5));
+    }
+
+    #[test]
+    fn config_parses_dkim_keys() {
+        let config = Configuration::parse_from_string(config()).unwrap();
+        let dkim = config.dkim.unwrap();
+        assert_eq!(dkim.headers.unwrap(), ["From", "To", "Subject"]);
+        assert_eq!(dkim.keys.len(), 1);
+        assert_eq!(dkim.keys[0].domain, "example.com");
+        assert_eq!(dkim.keys[0].selector, "eemail");
+        assert_eq!(dkim.keys[0].private_key, "keys/example.com.pem");
+    }
+
+    #[test]
+    fn config_parses_spf_actions() {
+        let config = Configuration::parse_from_string(config()).unwrap();
+        let spf = config.spf.unwrap();
+        assert_eq!(spf.softfail, Some(FilterAction::Reject));
+        assert_eq!(spf.temperror, Some(FilterAction::Tag));
+        assert_eq!(spf.fail, None);
+    }
+
+    #[test]
+    fn config_parses_dmarc_settings() {
+        let config = Configuration::parse_from_string(config()).unwrap();
+        let dmarc = config.dmarc.unwrap();
+        assert_eq!(dmarc.reject, Some(FilterAction::Tag));
+        assert_eq!(dmarc.quarantine, None);
+        assert_eq!(dmarc.reports, Some(false));
+        assert_eq!(dmarc.report_address, None);
+    }
+
     #[test]
     fn config_parses_accounts() {
         let config = Configuration::parse_from_string(config()).unwrap();
@@ -158,4 +409,119 @@ mod tests {
             "example@example.com"
         );
     }
+
+    #[test]
+    fn config_always_has_a_postmaster() {
+        let mut config = Configuration::parse_from_string(config()).unwrap();
+        let recipient = |config: &Configuration, address: &str| {
+            config
+                .get_recipient(address)
+                .map(|account| account.get_primary_address())
+        };
+
+        for address in [
+            "postmaster",
+            "PostMaster",
+            "postmaster@example.net",
+            "POSTMASTER@bücher.example",
+        ] {
+            assert_eq!(
+                recipient(&config, address).as_deref(),
+                Some("example@example.com")
+            );
+        }
+        assert_eq!(recipient(&config, "postmaster@example.org"), None);
+        assert_eq!(recipient(&config, "nobody@example.com"), None);
+        assert_eq!(
+            recipient(&config, "hi@example.com").as_deref(),
+            Some("example@example.com")
+        );
+
+        config.postmaster = Some("jörg@bücher.example".to_string());
+        assert_eq!(
+            recipient(&config, "postmaster").as_deref(),
+            Some("test@example.com")
+        );
+    }
+
+    #[test]
+    fn config_gets_user_from_idn_alias() {
+        let config = Configuration::parse_from_string(config()).unwrap();
+
+        for alias in [
+            "jörg@bücher.example",
+            "jörg@xn--bcher-kva.example",
+            "jörg@BÜCHER.example",
+        ] {
+            assert_eq!(
+                config
+                    .clone()
+                    .get_user_from_alias(alias)
+                    .unwrap()
+                    .get_primary_address(),
+                "test@example.com"
+            );
+        }
+
+        // Local parts are not folded
+        assert!(config.get_user_from_alias("JÖRG@bücher.example").is_none());
+    }
+
+    #[test]
+    fn config_matches_idn_local_domains() {
+        let config = Configuration::parse_from_string(config()).unwrap();
+        assert!(config.is_local_domain("example.com"));
+        assert!(config.is_local_domain("EXAMPLE.com"));
+        assert!(config.is_local_domain("xn--bcher-kva.example"));
+        assert!(config.is_local_domain("bücher.example"));
+        assert!(!config.is_local_domain("example.org"));
+    }
+
+    #[test]
+    fn normalize_address_converts_domain_to_a_label() {
+        assert_eq!(
+            normalize_address("用户@例え.テスト"),
+            "用户@xn--r8jz45g.xn--zckzah"
+        );
+        assert_eq!(normalize_address("User@Example.COM"), "User@example.com");
+        assert_eq!(normalize_address("postmaster"), "postmaster");
+    }
+
+    #[test]
+    fn config_checks_send_as_grants() {
+        let config = Configuration::parse_from_string(config()).unwrap();
+        let example = config
+            .clone()
+            .get_user_from_alias("example@example.com")
+            .unwrap();
+        let test = config
+            .clone()
+            .get_user_from_alias("test@example.com")
+            .unwrap();
+
+        // Own addresses, however the domain is written
+        assert!(config.may_send_as(&test, "test@example.com"));
+        assert!(config.may_send_as(&test, "jörg@xn--bcher-kva.example"));
+
+        // Granted the whole of the other account through one of its aliases, and a single outside address
+        assert!(config.may_send_as(&test, "example@example.net"));
+        assert!(config.may_send_as(&test, "postmaster@example.org"));
+        assert!(!config.may_send_as(&test, "someone@example.org"));
+
+        // Grants only go one way
+        assert!(!config.may_send_as(&example, "test@example.com"));
+    }
+
+    #[test]
+    fn config_checks_relay_networks() {
+        let config = Configuration::parse_from_string(config()).unwrap();
+        assert!(config.is_trusted_relay("192.0.2.10".parse().unwrap()));
+        assert!(config.is_trusted_relay("2001:db8::1".parse().unwrap()));
+        assert!(!config.is_trusted_relay("198.51.100.1".parse().unwrap()));
+        assert!(!config.is_trusted_relay("127.0.0.1".parse().unwrap()));
+
+        let mut config = config;
+        config.smtp = None;
+        assert!(!config.is_trusted_relay("192.0.2.10".parse().unwrap()));
+    }
 }
@@ -0,0 +1,322 @@
+use log::{debug, info, warn};
+use rustls::{ClientConfig, RootCertStore, pki_types::ServerName};
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_rustls::TlsConnector;
+
+use eemail_component_configurator::{Configuration, normalize_domain};
+use eemail_lib_dns::{Resolver, mail_exchangers};
+use eemail_lib_protocols_smtp_client::{Client, Error, Message, Reply};
+
+#[cfg(test)]
+mod test_utils;
+
+const SMTP_PORT: u16 = 25;
+// RFC 5321 doesn't give one for the connection itself, this is well short of the 5 minute greeting timeout
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How one recipient went
+pub struct Delivery {
+    pub recipient: String,
+    pub reply: Reply,
+    // Whether the server that gave the reply offered DSN, if it didn't nothing more will be reported about the mail
+    pub dsn: bool,
+}
+
+// Hands mail for other domains to their mail exchangers
+pub struct Outbound {
+    pub resolver: Arc<dyn Resolver>,
+    // Sent in EHLO, this is `sending_fqdn`
+    pub helo_name: String,
+    pub tls: TlsConnector,
+    // Mail exchangers are always on 25, this is only changed for tests
+    pub port: u16,
+}
+
+impl Outbound {
+    pub fn new(config: &Configuration, resolver: Arc<dyn Resolver>) -> Self {
+        let roots: RootCertStore = webpki_roots::TLS_SERVER_ROOTS.iter().cloned().collect();
+        let tls = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Self {
+            resolver,
+            helo_name: config.sending_fqdn.clone(),
+            tls: TlsConnector::from(Arc::new(tls)),
+            port: SMTP_PORT,
+        }
+    }
+
+    // Tries every recipient once, giving back the reply that decided each one in the order they were given.
+    // Replies for failures that never reached a server (DNS, no connection) are made up here
+    pub async fn deliver(&self, message: &Message<'_>) -> Vec<Delivery> {
+        let mut domains: Vec<(String, Vec<String>)> = Vec::new();
+        for recipient in message.to {
+            let domain = recipient
+                .rsplit_once('@')
+                .map(|(_, domain)| normalize_domain(domain))
+                .unwrap_or_default();
+            match domains.iter_mut().find(|(existing, _)| *existing == domain) {
+                Some((_, recipients)) => recipients.push(recipient.clone()),
+                None => domains.push((domain, vec![recipient.clone()])),
+            }
+        }
+
+        let mut results = Vec::new();
+        for (domain, recipients) in domains {
+            let message = Message {
+                to: &recipients,
+                ..*message
+            };
+            results.extend(self.deliver_to_domain(&domain, &message).await);
+        }
+
+        results.sort_by_key(|delivery| message.to.iter().position(|to| *to == delivery.recipient));
+        results
+    }
+
+    // RFC 5321 5.1, works down the mail exchangers until one of them takes part in a transaction
+    async fn deliver_to_domain(&self, domain: &str, message: &Message<'_>) -> Vec<Delivery> {
+        let exchangers = match mail_exchangers(&*self.resolver, domain).await {
+            Ok(exchangers) if exchangers.is_empty() => {
+                return everyone(
+                    message.to,
+                    Reply::new(556, format!("5.1.10 {} does not accept mail", domain)),
+                );
+            }
+            Ok(exchangers) => exchangers,
+            Err(eemail_lib_dns::Error::NoSuchDomain) => {
+                return everyone(
+                    message.to,
+                    Reply::new(550, format!("5.1.2 {} does not exist", domain)),
+                );
+            }
+            Err(e) => {
+                return everyone(
+                    message.to,
+                    Reply::new(451, format!("4.4.3 MX lookup for {} failed: {}", domain, e)),
+                );
+            }
+        };
+        debug!("Mail exchangers for {}: {:?}", domain, exchangers);
+
+        let mut last_error = String::from("no addresses found");
+        for exchanger in exchangers {
+            let ips = match self.resolver.ip(&exchanger).await {
+                Ok(ips) => ips,
+                Err(e) => {
+                    warn!("Address lookup for {} failed: {}", exchanger, e);
+                    last_error = format!("{}: {}", exchanger, e);
+                    continue;
+                }
+            };
+
+            for ip in ips {
+                match self.attempt(&exchanger, ip, message).await {
+                    Ok(results) => return results,
+                    Err(e) => {
+                        warn!("Delivery to {} ({}) failed: {}", exchanger, ip, e);
+                        last_error = format!("{}: {}", exchanger, e);
+                    }
+                }
+            }
+        }
+
+        everyone(
+            message.to,
+            Reply::new(
+                451,
+                format!(
+                    "4.4.1 No mail exchanger for {} took the message, last error {}",
+                    domain, last_error
+                ),
+            ),
+        )
+    }
+
+    // Opportunistic TLS, if the handshake fails the same server is tried again in plaintext
+    async fn attempt(
+        &self,
+        exchanger: &str,
+        ip: IpAddr,
+        message: &Message<'_>,
+    ) -> Result<Vec<Delivery>, Error> {
+        let tls = ServerName::try_from(exchanger.to_string())
+            .ok()
+            .map(|name| (&self.tls, name));
+
+        match self.session(ip, tls, message).await {
+            Err(Error::Tls(e)) => {
+                info!(
+                    "TLS with {} failed ({}), retrying in plaintext",
+                    exchanger, e
+                );
+                self.session(ip, None, message).await
+            }
+            result => result,
+        }
+    }
+
+    async fn session(
+        &self,
+        ip: IpAddr,
+        tls: Option<(&TlsConnector, ServerName<'static>)>,
+        message: &Message<'_>,
+    ) -> Result<Vec<Delivery>, Error> {
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect((ip, self.port)))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        let mut client = Client::connect(stream, &self.helo_name, tls).await?;
+        debug!("Connected to {} (encrypted? {})", ip, client.is_encrypted());
+
+        let dsn = client.supports("DSN");
+        let results = client.send(message).await?;
+        client.quit().await;
+        Ok(results
+            .into_iter()
+            .map(|(recipient, reply)| Delivery {
+                recipient,
+                reply,
+                dsn,
+            })
+            .collect())
+    }
+}
+
+// Only used when no server got as far as answering, so there's no DSN to speak of
+fn everyone(to: &[String], reply: Reply) -> Vec<Delivery> {
+    to.iter()
+        .map(|recipient| Delivery {
+            recipient: recipient.clone(),
+            reply: reply.clone(),
+            dsn: false,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_dns::StaticResolver;
+    use eemail_lib_shared::{BodyType, Dsn};
+    use std::sync::LazyLock;
+
+    use super::*;
+    use crate::test_utils::*;
+
+    static NO_DSN: LazyLock<Dsn> = LazyLock::new(Dsn::default);
+
+    fn message<'a>(to: &'a [String]) -> Message<'a> {
+        Message {
+            from: "sender@example.org",
+            to,
+            body: BodyType::SevenBit,
+            smtputf8: false,
+            dsn: &NO_DSN,
+            data: b"Subject: hi\r\n\r\nHello\r\n",
+        }
+    }
+
+    fn localhost() -> IpAddr {
+        "127.0.0.1".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn outbound_delivers_to_the_mail_exchanger_over_tls() {
+        // Plaintext sessions can't send mail to this one
+        let mut sink = sink(true).await;
+        let resolver = StaticResolver::default()
+            .mx("example.com", 10, "localhost.")
+            .ip("localhost", localhost());
+        let outbound = outbound(resolver, sink.port, true);
+
+        let to = ["test@example.com".to_string()];
+        let results = outbound.deliver(&message(&to)).await;
+        assert!(results[0].reply.is_positive());
+        assert!(results[0].dsn);
+
+        let mail = sink.transactions.recv().await.unwrap();
+        assert_eq!(mail.from, "sender@example.org");
+        assert_eq!(mail.to, ["test@example.com"]);
+        assert_eq!(mail.data, b"Subject: hi\r\n\r\nHello\r\n");
+    }
+
+    #[tokio::test]
+    async fn outbound_falls_back_to_plaintext_when_tls_fails() {
+        let mut sink = sink(false).await;
+        let resolver = StaticResolver::default().ip("example.com", localhost());
+        // The public roots don't trust the test certificate
+        let outbound = outbound(resolver, sink.port, false);
+
+        let to = ["test@example.com".to_string()];
+        assert!(outbound.deliver(&message(&to)).await[0].reply.is_positive());
+        assert_eq!(sink.transactions.recv().await.unwrap().to, to);
+    }
+
+    #[tokio::test]
+    async fn outbound_moves_on_to_the_next_exchanger() {
+        let mut sink = sink(false).await;
+        // Nothing is listening on 127.0.0.2
+        let resolver = StaticResolver::default()
+            .mx("example.com", 5, "down.example.com")
+            .mx("example.com", 10, "localhost")
+            .ip("down.example.com", "127.0.0.2".parse().unwrap())
+            .ip("localhost", localhost());
+        let outbound = outbound(resolver, sink.port, true);
+
+        let to = ["test@example.com".to_string()];
+        assert!(outbound.deliver(&message(&to)).await[0].reply.is_positive());
+        assert_eq!(sink.transactions.recv().await.unwrap().to, to);
+    }
+
+    #[tokio::test]
+    async fn outbound_reports_each_recipient_in_order() {
+        let mut sink = sink(false).await;
+        let resolver = StaticResolver::default()
+            .mx("example.com", 10, "localhost")
+            .mx("example.net", 10, "localhost")
+            .ip("localhost", localhost())
+            .mx("null.example", 0, ".")
+            .fail("broken.example");
+        let outbound = outbound(resolver, sink.port, true);
+
+        let to = [
+            "test@example.com".to_string(),
+            "a@null.example".to_string(),
+            "example@example.net".to_string(),
+            "b@missing.example".to_string(),
+            "c@broken.example".to_string(),
+            "nobody@example.com".to_string(),
+        ];
+        let results = outbound.deliver(&message(&to)).await;
+        let recipients: Vec<&String> = results.iter().map(|delivery| &delivery.recipient).collect();
+        assert_eq!(recipients, to.iter().collect::<Vec<_>>());
+
+        let codes: Vec<u16> = results.iter().map(|delivery| delivery.reply.code).collect();
+        assert_eq!(codes, [250, 556, 250, 550, 451, 550]);
+
+        // One transaction per domain
+        assert_eq!(
+            sink.transactions.recv().await.unwrap().to,
+            ["test@example.com"]
+        );
+        assert_eq!(
+            sink.transactions.recv().await.unwrap().to,
+            ["example@example.net"]
+        );
+    }
+
+    #[tokio::test]
+    async fn outbound_defers_when_nothing_answers() {
+        let resolver = StaticResolver::default()
+            .mx("example.com", 10, "down.example.com")
+            .ip("down.example.com", "127.0.0.2".parse().unwrap());
+        let outbound = outbound(resolver, 2525, true);
+
+        let to = ["test@example.com".to_string()];
+        let results = outbound.deliver(&message(&to)).await;
+        assert!(results[0].reply.is_transient());
+        assert!(!results[0].dsn);
+        assert!(results[0].reply.lines[0].starts_with("4.4.1"));
+    }
+}
@@ -0,0 +1,103 @@
+// A real SMTP server on loopback for outbound mail to be delivered to
+use std::sync::Arc;
+
+use eemail_lib_dns::StaticResolver;
+use eemail_lib_protocols_smtp_server::{Filtering, Mail, handle_smtp};
+use eemail_lib_shared::{BareLfPolicy, SMTPPortConfiguration, VrfyPolicy};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use rustls_pemfile::{certs, private_key};
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::Outbound;
+
+const CERT: &[u8] = include_bytes!("../../../tests/certs/cert.pem");
+const KEY: &[u8] = include_bytes!("../../../tests/certs/key.pem");
+const CA: &[u8] = include_bytes!("../../../tests/certs/ca.pem");
+
+pub fn service_config() -> eemail_component_configurator::Configuration {
+    eemail_component_configurator::Configuration::parse_from_string(
+        include_str!("../../../tests/config.toml").to_string(),
+    )
+    .unwrap()
+}
+
+fn acceptor() -> TlsAcceptor {
+    let cert_chain = certs(&mut &CERT[..]).collect::<Result<_, _>>().unwrap();
+    let key = private_key(&mut &KEY[..]).unwrap().unwrap();
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+// Trusts the test CA that signed `CERT`
+fn connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(&mut &CA[..]) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+// Delivers to `port`, trusting the test certificate only when `trust_test_ca` is set
+pub fn outbound(resolver: StaticResolver, port: u16, trust_test_ca: bool) -> Outbound {
+    let mut outbound = Outbound::new(&service_config(), Arc::new(resolver));
+    outbound.port = port;
+    if trust_test_ca {
+        outbound.tls = connector();
+    }
+    outbound
+}
+
+pub struct Sink {
+    pub port: u16,
+    pub transactions: mpsc::Receiver<Mail>,
+}
+
+// Takes any number of connections, every message accepted comes out of `transactions`
+pub async fn sink(require_tls: bool) -> Sink {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, transactions) = mpsc::channel(16);
+    let config = SMTPPortConfiguration {
+        auth_enabled: false,
+        filtering_enabled: false,
+        implicit_tls: false,
+        port,
+        max_connections: 1,
+        max_connections_per_ip: 1,
+        vrfy_policy: VrfyPolicy::Disabled,
+        max_message_size: 64 * 1024,
+        bare_lf_policy: BareLfPolicy::Reject,
+        require_tls,
+        require_auth: false,
+        check_header_from: false,
+    };
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let _ = handle_smtp(
+                    socket,
+                    config,
+                    acceptor(),
+                    service_config(),
+                    sender,
+                    Filtering {
+                        resolver: Arc::new(StaticResolver::default()),
+                        reports: None,
+                    },
+                )
+                .await;
+            });
+        }
+    });
+
+    Sink { port, transactions }
+}
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+
+use eemail_lib_protocols_smtp_server::Mail;
+use eemail_lib_shared::{BodyType, Dsn, Notify, RecipientDsn, Ret};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Recipient {
+    pub address: String,
+    pub status: Status,
+    // The last reply for this recipient, e.g. "451 4.4.1 ..."
+    pub reply: Option<String>,
+
+    // RFC 3461, as given on RCPT TO
+    pub notify: Option<Notify>,
+    pub orcpt: Option<String>,
+}
+
+impl Recipient {
+    // What the sender wants to hear about this recipient, the default if they didn't say
+    pub fn notify(&self) -> Notify {
+        self.notify.unwrap_or_default()
+    }
+}
+
+// Everything about a queued message apart from the message itself, which is kept in its own file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub id: String,
+    // Empty for the null sender, which is never sent any notices
+    pub from: String,
+    pub body: BodyType,
+    pub smtputf8: bool,
+    // RFC 3461, as given on MAIL FROM
+    pub ret: Option<Ret>,
+    pub envid: Option<String>,
+
+    // Unix timestamps, in seconds
+    pub created: u64,
+    pub next_attempt: u64,
+    pub attempts: u32,
+
+    // Whether the sender has already been told their mail is taking a while
+    pub warned: bool,
+    // Held entries are left alone until they're released
+    pub held: bool,
+
+    pub recipients: Vec<Recipient>,
+}
+
+impl Envelope {
+    // Everyone in `recipients` starts out pending
+    pub fn new(mail: &Mail, recipients: Vec<String>, now: u64) -> Self {
+        Self {
+            id: mail.id.clone(),
+            from: mail.from.clone(),
+            body: mail.body,
+            smtputf8: mail.smtputf8,
+            ret: mail.dsn.ret,
+            envid: mail.dsn.envid.clone(),
+            created: now,
+            next_attempt: now,
+            attempts: 0,
+            warned: false,
+            held: false,
+            recipients: recipients
+                .into_iter()
+                .map(|address| {
+                    let dsn = mail.dsn.recipient(&address);
+                    Recipient {
+                        address,
+                        status: Status::Pending,
+                        reply: None,
+                        notify: dsn.notify,
+                        orcpt: dsn.orcpt,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn pending(&self) -> Vec<String> {
+        self.recipients
+            .iter()
+            .filter(|recipient| recipient.status == Status::Pending)
+            .map(|recipient| recipient.address.clone())
+            .collect()
+    }
+
+    // The DSN parameters to pass on with the message
+    pub fn dsn(&self) -> Dsn {
+        Dsn {
+            ret: self.ret,
+            envid: self.envid.clone(),
+            recipients: self
+                .recipients
+                .iter()
+                .map(|recipient| {
+                    (
+                        recipient.address.clone(),
+                        RecipientDsn {
+                            notify: recipient.notify,
+                            orcpt: recipient.orcpt.clone(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,843 @@
+use anyhow::bail;
+use log::{debug, error, info, warn};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs,
+    sync::{Mutex, Notify, Semaphore, mpsc},
+    task,
+    time::sleep,
+};
+
+use eemail_component_outbound::{Delivery, Outbound};
+use eemail_lib_protocols_smtp_client::Message;
+use eemail_lib_protocols_smtp_server::Mail;
+
+pub use crate::envelope::{Envelope, Recipient, Status};
+pub use crate::notice::format_date;
+
+mod envelope;
+mod notice;
+#[cfg(test)]
+mod test_utils;
+
+const DEFAULT_LIFETIME_HOURS: u64 = 5 * 24;
+const DEFAULT_DELAY_WARNING_HOURS: u64 = 4;
+const DEFAULT_CONCURRENT_DELIVERIES: usize = 20;
+
+// The first retry waits this long, doubling every attempt up to MAX_RETRY
+const MIN_RETRY: Duration = Duration::from_secs(5 * 60);
+const MAX_RETRY: Duration = Duration::from_secs(4 * 60 * 60);
+
+// How long the queue sleeps with nothing due, new mail and flushes wake it up sooner
+const IDLE_WAIT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    // How long a message is retried for before it's given up on
+    pub lifetime: Duration,
+    // How long before the sender is told their mail is delayed
+    pub delay_warning: Duration,
+    // How many delivery attempts run at once, so a big backlog doesn't open every connection in one go
+    pub max_concurrent_deliveries: usize,
+}
+
+impl Settings {
+    pub fn from_config(config: &eemail_component_configurator::Configuration) -> Self {
+        let queue = config.queue.clone().unwrap_or_default();
+        let hours = |hours: u64| Duration::from_secs(hours * 60 * 60);
+        Self {
+            lifetime: hours(queue.lifetime_hours.unwrap_or(DEFAULT_LIFETIME_HOURS)),
+            delay_warning: hours(
+                queue
+                    .delay_warning_hours
+                    .unwrap_or(DEFAULT_DELAY_WARNING_HOURS),
+            ),
+            max_concurrent_deliveries: queue
+                .max_concurrent_deliveries
+                .unwrap_or(DEFAULT_CONCURRENT_DELIVERIES)
+                .max(1),
+        }
+    }
+}
+
+// Outbound mail on disk until every recipient has either taken it or turned it down for good.
+// Each entry is an envelope in `envelopes/<id>.toml` plus the message in `messages/<id>.eml`
+pub struct Queue {
+    envelopes: PathBuf,
+    messages: PathBuf,
+    settings: Settings,
+    outbound: Arc<Outbound>,
+    // Notices for senders are handed back to the SMTP component, as they may be for a local mailbox
+    notices: mpsc::Sender<Mail>,
+
+    // Held while an envelope is read and written back, so nothing changes underneath a delivery attempt
+    lock: Mutex<()>,
+    // Entries with a delivery attempt running, so they're never tried twice at once
+    in_flight: std::sync::Mutex<HashSet<String>>,
+    // One permit per attempt that may run, `settings.max_concurrent_deliveries` of them
+    deliveries: Arc<Semaphore>,
+    wake: Notify,
+}
+
+impl Queue {
+    pub async fn open(
+        path: impl Into<PathBuf>,
+        settings: Settings,
+        outbound: Arc<Outbound>,
+        notices: mpsc::Sender<Mail>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let path = path.into();
+        let envelopes = path.join("envelopes");
+        let messages = path.join("messages");
+        fs::create_dir_all(&envelopes).await?;
+        fs::create_dir_all(&messages).await?;
+
+        Ok(Arc::new(Self {
+            envelopes,
+            messages,
+            settings,
+            outbound,
+            notices,
+            lock: Mutex::new(()),
+            in_flight: std::sync::Mutex::new(HashSet::new()),
+            deliveries: Arc::new(Semaphore::new(settings.max_concurrent_deliveries)),
+            wake: Notify::new(),
+        }))
+    }
+
+    // Stores `mail` for `recipients`, the first attempt is made straight away
+    pub async fn enqueue(&self, mail: &Mail, recipients: Vec<String>) -> anyhow::Result<()> {
+        check_id(&mail.id)?;
+        let envelope = Envelope::new(mail, recipients, now());
+
+        // The envelope is what makes an entry exist, so the message has to be safely on disk first
+        write_atomic(self.message_path(&mail.id), &mail.data).await?;
+        {
+            let _guard = self.lock.lock().await;
+            self.save(&envelope).await?;
+        }
+        debug!("Queued message {} for {:?}", mail.id, envelope.pending());
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    // Tells the sender about mail we kept for our own users in `recipients`, if they asked to hear (RFC 3461 NOTIFY=SUCCESS)
+    pub async fn delivered(&self, mail: &Mail, recipients: Vec<String>) {
+        let now = now();
+        let envelope = Envelope::new(mail, recipients.clone(), now);
+        let notice = notice::delivered(
+            &envelope,
+            &recipients,
+            false,
+            &self.outbound.helo_name,
+            now,
+            &mail.data,
+        );
+        self.send_notice(&mail.id, notice).await;
+    }
+
+    // Every entry, oldest first
+    pub async fn list(&self) -> anyhow::Result<Vec<Envelope>> {
+        let mut envelopes = Vec::new();
+        let mut entries = fs::read_dir(&self.envelopes).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "toml") {
+                continue;
+            }
+            match toml::from_str::<Envelope>(&fs::read_to_string(&path).await?) {
+                Ok(envelope) => envelopes.push(envelope),
+                Err(e) => warn!("Skipping unreadable queue entry {}: {}", path.display(), e),
+            }
+        }
+        envelopes.sort_by_key(|envelope| envelope.created);
+        Ok(envelopes)
+    }
+
+    // Makes the entry due now, false if there's no such entry
+    pub async fn flush(&self, id: &str) -> anyhow::Result<bool> {
+        self.update(id, |envelope| envelope.next_attempt = now())
+            .await
+    }
+
+    pub async fn flush_all(&self) -> anyhow::Result<()> {
+        for envelope in self.list().await? {
+            self.flush(&envelope.id).await?;
+        }
+        Ok(())
+    }
+
+    // Stops the entry being tried (or expiring) until it's released
+    pub async fn hold(&self, id: &str) -> anyhow::Result<bool> {
+        self.update(id, |envelope| envelope.held = true).await
+    }
+
+    pub async fn release(&self, id: &str) -> anyhow::Result<bool> {
+        self.update(id, |envelope| envelope.held = false).await
+    }
+
+    // Drops the entry without telling anyone
+    pub async fn delete(&self, id: &str) -> anyhow::Result<bool> {
+        check_id(id)?;
+        let _guard = self.lock.lock().await;
+        let existed = fs::try_exists(self.envelope_path(id)).await?;
+        self.remove(id).await?;
+        Ok(existed)
+    }
+
+    // Works through the queue forever, making each attempt as it falls due
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let wait = match self.process().await {
+                Ok(wait) => wait,
+                Err(e) => {
+                    error!("Failed to process the outbound queue: {}", e);
+                    IDLE_WAIT
+                }
+            };
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = sleep(wait) => {}
+            }
+        }
+    }
+
+    // Expires, warns about and starts attempts on whatever is due, giving back how long until something else is
+    async fn process(self: &Arc<Self>) -> anyhow::Result<Duration> {
+        let now = now();
+        let lifetime = self.settings.lifetime.as_secs();
+        let delay_warning = self.settings.delay_warning.as_secs();
+        let mut wait = IDLE_WAIT;
+
+        for envelope in self.list().await? {
+            if envelope.held || self.in_flight.lock().unwrap().contains(&envelope.id) {
+                continue;
+            }
+
+            if now >= envelope.created + lifetime {
+                self.expire(&envelope.id).await?;
+                continue;
+            }
+
+            if !envelope.warned && now >= envelope.created + delay_warning {
+                self.warn_delayed(&envelope.id).await?;
+            }
+
+            if envelope.next_attempt <= now {
+                // Left due for the next pass, which every finished attempt sets off
+                let Ok(permit) = self.deliveries.clone().try_acquire_owned() else {
+                    continue;
+                };
+                self.in_flight.lock().unwrap().insert(envelope.id.clone());
+                let queue = self.clone();
+                task::spawn(async move {
+                    if let Err(e) = queue.attempt(&envelope.id).await {
+                        error!("Delivery attempt for {} failed: {}", envelope.id, e);
+                    }
+                    queue.in_flight.lock().unwrap().remove(&envelope.id);
+                    drop(permit);
+                    // Its next attempt may now be sooner than whatever the queue is waiting on
+                    queue.wake.notify_one();
+                });
+            } else {
+                wait = wait.min(Duration::from_secs(envelope.next_attempt - now));
+            }
+        }
+
+        Ok(wait)
+    }
+
+    // Tries every pending recipient once and records how it went, the sender hears about any that failed for good
+    async fn attempt(&self, id: &str) -> anyhow::Result<()> {
+        let Some(envelope) = self.load(id).await? else {
+            return Ok(());
+        };
+        let pending = envelope.pending();
+        let data = fs::read(self.message_path(id)).await?;
+
+        let dsn = envelope.dsn();
+        let message = Message {
+            from: &envelope.from,
+            to: &pending,
+            body: envelope.body,
+            smtputf8: envelope.smtputf8,
+            dsn: &dsn,
+            data: &data,
+        };
+        let results = self.outbound.deliver(&message).await;
+
+        let guard = self.lock.lock().await;
+        // It may have been deleted while we were busy
+        let Some(mut envelope) = self.load(id).await? else {
+            return Ok(());
+        };
+        let mut failed = Vec::new();
+        let mut relayed = Vec::new();
+        for Delivery {
+            recipient: address,
+            reply,
+            dsn,
+        } in results
+        {
+            let Some(recipient) = envelope
+                .recipients
+                .iter_mut()
+                .find(|recipient| recipient.address == address)
+            else {
+                continue;
+            };
+            if reply.is_positive() {
+                info!("Message {} delivered to {}: {}", id, address, reply);
+                recipient.status = Status::Delivered;
+                // RFC 3461, once it's with a server that won't report on it the sender is told as much
+                if !dsn {
+                    relayed.push(address);
+                }
+            } else if reply.is_permanent() {
+                warn!("Message {} rejected for {}: {}", id, address, reply);
+                recipient.status = Status::Failed;
+                failed.push(address);
+            } else {
+                debug!("Message {} deferred for {}: {}", id, address, reply);
+            }
+            recipient.reply = Some(reply.to_string());
+        }
+
+        envelope.attempts += 1;
+        envelope.next_attempt = now() + retry_delay(envelope.attempts).as_secs();
+
+        if envelope.pending().is_empty() {
+            debug!("Message {} is finished with, removing it", id);
+            self.remove(id).await?;
+        } else {
+            self.save(&envelope).await?;
+        }
+        drop(guard);
+
+        if !relayed.is_empty() {
+            let notice = notice::delivered(
+                &envelope,
+                &relayed,
+                true,
+                &self.outbound.helo_name,
+                now(),
+                &data,
+            );
+            self.send_notice(id, notice).await;
+        }
+        if !failed.is_empty() {
+            let notice = notice::failure(
+                &envelope,
+                &failed,
+                false,
+                &self.outbound.helo_name,
+                now(),
+                &data,
+            );
+            self.send_notice(id, notice).await;
+        }
+        Ok(())
+    }
+
+    // Gives up on everyone still pending, and tells the sender so
+    async fn expire(&self, id: &str) -> anyhow::Result<()> {
+        let guard = self.lock.lock().await;
+        let Some(envelope) = self.load(id).await? else {
+            return Ok(());
+        };
+        let expired = envelope.pending();
+        for recipient in &expired {
+            warn!(
+                "Message {} expired without being delivered to {}",
+                id, recipient
+            );
+        }
+        // A missing message still gets a report, just without anything returned
+        let data = fs::read(self.message_path(id)).await.unwrap_or_default();
+        self.remove(id).await?;
+        drop(guard);
+
+        let notice = notice::failure(
+            &envelope,
+            &expired,
+            true,
+            &self.outbound.helo_name,
+            now(),
+            &data,
+        );
+        self.send_notice(id, notice).await;
+        Ok(())
+    }
+
+    async fn warn_delayed(&self, id: &str) -> anyhow::Result<()> {
+        let guard = self.lock.lock().await;
+        let Some(mut envelope) = self.load(id).await? else {
+            return Ok(());
+        };
+        envelope.warned = true;
+        self.save(&envelope).await?;
+        let data = fs::read(self.message_path(id)).await.unwrap_or_default();
+        drop(guard);
+
+        let expires = envelope.created + self.settings.lifetime.as_secs();
+        let notice =
+            notice::delay_warning(&envelope, &self.outbound.helo_name, now(), expires, &data);
+        self.send_notice(id, notice).await;
+        Ok(())
+    }
+
+    // Sent without the lock held, whatever takes notices may well queue them straight back up
+    async fn send_notice(&self, id: &str, notice: Option<Mail>) {
+        let Some(notice) = notice else {
+            return;
+        };
+        info!("Sending {} a report about message {}", notice.to[0], id);
+        if self.notices.send(notice).await.is_err() {
+            error!(
+                "Nothing is taking queue notices, the report about {} is lost",
+                id
+            );
+        }
+    }
+
+    async fn update(&self, id: &str, change: impl FnOnce(&mut Envelope)) -> anyhow::Result<bool> {
+        check_id(id)?;
+        {
+            let _guard = self.lock.lock().await;
+            let Some(mut envelope) = self.load(id).await? else {
+                return Ok(false);
+            };
+            change(&mut envelope);
+            self.save(&envelope).await?;
+        }
+        self.wake.notify_one();
+        Ok(true)
+    }
+
+    async fn load(&self, id: &str) -> anyhow::Result<Option<Envelope>> {
+        match fs::read_to_string(self.envelope_path(id)).await {
+            Ok(contents) => Ok(Some(toml::from_str(&contents)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save(&self, envelope: &Envelope) -> anyhow::Result<()> {
+        write_atomic(
+            self.envelope_path(&envelope.id),
+            toml::to_string(envelope)?.as_bytes(),
+        )
+        .await
+    }
+
+    // Envelope first, so a crash part way through never leaves an entry without its message
+    async fn remove(&self, id: &str) -> anyhow::Result<()> {
+        for path in [self.envelope_path(id), self.message_path(id)] {
+            match fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn envelope_path(&self, id: &str) -> PathBuf {
+        self.envelopes.join(format!("{}.toml", id))
+    }
+
+    fn message_path(&self, id: &str) -> PathBuf {
+        self.messages.join(format!("{}.eml", id))
+    }
+}
+
+// Ids end up in file names, so only what a UUID can contain is allowed
+fn check_id(id: &str) -> anyhow::Result<()> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        bail!("Invalid queue id {:?}", id);
+    }
+    Ok(())
+}
+
+// A reader never sees half a file, it's either the old one or the new one
+async fn write_atomic(path: PathBuf, contents: &[u8]) -> anyhow::Result<()> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, contents).await?;
+    fs::rename(&temporary, &path).await?;
+    Ok(())
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    let doublings = attempts.saturating_sub(1).min(16);
+    (MIN_RETRY * 2u32.pow(doublings)).min(MAX_RETRY)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_dns::StaticResolver;
+    use eemail_lib_shared::{BodyType, Notify, RecipientDsn};
+
+    use super::*;
+    use crate::test_utils::*;
+
+    fn wants_success() -> RecipientDsn {
+        RecipientDsn {
+            notify: Some(Notify {
+                success: true,
+                failure: true,
+                delay: false,
+            }),
+            orcpt: None,
+        }
+    }
+
+    fn mail(id: &str) -> Mail {
+        Mail {
+            id: id.to_string(),
+            from: "sender@example.org".to_string(),
+            body: BodyType::SevenBit,
+            data: b"Subject: queued\r\n\r\nHi\r\n".to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn retry_delay_backs_off_exponentially() {
+        assert_eq!(retry_delay(1), Duration::from_secs(5 * 60));
+        assert_eq!(retry_delay(2), Duration::from_secs(10 * 60));
+        assert_eq!(retry_delay(3), Duration::from_secs(20 * 60));
+        assert_eq!(retry_delay(7), MAX_RETRY);
+        assert_eq!(retry_delay(1000), MAX_RETRY);
+    }
+
+    #[tokio::test]
+    async fn queue_survives_being_reopened() {
+        let directory = TempDir::new();
+        let (queue, _notices) = open_queue(&directory, StaticResolver::default(), 0).await;
+        queue
+            .enqueue(&mail("one"), vec!["a@example.com".to_string()])
+            .await
+            .unwrap();
+        drop(queue);
+
+        assert!(directory.path().join("messages/one.eml").exists());
+        let (queue, _notices) = open_queue(&directory, StaticResolver::default(), 0).await;
+        let entries = queue.list().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, "one");
+        assert_eq!(entries[0].from, "sender@example.org");
+        assert_eq!(entries[0].pending(), ["a@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn queue_removes_delivered_mail() {
+        let directory = TempDir::new();
+        let mut sink = sink().await;
+        let resolver = StaticResolver::default().ip("example.com", "127.0.0.1".parse().unwrap());
+        let (queue, mut notices) = open_queue(&directory, resolver, sink.port).await;
+        let mut mail = mail("delivered");
+        mail.dsn
+            .recipients
+            .insert("test@example.com".to_string(), wants_success());
+        queue
+            .enqueue(
+                &mail,
+                vec![
+                    "test@example.com".to_string(),
+                    "nobody@example.com".to_string(),
+                ],
+            )
+            .await
+            .unwrap();
+
+        queue.attempt("delivered").await.unwrap();
+        assert!(queue.list().await.unwrap().is_empty());
+        assert!(!directory.path().join("messages/delivered.eml").exists());
+
+        let mail = sink.transactions.recv().await.unwrap();
+        assert_eq!(mail.to, ["test@example.com"]);
+        assert_eq!(mail.data, b"Subject: queued\r\n\r\nHi\r\n");
+        // The sink offers DSN, so reporting the success is up to it
+        let bounce = String::from_utf8(notices.recv().await.unwrap().data).unwrap();
+        assert!(bounce.contains("Action: failed\r\n"));
+        assert!(notices.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn queue_reports_relaying_to_servers_without_dsn() {
+        let directory = TempDir::new();
+        let (port, relay) = relay().await;
+        let resolver = StaticResolver::default().ip("example.com", "127.0.0.1".parse().unwrap());
+        let (queue, mut notices) = open_queue(&directory, resolver, port).await;
+        let mut mail = mail("relayed");
+        mail.dsn
+            .recipients
+            .insert("loud@example.com".to_string(), wants_success());
+        queue
+            .enqueue(
+                &mail,
+                vec![
+                    "loud@example.com".to_string(),
+                    "quiet@example.com".to_string(),
+                ],
+            )
+            .await
+            .unwrap();
+
+        queue.attempt("relayed").await.unwrap();
+        assert!(queue.list().await.unwrap().is_empty());
+        assert_eq!(
+            relay.await.unwrap(),
+            ["loud@example.com", "quiet@example.com"]
+        );
+
+        let notice = notices.recv().await.unwrap();
+        assert_eq!(notice.to, ["sender@example.org"]);
+        let data = String::from_utf8(notice.data).unwrap();
+        assert!(data.contains(
+            "Final-Recipient: rfc822; loud@example.com\r\n\
+             Action: relayed\r\n\
+             Status: 2.0.0\r\n"
+        ));
+        assert!(!data.contains("quiet@example.com"));
+        assert!(notices.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn queue_reports_local_delivery_when_asked() {
+        let directory = TempDir::new();
+        let (queue, mut notices) = open_queue(&directory, StaticResolver::default(), 0).await;
+        let mut mail = mail("local");
+        mail.dsn
+            .recipients
+            .insert("hi@example.com".to_string(), wants_success());
+
+        queue
+            .delivered(&mail, vec!["test@example.com".to_string()])
+            .await;
+        assert!(notices.try_recv().is_err());
+
+        queue
+            .delivered(&mail, vec!["hi@example.com".to_string()])
+            .await;
+        let data = String::from_utf8(notices.recv().await.unwrap().data).unwrap();
+        assert!(data.contains(
+            "Final-Recipient: rfc822; hi@example.com\r\n\
+             Action: delivered\r\n\
+             Status: 2.0.0\r\n"
+        ));
+        // Nothing was queued for it
+        assert!(queue.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn queue_keeps_deferred_mail_for_later() {
+        let directory = TempDir::new();
+        let resolver = StaticResolver::default()
+            .fail("example.com")
+            .mx("example.net", 10, ".");
+        let (queue, mut notices) = open_queue(&directory, resolver, 0).await;
+        queue
+            .enqueue(
+                &mail("deferred"),
+                vec!["a@example.com".to_string(), "b@example.net".to_string()],
+            )
+            .await
+            .unwrap();
+
+        queue.attempt("deferred").await.unwrap();
+        let entry = &queue.list().await.unwrap()[0];
+        assert_eq!(entry.attempts, 1);
+        assert!(entry.next_attempt >= now() + MIN_RETRY.as_secs() - 1);
+        assert_eq!(entry.pending(), ["a@example.com"]);
+        assert_eq!(entry.recipients[1].status, Status::Failed);
+        assert!(
+            entry.recipients[0]
+                .reply
+                .as_ref()
+                .unwrap()
+                .starts_with("451 4.4.3")
+        );
+        assert!(
+            entry.recipients[1]
+                .reply
+                .as_ref()
+                .unwrap()
+                .starts_with("556")
+        );
+
+        // Only the permanent failure is bounced, the other recipient is still being tried
+        let bounce = String::from_utf8(notices.recv().await.unwrap().data).unwrap();
+        assert!(bounce.contains("Final-Recipient: rfc822; b@example.net\r\nAction: failed\r\n"));
+        assert!(!bounce.contains("rfc822; a@example.com"));
+        assert!(notices.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn queue_keeps_dsn_parameters() {
+        let directory = TempDir::new();
+        let resolver = StaticResolver::default().mx("example.net", 10, ".");
+        let (queue, mut notices) = open_queue(&directory, resolver, 0).await;
+        let mut mail = mail("dsn");
+        mail.dsn.envid = Some("envelope-1".to_string());
+        mail.dsn.recipients.insert(
+            "quiet@example.net".to_string(),
+            RecipientDsn {
+                notify: Some(Notify::NEVER),
+                orcpt: None,
+            },
+        );
+        queue
+            .enqueue(
+                &mail,
+                vec![
+                    "quiet@example.net".to_string(),
+                    "loud@example.net".to_string(),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let entry = &queue.list().await.unwrap()[0];
+        assert_eq!(entry.envid.as_deref(), Some("envelope-1"));
+        assert_eq!(
+            entry.dsn().recipient("quiet@example.net").notify,
+            Some(Notify::NEVER)
+        );
+
+        queue.attempt("dsn").await.unwrap();
+        let bounce = String::from_utf8(notices.recv().await.unwrap().data).unwrap();
+        assert!(bounce.contains("Original-Envelope-Id: envelope-1\r\n"));
+        assert!(bounce.contains("loud@example.net"));
+        assert!(!bounce.contains("quiet@example.net"));
+    }
+
+    #[tokio::test]
+    async fn queue_can_hold_flush_and_delete_entries() {
+        let directory = TempDir::new();
+        let resolver = StaticResolver::default().fail("example.com");
+        let (queue, _notices) = open_queue(&directory, resolver, 0).await;
+        queue
+            .enqueue(&mail("managed"), vec!["a@example.com".to_string()])
+            .await
+            .unwrap();
+
+        assert!(queue.hold("managed").await.unwrap());
+        assert!(queue.list().await.unwrap()[0].held);
+        assert!(queue.release("managed").await.unwrap());
+        assert!(!queue.list().await.unwrap()[0].held);
+
+        queue.attempt("managed").await.unwrap();
+        assert!(queue.list().await.unwrap()[0].next_attempt > now());
+        assert!(queue.flush("managed").await.unwrap());
+        assert!(queue.list().await.unwrap()[0].next_attempt <= now());
+
+        assert!(queue.delete("managed").await.unwrap());
+        assert!(!queue.delete("managed").await.unwrap());
+        assert!(!queue.hold("managed").await.unwrap());
+        assert!(queue.list().await.unwrap().is_empty());
+        assert!(!directory.path().join("messages/managed.eml").exists());
+
+        assert!(queue.delete("../escape").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn queue_limits_concurrent_deliveries() {
+        let directory = TempDir::new();
+        let resolver = StaticResolver::default().fail("example.com");
+        let mut settings = settings();
+        settings.max_concurrent_deliveries = 1;
+        let (queue, _notices) = open_queue_with(&directory, resolver, 0, settings).await;
+        for id in ["first", "second"] {
+            queue
+                .enqueue(&mail(id), vec!["a@example.com".to_string()])
+                .await
+                .unwrap();
+        }
+
+        // Only one attempt gets started, the other entry is still due once it's done
+        queue.process().await.unwrap();
+        assert_eq!(queue.in_flight.lock().unwrap().len(), 1);
+        while !queue.in_flight.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let waiting: Vec<String> = queue
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|envelope| envelope.attempts == 0)
+            .map(|envelope| envelope.id)
+            .collect();
+        assert_eq!(waiting.len(), 1);
+
+        queue.process().await.unwrap();
+        assert!(queue.in_flight.lock().unwrap().contains(&waiting[0]));
+        while !queue.in_flight.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert!(
+            queue
+                .list()
+                .await
+                .unwrap()
+                .iter()
+                .all(|envelope| envelope.attempts == 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn queue_warns_about_delays_once_and_expires_mail() {
+        let directory = TempDir::new();
+        let resolver = StaticResolver::default().fail("example.com");
+        let mut settings = settings();
+        settings.delay_warning = Duration::ZERO;
+        let (queue, mut notices) = open_queue_with(&directory, resolver.clone(), 0, settings).await;
+        queue
+            .enqueue(&mail("slow"), vec!["a@example.com".to_string()])
+            .await
+            .unwrap();
+        queue.hold("slow").await.unwrap();
+
+        // Held entries are left alone entirely
+        queue.process().await.unwrap();
+        assert!(notices.try_recv().is_err());
+
+        queue.release("slow").await.unwrap();
+        queue.process().await.unwrap();
+        let notice = notices.recv().await.unwrap();
+        assert_eq!(notice.from, "");
+        assert_eq!(notice.to, ["sender@example.org"]);
+        assert!(queue.list().await.unwrap()[0].warned);
+
+        // Wait out the attempt that was started, the entry is skipped while it runs
+        while !queue.in_flight.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        queue.flush("slow").await.unwrap();
+        queue.process().await.unwrap();
+        assert!(notices.try_recv().is_err());
+        while !queue.in_flight.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        settings.lifetime = Duration::ZERO;
+        let (queue, mut notices) = open_queue_with(&directory, resolver, 0, settings).await;
+        queue.process().await.unwrap();
+        assert!(queue.list().await.unwrap().is_empty());
+        let bounce = String::from_utf8(notices.recv().await.unwrap().data).unwrap();
+        assert!(bounce.contains("Status: 4.4.7\r\n"));
+        assert!(bounce.contains("Subject: queued\r\n\r\nHi\r\n"));
+    }
+}
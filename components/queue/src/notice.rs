@@ -0,0 +1,530 @@
+// Delivery status notifications (RFC 3464) the queue sends back to senders about their mail
+use uuid::Uuid;
+
+use eemail_lib_protocols_smtp_server::Mail;
+use eemail_lib_shared::{BodyType, Ret};
+
+use crate::envelope::{Envelope, Recipient, Status};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+// 1970-01-01 was a Thursday
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+// Tells the sender some of their recipients haven't got the message yet, and when we'll stop trying
+pub fn delay_warning(
+    envelope: &Envelope,
+    fqdn: &str,
+    now: u64,
+    expires: u64,
+    data: &[u8],
+) -> Option<Mail> {
+    let recipients: Vec<(&Recipient, String)> = envelope
+        .recipients
+        .iter()
+        .filter(|recipient| recipient.status == Status::Pending && recipient.notify().delay)
+        .map(|recipient| {
+            let fields = format!(
+                "Action: delayed\r\n\
+                 Status: {}\r\n\
+                 Will-Retry-Until: {}\r\n",
+                status(recipient.reply.as_deref(), "4.0.0"),
+                format_date(expires)
+            );
+            (recipient, fields)
+        })
+        .collect();
+
+    let text = format!(
+        "This is the mail system at {fqdn}.\r\n\r\n\
+         Your message could not be delivered to the following recipients yet.\r\n\
+         It will be retried until {}, there is nothing you need to do.\r\n\r\n",
+        format_date(expires)
+    );
+    report(
+        envelope,
+        fqdn,
+        now,
+        "Delayed Mail (still being retried)",
+        text,
+        &recipients,
+        headers(data),
+    )
+}
+
+// RFC 3464, tells the sender the message will never reach `failed`. Once it has `expired` they were still being retried
+pub fn failure(
+    envelope: &Envelope,
+    failed: &[String],
+    expired: bool,
+    fqdn: &str,
+    now: u64,
+    data: &[u8],
+) -> Option<Mail> {
+    let recipients: Vec<(&Recipient, String)> = envelope
+        .recipients
+        .iter()
+        .filter(|recipient| failed.contains(&recipient.address) && recipient.notify().failure)
+        .map(|recipient| {
+            // RFC 3463 X.4.7, delivery time expired
+            let status = if expired {
+                "4.4.7".to_string()
+            } else {
+                status(recipient.reply.as_deref(), "5.0.0")
+            };
+            (
+                recipient,
+                format!("Action: failed\r\nStatus: {}\r\n", status),
+            )
+        })
+        .collect();
+
+    let text = format!(
+        "This is the mail system at {fqdn}.\r\n\r\n\
+         Your message could not be delivered to the following recipients{}.\r\n\
+         It will not be retried.\r\n\r\n",
+        if expired {
+            " before it expired from the queue"
+        } else {
+            ""
+        }
+    );
+    // Binary can't be returned inside a report that may have to go over DATA, so it only gets the headers
+    let returned = if envelope.ret == Some(Ret::Headers) || envelope.body == BodyType::BinaryMime {
+        headers(data)
+    } else {
+        Returned::Full(data)
+    };
+
+    report(
+        envelope,
+        fqdn,
+        now,
+        "Undelivered Mail Returned to Sender",
+        text,
+        &recipients,
+        returned,
+    )
+}
+
+// RFC 3461 6.2, tells the sender the message reached `delivered`. Mail we keep ourselves has been delivered,
+// mail handed to a server without DSN has been `relayed` and won't be reported on any further
+pub fn delivered(
+    envelope: &Envelope,
+    delivered: &[String],
+    relayed: bool,
+    fqdn: &str,
+    now: u64,
+    data: &[u8],
+) -> Option<Mail> {
+    let action = if relayed { "relayed" } else { "delivered" };
+    let recipients: Vec<(&Recipient, String)> = envelope
+        .recipients
+        .iter()
+        .filter(|recipient| delivered.contains(&recipient.address) && recipient.notify().success)
+        .map(|recipient| {
+            (
+                recipient,
+                format!(
+                    "Action: {}\r\nStatus: {}\r\n",
+                    action,
+                    status(recipient.reply.as_deref(), "2.0.0")
+                ),
+            )
+        })
+        .collect();
+
+    let text = format!(
+        "This is the mail system at {fqdn}.\r\n\r\n\
+         Your message was {}.\r\n\r\n",
+        if relayed {
+            "passed on to the following recipients, their mail system won't report on it any further"
+        } else {
+            "delivered to the following recipients"
+        }
+    );
+    // RFC 3461 4.3, the full message only ever goes back with a failure
+    report(
+        envelope,
+        fqdn,
+        now,
+        "Successful Mail Delivery Report",
+        text,
+        &recipients,
+        headers(data),
+    )
+}
+
+// How much of the original message goes back with a report
+enum Returned<'a> {
+    Full(&'a [u8]),
+    Headers(&'a [u8]),
+}
+
+// Everything up to and including the blank line after the header fields
+fn headers(data: &[u8]) -> Returned<'_> {
+    let end = data
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(data.len(), |position| position + 4);
+    Returned::Headers(&data[..end])
+}
+
+// The enhanced status code from a reply like "550 5.1.1 No such user", or `fallback` if it didn't have one
+fn status(reply: Option<&str>, fallback: &str) -> String {
+    let Some(code) = reply.and_then(|reply| reply.split_whitespace().nth(1)) else {
+        return fallback.to_string();
+    };
+    let parts: Vec<&str> = code.split('.').collect();
+    let valid = parts.len() == 3
+        && matches!(parts[0], "2" | "4" | "5")
+        && parts[1..]
+            .iter()
+            .all(|part| (1..=3).contains(&part.len()) && part.bytes().all(|b| b.is_ascii_digit()));
+    if valid {
+        code.to_string()
+    } else {
+        fallback.to_string()
+    }
+}
+
+// RFC 6533 3, UTF-8 addresses get their own type
+fn address_type(address: &str) -> &'static str {
+    if address.is_ascii() {
+        "rfc822"
+    } else {
+        "utf-8"
+    }
+}
+
+fn describe(recipient: &Recipient) -> String {
+    match &recipient.reply {
+        Some(reply) => format!("<{}>: {}\r\n", recipient.address, reply),
+        None => format!("<{}>\r\n", recipient.address),
+    }
+}
+
+// RFC 3462, a multipart/report with the human readable text, the delivery status and some or all of the message.
+// Nothing is sent to the null sender, or if no recipient wanted to hear about it
+fn report(
+    envelope: &Envelope,
+    fqdn: &str,
+    now: u64,
+    subject: &str,
+    mut text: String,
+    // Each with its Action, Status and anything else that depends on the kind of report
+    recipients: &[(&Recipient, String)],
+    returned: Returned,
+) -> Option<Mail> {
+    if envelope.from.is_empty() || recipients.is_empty() {
+        return None;
+    }
+
+    let id = Uuid::now_v7().to_string();
+    let boundary = format!("{}/{}", id, fqdn);
+    // RFC 6533, reports about internationalised mail use the global types throughout
+    let (status_type, full_type, headers_type) = if envelope.smtputf8 {
+        (
+            "message/global-delivery-status",
+            "message/global",
+            "message/global-headers",
+        )
+    } else {
+        (
+            "message/delivery-status",
+            "message/rfc822",
+            "text/rfc822-headers",
+        )
+    };
+
+    let mut status = format!("Reporting-MTA: dns; {fqdn}\r\n");
+    if let Some(envid) = &envelope.envid {
+        status.push_str(&format!("Original-Envelope-Id: {}\r\n", envid));
+    }
+    status.push_str(&format!(
+        "Arrival-Date: {}\r\n",
+        format_date(envelope.created)
+    ));
+    for (recipient, fields) in recipients {
+        text.push_str(&describe(recipient));
+
+        status.push_str("\r\n");
+        if let Some(orcpt) = &recipient.orcpt {
+            status.push_str(&format!("Original-Recipient: {}\r\n", orcpt));
+        }
+        status.push_str(&format!(
+            "Final-Recipient: {}; {}\r\n",
+            address_type(&recipient.address),
+            recipient.address
+        ));
+        status.push_str(fields);
+        if let Some(reply) = &recipient.reply {
+            status.push_str(&format!("Diagnostic-Code: smtp; {}\r\n", reply));
+        }
+    }
+
+    let (content_type, content) = match returned {
+        Returned::Full(data) => (full_type, data),
+        Returned::Headers(data) => (headers_type, data),
+    };
+
+    let mut data = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{fqdn}>\r\n\
+         To: <{}>\r\n\
+         Subject: {subject}\r\n\
+         Date: {}\r\n\
+         Message-ID: <{id}@{fqdn}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n\
+         \tboundary=\"{boundary}\"\r\n\
+         \r\n\
+         This is a MIME-encapsulated delivery status notification.\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\
+         \r\n\
+         {text}\r\n\
+         --{boundary}\r\n\
+         Content-Type: {status_type}\r\n\
+         \r\n\
+         {status}\r\n\
+         --{boundary}\r\n\
+         Content-Type: {content_type}\r\n",
+        envelope.from,
+        format_date(now),
+    )
+    .into_bytes();
+    if !content.is_ascii() {
+        data.extend_from_slice(b"Content-Transfer-Encoding: 8bit\r\n");
+    }
+    data.extend_from_slice(b"\r\n");
+    data.extend_from_slice(content);
+    if !content.ends_with(b"\r\n") {
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    Some(Mail {
+        id,
+        from: String::new(),
+        to: vec![envelope.from.clone()],
+        body: if data.is_ascii() {
+            BodyType::SevenBit
+        } else {
+            BodyType::EightBitMime
+        },
+        smtputf8: !envelope.from.is_ascii(),
+        data,
+        ..Default::default()
+    })
+}
+
+// RFC 5322 3.3, always given in UTC
+pub fn format_date(unix: u64) -> String {
+    let days = unix / 86400;
+    let seconds = unix % 86400;
+
+    // Howard Hinnant's days to civil date conversion
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_shared::Notify;
+
+    use super::*;
+
+    #[test]
+    fn dates_are_formatted_in_utc() {
+        assert_eq!(format_date(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        assert_eq!(format_date(951782400), "Tue, 29 Feb 2000 00:00:00 +0000");
+        assert_eq!(format_date(1700000000), "Tue, 14 Nov 2023 22:13:20 +0000");
+    }
+
+    fn envelope() -> Envelope {
+        let recipient = |address: &str, status, reply: &str| Recipient {
+            address: address.to_string(),
+            status,
+            reply: Some(reply.to_string()),
+            notify: None,
+            orcpt: None,
+        };
+        Envelope {
+            id: "id".to_string(),
+            from: "sender@example.com".to_string(),
+            body: BodyType::SevenBit,
+            smtputf8: false,
+            ret: None,
+            envid: Some("envelope-1".to_string()),
+            created: 0,
+            next_attempt: 0,
+            attempts: 2,
+            warned: false,
+            held: false,
+            recipients: vec![
+                recipient("late@example.org", Status::Pending, "451 4.4.1 Try again"),
+                recipient("done@example.org", Status::Delivered, "250 2.0.0 OK"),
+                recipient("gone@example.org", Status::Failed, "550 5.1.1 No such user"),
+            ],
+        }
+    }
+
+    const MESSAGE: &[u8] = b"Subject: hello\r\n\r\nThe body\r\n";
+
+    #[test]
+    fn delay_warning_lists_pending_recipients() {
+        let mail = delay_warning(&envelope(), "example.com", 0, 86400, MESSAGE).unwrap();
+        let data = String::from_utf8(mail.data).unwrap();
+        assert_eq!(mail.from, "");
+        assert_eq!(mail.to, ["sender@example.com"]);
+        assert!(data.contains("\r\nAuto-Submitted: auto-replied\r\n"));
+        assert!(data.contains("multipart/report; report-type=delivery-status;"));
+        assert!(data.contains("<late@example.org>: 451 4.4.1 Try again\r\n"));
+        assert!(data.contains(
+            "Final-Recipient: rfc822; late@example.org\r\n\
+             Action: delayed\r\n\
+             Status: 4.4.1\r\n\
+             Will-Retry-Until: Fri, 02 Jan 1970 00:00:00 +0000\r\n\
+             Diagnostic-Code: smtp; 451 4.4.1 Try again\r\n"
+        ));
+        assert!(!data.contains("done@example.org"));
+        assert!(data.contains("until Fri, 02 Jan 1970 00:00:00 +0000"));
+
+        // Only the headers go back with a warning
+        assert!(data.contains("Content-Type: text/rfc822-headers\r\n\r\nSubject: hello\r\n\r\n"));
+        assert!(!data.contains("The body"));
+    }
+
+    #[test]
+    fn failure_report_follows_rfc_3464() {
+        let mut envelope = envelope();
+        envelope.recipients[2].orcpt = Some("rfc822;alias@example.org".to_string());
+        let failed = ["gone@example.org".to_string()];
+        let mail = failure(&envelope, &failed, false, "example.com", 0, MESSAGE).unwrap();
+        let data = String::from_utf8(mail.data).unwrap();
+        assert_eq!(mail.to, ["sender@example.com"]);
+        assert!(data.contains("Subject: Undelivered Mail Returned to Sender\r\n"));
+        assert!(data.contains(
+            "Content-Type: message/delivery-status\r\n\
+             \r\n\
+             Reporting-MTA: dns; example.com\r\n\
+             Original-Envelope-Id: envelope-1\r\n\
+             Arrival-Date: Thu, 01 Jan 1970 00:00:00 +0000\r\n\
+             \r\n\
+             Original-Recipient: rfc822;alias@example.org\r\n\
+             Final-Recipient: rfc822; gone@example.org\r\n\
+             Action: failed\r\n\
+             Status: 5.1.1\r\n\
+             Diagnostic-Code: smtp; 550 5.1.1 No such user\r\n"
+        ));
+        assert!(!data.contains("late@example.org"));
+        assert!(
+            data.contains("Content-Type: message/rfc822\r\n\r\nSubject: hello\r\n\r\nThe body\r\n")
+        );
+        assert!(data.ends_with("--\r\n"));
+
+        envelope.ret = Some(Ret::Headers);
+        let mail = failure(&envelope, &failed, false, "example.com", 0, MESSAGE).unwrap();
+        assert!(!String::from_utf8(mail.data).unwrap().contains("The body"));
+    }
+
+    #[test]
+    fn failure_report_on_expiry() {
+        let failed = ["late@example.org".to_string()];
+        let mail = failure(&envelope(), &failed, true, "example.com", 0, MESSAGE).unwrap();
+        let data = String::from_utf8(mail.data).unwrap();
+        assert!(data.contains("before it expired from the queue"));
+        assert!(data.contains("Action: failed\r\nStatus: 4.4.7\r\n"));
+    }
+
+    #[test]
+    fn success_reports_are_only_for_those_who_asked() {
+        let mut envelope = envelope();
+        let delivered = ["done@example.org".to_string()];
+        assert!(delivered_report(&envelope, &delivered, false).is_none());
+
+        envelope.recipients[1].notify = Some(Notify {
+            success: true,
+            failure: true,
+            delay: false,
+        });
+        let data = delivered_report(&envelope, &delivered, false).unwrap();
+        assert!(data.contains("Subject: Successful Mail Delivery Report\r\n"));
+        assert!(data.contains(
+            "Final-Recipient: rfc822; done@example.org\r\n\
+             Action: delivered\r\n\
+             Status: 2.0.0\r\n"
+        ));
+        assert!(!data.contains("gone@example.org"));
+        // Only the headers, even though RET wasn't HDRS
+        assert!(data.contains("Content-Type: text/rfc822-headers\r\n"));
+        assert!(!data.contains("The body"));
+
+        let data = delivered_report(&envelope, &delivered, true).unwrap();
+        assert!(data.contains("won't report on it any further"));
+        assert!(data.contains("Action: relayed\r\n"));
+    }
+
+    fn delivered_report(
+        envelope: &Envelope,
+        recipients: &[String],
+        relayed: bool,
+    ) -> Option<String> {
+        delivered(envelope, recipients, relayed, "example.com", 0, MESSAGE)
+            .map(|mail| String::from_utf8(mail.data).unwrap())
+    }
+
+    #[test]
+    fn reports_respect_notify_and_the_null_sender() {
+        let mut envelope = envelope();
+        let failed = ["gone@example.org".to_string()];
+        envelope.recipients[2].notify = Some(Notify::NEVER);
+        assert!(failure(&envelope, &failed, false, "example.com", 0, MESSAGE).is_none());
+
+        envelope.recipients[0].notify = Some(Notify {
+            success: false,
+            failure: true,
+            delay: false,
+        });
+        assert!(delay_warning(&envelope, "example.com", 0, 0, MESSAGE).is_none());
+
+        let mut envelope = self::envelope();
+        envelope.from = String::new();
+        assert!(failure(&envelope, &failed, false, "example.com", 0, MESSAGE).is_none());
+    }
+
+    #[test]
+    fn status_comes_from_the_reply() {
+        assert_eq!(status(Some("550 5.7.1 Denied"), "5.0.0"), "5.7.1");
+        assert_eq!(status(Some("550 Denied"), "5.0.0"), "5.0.0");
+        assert_eq!(status(Some("550 5.x.1 Denied"), "5.0.0"), "5.0.0");
+        assert_eq!(status(None, "4.0.0"), "4.0.0");
+    }
+}
@@ -0,0 +1,187 @@
+// Scratch queue directories, plus a real SMTP server on loopback for queued mail to be delivered to
+use std::{path::PathBuf, sync::Arc};
+
+use eemail_component_outbound::Outbound;
+use eemail_lib_dns::StaticResolver;
+use eemail_lib_protocols_smtp_server::{Filtering, Mail, handle_smtp};
+use eemail_lib_shared::{BareLfPolicy, SMTPPortConfiguration, VrfyPolicy};
+use rustls::ServerConfig;
+use rustls_pemfile::{certs, private_key};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tokio_rustls::TlsAcceptor;
+use uuid::Uuid;
+
+use crate::{Queue, Settings};
+
+const CERT: &[u8] = include_bytes!("../../../tests/certs/cert.pem");
+const KEY: &[u8] = include_bytes!("../../../tests/certs/key.pem");
+
+pub fn service_config() -> eemail_component_configurator::Configuration {
+    eemail_component_configurator::Configuration::parse_from_string(
+        include_str!("../../../tests/config.toml").to_string(),
+    )
+    .unwrap()
+}
+
+// Removed again when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("eemail-queue-{}", Uuid::now_v7()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+pub fn settings() -> Settings {
+    Settings::from_config(&service_config())
+}
+
+// A queue in `directory` that delivers to `port`, notices come out of the receiver
+pub async fn open_queue(
+    directory: &TempDir,
+    resolver: StaticResolver,
+    port: u16,
+) -> (Arc<Queue>, mpsc::Receiver<Mail>) {
+    open_queue_with(directory, resolver, port, settings()).await
+}
+
+pub async fn open_queue_with(
+    directory: &TempDir,
+    resolver: StaticResolver,
+    port: u16,
+    settings: Settings,
+) -> (Arc<Queue>, mpsc::Receiver<Mail>) {
+    let mut outbound = Outbound::new(&service_config(), Arc::new(resolver));
+    outbound.port = port;
+    let (sender, notices) = mpsc::channel(16);
+    let queue = Queue::open(directory.path(), settings, Arc::new(outbound), sender)
+        .await
+        .unwrap();
+    (queue, notices)
+}
+
+fn acceptor() -> TlsAcceptor {
+    let cert_chain = certs(&mut &CERT[..]).collect::<Result<_, _>>().unwrap();
+    let key = private_key(&mut &KEY[..]).unwrap().unwrap();
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+pub struct Sink {
+    pub port: u16,
+    pub transactions: mpsc::Receiver<Mail>,
+}
+
+// Takes any number of connections, every message accepted comes out of `transactions`
+pub async fn sink() -> Sink {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, transactions) = mpsc::channel(16);
+    let config = SMTPPortConfiguration {
+        auth_enabled: false,
+        filtering_enabled: false,
+        implicit_tls: false,
+        port,
+        max_connections: 1,
+        max_connections_per_ip: 1,
+        vrfy_policy: VrfyPolicy::Disabled,
+        max_message_size: 64 * 1024,
+        bare_lf_policy: BareLfPolicy::Reject,
+        require_tls: false,
+        require_auth: false,
+        check_header_from: false,
+    };
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let _ = handle_smtp(
+                    socket,
+                    config,
+                    acceptor(),
+                    service_config(),
+                    sender,
+                    Filtering {
+                        resolver: Arc::new(StaticResolver::default()),
+                        reports: None,
+                    },
+                )
+                .await;
+            });
+        }
+    });
+
+    Sink { port, transactions }
+}
+
+// Takes one message without offering any extensions, so it never takes DSN parameters. Gives back the recipients
+pub async fn relay() -> (u16, tokio::task::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let handle = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(socket);
+        let mut recipients = Vec::new();
+        let mut in_data = false;
+        reader
+            .get_mut()
+            .write_all(b"220 relay.example\r\n")
+            .await
+            .unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap() == 0 {
+                break;
+            }
+            let line = line.trim_end();
+            let reply = if in_data {
+                if line != "." {
+                    continue;
+                }
+                in_data = false;
+                "250 Queued"
+            } else if line.starts_with("EHLO") {
+                "250 relay.example"
+            } else if let Some(recipient) = line.strip_prefix("RCPT TO:") {
+                recipients.push(recipient.trim_matches(['<', '>']).to_string());
+                "250 OK"
+            } else if line == "DATA" {
+                in_data = true;
+                "354 Go ahead"
+            } else if line == "QUIT" {
+                "221 Bye"
+            } else {
+                "250 OK"
+            };
+            reader
+                .get_mut()
+                .write_all(format!("{}\r\n", reply).as_bytes())
+                .await
+                .unwrap();
+        }
+        recipients
+    });
+
+    (port, handle)
+}
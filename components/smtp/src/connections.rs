@@ -0,0 +1,105 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Tracks how many sessions a listener is running, both in total and per source IP
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    sessions: Arc<Semaphore>,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    max_per_ip: usize,
+}
+
+// Held for the lifetime of a session, dropping it frees the slot back up
+pub struct ConnectionGuard {
+    _permit: OwnedSemaphorePermit,
+    ip: IpAddr,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_sessions: usize, max_per_ip: usize) -> Self {
+        Self {
+            sessions: Arc::new(Semaphore::new(max_sessions)),
+            per_ip: Arc::new(Mutex::new(HashMap::new())),
+            max_per_ip,
+        }
+    }
+
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        if per_ip.get(&ip).copied().unwrap_or(0) >= self.max_per_ip {
+            return None;
+        }
+
+        let permit = self.sessions.clone().try_acquire_owned().ok()?;
+        *per_ip.entry(ip).or_insert(0) += 1;
+
+        Some(ConnectionGuard {
+            _permit: permit,
+            ip,
+            per_ip: self.per_ip.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn limiter_caps_sessions_per_ip() {
+        let limiter = ConnectionLimiter::new(10, 2);
+        let _first = limiter.try_acquire(ip(1)).unwrap();
+        let _second = limiter.try_acquire(ip(1)).unwrap();
+        assert!(limiter.try_acquire(ip(1)).is_none());
+        assert!(limiter.try_acquire(ip(2)).is_some());
+    }
+
+    #[test]
+    fn limiter_caps_total_sessions() {
+        let limiter = ConnectionLimiter::new(2, 2);
+        let _first = limiter.try_acquire(ip(1)).unwrap();
+        let _second = limiter.try_acquire(ip(2)).unwrap();
+        assert!(limiter.try_acquire(ip(3)).is_none());
+    }
+
+    #[test]
+    fn limiter_frees_slots_on_drop() {
+        let limiter = ConnectionLimiter::new(1, 1);
+        let first = limiter.try_acquire(ip(1)).unwrap();
+        assert!(limiter.try_acquire(ip(1)).is_none());
+        drop(first);
+        assert!(limiter.try_acquire(ip(1)).is_some());
+    }
+
+    #[test]
+    fn limiter_forgets_ips_with_no_sessions() {
+        let limiter = ConnectionLimiter::new(1, 5);
+        let first = limiter.try_acquire(ip(1)).unwrap();
+        assert!(limiter.try_acquire(ip(2)).is_none());
+        assert!(!limiter.per_ip.lock().unwrap().contains_key(&ip(2)));
+        drop(first);
+        assert!(limiter.per_ip.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+use log::{debug, error};
+use std::sync::Arc;
+use tokio::{fs, sync::mpsc};
+
+use eemail_component_queue::Queue;
+use eemail_lib_protocols_smtp_server::Mail;
+
+use crate::dkim::Signers;
+
+// Drains finished mail from the sessions on a listener (or from the queue), so slow disks never hold up the accept loop
+pub async fn worker(
+    mut receiver: mpsc::Receiver<Mail>,
+    source: String,
+    service_config: eemail_component_configurator::Configuration,
+    email_path: String,
+    queue: Arc<Queue>,
+    signers: Arc<Signers>,
+) {
+    while let Some(mut mail) = receiver.recv().await {
+        // Our own users' mail gets their domain's signature before any copy of it is made
+        if mail.authenticated.is_some() {
+            signers.sign(&mut mail);
+        }
+
+        match deliver(&mail, &service_config, &email_path).await {
+            Ok(delivered) => queue.delivered(&mail, delivered).await,
+            Err(e) => error!("Failed to deliver message {}: {}", mail.id, e),
+        }
+
+        let remote_recipients: Vec<String> = mail
+            .to
+            .iter()
+            .filter(|recipient| !is_local(recipient, &service_config))
+            .cloned()
+            .collect();
+
+        if remote_recipients.is_empty() {
+            continue;
+        }
+        // Anyone else's mail we pass on is sealed, so whoever gets it next can see it passed our checks
+        if mail.authenticated.is_none() {
+            signers.seal(&mut mail);
+        }
+        // Remote servers can take minutes to answer, so they're left to the queue rather than holding up local mail
+        if let Err(e) = queue.enqueue(&mail, remote_recipients).await {
+            error!("Failed to queue message {}: {}", mail.id, e);
+        }
+    }
+    debug!("Delivery worker for {} stopped", source);
+}
+
+fn is_local(
+    recipient: &str,
+    service_config: &eemail_component_configurator::Configuration,
+) -> bool {
+    // RCPT only takes an address without a domain for our own postmaster
+    recipient
+        .rsplit_once('@')
+        .is_none_or(|(_, domain)| service_config.is_local_domain(domain))
+}
+
+// Gives back the recipients, as the sender gave them, that now have the message in their Inbox
+async fn deliver(
+    mail: &Mail,
+    service_config: &eemail_component_configurator::Configuration,
+    email_path: &str,
+) -> anyhow::Result<Vec<String>> {
+    let (delivered, local_recipients): (Vec<String>, Vec<String>) = mail
+        .to
+        .iter()
+        .filter_map(|recipient| {
+            let account = service_config.get_recipient(recipient)?;
+            Some((recipient.clone(), account.get_primary_address()))
+        })
+        .unzip();
+
+    debug!("Local Recipients {:#?}", local_recipients);
+
+    // Only mail sent by someone who logged in is theirs to keep a copy of
+    if let Some(account) = &mail.authenticated {
+        let base = format!(
+            "{}/{}/Sent",
+            email_path,
+            account.clone().get_primary_address(),
+        );
+        fs::create_dir_all(base.clone()).await?;
+        fs::write(format!("{}/{}.eml", base, mail.id), mail.data.clone()).await?;
+    }
+
+    for recipient in local_recipients {
+        let base = format!("{}/{}/Inbox", email_path, recipient);
+        fs::create_dir_all(base.clone()).await?;
+        fs::write(format!("{}/{}.eml", base, mail.id), mail.data.clone()).await?;
+    }
+
+    Ok(delivered)
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_component_outbound::Outbound;
+    use eemail_component_queue::Settings;
+    use eemail_lib_dns::StaticResolver;
+    use eemail_lib_shared::{Notify, RecipientDsn};
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn local_delivery_is_reported_when_asked() {
+        let directory = std::env::temp_dir().join(format!("eemail-delivery-{}", Uuid::now_v7()));
+        let config = eemail_component_configurator::Configuration::parse_from_string(
+            include_str!("../../../tests/config.toml").to_string(),
+        )
+        .unwrap();
+        let (notice_sender, mut notices) = mpsc::channel(4);
+        let queue = Queue::open(
+            directory.join("queue"),
+            Settings::from_config(&config),
+            Arc::new(Outbound::new(&config, Arc::new(StaticResolver::default()))),
+            notice_sender,
+        )
+        .await
+        .unwrap();
+
+        let id = Uuid::now_v7().to_string();
+        let data = b"Subject: hello\r\n\r\nHi\r\n";
+        let mut mail = Mail {
+            id: id.clone(),
+            from: "sender@example.org".to_string(),
+            to: vec!["hi@example.com".to_string(), "test@example.com".to_string()],
+            data: data.to_vec(),
+            ..Default::default()
+        };
+        mail.dsn.recipients.insert(
+            "hi@example.com".to_string(),
+            RecipientDsn {
+                notify: Some(Notify {
+                    success: true,
+                    failure: false,
+                    delay: false,
+                }),
+                orcpt: None,
+            },
+        );
+        let (sender, receiver) = mpsc::channel(1);
+        sender.send(mail).await.unwrap();
+        drop(sender);
+        worker(
+            receiver,
+            "tests".to_string(),
+            config.clone(),
+            directory.to_string_lossy().to_string(),
+            queue,
+            Arc::new(Signers::load(&config)),
+        )
+        .await;
+
+        let inbox = directory.join(format!("example@example.com/Inbox/{}.eml", id));
+        let delivered = std::fs::read(inbox);
+        let notice = notices.try_recv();
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert_eq!(delivered.unwrap(), data);
+        let notice = notice.unwrap();
+        assert_eq!(notice.to, ["sender@example.org"]);
+        let data = String::from_utf8(notice.data).unwrap();
+        assert!(data.contains(
+            "Final-Recipient: rfc822; hi@example.com\r\n\
+             Action: delivered\r\n\
+             Status: 2.0.0\r\n"
+        ));
+        assert!(!data.contains("test@example.com"));
+    }
+}
@@ -0,0 +1,234 @@
+use log::{debug, error, info};
+use std::{
+    fs,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use eemail_component_configurator::{Configuration, normalize_domain};
+use eemail_lib_dkim::{DEFAULT_HEADERS, Signer, SigningKey};
+use eemail_lib_protocols_smtp_server::{
+    Mail,
+    headers::{header_values, mailbox_addresses},
+};
+
+// The configured DKIM keys, mail is signed with whichever belongs to the domain in its From: header
+pub struct Signers {
+    signers: Vec<Signer>,
+    // Relayed mail is ARC sealed with the key for our sending_fqdn (or its nearest parent), there may be none
+    sealer: Option<usize>,
+    // The authserv-id our Authentication-Results carry, repeated in ARC-Authentication-Results
+    hostname: String,
+}
+
+impl Signers {
+    // A key that can't be read is logged and left out, mail from that domain just goes unsigned
+    pub fn load(config: &Configuration) -> Self {
+        let Some(dkim) = &config.dkim else {
+            return Self {
+                signers: Vec::new(),
+                sealer: None,
+                hostname: config.fqdn.clone(),
+            };
+        };
+        let headers: Vec<String> = match &dkim.headers {
+            Some(headers) => headers.clone(),
+            None => DEFAULT_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        };
+
+        let mut signers = Vec::new();
+        for key in &dkim.keys {
+            let signing_key = match fs::read_to_string(&key.private_key)
+                .map_err(|e| e.to_string())
+                .and_then(|pem| SigningKey::from_pem(&pem).map_err(|e| e.to_string()))
+            {
+                Ok(signing_key) => signing_key,
+                Err(e) => {
+                    error!(
+                        "Failed to load the DKIM key for {} from {}: {}",
+                        key.domain, key.private_key, e
+                    );
+                    continue;
+                }
+            };
+            info!(
+                "Loaded {} DKIM key {} for {}",
+                signing_key.algorithm().name(),
+                key.selector,
+                key.domain
+            );
+            signers.push(Signer {
+                domain: normalize_domain(&key.domain),
+                selector: key.selector.clone(),
+                key: signing_key,
+                headers: headers.clone(),
+            });
+        }
+        let sending_fqdn = normalize_domain(&config.sending_fqdn);
+        let sealer = signers
+            .iter()
+            .enumerate()
+            .filter(|(_, signer)| {
+                sending_fqdn == signer.domain
+                    || sending_fqdn.ends_with(&format!(".{}", signer.domain))
+            })
+            .max_by_key(|(_, signer)| signer.domain.len())
+            .map(|(index, _)| index);
+        Self {
+            signers,
+            sealer,
+            hostname: config.fqdn.clone(),
+        }
+    }
+
+    // Puts a DKIM-Signature at the top of the message, if there's a key for its author's domain
+    pub fn sign(&self, mail: &mut Mail) {
+        let Some(domain) = header_values(&mail.data, "From")
+            .first()
+            .and_then(|from| mailbox_addresses(from).into_iter().next())
+            .and_then(|address| {
+                address
+                    .rsplit_once('@')
+                    .map(|(_, domain)| normalize_domain(domain))
+            })
+        else {
+            return;
+        };
+        let Some(signer) = self.signers.iter().find(|signer| signer.domain == domain) else {
+            debug!(
+                "No DKIM key for {}, message {} goes unsigned",
+                domain, mail.id
+            );
+            return;
+        };
+
+        match signer.sign(&mail.data, now()) {
+            Ok(signature) => {
+                let mut data = signature.into_bytes();
+                data.extend_from_slice(&mail.data);
+                mail.data = data;
+                debug!("Signed message {} for {}", mail.id, domain);
+            }
+            Err(e) => error!("Failed to sign message {}: {}", mail.id, e),
+        }
+    }
+
+    // Puts an ARC set at the top of mail we're passing on, recording what we made of it when it came in.
+    // Only mail that went through a filtering listener has anything to record
+    pub fn seal(&self, mail: &mut Mail) {
+        let Some(chain) = &mail.authentication.arc else {
+            return;
+        };
+        let Some(signer) = self.sealer.map(|index| &self.signers[index]) else {
+            debug!("No DKIM key to seal message {} with", mail.id);
+            return;
+        };
+
+        let results = mail.authentication.results(&self.hostname);
+        match signer.seal(&mail.data, chain, &results, now()) {
+            Ok(Some(set)) => {
+                let mut data = set.into_bytes();
+                data.extend_from_slice(&mail.data);
+                mail.data = data;
+                debug!("Sealed message {} for {}", mail.id, signer.domain);
+            }
+            Ok(None) => debug!("The ARC chain of message {} is closed", mail.id),
+            Err(e) => error!("Failed to seal message {}: {}", mail.id, e),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_component_configurator::{DkimConfiguration, DkimKeyConfiguration};
+    use eemail_lib_dkim::{Chain, ChainStatus};
+
+    use super::*;
+
+    fn config(private_key: &str) -> Configuration {
+        let mut config = Configuration::parse_from_string(
+            include_str!("../../../tests/config.toml").to_string(),
+        )
+        .unwrap();
+        config.dkim = Some(DkimConfiguration {
+            headers: None,
+            keys: vec![DkimKeyConfiguration {
+                domain: "EXAMPLE.com".to_string(),
+                selector: "eemail".to_string(),
+                private_key: private_key.to_string(),
+            }],
+        });
+        config
+    }
+
+    fn mail(from: &str) -> Mail {
+        Mail {
+            id: "id".to_string(),
+            data: format!("From: Test <{}>\r\nSubject: hi\r\n\r\nHi\r\n", from).into_bytes(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn mail_is_signed_for_the_from_domain() {
+        let signers = Signers::load(&config("../../tests/dkim/ed25519.pem"));
+
+        let mut signed = mail("test@example.com");
+        signers.sign(&mut signed);
+        let data = String::from_utf8(signed.data).unwrap();
+        assert!(data.starts_with(
+            "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=example.com; s=eemail;"
+        ));
+        assert!(data.ends_with("\r\nFrom: Test <test@example.com>\r\nSubject: hi\r\n\r\nHi\r\n"));
+
+        let mut unsigned = mail("someone@example.org");
+        signers.sign(&mut unsigned);
+        assert!(unsigned.data.starts_with(b"From:"));
+    }
+
+    #[test]
+    fn relayed_mail_is_sealed_with_the_sending_domain_key() {
+        let signers = Signers::load(&config("../../tests/dkim/ed25519.pem"));
+
+        let mut relayed = mail("someone@example.org");
+        relayed.authentication.arc = Some(Chain {
+            status: ChainStatus::None,
+            instance: 0,
+            sealer: None,
+            reason: None,
+        });
+        signers.seal(&mut relayed);
+        let data = String::from_utf8(relayed.data).unwrap();
+        assert!(
+            data.starts_with(
+                "ARC-Seal: i=1; a=ed25519-sha256; cv=none; d=example.com; s=eemail;\r\n"
+            )
+        );
+        assert!(data.contains(
+            "\r\nARC-Authentication-Results: i=1; mail.example.com;\r\n\tdkim=none;\r\n\tarc=none\r\n\
+             From: Test <someone@example.org>\r\n"
+        ));
+
+        // Mail that never went through a filtering listener has nothing to seal
+        let mut unchecked = mail("someone@example.org");
+        signers.seal(&mut unchecked);
+        assert!(unchecked.data.starts_with(b"From:"));
+    }
+
+    #[test]
+    fn unreadable_keys_are_skipped() {
+        let signers = Signers::load(&config("../../tests/dkim/missing.pem"));
+        let mut mail = mail("test@example.com");
+        signers.sign(&mut mail);
+        assert!(mail.data.starts_with(b"From:"));
+    }
+}
@@ -1,70 +1,227 @@
 use core::panic;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use rustls::{
     ServerConfig,
     pki_types::{CertificateDer, PrivateKeyDer},
 };
 use rustls_pemfile::{certs, private_key};
 use std::{fs::File, io::BufReader, sync::Arc};
-use tokio::{fs, net::TcpListener, task};
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpListener,
+    sync::mpsc,
+    task::{self, JoinSet},
+};
 use tokio_rustls::TlsAcceptor;
 
+use eemail_component_configurator::SmtpListenerConfiguration;
+use eemail_component_outbound::Outbound;
+use eemail_component_queue::{Queue, Settings};
+use eemail_lib_dns::{Resolver, SystemResolver};
+use eemail_lib_protocols_smtp_server::{
+    Filtering,
+    reply::{Reply, Status},
+};
 use eemail_lib_shared::SMTPPortConfiguration;
 
+use crate::{connections::ConnectionLimiter, dkim::Signers, reports::Reports};
+
+mod connections;
+mod delivery;
+mod dkim;
+mod reports;
+
+const DEFAULT_MAX_CONNECTIONS: usize = 100;
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 10;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+// How many finished messages can be waiting on the delivery worker before sessions start waiting
+const DELIVERY_QUEUE_SIZE: usize = 64;
+// DMARC results waiting to be counted, any more than this and sessions skip reporting rather than wait
+const REPORT_QUEUE_SIZE: usize = 256;
+
 pub async fn start_smtp(config: eemail_component_configurator::Configuration) {
-    let transfer = task::spawn(listen(
-        SMTPPortConfiguration {
-            auth_enabled: false,
-            filtering_enabled: true,
-            implicit_tls: false,
-            port: 2525,
-        },
-        config.clone(),
-    ));
+    let smtp_config = config.smtp.clone().unwrap_or_default();
+    let transfer_config = smtp_config.transfer.unwrap_or_default();
+    let submission_config = smtp_config.submission.unwrap_or_default();
+
+    // Do a sanity check on startup that the email path is set
+    let email_path = match std::env::var("EMAIL_PATH") {
+        Ok(path) => path,
+        Err(_) => {
+            error!("EMAIL_PATH environment variable not set, maybe check the docs?");
+            return;
+        }
+    };
 
-    let submission = task::spawn(listen(
-        SMTPPortConfiguration {
-            auth_enabled: true,
-            filtering_enabled: false,
-            implicit_tls: false,
-            port: 5870,
-        },
+    // Shared by every listener, mail for other domains goes out the same way whoever sent it
+    let resolver: Arc<dyn Resolver> = Arc::new(SystemResolver::new());
+    let outbound = Arc::new(Outbound::new(&config, resolver.clone()));
+    let (notice_sender, notice_receiver) = mpsc::channel(DELIVERY_QUEUE_SIZE);
+    let queue = match Queue::open(
+        format!("{}/queue", email_path),
+        Settings::from_config(&config),
+        outbound,
+        notice_sender.clone(),
+    )
+    .await
+    {
+        Ok(queue) => queue,
+        Err(e) => {
+            error!("Failed to open the outbound queue: {}", e);
+            return;
+        }
+    };
+    task::spawn(queue.clone().run());
+    let signers = Arc::new(Signers::load(&config));
+    // Notices from the queue (and DMARC reports) are delivered like any other mail, they're often for one of our own users
+    task::spawn(delivery::worker(
+        notice_receiver,
+        "queue notices".to_string(),
         config.clone(),
+        email_path.clone(),
+        queue.clone(),
+        signers.clone(),
     ));
 
-    tokio::select! {
-        transfer_result = transfer => {
-            match transfer_result {
-                Ok(Ok(_)) => info!("Transfer listener finished normally"),
-                Ok(Err(e)) => error!("Transfer listener failed: {}", e),
-                Err(e) => error!("Transfer task panicked: {}", e),
+    let reports_enabled = config
+        .dmarc
+        .as_ref()
+        .and_then(|dmarc| dmarc.reports)
+        .unwrap_or(true);
+    let reports = if reports_enabled {
+        let (report_sender, report_receiver) = mpsc::channel(REPORT_QUEUE_SIZE);
+        match Reports::open(
+            format!("{}/dmarc", email_path),
+            &config,
+            resolver.clone(),
+            signers.clone(),
+            notice_sender,
+        )
+        .await
+        {
+            Ok(reports) => {
+                task::spawn(reports.run(report_receiver));
+                Some(report_sender)
             }
-        }
-        submission_result = submission => {
-            match submission_result {
-                Ok(Ok(_)) => info!("Submission listener finished normally"),
-                Ok(Err(e)) => error!("Submission listener failed: {}", e),
-                Err(e) => error!("Submission task panicked: {}", e),
+            Err(e) => {
+                error!("Failed to open the DMARC reports: {}", e);
+                return;
             }
         }
+    } else {
+        None
+    };
+    let filtering = Filtering { resolver, reports };
+
+    let mut listeners = JoinSet::new();
+
+    let transfer = port_config(&transfer_config, 2525, false, true, false);
+    listeners.spawn(run(
+        "Transfer",
+        listen(
+            transfer,
+            config.clone(),
+            email_path.clone(),
+            queue.clone(),
+            signers.clone(),
+            filtering.clone(),
+        ),
+    ));
+
+    let submission = port_config(&submission_config, 5870, true, false, false);
+    listeners.spawn(run(
+        "Submission",
+        listen(
+            submission,
+            config.clone(),
+            email_path.clone(),
+            queue.clone(),
+            signers.clone(),
+            filtering.clone(),
+        ),
+    ));
+
+    if let Some(submissions_config) = smtp_config.submissions {
+        let submissions = port_config(&submissions_config, 4650, true, false, true);
+        listeners.spawn(run(
+            "Submissions",
+            listen(
+                submissions,
+                config.clone(),
+                email_path.clone(),
+                queue.clone(),
+                signers.clone(),
+                filtering.clone(),
+            ),
+        ));
     }
+
+    // Listeners only stop when something has gone wrong, so stop as soon as any of them does
+    match listeners.join_next().await {
+        Some(Ok((name, Ok(_)))) => info!("{} listener finished normally", name),
+        Some(Ok((name, Err(e)))) => error!("{} listener failed: {}", name, e),
+        Some(Err(e)) => error!("Listener task panicked: {}", e),
+        None => {}
+    }
+}
+
+// Tags a listener's result with its name for the log
+async fn run(
+    name: &'static str,
+    listener: impl Future<Output = anyhow::Result<()>>,
+) -> (&'static str, anyhow::Result<()>) {
+    (name, listener.await)
+}
+
+fn port_config(
+    listener: &SmtpListenerConfiguration,
+    port: u16,
+    auth_enabled: bool,
+    filtering_enabled: bool,
+    implicit_tls: bool,
+) -> SMTPPortConfiguration {
+    SMTPPortConfiguration {
+        auth_enabled,
+        filtering_enabled,
+        implicit_tls,
+        port,
+        max_connections: max_connections(listener),
+        max_connections_per_ip: max_connections_per_ip(listener),
+        vrfy_policy: listener.vrfy.unwrap_or_default(),
+        max_message_size: max_message_size(listener),
+        bare_lf_policy: listener.bare_lf.unwrap_or_default(),
+        require_tls: listener.require_tls.unwrap_or(auth_enabled),
+        require_auth: listener.require_auth.unwrap_or(auth_enabled),
+        check_header_from: listener.check_header_from.unwrap_or(false),
+    }
+}
+
+fn max_connections(listener: &SmtpListenerConfiguration) -> usize {
+    listener.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS)
+}
+
+fn max_connections_per_ip(listener: &SmtpListenerConfiguration) -> usize {
+    listener
+        .max_connections_per_ip
+        .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP)
+}
+
+fn max_message_size(listener: &SmtpListenerConfiguration) -> usize {
+    listener
+        .max_message_size
+        .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
 }
 
 // Fn that will bind to the ports and do the initial worker handoff
 async fn listen(
     config: SMTPPortConfiguration,
     service_config: eemail_component_configurator::Configuration,
+    email_path: String,
+    queue: Arc<Queue>,
+    signers: Arc<Signers>,
+    filtering: Filtering,
 ) -> anyhow::Result<()> {
-    // Do a sanity check on startup that the email path is set
-    let email_path = match std::env::var("EMAIL_PATH") {
-        Ok(path) => path,
-        Err(_) => {
-            error!("EMAIL_PATH environment variable not set, maybe check the docs?");
-            return Ok(());
-        }
-    };
-
     let cert_path: String = std::env::var("CERT_PATH").unwrap();
     let key_path: String = std::env::var("KEY_PATH").unwrap();
 
@@ -78,67 +235,59 @@ async fn listen(
         config.port, config.auth_enabled, config.filtering_enabled, config.implicit_tls
     );
 
+    let limiter = ConnectionLimiter::new(config.max_connections, config.max_connections_per_ip);
+    let (delivery_sender, delivery_receiver) = mpsc::channel(DELIVERY_QUEUE_SIZE);
+    task::spawn(delivery::worker(
+        delivery_receiver,
+        format!("port {}", config.port),
+        service_config.clone(),
+        email_path,
+        queue,
+        signers,
+    ));
+
     loop {
         match listener.accept().await {
-            Ok((socket, addr)) => {
+            Ok((mut socket, addr)) => {
+                let Some(guard) = limiter.try_acquire(addr.ip()) else {
+                    warn!(
+                        "Rejecting connection from {} on port {}, too many sessions",
+                        addr, config.port
+                    );
+                    task::spawn(async move {
+                        let reply = Reply::new(
+                            Status::ServiceUnavailable,
+                            "Too many connections, try again later",
+                        );
+                        let _ = socket.write_all(&reply.to_bytes()).await;
+                        let _ = socket.shutdown().await;
+                    });
+                    continue;
+                };
+
                 info!("New connection from {} on port {}", addr, config.port);
-                // Spawn handler thread
-                match eemail_lib_protocols_smtp_server::handle_smtp(
-                    socket,
-                    config,
-                    tls_acceptor.clone(),
-                    service_config.clone(),
-                )
-                .await
-                {
-                    Ok(mail) => {
-                        let from_account = service_config
-                            .accounts
-                            .iter()
-                            .find(|&x| x.clone().get_all_addresses().contains(&mail.from));
-
-                        let local_recipients: Vec<String> = mail
-                            .to
-                            .iter()
-                            .filter(|recipient| {
-                                service_config.accounts.iter().any(|account| {
-                                    account.clone().get_all_addresses().contains(recipient)
-                                })
-                            })
-                            .cloned()
-                            .collect();
-
-                        debug!("Local Recipients {:#?}", local_recipients);
-
-                        if from_account.is_some() && config.auth_enabled {
-                            // If from_account exists & auth is enabled, we can assume it is from an account on this server
-                            let base = format!(
-                                "{}/{}/Sent",
-                                email_path,
-                                from_account.unwrap().clone().get_primary_address(),
-                            );
-                            fs::create_dir_all(base.clone()).await?;
-                            fs::write(format!("{}/{}.eml", base, mail.id), mail.data.clone())
-                                .await?;
-                        }
-
-                        for recipient in local_recipients {
-                            let recipient = service_config
-                                .clone()
-                                .get_user_from_alias(&recipient)
-                                .unwrap()
-                                .get_primary_address();
-                            let base = format!("{}/{}/Inbox", email_path, recipient);
-                            fs::create_dir_all(base.clone()).await?;
-                            fs::write(format!("{}/{}.eml", base, mail.id), mail.data.clone())
-                                .await?;
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error processing connection from {}", e);
+                let tls_acceptor = tls_acceptor.clone();
+                let service_config = service_config.clone();
+                let delivery_sender = delivery_sender.clone();
+                let filtering = filtering.clone();
+
+                // Spawn handler task, the guard is held until the session ends
+                task::spawn(async move {
+                    let _guard = guard;
+                    if let Err(e) = eemail_lib_protocols_smtp_server::handle_smtp(
+                        socket,
+                        config,
+                        tls_acceptor,
+                        service_config,
+                        delivery_sender,
+                        filtering,
+                    )
+                    .await
+                    {
+                        error!("Error processing connection from {}: {}", addr, e);
                     }
-                }
-                info!("Quit Connection from {}", addr);
+                    info!("Quit Connection from {}", addr);
+                });
             }
             Err(e) => {
                 error!("Failed to accept connection on port {}: {}", config.port, e);
@@ -0,0 +1,328 @@
+// DMARC aggregate reports (RFC 7489 7.2). Results from the filtering listeners are counted up on disk,
+// one file per policy domain, and mailed to the domain once its reporting interval is up
+use log::{debug, error, info, warn};
+use std::{
+    io::ErrorKind,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, sync::mpsc, time::interval};
+use uuid::Uuid;
+
+use eemail_component_configurator::Configuration;
+use eemail_component_queue::format_date;
+use eemail_lib_dmarc::Report;
+use eemail_lib_dns::Resolver;
+use eemail_lib_protocols_smtp_server::Mail;
+use eemail_lib_shared::BodyType;
+
+use crate::dkim::Signers;
+
+// Domains can ask for reports hourly at most, so none goes out much later than asked
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+pub struct Reports {
+    directory: PathBuf,
+    // Who the reports say they're from, our sending_fqdn
+    submitter: String,
+    address: String,
+    resolver: Arc<dyn Resolver>,
+    signers: Arc<Signers>,
+    // Finished reports are delivered like queue notices, the address might well be one of our own
+    outgoing: mpsc::Sender<Mail>,
+}
+
+impl Reports {
+    pub async fn open(
+        path: impl Into<PathBuf>,
+        config: &Configuration,
+        resolver: Arc<dyn Resolver>,
+        signers: Arc<Signers>,
+        outgoing: mpsc::Sender<Mail>,
+    ) -> anyhow::Result<Self> {
+        let directory = path.into();
+        fs::create_dir_all(&directory).await?;
+        let address = config
+            .dmarc
+            .as_ref()
+            .and_then(|dmarc| dmarc.report_address.clone())
+            .unwrap_or_else(|| format!("postmaster@{}", config.sending_fqdn));
+        Ok(Self {
+            directory,
+            submitter: config.sending_fqdn.clone(),
+            address,
+            resolver,
+            signers,
+            outgoing,
+        })
+    }
+
+    // Counts results as they come in and sends whatever is due, until every listener has gone
+    pub async fn run(self, mut results: mpsc::Receiver<Report>) {
+        let mut timer = interval(CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                report = results.recv() => {
+                    let Some(report) = report else {
+                        break;
+                    };
+                    if let Err(e) = self.add(report).await {
+                        error!("Failed to store a DMARC result: {}", e);
+                    }
+                }
+                _ = timer.tick() => self.send_due(now()).await,
+            }
+        }
+        debug!("DMARC reports stopped");
+    }
+
+    // Merges `report` into whatever is already pending for its domain, which keeps the policy it started with
+    async fn add(&self, report: Report) -> anyhow::Result<()> {
+        let path = self.path(&report.domain)?;
+        let report = match fs::read_to_string(&path).await {
+            Ok(contents) => {
+                let mut pending: Report = toml::from_str(&contents)?;
+                for row in report.rows {
+                    pending.add(row);
+                }
+                pending
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => report,
+            Err(e) => return Err(e.into()),
+        };
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, toml::to_string(&report)?).await?;
+        fs::rename(&temporary, &path).await?;
+        Ok(())
+    }
+
+    async fn send_due(&self, now: u64) {
+        let mut entries = match fs::read_dir(&self.directory).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to read pending DMARC reports: {}", e);
+                return;
+            }
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "toml") {
+                continue;
+            }
+            let report: Report = match fs::read_to_string(&path)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|contents| Ok(toml::from_str(&contents)?))
+            {
+                Ok(report) => report,
+                Err(e) => {
+                    error!("Failed to read DMARC report {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if !report.is_due(now) {
+                continue;
+            }
+            self.send(&report, now).await;
+            if let Err(e) = fs::remove_file(&path).await {
+                error!("Failed to remove DMARC report {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    async fn send(&self, report: &Report, now: u64) {
+        let to = report.verified_destinations(&*self.resolver).await;
+        if to.is_empty() {
+            warn!(
+                "No address will take DMARC reports for {}, dropping the report",
+                report.domain
+            );
+            return;
+        }
+        let mut mail = self.mail(report, to, now);
+        self.signers.sign(&mut mail);
+        info!(
+            "Sending the DMARC report for {} to {:?}",
+            report.domain, mail.to
+        );
+        if self.outgoing.send(mail).await.is_err() {
+            error!(
+                "Nothing is taking outgoing mail, the DMARC report for {} is lost",
+                report.domain
+            );
+        }
+    }
+
+    // RFC 7489 7.2.1.1, the XML goes as an attachment named for the report. There's no gzip to hand so it's sent as is
+    fn mail(&self, report: &Report, to: Vec<String>, now: u64) -> Mail {
+        let id = Uuid::now_v7().to_string();
+        let boundary = format!("{}/{}", id, self.submitter);
+        let xml = report.to_xml(&self.submitter, &self.address, &id, now);
+        let encoding = if xml.is_ascii() { "7bit" } else { "8bit" };
+
+        let data = format!(
+            "From: <{address}>\r\n\
+             To: {}\r\n\
+             Subject: Report Domain: {domain} Submitter: {submitter} Report-ID: <{id}>\r\n\
+             Date: {}\r\n\
+             Message-ID: <{id}@{submitter}>\r\n\
+             Auto-Submitted: auto-generated\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: multipart/mixed;\r\n\
+             \tboundary=\"{boundary}\"\r\n\
+             \r\n\
+             This is a MIME-encapsulated DMARC aggregate report.\r\n\
+             \r\n\
+             --{boundary}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             \r\n\
+             This is an aggregate DMARC report for {domain} from {submitter}.\r\n\
+             \r\n\
+             --{boundary}\r\n\
+             Content-Type: text/xml; charset=utf-8\r\n\
+             Content-Transfer-Encoding: {encoding}\r\n\
+             Content-Disposition: attachment;\r\n\
+             \tfilename=\"{}\"\r\n\
+             \r\n\
+             {xml}\r\n\
+             --{boundary}--\r\n",
+            to.iter()
+                .map(|address| format!("<{}>", address))
+                .collect::<Vec<_>>()
+                .join(", "),
+            format_date(now),
+            report.file_name(&self.submitter, now),
+            address = self.address,
+            domain = report.domain,
+            submitter = self.submitter,
+        )
+        .into_bytes();
+
+        Mail {
+            id,
+            from: self.address.clone(),
+            smtputf8: !self.address.is_ascii() || !to.iter().all(|address| address.is_ascii()),
+            to,
+            body: if data.is_ascii() {
+                BodyType::SevenBit
+            } else {
+                BodyType::EightBitMime
+            },
+            data,
+            ..Default::default()
+        }
+    }
+
+    // The domain came from someone's From: header, so it has to look like one before it goes anywhere near a path
+    fn path(&self, domain: &str) -> anyhow::Result<PathBuf> {
+        let valid = !domain.is_empty()
+            && !domain.starts_with('.')
+            && domain
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '.');
+        if !valid {
+            anyhow::bail!("{:?} is not a domain", domain);
+        }
+        Ok(self.directory.join(format!("{}.toml", domain)))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_dmarc::{Identifiers, Policy, Row};
+    use eemail_lib_dns::StaticResolver;
+
+    use super::*;
+
+    fn config() -> Configuration {
+        Configuration::parse_from_string(include_str!("../../../tests/config.toml").to_string())
+            .unwrap()
+    }
+
+    async fn report(resolver: &StaticResolver, source_ip: &str) -> Report {
+        let evaluation =
+            eemail_lib_dmarc::evaluate(resolver, "example.org", &Identifiers::default()).await;
+        let mut report = Report::new(&evaluation, 1000).unwrap();
+        report.add(Row {
+            source_ip: source_ip.parse().unwrap(),
+            count: 1,
+            disposition: Policy::Reject,
+            dkim_aligned: false,
+            spf_aligned: false,
+            reason: None,
+            header_from: "example.org".to_string(),
+            envelope_from: None,
+            dkim: Vec::new(),
+            spf: Vec::new(),
+        });
+        report
+    }
+
+    #[tokio::test]
+    async fn results_are_merged_and_sent_when_due() {
+        let directory = std::env::temp_dir().join(format!("eemail-dmarc-{}", Uuid::now_v7()));
+        let resolver = StaticResolver::default().txt(
+            "_dmarc.example.org",
+            "v=DMARC1; p=reject; rua=mailto:dmarc@example.org,mailto:x@example.net; ri=3600",
+        );
+        let config = config();
+        let (sender, mut outgoing) = mpsc::channel(4);
+        let reports = Reports::open(
+            &directory,
+            &config,
+            Arc::new(resolver.clone()),
+            Arc::new(Signers::load(&config)),
+            sender,
+        )
+        .await
+        .unwrap();
+
+        reports
+            .add(report(&resolver, "192.0.2.1").await)
+            .await
+            .unwrap();
+        reports
+            .add(report(&resolver, "192.0.2.1").await)
+            .await
+            .unwrap();
+        reports
+            .add(report(&resolver, "192.0.2.2").await)
+            .await
+            .unwrap();
+        assert!(reports.path("../example.org").is_err());
+
+        reports.send_due(1000 + 3599).await;
+        assert!(outgoing.try_recv().is_err());
+        reports.send_due(1000 + 3600).await;
+        let mail = outgoing.try_recv().unwrap();
+        let _ = std::fs::remove_dir_all(&directory);
+
+        assert_eq!(mail.from, "postmaster@example.com");
+        // example.net never agreed to take reports for example.org
+        assert_eq!(mail.to, ["dmarc@example.org"]);
+        let data = String::from_utf8(mail.data).unwrap();
+        assert!(data.starts_with(
+            "From: <postmaster@example.com>\r\n\
+             To: <dmarc@example.org>\r\n\
+             Subject: Report Domain: example.org Submitter: example.com Report-ID: <"
+        ));
+        assert!(data.contains("\tfilename=\"example.com!example.org!1000!4600.xml\"\r\n"));
+        assert!(data.contains(
+            "<source_ip>192.0.2.1</source_ip>\r\n\
+             \x20     <count>2</count>\r\n"
+        ));
+        assert!(data.contains("<source_ip>192.0.2.2</source_ip>"));
+        assert!(data.ends_with(&format!(
+            "</feedback>\r\n\r\n--{}/example.com--\r\n",
+            mail.id
+        )));
+    }
+}
@@ -0,0 +1,469 @@
+// ARC (RFC 8617), sets of fields each handler adds on the way through, so the next can still see what the first one
+// made of the message after forwarding has broken SPF and DKIM
+use base64::{Engine, prelude::BASE64_STANDARD};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use eemail_lib_dns::Resolver;
+
+use crate::{
+    Algorithm, Error, Signer,
+    canonical::{Header, relaxed_body, relaxed_header, split},
+    verify::{base64, check, fetch_key, parse_tags, without_signature},
+};
+
+// RFC 8617 4.2.1, a chain can't get any longer than this
+const MAX_INSTANCES: u32 = 50;
+
+// The fields of a set, in the order an ARC-Seal signs them
+const RESULTS: &str = "ARC-Authentication-Results";
+const MESSAGE_SIGNATURE: &str = "ARC-Message-Signature";
+const SEAL: &str = "ARC-Seal";
+const FIELDS: [&str; 3] = [RESULTS, MESSAGE_SIGNATURE, SEAL];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainStatus {
+    // The message has no ARC fields at all
+    None,
+    Pass,
+    Fail,
+}
+
+impl ChainStatus {
+    // As it appears in cv= and Authentication-Results
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChainStatus::None => "none",
+            ChainStatus::Pass => "pass",
+            ChainStatus::Fail => "fail",
+        }
+    }
+}
+
+// What validation made of the chain a message arrived with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain {
+    pub status: ChainStatus,
+    // The highest i= in the message, 0 without a chain
+    pub instance: u32,
+    // d= of the latest ARC-Seal, if the chain passed
+    pub sealer: Option<String>,
+    // Why it failed
+    pub reason: Option<String>,
+}
+
+// The three fields with one i=
+struct Set<'a> {
+    results: &'a Header<'a>,
+    signature: &'a Header<'a>,
+    seal: &'a Header<'a>,
+}
+
+impl Set<'_> {
+    fn canonical(&self) -> Vec<u8> {
+        [self.results, self.signature, self.seal]
+            .iter()
+            .flat_map(|header| relaxed_header(header.name, header.value()))
+            .collect()
+    }
+}
+
+// RFC 8617 5.2, checks the chain in `message`. `now` is in unix seconds, to check x= against
+pub async fn validate_chain(resolver: &dyn Resolver, message: &[u8], now: u64) -> Chain {
+    let (headers, body) = split(message);
+    let instances: Vec<Option<u32>> = headers
+        .iter()
+        .filter_map(|header| field(header).map(|field| instance_of(header, field)))
+        .collect();
+    if instances.is_empty() {
+        return Chain {
+            status: ChainStatus::None,
+            instance: 0,
+            sealer: None,
+            reason: None,
+        };
+    }
+
+    let mut chain = Chain {
+        status: ChainStatus::Fail,
+        instance: instances.into_iter().flatten().max().unwrap_or(0),
+        sealer: None,
+        reason: None,
+    };
+    match check_chain(resolver, &headers, body, now).await {
+        Ok(sealer) => {
+            chain.status = ChainStatus::Pass;
+            chain.sealer = Some(sealer);
+        }
+        Err(reason) => chain.reason = Some(reason),
+    }
+    chain
+}
+
+// The d= of the latest seal when everything holds
+async fn check_chain(
+    resolver: &dyn Resolver,
+    headers: &[Header<'_>],
+    body: &[u8],
+    now: u64,
+) -> Result<String, String> {
+    let sets = sets(headers)?;
+    let seals = sets
+        .iter()
+        .map(|set| tags(set.seal))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // The first seal saw no chain and every one after it saw a passing one, anything else is broken
+    for (index, seal) in seals.iter().enumerate() {
+        let expected = if index == 0 { "none" } else { "pass" };
+        let cv = seal.get("cv").map_or("", String::as_str);
+        if cv != expected {
+            return Err(format!("ARC-Seal i={} has cv={}", index + 1, cv));
+        }
+    }
+
+    // Only the newest message signature has to hold, the message will have changed since the older ones were made
+    let Some((latest, _)) = sets.split_last() else {
+        return Err("no ARC sets".to_string());
+    };
+    let mut signature = tags(latest.signature)?;
+    if signature.get("h").is_some_and(|names| {
+        names
+            .split(':')
+            .any(|name| name.trim().eq_ignore_ascii_case(SEAL))
+    }) {
+        return Err(format!(
+            "ARC-Message-Signature i={} signs ARC-Seal",
+            sets.len()
+        ));
+    }
+    // Checked as a DKIM signature, i= is the instance rather than an identity
+    signature.remove("i");
+    signature.insert("v".to_string(), "1".to_string());
+    if let Err(outcome) = check(resolver, headers, latest.signature, body, &signature, now).await {
+        return Err(format!(
+            "ARC-Message-Signature i={}: {}",
+            sets.len(),
+            outcome.reason().unwrap_or_default()
+        ));
+    }
+
+    for index in (0..sets.len()).rev() {
+        check_seal(resolver, &sets[..=index], &seals[index])
+            .await
+            .map_err(|reason| format!("ARC-Seal i={}: {}", index + 1, reason))?;
+    }
+    Ok(seals[seals.len() - 1]
+        .get("d")
+        .map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+        .unwrap_or_default())
+}
+
+// RFC 8617 5.1.1, the last of `sets` is the one `tags` came from
+async fn check_seal(
+    resolver: &dyn Resolver,
+    sets: &[Set<'_>],
+    tags: &HashMap<String, String>,
+) -> Result<(), String> {
+    let required = |name: &str| {
+        tags.get(name)
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| format!("missing {}= tag", name))
+    };
+    let algorithm = required("a")?;
+    let algorithm = Algorithm::from_name(algorithm)
+        .ok_or_else(|| format!("unsupported algorithm {}", algorithm))?;
+    let domain = required("d")?.trim_end_matches('.').to_ascii_lowercase();
+    let selector = required("s")?;
+    let signature = base64(required("b")?).ok_or_else(|| "b= is not base64".to_string())?;
+    let key = fetch_key(resolver, algorithm, &domain, selector, &domain)
+        .await
+        .map_err(|outcome| outcome.reason().unwrap_or_default().to_string())?;
+
+    let Some((own, earlier)) = sets.split_last() else {
+        return Err("no ARC sets".to_string());
+    };
+    let mut data: Vec<u8> = earlier.iter().flat_map(Set::canonical).collect();
+    data.extend(relaxed_header(own.results.name, own.results.value()));
+    data.extend(relaxed_header(own.signature.name, own.signature.value()));
+    let value = own.seal.value();
+    data.extend(own_seal(&without_signature(
+        value.strip_suffix(b"\r\n").unwrap_or(value),
+    )));
+
+    if !key.verify(&data, &signature) {
+        return Err("seal did not verify".to_string());
+    }
+    Ok(())
+}
+
+impl Signer {
+    // RFC 8617 5.1, the set to put at the top of `message` when passing it on, with its CRLF. `chain` is what validation
+    // made of it on the way in and `results` our Authentication-Results value. None if the chain can't take another set
+    pub fn seal(
+        &self,
+        message: &[u8],
+        chain: &Chain,
+        results: &str,
+        timestamp: u64,
+    ) -> Result<Option<String>, Error> {
+        let (headers, body) = split(message);
+        let instance = chain.instance + 1;
+        if instance > MAX_INSTANCES {
+            return Ok(None);
+        }
+        // Once a seal has recorded a failure, nothing more gets added to the chain
+        let closed = headers.iter().any(|header| {
+            field(header) == Some(2)
+                && instance_of(header, 2) == Some(chain.instance)
+                && tags(header).is_ok_and(|tags| tags.get("cv").is_some_and(|cv| cv == "fail"))
+        });
+        if closed {
+            return Ok(None);
+        }
+
+        let results = format!("i={}; {}", instance, results);
+        let body_hash = BASE64_STANDARD.encode(Sha256::digest(relaxed_body(body)));
+        let names = self.signed_names(&headers);
+        let tags = format!(
+            "i={}; a={}; c=relaxed/relaxed; d={}; s={};\r\n\tt={}; h={};\r\n\tbh={};\r\n\tb=",
+            instance,
+            self.key.algorithm().name(),
+            self.domain,
+            self.selector,
+            timestamp,
+            names.join(":"),
+            body_hash
+        );
+        let signature = self.signature(&headers, &names, MESSAGE_SIGNATURE, &tags)?;
+        let signature = format!("{}{}", tags, BASE64_STANDARD.encode(signature));
+
+        let tags = format!(
+            "i={}; a={}; cv={}; d={}; s={};\r\n\tt={}; b=",
+            instance,
+            self.key.algorithm().name(),
+            chain.status.name(),
+            self.domain,
+            self.selector,
+            timestamp
+        );
+        let mut data = Vec::new();
+        // RFC 8617 5.1.2, a broken chain is left out and only our own set is sealed
+        if chain.status == ChainStatus::Pass {
+            let sets = sets(&headers).map_err(Error::Signing)?;
+            data.extend(sets.iter().flat_map(Set::canonical));
+        }
+        data.extend(relaxed_header(RESULTS, results.as_bytes()));
+        data.extend(relaxed_header(MESSAGE_SIGNATURE, signature.as_bytes()));
+        data.extend(own_seal(tags.as_bytes()));
+        let seal = self.key.sign(&data)?;
+
+        Ok(Some(format!(
+            "{}: {}{}\r\n{}: {}\r\n{}: {}\r\n",
+            SEAL,
+            tags,
+            BASE64_STANDARD.encode(seal),
+            MESSAGE_SIGNATURE,
+            signature,
+            RESULTS,
+            results
+        )))
+    }
+}
+
+// Every instance from 1 up, each with exactly one of the three fields
+fn sets<'a>(headers: &'a [Header<'a>]) -> Result<Vec<Set<'a>>, String> {
+    let mut found: HashMap<(u32, usize), &Header> = HashMap::new();
+    let mut highest = 0;
+    for header in headers {
+        let Some(field) = field(header) else {
+            continue;
+        };
+        let instance = instance_of(header, field)
+            .filter(|instance| (1..=MAX_INSTANCES).contains(instance))
+            .ok_or_else(|| format!("{} has a bad i= tag", FIELDS[field]))?;
+        if found.insert((instance, field), header).is_some() {
+            return Err(format!(
+                "more than one {} with i={}",
+                FIELDS[field], instance
+            ));
+        }
+        highest = highest.max(instance);
+    }
+
+    (1..=highest)
+        .map(|instance| {
+            let get = |field: usize| {
+                found
+                    .get(&(instance, field))
+                    .copied()
+                    .ok_or_else(|| format!("no {} with i={}", FIELDS[field], instance))
+            };
+            Ok(Set {
+                results: get(0)?,
+                signature: get(1)?,
+                seal: get(2)?,
+            })
+        })
+        .collect()
+}
+
+// Which of FIELDS `header` is, if any
+fn field(header: &Header) -> Option<usize> {
+    FIELDS
+        .iter()
+        .position(|name| header.name.trim_end().eq_ignore_ascii_case(name))
+}
+
+// i=, which ARC-Authentication-Results has to give first as the rest of it isn't a tag list
+fn instance_of(header: &Header, field: usize) -> Option<u32> {
+    let value = if field == 0 {
+        let value = std::str::from_utf8(header.value()).ok()?;
+        let (name, value) = value.split(';').next()?.split_once('=')?;
+        (name.trim() == "i").then(|| value.to_string())?
+    } else {
+        tags(header).ok()?.remove("i")?
+    };
+    value.trim().parse().ok()
+}
+
+fn tags(header: &Header) -> Result<HashMap<String, String>, String> {
+    std::str::from_utf8(header.value())
+        .map_err(|_| format!("{} is not valid UTF-8", header.name.trim_end()))
+        .and_then(parse_tags)
+}
+
+// The seal being made or checked goes last, with an empty b= and no CRLF
+fn own_seal(value: &[u8]) -> Vec<u8> {
+    let mut own = relaxed_header(SEAL, value);
+    own.truncate(own.len() - 2);
+    own
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_dns::StaticResolver;
+
+    use super::*;
+    use crate::{DEFAULT_HEADERS, SigningKey};
+
+    const MESSAGE: &[u8] = b"From: Joe <joe@example.org>\r\n\
+        To: list@lists.example.net\r\n\
+        Subject: Dinner\r\n\
+        \r\n\
+        Are you hungry yet?\r\n";
+
+    fn signer(domain: &str) -> Signer {
+        Signer {
+            domain: domain.to_string(),
+            selector: "arc".to_string(),
+            key: SigningKey::generate(Algorithm::Ed25519Sha256).unwrap(),
+            headers: DEFAULT_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+
+    fn publish(resolver: StaticResolver, signer: &Signer) -> StaticResolver {
+        resolver.txt(
+            &format!("{}._domainkey.{}", signer.selector, signer.domain),
+            &signer.key.dns_record().unwrap(),
+        )
+    }
+
+    // `message` with a set from `signer` on top
+    fn sealed(signer: &Signer, message: &[u8], chain: &Chain) -> Vec<u8> {
+        let set = signer
+            .seal(message, chain, "mx.example.net; spf=pass", 0)
+            .unwrap()
+            .unwrap();
+        [set.as_bytes(), message].concat()
+    }
+
+    #[tokio::test]
+    async fn chains_grow_and_pass() {
+        let first = signer("lists.example.net");
+        let second = signer("forwarder.example");
+        let resolver = publish(publish(StaticResolver::default(), &first), &second);
+
+        let chain = validate_chain(&resolver, MESSAGE, 0).await;
+        assert_eq!(chain.status, ChainStatus::None);
+        let once = sealed(&first, MESSAGE, &chain);
+        assert!(
+            once.starts_with(
+                b"ARC-Seal: i=1; a=ed25519-sha256; cv=none; d=lists.example.net; s=arc;"
+            )
+        );
+        assert!(
+            String::from_utf8_lossy(&once)
+                .contains("\r\nARC-Authentication-Results: i=1; mx.example.net; spf=pass\r\nFrom:")
+        );
+
+        let chain = validate_chain(&resolver, &once, 0).await;
+        assert_eq!(chain.status, ChainStatus::Pass, "{:?}", chain.reason);
+        assert_eq!(chain.instance, 1);
+        assert_eq!(chain.sealer.as_deref(), Some("lists.example.net"));
+
+        let twice = sealed(&second, &once, &chain);
+        assert!(twice.starts_with(b"ARC-Seal: i=2; a=ed25519-sha256; cv=pass;"));
+        let chain = validate_chain(&resolver, &twice, 0).await;
+        assert_eq!(chain.status, ChainStatus::Pass, "{:?}", chain.reason);
+        assert_eq!(chain.instance, 2);
+        assert_eq!(chain.sealer.as_deref(), Some("forwarder.example"));
+    }
+
+    #[tokio::test]
+    async fn changes_break_the_chain() {
+        let signer = signer("lists.example.net");
+        let resolver = publish(StaticResolver::default(), &signer);
+        let none = validate_chain(&resolver, MESSAGE, 0).await;
+        let message = sealed(&signer, MESSAGE, &none);
+        let text = String::from_utf8(message).unwrap();
+
+        let changed_body = text.replace("hungry", "thirsty");
+        let chain = validate_chain(&resolver, changed_body.as_bytes(), 0).await;
+        assert_eq!(chain.status, ChainStatus::Fail);
+        assert_eq!(
+            chain.reason.as_deref(),
+            Some("ARC-Message-Signature i=1: body hash did not verify")
+        );
+
+        let changed_results = text.replace("spf=pass", "spf=fail");
+        let chain = validate_chain(&resolver, changed_results.as_bytes(), 0).await;
+        assert_eq!(
+            chain.reason.as_deref(),
+            Some("ARC-Seal i=1: seal did not verify")
+        );
+
+        let missing = text.replace("ARC-Authentication-Results: i=1;", "X-Results: i=1;");
+        let chain = validate_chain(&resolver, missing.as_bytes(), 0).await;
+        assert_eq!(chain.instance, 1);
+        assert_eq!(
+            chain.reason.as_deref(),
+            Some("no ARC-Authentication-Results with i=1")
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_chains_are_closed() {
+        let signer = signer("forwarder.example");
+        let resolver = publish(StaticResolver::default(), &signer);
+        let broken = Chain {
+            status: ChainStatus::Fail,
+            instance: 0,
+            sealer: None,
+            reason: None,
+        };
+        let message = sealed(&signer, MESSAGE, &broken);
+        assert!(message.starts_with(b"ARC-Seal: i=1; a=ed25519-sha256; cv=fail;"));
+
+        let chain = validate_chain(&resolver, &message, 0).await;
+        assert_eq!(chain.status, ChainStatus::Fail);
+        assert_eq!(chain.reason.as_deref(), Some("ARC-Seal i=1 has cv=fail"));
+        assert_eq!(
+            signer.seal(&message, &chain, "mx.example.net; spf=pass", 0),
+            Ok(None)
+        );
+    }
+}
@@ -0,0 +1,229 @@
+// RFC 6376 3.4, turning a message into the exact bytes that get hashed
+
+// One header field, `raw` is the whole thing as it appears in the message including any folding and the final CRLF
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header<'a> {
+    pub name: &'a str,
+    pub raw: &'a [u8],
+}
+
+impl Header<'_> {
+    // Everything after the colon, still folded
+    pub fn value(&self) -> &[u8] {
+        &self.raw[self.name.len() + 1..]
+    }
+}
+
+// Splits a message into its header fields and body. A message without a blank line is all header
+pub fn split(message: &[u8]) -> (Vec<Header<'_>>, &[u8]) {
+    let mut headers = Vec::new();
+    // Where the field being read started, and its name
+    let mut current: Option<(usize, &str)> = None;
+    let mut position = 0;
+    let mut body = &message[message.len()..];
+
+    while position < message.len() {
+        let rest = &message[position..];
+        // The blank line, `position` is left at it so the last field ends there
+        if rest.starts_with(b"\r\n") {
+            body = &rest[2..];
+            break;
+        }
+        let line_end = rest
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .map_or(rest.len(), |end| end + 2);
+
+        // Folded lines carry on the field before, anything else starts a new one
+        if !rest.starts_with(b" ") && !rest.starts_with(b"\t") {
+            if let Some((start, name)) = current.take() {
+                headers.push(Header {
+                    name,
+                    raw: &message[start..position],
+                });
+            }
+            current = rest[..line_end]
+                .iter()
+                .position(|&b| b == b':')
+                .and_then(|colon| std::str::from_utf8(&rest[..colon]).ok())
+                .map(|name| (position, name));
+        }
+        position += line_end;
+    }
+
+    if let Some((start, name)) = current {
+        headers.push(Header {
+            name,
+            raw: &message[start..position],
+        });
+    }
+    (headers, body)
+}
+
+// RFC 6376 3.4, how a signature says its headers and body were prepared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+impl Canonicalization {
+    // c=, `header/body` or just `header` with the body left simple
+    pub fn parse(value: &str) -> Option<(Self, Self)> {
+        let parse_one = |name: &str| match name {
+            "simple" => Some(Canonicalization::Simple),
+            "relaxed" => Some(Canonicalization::Relaxed),
+            _ => None,
+        };
+        match value.split_once('/') {
+            Some((header, body)) => Some((parse_one(header)?, parse_one(body)?)),
+            None => Some((parse_one(value)?, Canonicalization::Simple)),
+        }
+    }
+
+    pub fn header(self, header: &Header) -> Vec<u8> {
+        match self {
+            Canonicalization::Simple => header.raw.to_vec(),
+            Canonicalization::Relaxed => relaxed_header(header.name, header.value()),
+        }
+    }
+
+    pub fn body(self, body: &[u8]) -> Vec<u8> {
+        match self {
+            Canonicalization::Simple => simple_body(body),
+            Canonicalization::Relaxed => relaxed_body(body),
+        }
+    }
+}
+
+// RFC 6376 3.4.2, lowercase name, unfolded and with runs of whitespace squashed to one space
+pub fn relaxed_header(name: &str, value: &[u8]) -> Vec<u8> {
+    let mut canonical = name.trim_end().to_ascii_lowercase().into_bytes();
+    canonical.push(b':');
+
+    let mut pending_space = false;
+    let mut started = false;
+    for &byte in value {
+        match byte {
+            b'\r' | b'\n' => {}
+            b' ' | b'\t' => pending_space = started,
+            _ => {
+                if pending_space {
+                    canonical.push(b' ');
+                    pending_space = false;
+                }
+                canonical.push(byte);
+                started = true;
+            }
+        }
+    }
+
+    canonical.extend_from_slice(b"\r\n");
+    canonical
+}
+
+// RFC 6376 3.4.4, whitespace squashed within lines and dropped at their ends, then no empty lines at the end
+pub fn relaxed_body(body: &[u8]) -> Vec<u8> {
+    let mut canonical = Vec::with_capacity(body.len());
+    let mut lines = body.split(|&b| b == b'\n').peekable();
+
+    while let Some(line) = lines.next() {
+        // The split leaves an empty piece after the final CRLF, it isn't a line of its own
+        if lines.peek().is_none() && line.is_empty() {
+            break;
+        }
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        let mut pending_space = false;
+        for &byte in line {
+            if byte == b' ' || byte == b'\t' {
+                pending_space = true;
+            } else {
+                if pending_space {
+                    canonical.push(b' ');
+                    pending_space = false;
+                }
+                canonical.push(byte);
+            }
+        }
+        canonical.extend_from_slice(b"\r\n");
+    }
+
+    while canonical.ends_with(b"\r\n\r\n") {
+        canonical.truncate(canonical.len() - 2);
+    }
+    if canonical == b"\r\n" {
+        canonical.clear();
+    }
+    canonical
+}
+
+// RFC 6376 3.4.3, the body as it is apart from any empty lines at the end. Nothing at all becomes one CRLF
+pub fn simple_body(body: &[u8]) -> Vec<u8> {
+    let mut canonical = body.to_vec();
+    while canonical.ends_with(b"\r\n") {
+        canonical.truncate(canonical.len() - 2);
+    }
+    canonical.extend_from_slice(b"\r\n");
+    canonical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_keeps_folded_headers_together() {
+        let message = b"From: a@example.com\r\nSubject: one\r\n two\r\n\r\nBody\r\n";
+        let (headers, body) = split(message);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].name, "From");
+        assert_eq!(headers[1].raw, b"Subject: one\r\n two\r\n");
+        assert_eq!(headers[1].value(), b" one\r\n two\r\n");
+        assert_eq!(body, b"Body\r\n");
+
+        let (headers, body) = split(b"Subject: no body\r\n");
+        assert_eq!(headers.len(), 1);
+        assert!(body.is_empty());
+    }
+
+    // The examples from RFC 6376 3.4.5
+    #[test]
+    fn relaxed_canonicalization_matches_the_rfc() {
+        assert_eq!(relaxed_header("A", b" X \r\n"), b"a:X\r\n");
+        assert_eq!(relaxed_header("B ", b" Y\t\r\n\tZ  \r\n"), b"b:Y Z\r\n");
+        assert_eq!(relaxed_body(b" C \r\nD \t E\r\n\r\n\r\n"), b" C\r\nD E\r\n");
+    }
+
+    #[test]
+    fn relaxed_body_handles_empty_and_unterminated_bodies() {
+        assert_eq!(relaxed_body(b""), b"");
+        assert_eq!(relaxed_body(b"\r\n\r\n"), b"");
+        assert_eq!(relaxed_body(b"no newline"), b"no newline\r\n");
+    }
+
+    #[test]
+    fn simple_body_only_drops_trailing_empty_lines() {
+        assert_eq!(
+            simple_body(b" C \r\nD \t E\r\n\r\n\r\n"),
+            b" C \r\nD \t E\r\n"
+        );
+        assert_eq!(simple_body(b""), b"\r\n");
+        assert_eq!(simple_body(b"\r\n\r\n"), b"\r\n");
+    }
+
+    #[test]
+    fn canonicalization_defaults_the_body_to_simple() {
+        use Canonicalization::*;
+        assert_eq!(
+            Canonicalization::parse("relaxed/relaxed"),
+            Some((Relaxed, Relaxed))
+        );
+        assert_eq!(Canonicalization::parse("relaxed"), Some((Relaxed, Simple)));
+        assert_eq!(
+            Canonicalization::parse("simple/relaxed"),
+            Some((Simple, Relaxed))
+        );
+        assert_eq!(Canonicalization::parse("nofws"), None);
+    }
+}
@@ -0,0 +1,217 @@
+// Signing keys, and the DNS record that publishes the public half
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ed25519_dalek::{
+    Signer as _,
+    pkcs8::{DecodePrivateKey as _, EncodePrivateKey as _},
+};
+use rand_core::OsRng;
+use rsa::{
+    RsaPrivateKey, RsaPublicKey,
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs1v15,
+    pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding},
+    signature::{SignatureEncoding, Verifier as _},
+    traits::PublicKeyParts,
+};
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+// Big enough for RFC 8301, still small enough to fit in a couple of TXT strings
+const RSA_BITS: usize = 2048;
+// RFC 8301 3.2, signatures from anything shorter aren't worth checking
+const MIN_RSA_BITS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    RsaSha256,
+    // RFC 8463
+    Ed25519Sha256,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "rsa-sha256" => Some(Algorithm::RsaSha256),
+            "ed25519-sha256" => Some(Algorithm::Ed25519Sha256),
+            _ => None,
+        }
+    }
+
+    // As it appears in a=
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::RsaSha256 => "rsa-sha256",
+            Algorithm::Ed25519Sha256 => "ed25519-sha256",
+        }
+    }
+
+    // As it appears in k= of the key record
+    pub fn key_type(&self) -> &'static str {
+        match self {
+            Algorithm::RsaSha256 => "rsa",
+            Algorithm::Ed25519Sha256 => "ed25519",
+        }
+    }
+}
+
+pub enum SigningKey {
+    Rsa(RsaPrivateKey),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl SigningKey {
+    pub fn generate(algorithm: Algorithm) -> Result<Self, Error> {
+        match algorithm {
+            Algorithm::RsaSha256 => RsaPrivateKey::new(&mut OsRng, RSA_BITS)
+                .map(SigningKey::Rsa)
+                .map_err(|e| Error::Key(e.to_string())),
+            Algorithm::Ed25519Sha256 => Ok(SigningKey::Ed25519(
+                ed25519_dalek::SigningKey::generate(&mut OsRng),
+            )),
+        }
+    }
+
+    // PKCS#8 for either kind, or PKCS#1 ("BEGIN RSA PRIVATE KEY") for RSA
+    pub fn from_pem(pem: &str) -> Result<Self, Error> {
+        if let Ok(key) = rsa::pkcs8::DecodePrivateKey::from_pkcs8_pem(pem) {
+            return Ok(SigningKey::Rsa(key));
+        }
+        if let Ok(key) = RsaPrivateKey::from_pkcs1_pem(pem) {
+            return Ok(SigningKey::Rsa(key));
+        }
+        ed25519_dalek::SigningKey::from_pkcs8_pem(pem)
+            .map(SigningKey::Ed25519)
+            .map_err(|_| Error::Key("not an RSA or Ed25519 private key".to_string()))
+    }
+
+    pub fn to_pem(&self) -> Result<String, Error> {
+        let pem = match self {
+            SigningKey::Rsa(key) => rsa::pkcs8::EncodePrivateKey::to_pkcs8_pem(key, LineEnding::LF),
+            SigningKey::Ed25519(key) => key.to_pkcs8_pem(LineEnding::LF),
+        };
+        pem.map(|pem| pem.to_string())
+            .map_err(|e| Error::Key(e.to_string()))
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            SigningKey::Rsa(_) => Algorithm::RsaSha256,
+            SigningKey::Ed25519(_) => Algorithm::Ed25519Sha256,
+        }
+    }
+
+    // RFC 6376 3.6.1, the TXT record to publish at <selector>._domainkey.<domain>
+    pub fn dns_record(&self) -> Result<String, Error> {
+        let public = match self {
+            SigningKey::Rsa(key) => key
+                .to_public_key()
+                .to_public_key_der()
+                .map_err(|e| Error::Key(e.to_string()))?
+                .into_vec(),
+            // RFC 8463 4.2, just the 32 bytes rather than a SubjectPublicKeyInfo
+            SigningKey::Ed25519(key) => key.verifying_key().to_bytes().to_vec(),
+        };
+        Ok(format!(
+            "v=DKIM1; k={}; p={}",
+            self.algorithm().key_type(),
+            BASE64_STANDARD.encode(public)
+        ))
+    }
+
+    // Signs the canonicalized headers, both algorithms hash them with SHA-256 first
+    pub(crate) fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            SigningKey::Rsa(key) => {
+                let signer = pkcs1v15::SigningKey::<Sha256>::new(key.clone());
+                rsa::signature::Signer::try_sign(&signer, data)
+                    .map(|signature| signature.to_vec())
+                    .map_err(|e| Error::Signing(e.to_string()))
+            }
+            // RFC 8463 3, the hash is what gets signed, not the data itself
+            SigningKey::Ed25519(key) => Ok(key.sign(&Sha256::digest(data)).to_vec()),
+        }
+    }
+}
+
+// The public half of someone else's key, from the p= of their key record
+pub(crate) enum VerifyingKey {
+    Rsa(RsaPublicKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl VerifyingKey {
+    // RSA keys are published as a SubjectPublicKeyInfo, though some people put up the bare PKCS#1 key
+    pub(crate) fn from_record(algorithm: Algorithm, data: &[u8]) -> Result<Self, String> {
+        match algorithm {
+            Algorithm::RsaSha256 => {
+                let key = RsaPublicKey::from_public_key_der(data)
+                    .or_else(|_| RsaPublicKey::from_pkcs1_der(data))
+                    .map_err(|_| "key is not a valid RSA public key".to_string())?;
+                if key.n().bits() < MIN_RSA_BITS {
+                    return Err(format!("RSA key is shorter than {} bits", MIN_RSA_BITS));
+                }
+                Ok(VerifyingKey::Rsa(key))
+            }
+            Algorithm::Ed25519Sha256 => data
+                .try_into()
+                .ok()
+                .and_then(|bytes| ed25519_dalek::VerifyingKey::from_bytes(bytes).ok())
+                .map(VerifyingKey::Ed25519)
+                .ok_or_else(|| "key is not a valid Ed25519 public key".to_string()),
+        }
+    }
+
+    // The other side of `SigningKey::sign`
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        match self {
+            VerifyingKey::Rsa(key) => {
+                pkcs1v15::Signature::try_from(signature).is_ok_and(|signature| {
+                    pkcs1v15::VerifyingKey::<Sha256>::new(key.clone())
+                        .verify(data, &signature)
+                        .is_ok()
+                })
+            }
+            VerifyingKey::Ed25519(key) => ed25519_dalek::Signature::from_slice(signature)
+                .is_ok_and(|signature| {
+                    key.verify_strict(&Sha256::digest(data), &signature).is_ok()
+                }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSA: &str = include_str!("../../../tests/dkim/rsa.pem");
+    const ED25519: &str = include_str!("../../../tests/dkim/ed25519.pem");
+
+    #[test]
+    fn keys_load_from_pem() {
+        let rsa = SigningKey::from_pem(RSA).unwrap();
+        assert_eq!(rsa.algorithm(), Algorithm::RsaSha256);
+        assert!(
+            rsa.dns_record()
+                .unwrap()
+                .starts_with("v=DKIM1; k=rsa; p=MIIBIjAN")
+        );
+
+        let ed25519 = SigningKey::from_pem(ED25519).unwrap();
+        assert_eq!(ed25519.algorithm(), Algorithm::Ed25519Sha256);
+        // 32 bytes is 44 characters of base64
+        assert_eq!(
+            ed25519.dns_record().unwrap().len(),
+            "v=DKIM1; k=ed25519; p=".len() + 44
+        );
+
+        assert!(SigningKey::from_pem("not a key").is_err());
+    }
+
+    #[test]
+    fn generated_keys_round_trip() {
+        let key = SigningKey::generate(Algorithm::Ed25519Sha256).unwrap();
+        let loaded = SigningKey::from_pem(&key.to_pem().unwrap()).unwrap();
+        assert_eq!(loaded.dns_record().unwrap(), key.dns_record().unwrap());
+    }
+}
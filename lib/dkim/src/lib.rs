@@ -0,0 +1,252 @@
+// DKIM (RFC 6376) signing of outgoing mail and verification of incoming, with RSA-SHA256 or Ed25519-SHA256 (RFC 8463) keys.
+// ARC (RFC 8617) is built from the same pieces, so it lives here too
+use base64::{Engine, prelude::BASE64_STANDARD};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::canonical::{Header, relaxed_body, relaxed_header, split};
+
+pub use crate::arc::{Chain, ChainStatus, validate_chain};
+pub use crate::key::{Algorithm, SigningKey};
+pub use crate::verify::{Outcome, Verification, verify};
+
+mod arc;
+mod canonical;
+mod key;
+mod verify;
+
+// RFC 6376 8.15, these are signed once more than they appear so none can be added without breaking the signature.
+// Readers tend to show only one of them, which needn't be the signed one
+const OVERSIGNED: [&str; 4] = ["from", "reply-to", "subject", "to"];
+
+// Signed when the configuration doesn't give a list, RFC 6376 5.4.1
+pub const DEFAULT_HEADERS: [&str; 12] = [
+    "From",
+    "Reply-To",
+    "Subject",
+    "Date",
+    "To",
+    "Cc",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+    "MIME-Version",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // The key couldn't be read, written or generated
+    Key(String),
+    Signing(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Key(reason) => write!(f, "bad DKIM key: {}", reason),
+            Error::Signing(reason) => write!(f, "DKIM signing failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Signs for one domain, always with relaxed/relaxed canonicalization
+pub struct Signer {
+    // d=, as an A-label
+    pub domain: String,
+    // s=, the key record is at <selector>._domainkey.<domain>
+    pub selector: String,
+    pub key: SigningKey,
+    // Header fields to sign, From is signed whether it's listed or not
+    pub headers: Vec<String>,
+}
+
+impl Signer {
+    // The DKIM-Signature field to put at the top of `message`, with its CRLF. `timestamp` is the t= in unix seconds
+    pub fn sign(&self, message: &[u8], timestamp: u64) -> Result<String, Error> {
+        let (headers, body) = split(message);
+        let body_hash = BASE64_STANDARD.encode(Sha256::digest(relaxed_body(body)));
+        let names = self.signed_names(&headers);
+
+        let tags = format!(
+            "v=1; a={}; c=relaxed/relaxed; d={}; s={};\r\n\tt={}; h={};\r\n\tbh={};\r\n\tb=",
+            self.key.algorithm().name(),
+            self.domain,
+            self.selector,
+            timestamp,
+            names.join(":"),
+            body_hash
+        );
+        let signature = self.signature(&headers, &names, "DKIM-Signature", &tags)?;
+        Ok(format!(
+            "DKIM-Signature: {}{}\r\n",
+            tags,
+            BASE64_STANDARD.encode(signature)
+        ))
+    }
+
+    // One entry for every instance in the message, so nothing signed can be left out or reordered unnoticed.
+    // OVERSIGNED names get one more on top, which stands for an instance that isn't there
+    fn signed_names(&self, headers: &[Header]) -> Vec<String> {
+        let mut wanted = vec!["from".to_string()];
+        for name in &self.headers {
+            let name = name.trim().to_ascii_lowercase();
+            if !wanted.contains(&name) {
+                wanted.push(name);
+            }
+        }
+
+        let mut names = Vec::new();
+        for name in wanted {
+            let count = headers
+                .iter()
+                .filter(|header| header.name.trim_end().eq_ignore_ascii_case(&name))
+                .count();
+            let count = count + usize::from(OVERSIGNED.contains(&name.as_str()));
+            names.extend(std::iter::repeat_n(name, count));
+        }
+        names
+    }
+
+    // RFC 6376 3.7, `tags` is the whole value of the `field` being made with an empty b=
+    fn signature(
+        &self,
+        headers: &[Header],
+        names: &[String],
+        field: &str,
+        tags: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        for header in select(headers, names) {
+            data.extend(relaxed_header(header.name, header.value()));
+        }
+        let mut own = relaxed_header(field, tags.as_bytes());
+        own.truncate(own.len() - 2);
+        data.extend(own);
+
+        self.key.sign(&data)
+    }
+}
+
+// RFC 6376 5.4.2, each name takes the last instance of that field not already taken. Names with none left are skipped
+fn select<'a>(headers: &'a [Header<'a>], names: &[String]) -> Vec<&'a Header<'a>> {
+    let mut taken = vec![false; headers.len()];
+    let mut selected = Vec::new();
+    for name in names {
+        if let Some(index) = (0..headers.len()).rev().find(|&index| {
+            !taken[index]
+                && headers[index]
+                    .name
+                    .trim_end()
+                    .eq_ignore_ascii_case(name.trim())
+        }) {
+            taken[index] = true;
+            selected.push(&headers[index]);
+        }
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 8463 appendix A
+    const MESSAGE: &[u8] = b"From: Joe SixPack <joe@football.example.com>\r\n\
+        To: Suzie Q <suzie@shopping.example.net>\r\n\
+        Subject: Is dinner ready?\r\n\
+        Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n\
+        Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n\
+        \r\n\
+        Hi.\r\n\
+        \r\n\
+        We lost the game.  Are you hungry yet?\r\n\
+        \r\n\
+        Joe.\r\n";
+
+    fn rfc_8463_signer() -> Signer {
+        let seed: [u8; 32] = BASE64_STANDARD
+            .decode("nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A=")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        Signer {
+            domain: "football.example.com".to_string(),
+            selector: "brisbane".to_string(),
+            key: SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&seed)),
+            headers: DEFAULT_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn signature_matches_rfc_8463() {
+        let signer = rfc_8463_signer();
+        let (headers, _) = split(MESSAGE);
+        let names: Vec<String> = "from : to : subject : date : message-id : from : subject : date"
+            .split(':')
+            .map(str::to_string)
+            .collect();
+        let tags = "v=1; a=ed25519-sha256; c=relaxed/relaxed;\r\n \
+            d=football.example.com; i=@football.example.com;\r\n \
+            q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r\n \
+            subject : date : message-id : from : subject : date;\r\n \
+            bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n \
+            b=";
+
+        let signature = signer
+            .signature(&headers, &names, "DKIM-Signature", tags)
+            .unwrap();
+        assert_eq!(
+            BASE64_STANDARD.encode(signature),
+            "/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11BusFa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw=="
+        );
+    }
+
+    #[test]
+    fn sign_builds_the_whole_field() {
+        let header = rfc_8463_signer().sign(MESSAGE, 1528637909).unwrap();
+        assert!(header.starts_with(
+            "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=football.example.com; s=brisbane;\r\n\
+             \tt=1528637909; h=from:from:reply-to:subject:subject:date:to:to:message-id;\r\n\
+             \tbh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n\
+             \tb="
+        ));
+        assert!(header.ends_with("==\r\n"));
+    }
+
+    #[test]
+    fn from_is_always_signed() {
+        let mut signer = rfc_8463_signer();
+        signer.headers = vec!["Subject".to_string(), "X-Missing".to_string()];
+        let header = signer.sign(MESSAGE, 0).unwrap();
+        assert!(header.contains(" h=from:from:subject:subject;"));
+
+        let (headers, _) = split(b"From: a@example.com\r\nReceived: one\r\nReceived: two\r\n\r\n");
+        signer.headers = vec!["Received".to_string()];
+        assert_eq!(
+            signer.signed_names(&headers),
+            ["from", "from", "received", "received"]
+        );
+        let selected = select(&headers, &signer.signed_names(&headers));
+        assert_eq!(selected[1].raw, b"Received: two\r\n");
+        assert_eq!(selected[2].raw, b"Received: one\r\n");
+    }
+
+    #[test]
+    fn rsa_signatures_are_made() {
+        let signer = Signer {
+            key: SigningKey::from_pem(include_str!("../../../tests/dkim/rsa.pem")).unwrap(),
+            ..rfc_8463_signer()
+        };
+        let header = signer.sign(MESSAGE, 0).unwrap();
+        assert!(header.contains(" a=rsa-sha256;"));
+        // 2048 bits is 256 bytes, 344 characters of base64
+        assert_eq!(header.trim_end().rsplit("b=").next().unwrap().len(), 344);
+    }
+}
@@ -0,0 +1,544 @@
+// RFC 6376 6, checking the DKIM-Signature fields on incoming mail
+use base64::{Engine, prelude::BASE64_STANDARD};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use eemail_lib_dns::{Error as DnsError, Resolver};
+
+use crate::{
+    Algorithm,
+    canonical::{Canonicalization, Header, split},
+    key::VerifyingKey,
+    select,
+};
+
+// Every signature costs a DNS lookup, so a message can't make us check more than this many
+const MAX_SIGNATURES: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    // The key was found, but the message doesn't match the signature
+    Fail(String),
+    // The key couldn't be looked up this time, it might work later
+    TempError(String),
+    // The signature is broken, or its key is missing or unusable
+    PermError(String),
+}
+
+impl Outcome {
+    // As it appears in Authentication-Results (RFC 8601 2.7.1)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Pass => "pass",
+            Outcome::Fail(_) => "fail",
+            Outcome::TempError(_) => "temperror",
+            Outcome::PermError(_) => "permerror",
+        }
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            Outcome::Pass => None,
+            Outcome::Fail(reason) | Outcome::TempError(reason) | Outcome::PermError(reason) => {
+                Some(reason)
+            }
+        }
+    }
+}
+
+// The result for one DKIM-Signature field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    // d= and s=, empty if the signature was too broken to have them
+    pub domain: String,
+    pub selector: String,
+    // i=, if the signature gave one
+    pub identity: Option<String>,
+    // b= without any whitespace, its start tells signatures apart in Authentication-Results (RFC 6008)
+    pub signature: String,
+    pub outcome: Outcome,
+}
+
+// Checks every DKIM-Signature in `message`, top to bottom. `now` is in unix seconds, to check x= against
+pub async fn verify(resolver: &dyn Resolver, message: &[u8], now: u64) -> Vec<Verification> {
+    let (headers, body) = split(message);
+    let mut verifications = Vec::new();
+    for header in headers
+        .iter()
+        .filter(|header| {
+            header
+                .name
+                .trim_end()
+                .eq_ignore_ascii_case("DKIM-Signature")
+        })
+        .take(MAX_SIGNATURES)
+    {
+        let tags = std::str::from_utf8(header.value())
+            .map_err(|_| "signature is not valid UTF-8".to_string())
+            .and_then(parse_tags);
+        let tag = |name: &str| tags.as_ref().ok().and_then(|tags| tags.get(name)).cloned();
+
+        let mut verification = Verification {
+            domain: tag("d").unwrap_or_default().to_ascii_lowercase(),
+            selector: tag("s").unwrap_or_default(),
+            identity: tag("i"),
+            signature: tag("b").unwrap_or_default().split_whitespace().collect(),
+            outcome: Outcome::Pass,
+        };
+        let result = match &tags {
+            Ok(tags) => check(resolver, &headers, header, body, tags, now).await,
+            Err(reason) => Err(Outcome::PermError(reason.clone())),
+        };
+        if let Err(outcome) = result {
+            verification.outcome = outcome;
+        }
+        verifications.push(verification);
+    }
+    verifications
+}
+
+// Everything a signature says, once it's been checked to make sense
+struct Signature {
+    algorithm: Algorithm,
+    header_canonicalization: Canonicalization,
+    body_canonicalization: Canonicalization,
+    domain: String,
+    selector: String,
+    // The domain part of i=, which is d= when it isn't given
+    identity_domain: String,
+    headers: Vec<String>,
+    body_hash: Vec<u8>,
+    signature: Vec<u8>,
+    length: Option<usize>,
+}
+
+impl Signature {
+    // RFC 6376 3.5 and 6.1.1
+    fn from_tags(tags: &HashMap<String, String>, now: u64) -> Result<Self, String> {
+        let required = |name: &str| {
+            tags.get(name)
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| format!("missing {}= tag", name))
+        };
+        let number = |name: &str| {
+            tags.get(name)
+                .map(|value| {
+                    value
+                        .parse::<u64>()
+                        .map_err(|_| format!("{}= is not a number", name))
+                })
+                .transpose()
+        };
+
+        if required("v")? != "1" {
+            return Err("unsupported signature version".to_string());
+        }
+        let algorithm = required("a")?;
+        let algorithm = Algorithm::from_name(algorithm)
+            .ok_or_else(|| format!("unsupported algorithm {}", algorithm))?;
+        let (header_canonicalization, body_canonicalization) = Canonicalization::parse(
+            &tags
+                .get("c")
+                .map_or("simple", String::as_str)
+                .to_ascii_lowercase(),
+        )
+        .ok_or_else(|| "unsupported canonicalization".to_string())?;
+
+        let domain = required("d")?.trim_end_matches('.').to_ascii_lowercase();
+        let selector = required("s")?.clone();
+        let identity_domain = match tags.get("i") {
+            Some(identity) => {
+                let (_, identity_domain) = identity
+                    .rsplit_once('@')
+                    .ok_or_else(|| "i= is not an address".to_string())?;
+                let identity_domain = identity_domain.to_ascii_lowercase();
+                if identity_domain != domain && !identity_domain.ends_with(&format!(".{}", domain))
+                {
+                    return Err("i= is not within d=".to_string());
+                }
+                identity_domain
+            }
+            None => domain.clone(),
+        };
+
+        let headers: Vec<String> = required("h")?
+            .split(':')
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect();
+        if !headers.iter().any(|name| name == "from") {
+            return Err("From is not signed".to_string());
+        }
+
+        if let Some(methods) = tags.get("q")
+            && !methods.split(':').any(|method| method.trim() == "dns/txt")
+        {
+            return Err("unsupported query method".to_string());
+        }
+
+        let timestamp = number("t")?;
+        if let Some(expires) = number("x")? {
+            if timestamp.is_some_and(|timestamp| expires < timestamp) {
+                return Err("x= is before t=".to_string());
+            }
+            if expires < now {
+                return Err("signature expired".to_string());
+            }
+        }
+
+        Ok(Signature {
+            algorithm,
+            header_canonicalization,
+            body_canonicalization,
+            domain,
+            selector,
+            identity_domain,
+            headers,
+            body_hash: base64(required("bh")?).ok_or_else(|| "bh= is not base64".to_string())?,
+            signature: base64(required("b")?).ok_or_else(|| "b= is not base64".to_string())?,
+            length: number("l")?.map(|length| length as usize),
+        })
+    }
+}
+
+// RFC 6376 6.1, the reason is in the outcome when it doesn't pass
+pub(crate) async fn check(
+    resolver: &dyn Resolver,
+    headers: &[Header<'_>],
+    field: &Header<'_>,
+    body: &[u8],
+    tags: &HashMap<String, String>,
+    now: u64,
+) -> Result<(), Outcome> {
+    let signature = Signature::from_tags(tags, now).map_err(Outcome::PermError)?;
+    let key = fetch_key(
+        resolver,
+        signature.algorithm,
+        &signature.domain,
+        &signature.selector,
+        &signature.identity_domain,
+    )
+    .await?;
+
+    let mut body = signature.body_canonicalization.body(body);
+    if let Some(length) = signature.length {
+        if length > body.len() {
+            return Err(Outcome::PermError("l= is longer than the body".to_string()));
+        }
+        body.truncate(length);
+    }
+    if Sha256::digest(&body).as_slice() != signature.body_hash {
+        return Err(Outcome::Fail("body hash did not verify".to_string()));
+    }
+
+    let mut data = Vec::new();
+    for header in select(headers, &signature.headers) {
+        data.extend(signature.header_canonicalization.header(header));
+    }
+    let value = field.value().strip_suffix(b"\r\n").unwrap_or(field.value());
+    let own = Header {
+        name: field.name,
+        raw: &[
+            &field.raw[..field.name.len() + 1],
+            &without_signature(value)[..],
+        ]
+        .concat(),
+    };
+    let mut own = signature.header_canonicalization.header(&own);
+    if own.ends_with(b"\r\n") {
+        own.truncate(own.len() - 2);
+    }
+    data.extend(own);
+
+    if !key.verify(&data, &signature.signature) {
+        return Err(Outcome::Fail("signature did not verify".to_string()));
+    }
+    Ok(())
+}
+
+// RFC 6376 3.6.2, the key record at <selector>._domainkey.<domain>. `identity_domain` is the domain part of i=
+pub(crate) async fn fetch_key(
+    resolver: &dyn Resolver,
+    algorithm: Algorithm,
+    domain: &str,
+    selector: &str,
+    identity_domain: &str,
+) -> Result<VerifyingKey, Outcome> {
+    let name = format!("{}._domainkey.{}", selector, domain);
+    let records = match resolver.txt(&name).await {
+        Ok(records) => records,
+        Err(DnsError::NoSuchDomain) => Vec::new(),
+        Err(DnsError::Temporary(reason)) => {
+            return Err(Outcome::TempError(format!("key lookup failed: {}", reason)));
+        }
+    };
+    let Some(record) = records.first() else {
+        return Err(Outcome::PermError("no key for signature".to_string()));
+    };
+    parse_key_record(record, algorithm, identity_domain != domain).map_err(Outcome::PermError)
+}
+
+// RFC 6376 3.6.1, `subdomain` is whether i= is below d=
+fn parse_key_record(
+    record: &str,
+    algorithm: Algorithm,
+    subdomain: bool,
+) -> Result<VerifyingKey, String> {
+    let tags = parse_tags(record)?;
+    let listed = |name: &str, wanted: &[&str]| {
+        tags.get(name).is_none_or(|values| {
+            values
+                .split(':')
+                .any(|value| wanted.contains(&value.trim()))
+        })
+    };
+
+    if tags.get("v").is_some_and(|version| version != "DKIM1") {
+        return Err("unsupported key record version".to_string());
+    }
+    if !listed("h", &["sha256"]) {
+        return Err("key does not allow sha256".to_string());
+    }
+    if tags.get("k").map_or("rsa", String::as_str) != algorithm.key_type() {
+        return Err("key type does not match a=".to_string());
+    }
+    if !listed("s", &["*", "email"]) {
+        return Err("key is not for email".to_string());
+    }
+    // The s flag says i= can't be a subdomain of d=
+    if tags
+        .get("t")
+        .is_some_and(|flags| flags.split(':').any(|flag| flag.trim() == "s"))
+        && subdomain
+    {
+        return Err("key does not allow subdomains in i=".to_string());
+    }
+
+    let public = tags
+        .get("p")
+        .ok_or_else(|| "key record has no p= tag".to_string())?;
+    if public.is_empty() {
+        return Err("key revoked".to_string());
+    }
+    let public = base64(public).ok_or_else(|| "p= is not base64".to_string())?;
+    VerifyingKey::from_record(algorithm, &public)
+}
+
+// RFC 6376 3.2, `name=value` pairs split by semicolons, with whitespace ignored around either
+pub(crate) fn parse_tags(list: &str) -> Result<HashMap<String, String>, String> {
+    let mut tags = HashMap::new();
+    for spec in list.split(';') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        let Some((name, value)) = spec.split_once('=') else {
+            return Err(format!("malformed tag {}", spec));
+        };
+        let name = name.trim();
+        if tags
+            .insert(name.to_string(), value.trim().to_string())
+            .is_some()
+        {
+            return Err(format!("duplicate {}= tag", name));
+        }
+    }
+    Ok(tags)
+}
+
+// b=, bh= and p= can be folded anywhere, so whitespace inside them doesn't count
+pub(crate) fn base64(value: &str) -> Option<Vec<u8>> {
+    let value: String = value.split_whitespace().collect();
+    BASE64_STANDARD.decode(value).ok()
+}
+
+// RFC 6376 3.7, the field is hashed with the value of b= taken out and everything else as it was
+pub(crate) fn without_signature(value: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(value.len());
+    for (index, spec) in value.split(|&b| b == b';').enumerate() {
+        if index > 0 {
+            stripped.push(b';');
+        }
+        match spec.iter().position(|&b| b == b'=') {
+            Some(equals) if spec[..equals].trim_ascii() == b"b" => {
+                stripped.extend_from_slice(&spec[..=equals])
+            }
+            _ => stripped.extend_from_slice(spec),
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_dns::StaticResolver;
+
+    use super::*;
+    use crate::{DEFAULT_HEADERS, Signer, SigningKey};
+
+    // RFC 8463 appendix A, with the Ed25519 signature from A.3
+    const SIGNED: &[u8] = b"DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r\n \
+        d=football.example.com; i=@football.example.com;\r\n \
+        q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r\n \
+        subject : date : message-id : from : subject : date;\r\n \
+        bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n \
+        b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11BusFa3bT3FY\r\n \
+        5OsU7ZbnKELq+eXdp1Q1Dw==\r\n\
+        From: Joe SixPack <joe@football.example.com>\r\n\
+        To: Suzie Q <suzie@shopping.example.net>\r\n\
+        Subject: Is dinner ready?\r\n\
+        Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n\
+        Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n\
+        \r\n\
+        Hi.\r\n\
+        \r\n\
+        We lost the game.  Are you hungry yet?\r\n\
+        \r\n\
+        Joe.\r\n";
+
+    const KEY_NAME: &str = "brisbane._domainkey.football.example.com";
+    const KEY_RECORD: &str = "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+    async fn outcomes(resolver: &StaticResolver, message: &[u8]) -> Vec<Outcome> {
+        verify(resolver, message, 1528637909)
+            .await
+            .into_iter()
+            .map(|verification| verification.outcome)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rfc_8463_signature_passes() {
+        let resolver = StaticResolver::default().txt(KEY_NAME, KEY_RECORD);
+        let verifications = verify(&resolver, SIGNED, 1528637909).await;
+        assert_eq!(
+            verifications,
+            [Verification {
+                domain: "football.example.com".to_string(),
+                selector: "brisbane".to_string(),
+                identity: Some("@football.example.com".to_string()),
+                signature: "/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11BusFa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==".to_string(),
+                outcome: Outcome::Pass,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn changes_after_signing_fail() {
+        let resolver = StaticResolver::default().txt(KEY_NAME, KEY_RECORD);
+        let message = String::from_utf8(SIGNED.to_vec()).unwrap();
+
+        let body = message.replace("hungry", "thirsty");
+        assert_eq!(
+            outcomes(&resolver, body.as_bytes()).await,
+            [Outcome::Fail("body hash did not verify".to_string())]
+        );
+        let subject = message.replace("dinner", "lunch");
+        assert_eq!(
+            outcomes(&resolver, subject.as_bytes()).await,
+            [Outcome::Fail("signature did not verify".to_string())]
+        );
+        // Relaxed canonicalization doesn't mind whitespace changes
+        let spaced = message.replace("Subject: Is", "Subject:   Is");
+        assert_eq!(
+            outcomes(&resolver, spaced.as_bytes()).await,
+            [Outcome::Pass]
+        );
+    }
+
+    #[tokio::test]
+    async fn key_problems_are_errors() {
+        assert_eq!(
+            outcomes(&StaticResolver::default(), SIGNED).await,
+            [Outcome::PermError("no key for signature".to_string())]
+        );
+        assert!(matches!(
+            outcomes(&StaticResolver::default().fail(KEY_NAME), SIGNED).await[..],
+            [Outcome::TempError(_)]
+        ));
+        let revoked = StaticResolver::default().txt(KEY_NAME, "v=DKIM1; k=ed25519; p=");
+        assert_eq!(
+            outcomes(&revoked, SIGNED).await,
+            [Outcome::PermError("key revoked".to_string())]
+        );
+        let rsa = StaticResolver::default().txt(
+            KEY_NAME,
+            "v=DKIM1; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=",
+        );
+        assert_eq!(
+            outcomes(&rsa, SIGNED).await,
+            [Outcome::PermError("key type does not match a=".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn broken_signatures_are_permanent_errors() {
+        let resolver = StaticResolver::default().txt(KEY_NAME, KEY_RECORD);
+        let message = String::from_utf8(SIGNED.to_vec()).unwrap();
+
+        let unsigned_from = message.replace("h=from : to :", "h=to :");
+        let unsigned_from = unsigned_from.replace(": from : subject", ": subject");
+        assert_eq!(
+            outcomes(&resolver, unsigned_from.as_bytes()).await,
+            [Outcome::PermError("From is not signed".to_string())]
+        );
+        let expired = message.replace("t=1528637909;", "t=1528637909; x=1528637910;");
+        assert_eq!(
+            verify(&resolver, expired.as_bytes(), 1528637911).await[0].outcome,
+            Outcome::PermError("signature expired".to_string())
+        );
+        let foreign = message.replace("i=@football.example.com", "i=@example.com");
+        assert_eq!(
+            outcomes(&resolver, foreign.as_bytes()).await,
+            [Outcome::PermError("i= is not within d=".to_string())]
+        );
+        let duplicated = message.replace("s=brisbane;", "s=brisbane; s=brisbane;");
+        let verifications = verify(&resolver, duplicated.as_bytes(), 0).await;
+        assert_eq!(
+            verifications[0].outcome,
+            Outcome::PermError("duplicate s= tag".to_string())
+        );
+        assert!(verifications[0].domain.is_empty());
+    }
+
+    #[tokio::test]
+    async fn our_own_signatures_verify() {
+        let key = SigningKey::from_pem(include_str!("../../../tests/dkim/rsa.pem")).unwrap();
+        let record = key.dns_record().unwrap();
+        let signer = Signer {
+            domain: "example.com".to_string(),
+            selector: "eemail".to_string(),
+            key,
+            headers: DEFAULT_HEADERS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        };
+        let message = b"From: a@example.com\r\nSubject: hi\r\n\r\nHi there\r\n\r\n";
+        let mut signed = signer.sign(message, 0).unwrap().into_bytes();
+        let end = signed.len();
+        signed.extend_from_slice(message);
+
+        let resolver = StaticResolver::default().txt("eemail._domainkey.example.com", &record);
+        assert_eq!(outcomes(&resolver, &signed).await, [Outcome::Pass]);
+
+        // Fields that weren't there when it was signed can't be slipped in either
+        for added in ["Subject: win\r\n", "Reply-To: b@example.org\r\n"] {
+            let mut changed = signed.clone();
+            changed.splice(end..end, added.bytes());
+            assert!(matches!(
+                outcomes(&resolver, &changed).await[..],
+                [Outcome::Fail(_)]
+            ));
+        }
+    }
+
+    #[test]
+    fn only_the_b_tag_value_is_removed() {
+        assert_eq!(
+            without_signature(b" v=1; bh=abc;\r\n b=def\r\n ghi; t=1"),
+            b" v=1; bh=abc;\r\n b=; t=1"
+        );
+        assert_eq!(without_signature(b"v=1; b = def"), b"v=1; b =");
+    }
+}
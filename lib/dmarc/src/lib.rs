@@ -0,0 +1,404 @@
+// DMARC (RFC 7489), whether mail really comes from the domain in its From: header
+use std::collections::HashMap;
+
+use eemail_lib_dns::{Error as DnsError, Resolver};
+use serde::{Deserialize, Serialize};
+
+pub use crate::{
+    record::{Alignment, Record},
+    report::{DkimResult, Report, Row, SpfResult},
+};
+
+mod record;
+mod report;
+
+// No public suffix list to hand, so the organizational domain comes from the records found walking up the tree
+// (as DMARCbis does it), which is limited to this many lookups
+const MAX_WALK: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl Policy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(Policy::None),
+            "quarantine" => Some(Policy::Quarantine),
+            "reject" => Some(Policy::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Policy::None => "none",
+            Policy::Quarantine => "quarantine",
+            Policy::Reject => "reject",
+        }
+    }
+
+    // RFC 7489 6.6.4, what mail left out by pct= gets instead
+    fn relaxed(self) -> Self {
+        match self {
+            Policy::Reject => Policy::Quarantine,
+            _ => Policy::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    // The domain has no policy
+    None,
+    Pass,
+    Fail,
+    // Looking for the policy failed, worth trying again later
+    TempError,
+}
+
+impl Outcome {
+    // As it appears in Authentication-Results
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::None => "none",
+            Outcome::Pass => "pass",
+            Outcome::Fail => "fail",
+            Outcome::TempError => "temperror",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Evaluation {
+    // From the From: header, lowercased
+    pub domain: String,
+    pub outcome: Outcome,
+    // What went wrong, for temperror
+    pub reason: Option<String>,
+    // Where the policy was found, the From: domain itself or one of its parents
+    pub policy_domain: Option<String>,
+    pub record: Option<Record>,
+    pub dkim_aligned: bool,
+    pub spf_aligned: bool,
+}
+
+impl Evaluation {
+    // What the domain asks for, sp= applies when the record came from further up
+    pub fn policy(&self) -> Policy {
+        match &self.record {
+            Some(record) if self.policy_domain.as_ref() != Some(&self.domain) => {
+                record.subdomain_policy.unwrap_or(record.policy)
+            }
+            Some(record) => record.policy,
+            None => Policy::None,
+        }
+    }
+
+    // RFC 7489 6.6.4, what should happen to the mail. `roll` is a random number below 100, pct= decides which get the policy
+    pub fn disposition(&self, roll: u8) -> Policy {
+        if self.outcome != Outcome::Fail {
+            return Policy::None;
+        }
+        let percent = self.record.as_ref().map_or(100, |record| record.percent);
+        if roll < percent {
+            self.policy()
+        } else {
+            self.policy().relaxed()
+        }
+    }
+}
+
+// What SPF and DKIM passed, only these count towards DMARC
+#[derive(Debug, Clone, Default)]
+pub struct Identifiers<'a> {
+    // The domain of the MAIL FROM identity, if SPF passed it
+    pub spf: Option<&'a str>,
+    // d= of every signature that verified
+    pub dkim: Vec<&'a str>,
+}
+
+// RFC 7489 6.6, `from` is the domain of the From: header
+pub async fn evaluate(resolver: &dyn Resolver, from: &str, passed: &Identifiers<'_>) -> Evaluation {
+    let from = normalize(from);
+    let mut evaluation = Evaluation {
+        domain: from.clone(),
+        outcome: Outcome::None,
+        reason: None,
+        policy_domain: None,
+        record: None,
+        dkim_aligned: false,
+        spf_aligned: false,
+    };
+    let mut walker = Walker {
+        resolver,
+        found: HashMap::new(),
+    };
+
+    let records = match walker.walk(&from).await {
+        Ok(records) => records,
+        Err(reason) => {
+            evaluation.outcome = Outcome::TempError;
+            evaluation.reason = Some(reason);
+            return evaluation;
+        }
+    };
+    // The closest record is the policy
+    let Some((policy_domain, record)) = records.first().cloned() else {
+        return evaluation;
+    };
+    let organization = organization(&from, &records);
+
+    for domain in &passed.dkim {
+        if walker
+            .aligned(&from, &organization, domain, record.dkim_alignment)
+            .await
+        {
+            evaluation.dkim_aligned = true;
+            break;
+        }
+    }
+    if let Some(domain) = passed.spf {
+        evaluation.spf_aligned = walker
+            .aligned(&from, &organization, domain, record.spf_alignment)
+            .await;
+    }
+
+    evaluation.outcome = if evaluation.dkim_aligned || evaluation.spf_aligned {
+        Outcome::Pass
+    } else {
+        Outcome::Fail
+    };
+    evaluation.policy_domain = Some(policy_domain);
+    evaluation.record = Some(record);
+    evaluation
+}
+
+// Looks up _dmarc records, each name only once per evaluation
+struct Walker<'a> {
+    resolver: &'a dyn Resolver,
+    found: HashMap<String, Option<Record>>,
+}
+
+impl Walker<'_> {
+    // Every record from `domain` up to the root, closest first
+    async fn walk(&mut self, domain: &str) -> Result<Vec<(String, Record)>, String> {
+        let labels: Vec<&str> = domain.split('.').collect();
+        // A long name jumps straight from itself to its last few labels
+        let lengths: Vec<usize> = if labels.len() > MAX_WALK {
+            std::iter::once(labels.len())
+                .chain((1..MAX_WALK).rev())
+                .collect()
+        } else {
+            (1..=labels.len()).rev().collect()
+        };
+
+        let mut records = Vec::new();
+        for length in lengths {
+            let name = labels[labels.len() - length..].join(".");
+            if let Some(record) = self.lookup(&name).await? {
+                records.push((name, record));
+            }
+        }
+        Ok(records)
+    }
+
+    async fn lookup(&mut self, name: &str) -> Result<Option<Record>, String> {
+        if let Some(record) = self.found.get(name) {
+            return Ok(record.clone());
+        }
+        let record = match self.resolver.txt(&format!("_dmarc.{}", name)).await {
+            Ok(records) => record::select(&records),
+            Err(DnsError::NoSuchDomain) => None,
+            Err(DnsError::Temporary(reason)) => {
+                return Err(format!("_dmarc.{}: {}", name, reason));
+            }
+        };
+        self.found.insert(name.to_string(), record.clone());
+        Ok(record)
+    }
+
+    // RFC 7489 3.1, whether an authenticated `domain` stands for `from`. Lookup failures here just mean it doesn't
+    async fn aligned(
+        &mut self,
+        from: &str,
+        from_organization: &str,
+        domain: &str,
+        alignment: Alignment,
+    ) -> bool {
+        let domain = normalize(domain);
+        if domain == from {
+            return true;
+        }
+        // Can't share an organizational domain without at least being under the same one
+        if alignment == Alignment::Strict || !is_within(&domain, from_organization) {
+            return false;
+        }
+        match self.walk(&domain).await {
+            Ok(records) => organization(&domain, &records) == from_organization,
+            Err(_) => false,
+        }
+    }
+}
+
+// DMARCbis 4.10.2: psd=n marks the organization and psd=y the public suffix above it,
+// otherwise it's the furthest record up. Records on a bare TLD never count without psd=
+fn organization(domain: &str, records: &[(String, Record)]) -> String {
+    for (name, record) in records {
+        match record.public_suffix {
+            Some(false) => return name.clone(),
+            Some(true) => {
+                let labels: Vec<&str> = domain.split('.').collect();
+                let below = (name.split('.').count() + 1).min(labels.len());
+                return labels[labels.len() - below..].join(".");
+            }
+            None => {}
+        }
+    }
+    records
+        .iter()
+        .rev()
+        .find(|(name, _)| name.contains('.'))
+        .map_or(domain.to_string(), |(name, _)| name.clone())
+}
+
+fn is_within(name: &str, domain: &str) -> bool {
+    name == domain || name.ends_with(&format!(".{}", domain))
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_dns::StaticResolver;
+
+    use super::*;
+
+    fn zone() -> StaticResolver {
+        StaticResolver::default()
+            .txt(
+                "_dmarc.example.org",
+                "v=DMARC1; p=reject; sp=quarantine; pct=50",
+            )
+            .txt(
+                "_dmarc.strict.example",
+                "v=DMARC1; p=reject; adkim=s; aspf=s",
+            )
+            .txt("_dmarc.example", "v=DMARC1; p=none; psd=y")
+            .txt("_dmarc.com", "v=DMARC1; p=none")
+            .txt("_dmarc.example.com", "v=DMARC1; p=quarantine")
+    }
+
+    async fn check(from: &str, spf: Option<&str>, dkim: &[&str]) -> Evaluation {
+        let passed = Identifiers {
+            spf,
+            dkim: dkim.to_vec(),
+        };
+        evaluate(&zone(), from, &passed).await
+    }
+
+    #[tokio::test]
+    async fn alignment_follows_the_record() {
+        // Relaxed alignment takes anything in the same organization, either way round
+        assert_eq!(
+            check("example.org", None, &["example.org"]).await.outcome,
+            Outcome::Pass
+        );
+        assert_eq!(
+            check("example.org", Some("bounces.example.org"), &[])
+                .await
+                .outcome,
+            Outcome::Pass
+        );
+        let evaluation = check("news.example.org", None, &["mail.example.org"]).await;
+        assert!(evaluation.dkim_aligned && !evaluation.spf_aligned);
+        assert_eq!(evaluation.policy_domain.as_deref(), Some("example.org"));
+
+        // Strict wants exact matches
+        let evaluation = check(
+            "strict.example",
+            Some("strict.example"),
+            &["mail.strict.example"],
+        )
+        .await;
+        assert!(evaluation.spf_aligned && !evaluation.dkim_aligned);
+        assert_eq!(
+            check("strict.example", Some("a.strict.example"), &[])
+                .await
+                .outcome,
+            Outcome::Fail
+        );
+
+        // Passing for someone else doesn't help
+        assert_eq!(
+            check("example.org", Some("example.net"), &["example.net"])
+                .await
+                .outcome,
+            Outcome::Fail
+        );
+        // The public suffix's record doesn't make its registrants one organization, and neither does a bare TLD's
+        assert_eq!(
+            check("a.example", None, &["b.example"]).await.outcome,
+            Outcome::Fail
+        );
+        assert_eq!(
+            check("example.com", None, &["other.com"]).await.outcome,
+            Outcome::Fail
+        );
+    }
+
+    #[tokio::test]
+    async fn policy_comes_from_the_closest_record() {
+        let evaluation = check("example.org", None, &[]).await;
+        assert_eq!(evaluation.policy(), Policy::Reject);
+        let evaluation = check("mail.example.org", None, &[]).await;
+        assert_eq!(evaluation.policy(), Policy::Quarantine);
+        let evaluation = check("example.net", None, &[]).await;
+        assert_eq!(evaluation.outcome, Outcome::None);
+        assert_eq!(evaluation.policy(), Policy::None);
+
+        let failing = zone().fail("_dmarc.example.net");
+        let evaluation = evaluate(&failing, "example.net", &Identifiers::default()).await;
+        assert_eq!(evaluation.outcome, Outcome::TempError);
+    }
+
+    #[tokio::test]
+    async fn percentage_softens_the_rest() {
+        let evaluation = check("example.org", None, &[]).await;
+        assert_eq!(evaluation.disposition(10), Policy::Reject);
+        assert_eq!(evaluation.disposition(60), Policy::Quarantine);
+        let evaluation = check("mail.example.org", None, &[]).await;
+        assert_eq!(evaluation.disposition(60), Policy::None);
+        let evaluation = check("example.org", None, &["example.org"]).await;
+        assert_eq!(evaluation.disposition(10), Policy::None);
+    }
+
+    #[test]
+    fn organization_comes_from_the_walk() {
+        let record = |psd| Record {
+            public_suffix: psd,
+            ..record::parse("v=DMARC1; p=none").unwrap()
+        };
+        let records = [
+            ("a.example.co.uk".to_string(), record(None)),
+            ("example.co.uk".to_string(), record(None)),
+            ("co.uk".to_string(), record(Some(true))),
+        ];
+        assert_eq!(organization("x.a.example.co.uk", &records), "example.co.uk");
+        assert_eq!(
+            organization("x.a.example.co.uk", &records[..2]),
+            "example.co.uk"
+        );
+        assert_eq!(
+            organization("x.a.example.co.uk", &records[..1]),
+            "a.example.co.uk"
+        );
+        assert_eq!(organization("x.a.example.co.uk", &[]), "x.a.example.co.uk");
+    }
+}
@@ -0,0 +1,179 @@
+// RFC 7489 6.3, the policy record published at _dmarc.<domain>
+use serde::{Deserialize, Serialize};
+
+use crate::Policy;
+
+// Reports are asked for at most hourly and sent at least daily, whatever ri= says
+const MIN_INTERVAL: u64 = 60 * 60;
+const DEFAULT_INTERVAL: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Alignment {
+    // The organizational domains have to match
+    #[default]
+    Relaxed,
+    // The domains have to match exactly
+    Strict,
+}
+
+impl Alignment {
+    // As it appears in a record and in reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            Alignment::Relaxed => "r",
+            Alignment::Strict => "s",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub policy: Policy,
+    // For subdomains of where the record was found, the same as `policy` if unset
+    pub subdomain_policy: Option<Policy>,
+    pub dkim_alignment: Alignment,
+    pub spf_alignment: Alignment,
+    // How much of the failing mail the policy is applied to, the rest gets the next policy down
+    pub percent: u8,
+    // Where aggregate reports go, only mailto: URIs are any use to us
+    pub aggregate: Vec<String>,
+    // Seconds between aggregate reports
+    pub interval: u64,
+    // psd=y marks a public suffix (so the organization is one label below it), psd=n an organization
+    pub public_suffix: Option<bool>,
+}
+
+// The one record out of all the TXT records at a name, any number other than one means there's no policy there
+pub(crate) fn select(records: &[String]) -> Option<Record> {
+    let mut dmarc = records.iter().filter_map(|record| parse(record));
+    match (dmarc.next(), dmarc.next()) {
+        (Some(record), None) => Some(record),
+        _ => None,
+    }
+}
+
+// None for anything that isn't a DMARC record at all. Unknown tags are ignored and bad values fall back to the default
+pub(crate) fn parse(record: &str) -> Option<Record> {
+    let mut tags = record
+        .split(';')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => (tag, ""),
+        });
+
+    // v= has to come first, then p= is only required if there's no rua= (RFC 7489 6.6.3)
+    match tags.next() {
+        Some(("v", "DMARC1")) => {}
+        _ => return None,
+    }
+    let mut parsed = Record {
+        policy: Policy::None,
+        subdomain_policy: None,
+        dkim_alignment: Alignment::Relaxed,
+        spf_alignment: Alignment::Relaxed,
+        percent: 100,
+        aggregate: Vec::new(),
+        interval: DEFAULT_INTERVAL,
+        public_suffix: None,
+    };
+    let mut policy = None;
+    for (name, value) in tags {
+        match name {
+            "p" => policy = Policy::parse(value),
+            "sp" => parsed.subdomain_policy = Policy::parse(value),
+            "adkim" => parsed.dkim_alignment = alignment(value),
+            "aspf" => parsed.spf_alignment = alignment(value),
+            "pct" => parsed.percent = value.parse().ok().filter(|&pct| pct <= 100).unwrap_or(100),
+            "rua" => {
+                parsed.aggregate = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|uri| !uri.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            "ri" => {
+                parsed.interval = value.parse().map_or(DEFAULT_INTERVAL, |ri: u64| {
+                    ri.clamp(MIN_INTERVAL, DEFAULT_INTERVAL)
+                })
+            }
+            "psd" => {
+                parsed.public_suffix = match value {
+                    "y" => Some(true),
+                    "n" => Some(false),
+                    _ => None,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match policy {
+        Some(policy) => parsed.policy = policy,
+        None if parsed.aggregate.is_empty() => return None,
+        None => {}
+    }
+    Some(parsed)
+}
+
+fn alignment(value: &str) -> Alignment {
+    match value {
+        "s" => Alignment::Strict,
+        _ => Alignment::Relaxed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_parse_with_defaults() {
+        let record = parse("v=DMARC1; p=reject").unwrap();
+        assert_eq!(record.policy, Policy::Reject);
+        assert_eq!(record.subdomain_policy, None);
+        assert_eq!(record.dkim_alignment, Alignment::Relaxed);
+        assert_eq!(record.percent, 100);
+        assert!(record.aggregate.is_empty());
+        assert_eq!(record.interval, DEFAULT_INTERVAL);
+
+        let record = parse(
+            "v=DMARC1;p=quarantine; sp=none; adkim=s; aspf=s; pct=25; \
+             rua=mailto:dmarc@example.org,mailto:other@example.net!10m; ri=3600; psd=n; fo=1",
+        )
+        .unwrap();
+        assert_eq!(record.policy, Policy::Quarantine);
+        assert_eq!(record.subdomain_policy, Some(Policy::None));
+        assert_eq!(record.dkim_alignment, Alignment::Strict);
+        assert_eq!(record.spf_alignment, Alignment::Strict);
+        assert_eq!(record.percent, 25);
+        assert_eq!(
+            record.aggregate,
+            ["mailto:dmarc@example.org", "mailto:other@example.net!10m"]
+        );
+        assert_eq!(record.interval, 3600);
+        assert_eq!(record.public_suffix, Some(false));
+    }
+
+    #[test]
+    fn only_real_records_are_used() {
+        assert!(parse("p=reject; v=DMARC1").is_none());
+        assert!(parse("v=spf1 -all").is_none());
+        assert!(parse("v=DMARC1; p=bogus").is_none());
+        // A bad policy with somewhere to report to is treated as p=none
+        assert_eq!(
+            parse("v=DMARC1; p=bogus; rua=mailto:d@example.org")
+                .unwrap()
+                .policy,
+            Policy::None
+        );
+        let records = |records: &[&str]| -> Vec<String> {
+            records.iter().map(|record| record.to_string()).collect()
+        };
+        assert!(select(&records(&["v=DMARC1; p=none", "v=DMARC1; p=reject"])).is_none());
+        assert!(select(&records(&["something else", "v=DMARC1; p=none"])).is_some());
+    }
+}
@@ -0,0 +1,405 @@
+// RFC 7489 7.2, aggregate reports telling a domain what became of mail claiming to be from it
+use std::net::IpAddr;
+
+use eemail_lib_dns::Resolver;
+use serde::{Deserialize, Serialize};
+
+use crate::{Alignment, Evaluation, Policy, is_within, normalize};
+
+// One reporting period for one policy domain, with the policy as it was published when it started
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub domain: String,
+    pub dkim_alignment: Alignment,
+    pub spf_alignment: Alignment,
+    pub policy: Policy,
+    pub subdomain_policy: Policy,
+    pub percent: u8,
+    // The rua= URIs
+    pub aggregate: Vec<String>,
+    // Seconds after `begin` that the report is due
+    pub interval: u64,
+    pub begin: u64,
+    pub rows: Vec<Row>,
+}
+
+// Messages that came from the same place and fared the same way
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub source_ip: IpAddr,
+    pub count: u64,
+    // What was actually done with the mail
+    pub disposition: Policy,
+    pub dkim_aligned: bool,
+    pub spf_aligned: bool,
+    // Why `disposition` isn't what the policy asked for, local_policy or sampled_out
+    pub reason: Option<String>,
+    pub header_from: String,
+    pub envelope_from: Option<String>,
+    pub dkim: Vec<DkimResult>,
+    pub spf: Vec<SpfResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DkimResult {
+    pub domain: String,
+    pub selector: String,
+    pub result: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SpfResult {
+    pub domain: String,
+    // mfrom or helo
+    pub scope: String,
+    pub result: String,
+}
+
+impl Report {
+    // None when the domain has no policy, or doesn't want reports
+    pub fn new(evaluation: &Evaluation, begin: u64) -> Option<Self> {
+        let record = evaluation.record.as_ref()?;
+        if record.aggregate.is_empty() {
+            return None;
+        }
+        Some(Self {
+            domain: evaluation.policy_domain.clone()?,
+            dkim_alignment: record.dkim_alignment,
+            spf_alignment: record.spf_alignment,
+            policy: record.policy,
+            subdomain_policy: record.subdomain_policy.unwrap_or(record.policy),
+            percent: record.percent,
+            aggregate: record.aggregate.clone(),
+            interval: record.interval,
+            begin,
+            rows: Vec::new(),
+        })
+    }
+
+    // Counts `row` in with any identical one
+    pub fn add(&mut self, row: Row) {
+        let same = self.rows.iter_mut().find(|existing| {
+            Row {
+                count: row.count,
+                ..(*existing).clone()
+            } == row
+        });
+        match same {
+            Some(existing) => existing.count += row.count,
+            None => self.rows.push(row),
+        }
+    }
+
+    pub fn is_due(&self, now: u64) -> bool {
+        now >= self.begin + self.interval
+    }
+
+    // The addresses from mailto: URIs, less any size limit. Other schemes are no use to a mail server
+    pub fn destinations(&self) -> Vec<String> {
+        self.aggregate
+            .iter()
+            .filter_map(|uri| {
+                let (scheme, rest) = uri.split_once(':')?;
+                if !scheme.eq_ignore_ascii_case("mailto") {
+                    return None;
+                }
+                let address = rest.split('!').next()?.split('?').next()?;
+                address.contains('@').then(|| address.to_string())
+            })
+            .collect()
+    }
+
+    // RFC 7489 7.1, addresses outside the policy domain have to have agreed to take its reports.
+    // A lookup that fails just leaves that address out this time
+    pub async fn verified_destinations(&self, resolver: &dyn Resolver) -> Vec<String> {
+        let mut verified = Vec::new();
+        for address in self.destinations() {
+            let Some((_, domain)) = address.rsplit_once('@') else {
+                continue;
+            };
+            let domain = normalize(domain);
+            if is_within(&domain, &self.domain) || is_within(&self.domain, &domain) {
+                verified.push(address);
+                continue;
+            }
+            let name = format!("{}._report._dmarc.{}", self.domain, domain);
+            let agreed = resolver.txt(&name).await.is_ok_and(|records| {
+                records
+                    .iter()
+                    .any(|record| record.split(';').next().map(str::trim) == Some("v=DMARC1"))
+            });
+            if agreed {
+                verified.push(address);
+            }
+        }
+        verified
+    }
+
+    // RFC 7489 7.2.1.1, receiver "!" policy-domain "!" begin "!" end
+    pub fn file_name(&self, receiver: &str, end: u64) -> String {
+        format!("{}!{}!{}!{}.xml", receiver, self.domain, self.begin, end)
+    }
+
+    // RFC 7489 appendix C
+    pub fn to_xml(&self, org_name: &str, email: &str, report_id: &str, end: u64) -> String {
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\r\n\
+             <feedback>\r\n\
+             \x20 <report_metadata>\r\n\
+             \x20   <org_name>{}</org_name>\r\n\
+             \x20   <email>{}</email>\r\n\
+             \x20   <report_id>{}</report_id>\r\n\
+             \x20   <date_range>\r\n\
+             \x20     <begin>{}</begin>\r\n\
+             \x20     <end>{}</end>\r\n\
+             \x20   </date_range>\r\n\
+             \x20 </report_metadata>\r\n\
+             \x20 <policy_published>\r\n\
+             \x20   <domain>{}</domain>\r\n\
+             \x20   <adkim>{}</adkim>\r\n\
+             \x20   <aspf>{}</aspf>\r\n\
+             \x20   <p>{}</p>\r\n\
+             \x20   <sp>{}</sp>\r\n\
+             \x20   <pct>{}</pct>\r\n\
+             \x20 </policy_published>\r\n",
+            escape(org_name),
+            escape(email),
+            escape(report_id),
+            self.begin,
+            end,
+            escape(&self.domain),
+            self.dkim_alignment.name(),
+            self.spf_alignment.name(),
+            self.policy.name(),
+            self.subdomain_policy.name(),
+            self.percent,
+        );
+        for row in &self.rows {
+            xml.push_str(&row.to_xml());
+        }
+        xml.push_str("</feedback>\r\n");
+        xml
+    }
+}
+
+impl Row {
+    fn to_xml(&self) -> String {
+        let result = |aligned: bool| if aligned { "pass" } else { "fail" };
+        let mut xml = format!(
+            "  <record>\r\n\
+             \x20   <row>\r\n\
+             \x20     <source_ip>{}</source_ip>\r\n\
+             \x20     <count>{}</count>\r\n\
+             \x20     <policy_evaluated>\r\n\
+             \x20       <disposition>{}</disposition>\r\n\
+             \x20       <dkim>{}</dkim>\r\n\
+             \x20       <spf>{}</spf>\r\n",
+            self.source_ip,
+            self.count,
+            self.disposition.name(),
+            result(self.dkim_aligned),
+            result(self.spf_aligned),
+        );
+        if let Some(reason) = &self.reason {
+            xml.push_str(&format!(
+                "        <reason>\r\n\
+                 \x20         <type>{}</type>\r\n\
+                 \x20       </reason>\r\n",
+                escape(reason)
+            ));
+        }
+        xml.push_str(
+            "      </policy_evaluated>\r\n\
+             \x20   </row>\r\n\
+             \x20   <identifiers>\r\n",
+        );
+        if let Some(envelope_from) = &self.envelope_from {
+            xml.push_str(&format!(
+                "      <envelope_from>{}</envelope_from>\r\n",
+                escape(envelope_from)
+            ));
+        }
+        xml.push_str(&format!(
+            "      <header_from>{}</header_from>\r\n\
+             \x20   </identifiers>\r\n\
+             \x20   <auth_results>\r\n",
+            escape(&self.header_from)
+        ));
+        for dkim in &self.dkim {
+            xml.push_str(&format!(
+                "      <dkim>\r\n\
+                 \x20       <domain>{}</domain>\r\n\
+                 \x20       <selector>{}</selector>\r\n\
+                 \x20       <result>{}</result>\r\n\
+                 \x20     </dkim>\r\n",
+                escape(&dkim.domain),
+                escape(&dkim.selector),
+                escape(&dkim.result)
+            ));
+        }
+        for spf in &self.spf {
+            xml.push_str(&format!(
+                "      <spf>\r\n\
+                 \x20       <domain>{}</domain>\r\n\
+                 \x20       <scope>{}</scope>\r\n\
+                 \x20       <result>{}</result>\r\n\
+                 \x20     </spf>\r\n",
+                escape(&spf.domain),
+                escape(&spf.scope),
+                escape(&spf.result)
+            ));
+        }
+        xml.push_str(
+            "    </auth_results>\r\n\
+             \x20 </record>\r\n",
+        );
+        xml
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_dns::StaticResolver;
+
+    use super::*;
+    use crate::{Outcome, record};
+
+    fn report() -> Report {
+        let evaluation = Evaluation {
+            domain: "example.org".to_string(),
+            outcome: Outcome::Fail,
+            reason: None,
+            policy_domain: Some("example.org".to_string()),
+            record: record::parse(
+                "v=DMARC1; p=reject; rua=mailto:dmarc@example.org!10m,https://example.org/r",
+            ),
+            dkim_aligned: false,
+            spf_aligned: false,
+        };
+        Report::new(&evaluation, 1000).unwrap()
+    }
+
+    fn row(source_ip: &str) -> Row {
+        Row {
+            source_ip: source_ip.parse().unwrap(),
+            count: 1,
+            disposition: Policy::Quarantine,
+            dkim_aligned: false,
+            spf_aligned: true,
+            reason: Some("local_policy".to_string()),
+            header_from: "example.org".to_string(),
+            envelope_from: Some("example.org".to_string()),
+            dkim: vec![DkimResult {
+                domain: "example.net".to_string(),
+                selector: "s&1".to_string(),
+                result: "pass".to_string(),
+            }],
+            spf: vec![SpfResult {
+                domain: "example.org".to_string(),
+                scope: "mfrom".to_string(),
+                result: "pass".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn identical_rows_are_counted_together() {
+        let mut report = report();
+        report.add(row("192.0.2.1"));
+        report.add(row("192.0.2.1"));
+        report.add(row("192.0.2.2"));
+        assert_eq!(report.rows.len(), 2);
+        assert_eq!(report.rows[0].count, 2);
+        assert_eq!(report.destinations(), ["dmarc@example.org"]);
+        assert!(!report.is_due(1000 + 86399));
+        assert!(report.is_due(1000 + 86400));
+        assert_eq!(
+            report.file_name("mail.example.com", 2000),
+            "mail.example.com!example.org!1000!2000.xml"
+        );
+    }
+
+    #[tokio::test]
+    async fn outside_destinations_have_to_agree() {
+        let mut report = report();
+        report.aggregate = vec![
+            "mailto:dmarc@reports.example.org".to_string(),
+            "mailto:agreed@example.net".to_string(),
+            "mailto:other@example.com".to_string(),
+        ];
+        let resolver =
+            StaticResolver::default().txt("example.org._report._dmarc.example.net", "v=DMARC1");
+        assert_eq!(
+            report.verified_destinations(&resolver).await,
+            ["dmarc@reports.example.org", "agreed@example.net"]
+        );
+    }
+
+    #[test]
+    fn reports_are_rfc_xml() {
+        let mut report = report();
+        report.add(row("192.0.2.1"));
+        assert_eq!(
+            report.to_xml("example.com", "postmaster@example.com", "id-1", 2000),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\r\n\
+             <feedback>\r\n\
+             \x20 <report_metadata>\r\n\
+             \x20   <org_name>example.com</org_name>\r\n\
+             \x20   <email>postmaster@example.com</email>\r\n\
+             \x20   <report_id>id-1</report_id>\r\n\
+             \x20   <date_range>\r\n\
+             \x20     <begin>1000</begin>\r\n\
+             \x20     <end>2000</end>\r\n\
+             \x20   </date_range>\r\n\
+             \x20 </report_metadata>\r\n\
+             \x20 <policy_published>\r\n\
+             \x20   <domain>example.org</domain>\r\n\
+             \x20   <adkim>r</adkim>\r\n\
+             \x20   <aspf>r</aspf>\r\n\
+             \x20   <p>reject</p>\r\n\
+             \x20   <sp>reject</sp>\r\n\
+             \x20   <pct>100</pct>\r\n\
+             \x20 </policy_published>\r\n\
+             \x20 <record>\r\n\
+             \x20   <row>\r\n\
+             \x20     <source_ip>192.0.2.1</source_ip>\r\n\
+             \x20     <count>1</count>\r\n\
+             \x20     <policy_evaluated>\r\n\
+             \x20       <disposition>quarantine</disposition>\r\n\
+             \x20       <dkim>fail</dkim>\r\n\
+             \x20       <spf>pass</spf>\r\n\
+             \x20       <reason>\r\n\
+             \x20         <type>local_policy</type>\r\n\
+             \x20       </reason>\r\n\
+             \x20     </policy_evaluated>\r\n\
+             \x20   </row>\r\n\
+             \x20   <identifiers>\r\n\
+             \x20     <envelope_from>example.org</envelope_from>\r\n\
+             \x20     <header_from>example.org</header_from>\r\n\
+             \x20   </identifiers>\r\n\
+             \x20   <auth_results>\r\n\
+             \x20     <dkim>\r\n\
+             \x20       <domain>example.net</domain>\r\n\
+             \x20       <selector>s&amp;1</selector>\r\n\
+             \x20       <result>pass</result>\r\n\
+             \x20     </dkim>\r\n\
+             \x20     <spf>\r\n\
+             \x20       <domain>example.org</domain>\r\n\
+             \x20       <scope>mfrom</scope>\r\n\
+             \x20       <result>pass</result>\r\n\
+             \x20     </spf>\r\n\
+             \x20   </auth_results>\r\n\
+             \x20 </record>\r\n\
+             </feedback>\r\n"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+// DNS lookups for mail, behind a trait so tests (or a different resolver) can stand in for the real thing
+use std::{fmt, net::IpAddr};
+
+use async_trait::async_trait;
+
+mod stub;
+mod system;
+
+pub use stub::StaticResolver;
+pub use system::SystemResolver;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mx {
+    pub preference: u16,
+    pub exchange: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // NXDOMAIN, asking again later won't change anything
+    NoSuchDomain,
+    // Timeouts, SERVFAIL and the like, worth another go later
+    Temporary(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoSuchDomain => write!(f, "domain does not exist"),
+            Error::Temporary(reason) => write!(f, "lookup failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// A name that exists but has no records of the asked for type is an empty list, not an error
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn mx(&self, domain: &str) -> Result<Vec<Mx>, Error>;
+    // Both A and AAAA, IPv4 first as it's far more likely to have working reverse DNS
+    async fn ip(&self, host: &str) -> Result<Vec<IpAddr>, Error>;
+    // One entry per record, with its strings joined together (RFC 6376 3.6.2.2, RFC 7208 3.3)
+    async fn txt(&self, name: &str) -> Result<Vec<String>, Error>;
+    // The names in the reverse zone for `ip`, without the root dot
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, Error>;
+}
+
+// The hosts to try for `domain`, most preferred first (RFC 5321 5.1). An empty list is a null MX (RFC 7505)
+pub async fn mail_exchangers(resolver: &dyn Resolver, domain: &str) -> Result<Vec<String>, Error> {
+    let mut records = resolver.mx(domain).await?;
+
+    // No MX at all means the domain itself is the implicit MX
+    if records.is_empty() {
+        return Ok(vec![domain.trim_end_matches('.').to_string()]);
+    }
+
+    if records.len() == 1 && matches!(records[0].exchange.as_str(), "" | ".") {
+        return Ok(Vec::new());
+    }
+
+    // Sorting is stable, so servers with the same preference keep the order they were given in
+    records.sort_by_key(|record| record.preference);
+    Ok(records
+        .into_iter()
+        .map(|record| record.exchange.trim_end_matches('.').to_string())
+        .filter(|exchange| !exchange.is_empty())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mail_exchangers_are_sorted_by_preference() {
+        let resolver = StaticResolver::default()
+            .mx("example.org", 20, "backup.example.org.")
+            .mx("example.org", 10, "mx1.example.org.")
+            .mx("example.org", 10, "mx2.example.org.");
+        assert_eq!(
+            mail_exchangers(&resolver, "example.org").await.unwrap(),
+            ["mx1.example.org", "mx2.example.org", "backup.example.org"]
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_exchangers_fall_back_to_the_domain() {
+        let resolver = StaticResolver::default().ip("example.org", "192.0.2.1".parse().unwrap());
+        assert_eq!(
+            mail_exchangers(&resolver, "example.org").await.unwrap(),
+            ["example.org"]
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_exchangers_honour_null_mx() {
+        let resolver = StaticResolver::default().mx("example.org", 0, ".");
+        assert!(
+            mail_exchangers(&resolver, "example.org")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_exchangers_pass_on_lookup_failures() {
+        let resolver = StaticResolver::default().fail("example.org");
+        assert!(matches!(
+            mail_exchangers(&resolver, "example.org").await,
+            Err(Error::Temporary(_))
+        ));
+        assert_eq!(
+            mail_exchangers(&StaticResolver::default(), "example.org").await,
+            Err(Error::NoSuchDomain)
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+use async_trait::async_trait;
+
+use crate::{Error, Mx, Resolver};
+
+// Answers from a fixed set of records, for tests. Any name it has no records for doesn't exist
+#[derive(Debug, Clone, Default)]
+pub struct StaticResolver {
+    mx: HashMap<String, Vec<Mx>>,
+    ip: HashMap<String, Vec<IpAddr>>,
+    txt: HashMap<String, Vec<String>>,
+    ptr: HashMap<IpAddr, Vec<String>>,
+    failing: HashSet<String>,
+}
+
+// Names are matched case insensitively and with or without the root dot
+fn key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl StaticResolver {
+    pub fn mx(mut self, domain: &str, preference: u16, exchange: &str) -> Self {
+        self.mx.entry(key(domain)).or_default().push(Mx {
+            preference,
+            exchange: exchange.to_string(),
+        });
+        self
+    }
+
+    pub fn ip(mut self, host: &str, ip: IpAddr) -> Self {
+        self.ip.entry(key(host)).or_default().push(ip);
+        self
+    }
+
+    pub fn txt(mut self, name: &str, record: &str) -> Self {
+        self.txt
+            .entry(key(name))
+            .or_default()
+            .push(record.to_string());
+        self
+    }
+
+    pub fn ptr(mut self, ip: IpAddr, name: &str) -> Self {
+        self.ptr.entry(ip).or_default().push(key(name));
+        self
+    }
+
+    // Every lookup for `name` fails as if the DNS server had timed out
+    pub fn fail(mut self, name: &str) -> Self {
+        self.failing.insert(key(name));
+        self
+    }
+
+    fn check(&self, name: &str) -> Result<(), Error> {
+        let name = key(name);
+        if self.failing.contains(&name) {
+            return Err(Error::Temporary(format!("no answer for {}", name)));
+        }
+        if !self.mx.contains_key(&name)
+            && !self.ip.contains_key(&name)
+            && !self.txt.contains_key(&name)
+        {
+            return Err(Error::NoSuchDomain);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn mx(&self, domain: &str) -> Result<Vec<Mx>, Error> {
+        self.check(domain)?;
+        Ok(self.mx.get(&key(domain)).cloned().unwrap_or_default())
+    }
+
+    async fn ip(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        self.check(host)?;
+        let mut ips = self.ip.get(&key(host)).cloned().unwrap_or_default();
+        ips.sort_by_key(|ip| ip.is_ipv6());
+        Ok(ips)
+    }
+
+    async fn txt(&self, name: &str) -> Result<Vec<String>, Error> {
+        self.check(name)?;
+        Ok(self.txt.get(&key(name)).cloned().unwrap_or_default())
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, Error> {
+        self.ptr.get(&ip).cloned().ok_or(Error::NoSuchDomain)
+    }
+}
@@ -0,0 +1,117 @@
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use hickory_resolver::{
+    ResolveError, TokioResolver, config::ResolverConfig, name_server::TokioConnectionProvider,
+};
+use log::warn;
+
+use crate::{Error, Mx, Resolver};
+
+// Uses the nameservers from /etc/resolv.conf (or the platform's equivalent)
+pub struct SystemResolver {
+    resolver: TokioResolver,
+}
+
+impl SystemResolver {
+    pub fn new() -> Self {
+        let resolver = match TokioResolver::builder_tokio() {
+            Ok(builder) => builder.build(),
+            Err(e) => {
+                warn!(
+                    "Couldn't read the system DNS configuration ({}), using public resolvers",
+                    e
+                );
+                TokioResolver::builder_with_config(
+                    ResolverConfig::default(),
+                    TokioConnectionProvider::default(),
+                )
+                .build()
+            }
+        };
+        Self { resolver }
+    }
+}
+
+impl Default for SystemResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Always looked up as fully qualified, the search domains must never be tacked on
+fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+// Sorts hickory's errors into the ones that are an answer and the ones that aren't
+fn classify<T>(error: ResolveError) -> Result<Vec<T>, Error> {
+    if error.is_nx_domain() {
+        Err(Error::NoSuchDomain)
+    } else if error.is_no_records_found() {
+        Ok(Vec::new())
+    } else {
+        Err(Error::Temporary(error.to_string()))
+    }
+}
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn mx(&self, domain: &str) -> Result<Vec<Mx>, Error> {
+        match self.resolver.mx_lookup(fqdn(domain)).await {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|mx| Mx {
+                    preference: mx.preference(),
+                    exchange: mx.exchange().to_utf8(),
+                })
+                .collect()),
+            Err(e) => classify(e),
+        }
+    }
+
+    async fn ip(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        let v4 = match self.resolver.ipv4_lookup(fqdn(host)).await {
+            Ok(lookup) => Ok(lookup.iter().map(|a| IpAddr::V4(a.0)).collect()),
+            Err(e) => classify(e),
+        };
+        let v6 = match self.resolver.ipv6_lookup(fqdn(host)).await {
+            Ok(lookup) => Ok(lookup.iter().map(|aaaa| IpAddr::V6(aaaa.0)).collect()),
+            Err(e) => classify(e),
+        };
+
+        // One family failing is fine as long as the other one gave us somewhere to go
+        match (v4, v6) {
+            (Ok(mut v4), Ok(v6)) => {
+                v4.extend(v6);
+                Ok(v4)
+            }
+            (Ok(ips), Err(e)) | (Err(e), Ok(ips)) if ips.is_empty() => Err(e),
+            (Ok(ips), Err(_)) | (Err(_), Ok(ips)) => Ok(ips),
+            (Err(e), Err(_)) => Err(e),
+        }
+    }
+
+    async fn txt(&self, name: &str) -> Result<Vec<String>, Error> {
+        match self.resolver.txt_lookup(fqdn(name)).await {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|txt| {
+                    let data: Vec<u8> = txt.txt_data().concat();
+                    String::from_utf8_lossy(&data).into_owned()
+                })
+                .collect()),
+            Err(e) => classify(e),
+        }
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, Error> {
+        match self.resolver.reverse_lookup(ip).await {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|ptr| ptr.0.to_utf8().trim_end_matches('.').to_string())
+                .collect()),
+            Err(e) => classify(e),
+        }
+    }
+}
@@ -0,0 +1,626 @@
+use log::debug;
+use rustls::pki_types::ServerName;
+use std::{fmt, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::TlsConnector;
+
+use eemail_lib_shared::{BodyType, Dsn, encode_xtext};
+
+use crate::stream::ClientStream;
+
+pub use crate::reply::Reply;
+
+mod reply;
+mod stream;
+#[cfg(test)]
+mod test_utils;
+
+// RFC 5321 4.5.3.2, how long to wait on the server at each step
+const GREETING_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const DATA_INITIATION_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+const DATA_BLOCK_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+const DATA_TERMINATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// Message data is written in blocks this big, each one gets DATA_BLOCK_TIMEOUT to go out
+const DATA_BLOCK: usize = 64 * 1024;
+
+// Replies longer than this are cut off, RFC 5321 only allows 512 bytes a line
+const MAX_REPLY_LINE: u64 = 8 * 1024;
+const MAX_REPLY_LINES: usize = 128;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Timeout,
+    // The server sent something that isn't an SMTP reply
+    Protocol(String),
+    // The server turned the session down at the greeting or HELO/EHLO
+    Rejected(Reply),
+    // The STARTTLS handshake failed, the connection can't be used any more
+    Tls(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "connection failed: {}", e),
+            Error::Timeout => write!(f, "timed out waiting for the server"),
+            Error::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            Error::Rejected(reply) => write!(f, "session rejected: {}", reply),
+            Error::Tls(e) => write!(f, "TLS handshake failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+// One message, going to every recipient in `to`
+pub struct Message<'a> {
+    pub from: &'a str,
+    pub to: &'a [String],
+    pub body: BodyType,
+    pub smtputf8: bool,
+    // Passed on when the server offers DSN (RFC 3461 5.2)
+    pub dsn: &'a Dsn,
+    // CRLF line endings, not yet dot stuffed
+    pub data: &'a [u8],
+}
+
+pub struct Client {
+    reader: BufReader<ClientStream>,
+    // The EHLO keywords (and their parameters) the server offered, uppercased
+    extensions: Vec<String>,
+}
+
+impl Client {
+    // Reads the greeting and says hello as `helo_name`. With `tls` the session is upgraded if the server offers STARTTLS
+    pub async fn connect(
+        stream: TcpStream,
+        helo_name: &str,
+        tls: Option<(&TlsConnector, ServerName<'static>)>,
+    ) -> Result<Self, Error> {
+        let mut client = Client {
+            reader: BufReader::new(ClientStream::Plain(stream)),
+            extensions: Vec::new(),
+        };
+
+        let greeting = client.read_reply(GREETING_TIMEOUT).await?;
+        if greeting.code != 220 {
+            return Err(Error::Rejected(greeting));
+        }
+        client.hello(helo_name).await?;
+
+        // Opportunistic (RFC 3207), a server that doesn't offer it or says no just gets the mail in plaintext
+        if let Some((connector, server_name)) = tls
+            && client.supports("STARTTLS")
+        {
+            let reply = client.command("STARTTLS", COMMAND_TIMEOUT).await?;
+            if reply.code == 220 {
+                client.start_tls(connector, server_name).await?;
+                // Nothing learnt before the handshake can be trusted (RFC 3207 4.2)
+                client.hello(helo_name).await?;
+            } else {
+                debug!("Server refused STARTTLS: {}", reply);
+            }
+        }
+
+        Ok(client)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self.reader.get_ref(), ClientStream::Tls(_))
+    }
+
+    pub fn supports(&self, keyword: &str) -> bool {
+        self.extension(keyword).is_some()
+    }
+
+    // The parameters after an EHLO keyword, e.g. the limit in "SIZE 1024"
+    fn extension(&self, keyword: &str) -> Option<&str> {
+        self.extensions.iter().find_map(|extension| {
+            let (name, parameters) = extension.split_once(' ').unwrap_or((extension, ""));
+            name.eq_ignore_ascii_case(keyword).then_some(parameters)
+        })
+    }
+
+    // Sends one message, returning the reply that decided the fate of each recipient in the order they were given
+    pub async fn send(&mut self, message: &Message<'_>) -> Result<Vec<(String, Reply)>, Error> {
+        if let Some(reply) = self.unsendable(message) {
+            return Ok(everyone(message.to, &reply));
+        }
+
+        let mut mail = format!("MAIL FROM:<{}>", message.from);
+        if self.supports("SIZE") {
+            mail.push_str(&format!(" SIZE={}", message.data.len()));
+        }
+        match message.body {
+            BodyType::SevenBit => {}
+            BodyType::EightBitMime => mail.push_str(" BODY=8BITMIME"),
+            BodyType::BinaryMime => mail.push_str(" BODY=BINARYMIME"),
+        }
+        if message.smtputf8 {
+            mail.push_str(" SMTPUTF8");
+        }
+        let dsn = self.supports("DSN");
+        if dsn {
+            if let Some(ret) = message.dsn.ret {
+                mail.push_str(&format!(" RET={}", ret));
+            }
+            if let Some(envid) = &message.dsn.envid {
+                mail.push_str(&format!(" ENVID={}", encode_xtext(envid)));
+            }
+        }
+
+        let reply = self.command(&mail, COMMAND_TIMEOUT).await?;
+        if !reply.is_positive() {
+            self.reset().await?;
+            return Ok(everyone(message.to, &reply));
+        }
+
+        let mut results = Vec::new();
+        for recipient in message.to {
+            let mut rcpt = format!("RCPT TO:<{}>", recipient);
+            if dsn {
+                rcpt.push_str(&recipient_parameters(message.dsn, recipient));
+            }
+            let reply = self.command(&rcpt, COMMAND_TIMEOUT).await?;
+            results.push((recipient.clone(), reply));
+        }
+
+        if !results.iter().any(|(_, reply)| reply.is_positive()) {
+            self.reset().await?;
+            return Ok(results);
+        }
+
+        let reply = if message.body == BodyType::BinaryMime {
+            self.write(format!("BDAT {} LAST\r\n", message.data.len()).as_bytes())
+                .await?;
+            self.write_data(message.data).await?;
+            self.read_reply(DATA_TERMINATION_TIMEOUT).await?
+        } else {
+            let reply = self.command("DATA", DATA_INITIATION_TIMEOUT).await?;
+            if reply.code == 354 {
+                self.write_data(&dot_stuff(message.data)).await?;
+                self.read_reply(DATA_TERMINATION_TIMEOUT).await?
+            } else {
+                self.reset().await?;
+                reply
+            }
+        };
+
+        // Without LMTP there's only the one reply to the data, it goes for everyone the server took
+        for (_, result) in results.iter_mut() {
+            if result.is_positive() {
+                *result = reply.clone();
+            }
+        }
+        Ok(results)
+    }
+
+    pub async fn quit(mut self) {
+        if let Err(e) = self.command("QUIT", COMMAND_TIMEOUT).await {
+            debug!("Server went away before QUIT was answered: {}", e);
+        }
+        let _ = self.reader.get_mut().shutdown().await;
+    }
+
+    // Anything the server would have to turn down because of what it didn't offer in its EHLO
+    fn unsendable(&self, message: &Message<'_>) -> Option<Reply> {
+        if message.smtputf8 && !self.supports("SMTPUTF8") {
+            return Some(Reply::new(
+                553,
+                "5.6.7 Remote server does not support SMTPUTF8",
+            ));
+        }
+
+        let body_supported = match message.body {
+            BodyType::SevenBit => true,
+            BodyType::EightBitMime => self.supports("8BITMIME"),
+            BodyType::BinaryMime => self.supports("BINARYMIME") && self.supports("CHUNKING"),
+        };
+        if !body_supported {
+            return Some(Reply::new(
+                554,
+                "5.6.3 Remote server does not support the message body type",
+            ));
+        }
+
+        // "SIZE" on its own, or "SIZE 0", means there's no fixed limit
+        let limit = self
+            .extension("SIZE")
+            .and_then(|limit| limit.trim().parse::<usize>().ok())
+            .filter(|&limit| limit > 0);
+        if let Some(limit) = limit
+            && message.data.len() > limit
+        {
+            return Some(Reply::new(
+                552,
+                format!(
+                    "5.3.4 Message is larger than the remote limit of {} bytes",
+                    limit
+                ),
+            ));
+        }
+
+        None
+    }
+
+    async fn hello(&mut self, helo_name: &str) -> Result<(), Error> {
+        let reply = self
+            .command(&format!("EHLO {}", helo_name), COMMAND_TIMEOUT)
+            .await?;
+        if reply.is_positive() {
+            self.extensions = reply
+                .lines
+                .iter()
+                .skip(1)
+                .map(|line| line.trim().to_ascii_uppercase())
+                .collect();
+            return Ok(());
+        }
+
+        // Servers from before ESMTP don't know EHLO, everything is sent without extensions then
+        if matches!(reply.code, 500 | 502) {
+            let reply = self
+                .command(&format!("HELO {}", helo_name), COMMAND_TIMEOUT)
+                .await?;
+            if reply.is_positive() {
+                self.extensions.clear();
+                return Ok(());
+            }
+            return Err(Error::Rejected(reply));
+        }
+
+        Err(Error::Rejected(reply))
+    }
+
+    async fn start_tls(
+        &mut self,
+        connector: &TlsConnector,
+        server_name: ServerName<'static>,
+    ) -> Result<(), Error> {
+        // Anything the server sent before the handshake must never be read as if it came over TLS
+        if !self.reader.buffer().is_empty() {
+            return Err(Error::Protocol(
+                "Plaintext data buffered before the TLS handshake".to_string(),
+            ));
+        }
+
+        let reader = std::mem::replace(&mut self.reader, BufReader::new(ClientStream::Closed));
+        let ClientStream::Plain(stream) = reader.into_inner() else {
+            return Err(Error::Protocol("TLS is already active".to_string()));
+        };
+
+        let stream = connector
+            .connect(server_name, stream)
+            .await
+            .map_err(Error::Tls)?;
+        self.reader = BufReader::new(ClientStream::Tls(Box::new(stream)));
+        Ok(())
+    }
+
+    // Clears out a failed transaction so the session can be used for the next message
+    async fn reset(&mut self) -> Result<(), Error> {
+        let reply = self.command("RSET", COMMAND_TIMEOUT).await?;
+        if !reply.is_positive() {
+            debug!("Server refused RSET: {}", reply);
+        }
+        Ok(())
+    }
+
+    async fn command(&mut self, command: &str, wait: Duration) -> Result<Reply, Error> {
+        debug!("Sending Command {}", command);
+        self.write(format!("{}\r\n", command).as_bytes()).await?;
+        self.read_reply(wait).await
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let stream = self.reader.get_mut();
+        timeout(COMMAND_TIMEOUT, async {
+            stream.write_all(data).await?;
+            stream.flush().await
+        })
+        .await
+        .map_err(|_| Error::Timeout)??;
+        Ok(())
+    }
+
+    async fn write_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        let stream = self.reader.get_mut();
+        for block in data.chunks(DATA_BLOCK) {
+            timeout(DATA_BLOCK_TIMEOUT, stream.write_all(block))
+                .await
+                .map_err(|_| Error::Timeout)??;
+        }
+        stream.flush().await?;
+        Ok(())
+    }
+
+    async fn read_reply(&mut self, wait: Duration) -> Result<Reply, Error> {
+        timeout(wait, self.read_reply_lines())
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    async fn read_reply_lines(&mut self) -> Result<Reply, Error> {
+        let mut code = None;
+        let mut lines = Vec::new();
+
+        loop {
+            let mut line = Vec::new();
+            let read = (&mut self.reader)
+                .take(MAX_REPLY_LINE)
+                .read_until(b'\n', &mut line)
+                .await?;
+            if read == 0 {
+                return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            let Some((line_code, last, text)) = Reply::parse_line(line) else {
+                return Err(Error::Protocol(format!("Malformed reply line {:?}", line)));
+            };
+            if *code.get_or_insert(line_code) != line_code {
+                return Err(Error::Protocol(
+                    "Reply code changed part way through a reply".to_string(),
+                ));
+            }
+            lines.push(text.to_string());
+
+            if last {
+                debug!("Received Reply {} {:?}", line_code, lines);
+                return Ok(Reply {
+                    code: line_code,
+                    lines,
+                });
+            }
+            if lines.len() >= MAX_REPLY_LINES {
+                return Err(Error::Protocol("Reply has too many lines".to_string()));
+            }
+        }
+    }
+}
+
+// NOTIFY and ORCPT for one recipient, with the leading space
+fn recipient_parameters(dsn: &Dsn, recipient: &str) -> String {
+    let recipient = dsn.recipient(recipient);
+    let mut parameters = String::new();
+    if let Some(notify) = recipient.notify {
+        parameters.push_str(&format!(" NOTIFY={}", notify));
+    }
+    if let Some((address_type, address)) = recipient
+        .orcpt
+        .as_deref()
+        .and_then(|orcpt| orcpt.split_once(';'))
+    {
+        parameters.push_str(&format!(
+            " ORCPT={};{}",
+            address_type,
+            encode_xtext(address)
+        ));
+    }
+    parameters
+}
+
+fn everyone(to: &[String], reply: &Reply) -> Vec<(String, Reply)> {
+    to.iter()
+        .map(|recipient| (recipient.clone(), reply.clone()))
+        .collect()
+}
+
+// RFC 5321 4.5.2, doubles any dot starting a line and adds the end of data marker
+fn dot_stuff(data: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(data.len() + 5);
+    let mut line_start = true;
+    for &byte in data {
+        if line_start && byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(byte);
+        line_start = byte == b'\n';
+    }
+
+    if !stuffed.is_empty() && !stuffed.ends_with(b"\r\n") {
+        stuffed.extend_from_slice(b"\r\n");
+    }
+    stuffed.extend_from_slice(b".\r\n");
+    stuffed
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_shared::{Notify, RecipientDsn, Ret};
+    use std::sync::LazyLock;
+
+    use super::*;
+    use crate::test_utils::*;
+
+    static NO_DSN: LazyLock<Dsn> = LazyLock::new(Dsn::default);
+
+    fn message<'a>(to: &'a [String], data: &'a [u8]) -> Message<'a> {
+        Message {
+            from: "sender@example.org",
+            to,
+            body: BodyType::SevenBit,
+            smtputf8: false,
+            dsn: &NO_DSN,
+            data,
+        }
+    }
+
+    #[test]
+    fn dot_stuff_escapes_leading_dots() {
+        assert_eq!(
+            dot_stuff(b".hidden\r\nnot.this\r\n..two\r\n"),
+            b"..hidden\r\nnot.this\r\n...two\r\n.\r\n"
+        );
+        assert_eq!(dot_stuff(b"no newline"), b"no newline\r\n.\r\n");
+        assert_eq!(dot_stuff(b""), b".\r\n");
+    }
+
+    #[tokio::test]
+    async fn client_delivers_over_starttls() {
+        let (stream, mut transactions) = sink().await;
+        let mut client = Client::connect(stream, "mail.example.org", Some(tls()))
+            .await
+            .unwrap();
+        assert!(client.is_encrypted());
+        assert!(client.supports("8BITMIME"));
+
+        let to = ["test@example.com".to_string()];
+        let results = client
+            .send(&message(&to, b"Subject: hi\r\n\r\n.leading dot\r\n"))
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].1.is_positive());
+        client.quit().await;
+
+        let mail = transactions.recv().await.unwrap();
+        assert_eq!(mail.from, "sender@example.org");
+        assert_eq!(mail.to, ["test@example.com"]);
+        assert_eq!(mail.data, b"Subject: hi\r\n\r\n.leading dot\r\n");
+    }
+
+    #[tokio::test]
+    async fn client_reports_each_recipient() {
+        let (stream, mut transactions) = sink().await;
+        let mut client = Client::connect(stream, "mail.example.org", None)
+            .await
+            .unwrap();
+        assert!(!client.is_encrypted());
+
+        let to = [
+            "nobody@example.com".to_string(),
+            "example@example.com".to_string(),
+        ];
+        let results = client.send(&message(&to, b"Hi\r\n")).await.unwrap();
+        assert_eq!(results[0].0, "nobody@example.com");
+        assert_eq!(results[0].1.code, 550);
+        assert_eq!(results[1].0, "example@example.com");
+        assert_eq!(results[1].1.code, 250);
+
+        // Nobody at all is accepted, so no data is sent and the session is still usable
+        let to = ["nobody@example.com".to_string()];
+        let results = client.send(&message(&to, b"Hi\r\n")).await.unwrap();
+        assert!(results[0].1.is_permanent());
+        client.quit().await;
+
+        assert_eq!(
+            transactions.recv().await.unwrap().to,
+            ["example@example.com"]
+        );
+        assert!(transactions.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn client_passes_on_dsn_parameters() {
+        let (stream, mut transactions) = sink().await;
+        let mut client = Client::connect(stream, "mail.example.org", None)
+            .await
+            .unwrap();
+        assert!(client.supports("DSN"));
+
+        let dsn = Dsn {
+            ret: Some(Ret::Headers),
+            envid: Some("envelope 1".to_string()),
+            recipients: [(
+                "test@example.com".to_string(),
+                RecipientDsn {
+                    notify: Some(Notify::NEVER),
+                    orcpt: Some("rfc822;first+alias@example.com".to_string()),
+                },
+            )]
+            .into(),
+        };
+        let to = [
+            "test@example.com".to_string(),
+            "example@example.com".to_string(),
+        ];
+        let mut message = message(&to, b"Hi\r\n");
+        message.dsn = &dsn;
+        assert!(client.send(&message).await.unwrap()[0].1.is_positive());
+        client.quit().await;
+
+        assert_eq!(transactions.recv().await.unwrap().dsn, dsn);
+    }
+
+    #[tokio::test]
+    async fn client_sends_binary_with_bdat() {
+        let (stream, mut transactions) = sink().await;
+        let mut client = Client::connect(stream, "mail.example.org", None)
+            .await
+            .unwrap();
+        let to = ["test@example.com".to_string()];
+        let mut message = message(&to, b"\x00\xff\r\n.\r\nbare\n");
+        message.body = BodyType::BinaryMime;
+        assert!(client.send(&message).await.unwrap()[0].1.is_positive());
+        client.quit().await;
+
+        let mail = transactions.recv().await.unwrap();
+        assert_eq!(mail.body, BodyType::BinaryMime);
+        assert_eq!(mail.data, b"\x00\xff\r\n.\r\nbare\n");
+    }
+
+    #[tokio::test]
+    async fn client_fails_when_the_certificate_is_not_trusted() {
+        let (stream, _transactions) = sink().await;
+        let connector = untrusting_connector();
+        let result = Client::connect(
+            stream,
+            "mail.example.org",
+            Some((&connector, ServerName::try_from("localhost").unwrap())),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Tls(_))));
+    }
+
+    #[tokio::test]
+    async fn client_falls_back_to_helo_and_checks_extensions() {
+        let (stream, script) = scripted(&[
+            "220 old.example.org",
+            "502 EHLO not implemented",
+            "250 old.example.org",
+            "221 Bye",
+        ])
+        .await;
+        let mut client = Client::connect(stream, "mail.example.org", Some(tls()))
+            .await
+            .unwrap();
+        assert!(!client.is_encrypted());
+
+        let to = ["someone@example.org".to_string()];
+        let mut eight_bit = message(&to, "Grüße\r\n".as_bytes());
+        eight_bit.body = BodyType::EightBitMime;
+        assert_eq!(client.send(&eight_bit).await.unwrap()[0].1.code, 554);
+
+        let mut utf8 = message(&to, b"Hi\r\n");
+        utf8.smtputf8 = true;
+        assert_eq!(client.send(&utf8).await.unwrap()[0].1.code, 553);
+        client.quit().await;
+
+        assert_eq!(
+            script.await.unwrap(),
+            ["EHLO mail.example.org", "HELO mail.example.org", "QUIT"]
+        );
+    }
+
+    #[tokio::test]
+    async fn client_gives_up_on_a_rejected_greeting() {
+        let (stream, _script) = scripted(&["554 No thanks"]).await;
+        let result = Client::connect(stream, "mail.example.org", None).await;
+        assert!(matches!(result, Err(Error::Rejected(reply)) if reply.code == 554));
+    }
+}
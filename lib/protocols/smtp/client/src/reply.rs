@@ -0,0 +1,83 @@
+use std::fmt;
+
+// A reply from the remote server, or one made up locally when a message can't be sent at all
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    // The text of every line, without the code and separator
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn new(code: u16, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            lines: vec![text.into()],
+        }
+    }
+
+    pub fn is_positive(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+
+    // 4xx, the same thing might work if tried again later
+    pub fn is_transient(&self) -> bool {
+        (400..500).contains(&self.code)
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        self.code >= 500
+    }
+
+    // Parses one line, giving the code and whether it's the last line of the reply
+    pub(crate) fn parse_line(line: &str) -> Option<(u16, bool, &str)> {
+        let code = line.get(..3)?;
+        if !code.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let (last, text) = match line.as_bytes().get(3) {
+            None => (true, ""),
+            Some(b' ') => (true, &line[4..]),
+            Some(b'-') => (false, &line[4..]),
+            Some(_) => return None,
+        };
+        Some((code.parse().ok()?, last, text))
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.lines.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_lines_are_parsed() {
+        assert_eq!(
+            Reply::parse_line("250-PIPELINING"),
+            Some((250, false, "PIPELINING"))
+        );
+        assert_eq!(
+            Reply::parse_line("550 5.1.1 No such user"),
+            Some((550, true, "5.1.1 No such user"))
+        );
+        assert_eq!(Reply::parse_line("354"), Some((354, true, "")));
+        assert_eq!(Reply::parse_line("25O OK"), None);
+        assert_eq!(Reply::parse_line("250_OK"), None);
+    }
+
+    #[test]
+    fn reply_classes() {
+        assert!(Reply::new(250, "OK").is_positive());
+        assert!(Reply::new(451, "Later").is_transient());
+        assert!(Reply::new(550, "No").is_permanent());
+        assert_eq!(
+            Reply::new(550, "5.1.1 No such user").to_string(),
+            "550 5.1.1 No such user"
+        );
+    }
+}
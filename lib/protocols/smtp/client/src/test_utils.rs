@@ -0,0 +1,125 @@
+// Servers for the client to talk to, the real SMTP server as a sink plus a scripted one for the odd cases
+use std::sync::{Arc, LazyLock};
+
+use eemail_lib_dns::StaticResolver;
+use eemail_lib_protocols_smtp_server::{Filtering, Mail, handle_smtp};
+use eemail_lib_shared::{BareLfPolicy, SMTPPortConfiguration, VrfyPolicy};
+use rustls::{ClientConfig, RootCertStore, ServerConfig, pki_types::ServerName};
+use rustls_pemfile::{certs, private_key};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+const CERT: &[u8] = include_bytes!("../../../../../tests/certs/cert.pem");
+const KEY: &[u8] = include_bytes!("../../../../../tests/certs/key.pem");
+const CA: &[u8] = include_bytes!("../../../../../tests/certs/ca.pem");
+
+// Trusts the test CA that signed `CERT`
+static CONNECTOR: LazyLock<TlsConnector> = LazyLock::new(|| {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(&mut &CA[..]) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    connector(roots)
+});
+
+fn connector(roots: RootCertStore) -> TlsConnector {
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+pub fn tls() -> (&'static TlsConnector, ServerName<'static>) {
+    (&CONNECTOR, ServerName::try_from("localhost").unwrap())
+}
+
+pub fn untrusting_connector() -> TlsConnector {
+    connector(RootCertStore::empty())
+}
+
+fn acceptor() -> TlsAcceptor {
+    let cert_chain = certs(&mut &CERT[..]).collect::<Result<_, _>>().unwrap();
+    let key = private_key(&mut &KEY[..]).unwrap().unwrap();
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+fn port_config() -> SMTPPortConfiguration {
+    SMTPPortConfiguration {
+        auth_enabled: false,
+        filtering_enabled: false,
+        implicit_tls: false,
+        port: 0,
+        max_connections: 1,
+        max_connections_per_ip: 1,
+        vrfy_policy: VrfyPolicy::Disabled,
+        max_message_size: 64 * 1024,
+        bare_lf_policy: BareLfPolicy::Reject,
+        require_tls: false,
+        require_auth: false,
+        check_header_from: false,
+    }
+}
+
+// Runs the SMTP server for one connection, every message it accepts comes out of the receiver
+pub async fn sink() -> (TcpStream, mpsc::Receiver<Mail>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (sender, transactions) = mpsc::channel(16);
+    let service_config = eemail_component_configurator::Configuration::parse_from_string(
+        include_str!("../../../../../tests/config.toml").to_string(),
+    )
+    .unwrap();
+
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let _ = handle_smtp(
+            socket,
+            port_config(),
+            acceptor(),
+            service_config,
+            sender,
+            Filtering {
+                resolver: Arc::new(StaticResolver::default()),
+                reports: None,
+            },
+        )
+        .await;
+    });
+
+    (TcpStream::connect(addr).await.unwrap(), transactions)
+}
+
+// Sends the first reply as the greeting, then answers each line with the next one. The handle gives back the lines it got
+pub async fn scripted(replies: &'static [&'static str]) -> (TcpStream, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let handle = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(socket);
+        let mut received = Vec::new();
+        for (index, reply) in replies.iter().enumerate() {
+            if index > 0 {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                received.push(line.trim_end().to_string());
+            }
+            let reply = format!("{}\r\n", reply);
+            reader.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        }
+        received
+    });
+
+    (TcpStream::connect(addr).await.unwrap(), handle)
+}
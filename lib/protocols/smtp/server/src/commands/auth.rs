@@ -1,125 +1,543 @@
 use base64::prelude::*;
-use log::{debug, error, warn};
-use tokio::io::{AsyncWriteExt, BufReader};
-use yescrypt::{PasswordHash, PasswordVerifier, Yescrypt};
+use eemail_component_configurator::Account;
+use eemail_lib_sasl::{Error, Identity, Step};
+use eemail_lib_shared::SMTPPortConfiguration;
+use log::{debug, warn};
 
-use crate::{Mail, SmtpStream, message_formatter};
+use crate::{
+    MAX_COMMAND_LINE, Session, check_sequence,
+    connection::{COMMAND_TIMEOUT, Connection},
+    reply::{Reply, Status},
+    state::Event,
+};
 
 pub async fn handle(
-    mail: &mut Mail,
+    session: &mut Session,
+    config: &SMTPPortConfiguration,
     service_config: &eemail_component_configurator::Configuration,
-    buffer: &mut BufReader<SmtpStream>,
+    connection: &mut Connection,
     cmd: Vec<String>,
 ) -> anyhow::Result<()> {
+    // Not advertised on this listener, so act like it doesn't exist
+    if !config.auth_enabled {
+        connection.reply(Reply::new(
+            Status::NotImplemented,
+            "Command not implemented",
+        ));
+        return Ok(());
+    }
+
     // According to RFC 4954 if authentication has already completed, or we are in the mail transaction we need to reject it
-    if mail.in_mail || mail.has_authed {
-        buffer
-            .get_mut()
-            .write_all(&message_formatter(
-                "503 Authentication already completed or already in mail",
-            ))
-            .await?;
+    if check_sequence(session, Event::Auth, connection)
+        .await?
+        .is_none()
+    {
+        return Ok(());
+    }
+    if session.authenticated.is_some() {
+        connection.reply(Reply::new(
+            Status::BadSequence,
+            "Authentication already completed",
+        ));
         return Ok(());
     }
 
-    debug!("RAW Auth Command: {:#?}", cmd);
-
-    if let Some(auth_type) = cmd.get(1) {
-        match auth_type.as_str() {
-            "PLAIN" => {
-                if let Some(base64) = cmd.get(2) {
-                    let decoded = BASE64_STANDARD.decode(base64.as_str())?;
-                    let parts: Vec<&[u8]> = decoded.split(|&b| b == 0).collect();
-
-                    if parts.len() != 3 {
-                        warn!(
-                            "Invalid PLAIN auth format: expected 3 parts, got {}",
-                            parts.len()
-                        );
-                        buffer
-                            .get_mut()
-                            .write_all(&message_formatter("535 Authentication failed"))
-                            .await?;
-                    }
+    // RFC 4954 section 4, PLAIN and LOGIN send the password as is so nothing is offered before TLS
+    if !session.has_tlsd {
+        connection.reply(Reply::new(
+            Status::EncryptionRequired,
+            "Encryption required for requested authentication mechanism",
+        ));
+        return Ok(());
+    }
 
-                    let username = String::from_utf8_lossy(parts[1]).to_string();
-                    let password = String::from_utf8_lossy(parts[2]).to_string();
-
-                    if let Some(user) = service_config.clone().get_user_from_alias(&username) {
-                        if let Some(src) = user.hashed_password {
-                            match PasswordHash::new(&src) {
-                                Ok(parsed_hash) => {
-                                    match Yescrypt
-                                        .verify_password(password.as_bytes(), &parsed_hash)
-                                    {
-                                        Ok(_) => {
-                                            debug!("Authentication Success!");
-                                            buffer
-                                                .get_mut()
-                                                .write_all(&message_formatter(
-                                                    "235 Authentication Successfull",
-                                                ))
-                                                .await?;
-                                        }
-                                        Err(_) => {
-                                            debug!(
-                                                "Password verification failed for user: {username}"
-                                            );
-                                            buffer
-                                                .get_mut()
-                                                .write_all(&message_formatter(
-                                                    "535 Authentication failed",
-                                                ))
-                                                .await?;
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    error!(
-                                        "Failed to parse password hash for user {username}: {e}"
-                                    );
-                                    buffer
-                                        .get_mut()
-                                        .write_all(&message_formatter("535 Authentication failed"))
-                                        .await?;
-                                }
-                            }
-                        } else {
-                            debug!("User doesn't have a password: {username}");
-                            buffer
-                                .get_mut()
-                                .write_all(&message_formatter("535 Authentication failed"))
-                                .await?;
-                        }
-                    } else {
-                        debug!("User not found for email: {username}");
-                        buffer
-                            .get_mut()
-                            .write_all(&message_formatter("535 Authentication failed"))
-                            .await?;
+    let (Some(name), None) = (cmd.get(1), cmd.get(3)) else {
+        connection.reply(Reply::new(
+            Status::SyntaxError,
+            "Syntax error in parameters",
+        ));
+        return Ok(());
+    };
+    let Some(mut mechanism) = eemail_lib_sasl::mechanism(name) else {
+        connection.reply(Reply::new(
+            Status::ParameterNotImplemented,
+            "Authentication mechanism not supported",
+        ));
+        return Ok(());
+    };
+    debug!("Starting {} authentication", name);
+
+    // A lone "=" is an initial response that happens to be empty
+    let mut response = match cmd.get(2).map(|initial| decode(initial)) {
+        None => None,
+        Some(Some(initial)) => Some(initial),
+        Some(None) => {
+            connection.reply(Reply::new(
+                Status::AuthResponseInvalid,
+                "Cannot decode response",
+            ));
+            return Ok(());
+        }
+    };
+
+    loop {
+        match mechanism.step(service_config, response.as_deref()) {
+            Step::Challenge(challenge) => {
+                connection.reply(Reply::new(
+                    Status::AuthChallenge,
+                    BASE64_STANDARD.encode(challenge),
+                ));
+                connection.flush().await?;
+
+                match read_response(connection).await? {
+                    Response::Data(data) => response = Some(data),
+                    Response::Cancelled => {
+                        debug!("Client cancelled authentication");
+                        connection.reply(Reply::new(
+                            Status::AuthCancelled,
+                            "Authentication cancelled",
+                        ));
+                        return Ok(());
+                    }
+                    Response::Invalid => {
+                        connection.reply(Reply::new(
+                            Status::AuthResponseInvalid,
+                            "Cannot decode response",
+                        ));
+                        return Ok(());
                     }
-                } else {
-                    buffer
-                        .get_mut()
-                        .write_all(&message_formatter("535 Authentication failed"))
-                        .await?;
+                    Response::Disconnected => return Ok(()),
                 }
             }
+            Step::Success(identity) => {
+                let Some(account) = authorize(service_config, &identity) else {
+                    warn!("{} may not act as {:?}", identity.authcid, identity.authzid);
+                    connection.reply(Reply::new(Status::AuthFailed, "Authentication failed"));
+                    return Ok(());
+                };
 
-            _ => {
-                buffer
-                    .get_mut()
-                    .write_all(&message_formatter(
-                        "504 Authentication mechanism not supported",
-                    ))
-                    .await?;
+                debug!(
+                    "Authentication Success for {}",
+                    account.clone().get_primary_address()
+                );
+                session.authenticated = Some(account);
+                connection.reply(Reply::new(
+                    Status::AuthSucceeded,
+                    "Authentication successful",
+                ));
+                return Ok(());
             }
+            Step::Failure(Error::Malformed) => {
+                warn!("Malformed {} authentication exchange", name);
+                connection.reply(Reply::new(
+                    Status::AuthResponseInvalid,
+                    "Cannot decode response",
+                ));
+                return Ok(());
+            }
+            Step::Failure(Error::InvalidCredentials) => {
+                connection.reply(Reply::new(Status::AuthFailed, "Authentication failed"));
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Finds the account behind the credentials. There's no proxy authorization, so an authzid
+// is only accepted if it's another address of that same account (RFC 4422 section 3.4.1)
+fn authorize(
+    service_config: &eemail_component_configurator::Configuration,
+    identity: &Identity,
+) -> Option<Account> {
+    let account = service_config
+        .clone()
+        .get_user_from_alias(&identity.authcid)?;
+
+    match &identity.authzid {
+        Some(authzid) if !account.has_address(authzid) => None,
+        _ => Some(account),
+    }
+}
+
+// What came back after a 334
+enum Response {
+    Data(Vec<u8>),
+    Cancelled,
+    Invalid,
+    Disconnected,
+}
+
+async fn read_response(connection: &mut Connection) -> anyhow::Result<Response> {
+    let mut line = Vec::new();
+    let bytes = connection
+        .read_chunk(&mut line, MAX_COMMAND_LINE, COMMAND_TIMEOUT)
+        .await?;
+    if bytes == 0 {
+        return Ok(Response::Disconnected);
+    }
+    if !line.ends_with(b"\n") {
+        connection.discard_line().await?;
+        return Ok(Response::Invalid);
+    }
+
+    let line = line.trim_ascii_end();
+    if line == b"*" {
+        return Ok(Response::Cancelled);
+    }
+    Ok(match std::str::from_utf8(line).ok().and_then(decode) {
+        Some(data) => Response::Data(data),
+        None => Response::Invalid,
+    })
+}
+
+fn decode(encoded: &str) -> Option<Vec<u8>> {
+    if encoded == "=" {
+        return Some(Vec::new());
+    }
+    BASE64_STANDARD.decode(encoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::*;
+    use tokio::net::TcpStream;
+
+    use crate::test_utils::*;
+
+    fn plain(username: &str, password: &str) -> String {
+        format!(
+            "AUTH PLAIN {}",
+            BASE64_STANDARD.encode(format!("\0{}\0{}", username, password))
+        )
+    }
+
+    fn submission_config() -> eemail_lib_shared::SMTPPortConfiguration {
+        let mut config = port_config();
+        config.auth_enabled = true;
+        config.require_tls = true;
+        config.require_auth = true;
+        config
+    }
+
+    #[tokio::test]
+    async fn auth_is_refused_in_cleartext() {
+        let mut session = start(submission_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(
+            client
+                .command(&plain("test@example.com", "password"))
+                .await
+                .starts_with("538 5.7.11")
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_is_refused_where_not_enabled() {
+        let mut session = start_implicit_tls(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(
+            client
+                .command(&plain("test@example.com", "password"))
+                .await
+                .starts_with("502")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_requires_tls_and_auth_when_configured() {
+        let mut session = start(submission_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(
+            client
+                .command("MAIL FROM:<test@example.com>")
+                .await
+                .starts_with("530 5.7.0 Must issue a STARTTLS")
+        );
+        client.command("STARTTLS").await;
+
+        let mut client = session.client.start_tls().await;
+        client.command("EHLO client.example").await;
+        assert!(
+            client
+                .command("MAIL FROM:<test@example.com>")
+                .await
+                .starts_with("530 5.7.0 Authentication required")
+        );
+        assert!(
+            client
+                .command(&plain("test@example.com", "wrong"))
+                .await
+                .starts_with("535")
+        );
+        assert!(
+            client
+                .command(&plain("test@example.com", "password"))
+                .await
+                .starts_with("235")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<test@example.com>")
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            client
+                .command(&plain("test@example.com", "password"))
+                .await
+                .starts_with("503")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_is_open_when_nothing_is_required() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org>")
+                .await
+                .starts_with("250")
+        );
+    }
+
+    async fn start_authenticating() -> TestSession<tokio_rustls::client::TlsStream<TcpStream>> {
+        let mut session = start_implicit_tls(submission_config(), service_config()).await;
+        session.client.command("EHLO client.example").await;
+        session
+    }
+
+    #[tokio::test]
+    async fn auth_plain_waits_for_the_response() {
+        let mut session = start_authenticating().await;
+        let client = &mut session.client;
+        assert_eq!(client.command("AUTH PLAIN").await, "334 \r\n");
+        assert!(
+            client
+                .command(&BASE64_STANDARD.encode("\0test@example.com\0password"))
+                .await
+                .starts_with("235 2.7.0")
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_login_asks_for_each_part() {
+        let mut session = start_authenticating().await;
+        let client = &mut session.client;
+        assert_eq!(client.command("AUTH LOGIN").await, "334 VXNlcm5hbWU6\r\n");
+        assert_eq!(
+            client
+                .command(&BASE64_STANDARD.encode("test@example.com"))
+                .await,
+            "334 UGFzc3dvcmQ6\r\n"
+        );
+        assert!(
+            client
+                .command(&BASE64_STANDARD.encode("password"))
+                .await
+                .starts_with("235")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<test@example.com>")
+                .await
+                .starts_with("250")
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_can_be_cancelled() {
+        let mut session = start_authenticating().await;
+        let client = &mut session.client;
+        client.command("AUTH LOGIN").await;
+        assert!(client.command("*").await.starts_with("501 5.0.0"));
+
+        let reply = client
+            .command(&format!(
+                "AUTH SCRAM-SHA-256 {}",
+                BASE64_STANDARD.encode("n,,n=test@example.com,r=abcdef")
+            ))
+            .await;
+        let server_first = BASE64_STANDARD.decode(reply[4..].trim_end()).unwrap();
+        assert!(
+            String::from_utf8(server_first)
+                .unwrap()
+                .starts_with("r=abcdef")
+        );
+        assert!(client.command("*").await.starts_with("501 5.0.0"));
+
+        // Nothing was authenticated along the way
+        assert!(
+            client
+                .command("MAIL FROM:<test@example.com>")
+                .await
+                .starts_with("530")
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_rejects_bad_responses() {
+        let mut session = start_authenticating().await;
+        let client = &mut session.client;
+        assert!(
+            client
+                .command("AUTH PLAIN !!!")
+                .await
+                .starts_with("501 5.5.2")
+        );
+        assert!(
+            client
+                .command("AUTH PLAIN =")
+                .await
+                .starts_with("501 5.5.2")
+        );
+        client.command("AUTH PLAIN").await;
+        assert!(client.command("not base64").await.starts_with("501 5.5.2"));
+        assert!(client.command("AUTH CRAM-MD5").await.starts_with("504"));
+        assert!(client.command("AUTH").await.starts_with("501"));
+        assert!(
+            client
+                .command(&plain("nobody@example.com", "password"))
+                .await
+                .starts_with("535 5.7.8")
+        );
+    }
+
+    #[tokio::test]
+    async fn ehlo_lists_every_mechanism() {
+        let mut session = start_authenticating().await;
+        let reply = session.client.command("EHLO client.example").await;
+        assert!(reply.ends_with("250 AUTH PLAIN LOGIN SCRAM-SHA-256\r\n"));
+    }
+
+    fn plain_as(authzid: &str, username: &str, password: &str) -> String {
+        format!(
+            "AUTH PLAIN {}",
+            BASE64_STANDARD.encode(format!("{}\0{}\0{}", authzid, username, password))
+        )
+    }
+
+    #[tokio::test]
+    async fn auth_failure_leaves_the_session_unauthenticated() {
+        let mut session = start_authenticating().await;
+        let client = &mut session.client;
+        assert!(
+            client
+                .command(&plain("test@example.com", "wrong"))
+                .await
+                .starts_with("535 5.7.8")
+        );
+        // No password configured at all
+        assert!(
+            client
+                .command(&plain("example@example.com", ""))
+                .await
+                .starts_with("535 5.7.8")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<test@example.com>")
+                .await
+                .starts_with("530")
+        );
+
+        // A failure doesn't stop a later attempt from working
+        assert!(
+            client
+                .command(&plain("test@example.com", "password"))
+                .await
+                .starts_with("235")
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_rejects_short_plain_messages() {
+        let mut session = start_authenticating().await;
+        let client = &mut session.client;
+        for message in ["test@example.com", "\0test@example.com"] {
+            let reply = client
+                .command(&format!("AUTH PLAIN {}", BASE64_STANDARD.encode(message)))
+                .await;
+            assert!(reply.starts_with("501 5.5.2"), "{:?}: {}", message, reply);
+        }
+        assert!(client.command("NOOP").await.starts_with("250"));
+    }
+
+    #[tokio::test]
+    async fn auth_rejects_acting_as_someone_else() {
+        let mut session = start_authenticating().await;
+        let client = &mut session.client;
+        assert!(
+            client
+                .command(&plain_as(
+                    "example@example.com",
+                    "test@example.com",
+                    "password"
+                ))
+                .await
+                .starts_with("535")
+        );
+        assert!(
+            client
+                .command(&plain_as(
+                    "jörg@xn--bcher-kva.example",
+                    "test@example.com",
+                    "password"
+                ))
+                .await
+                .starts_with("235")
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_identity_is_carried_with_the_mail() {
+        let mut session = start_authenticating().await;
+        let client = &mut session.client;
+        assert!(
+            client
+                .command(&plain("jörg@bücher.example", "password"))
+                .await
+                .starts_with("235")
+        );
+        client.command("MAIL FROM:<test@example.com>").await;
+        client.command("RCPT TO:<example@example.com>").await;
+        client.command("DATA").await;
+        assert!(client.command("Hi\r\n.").await.starts_with("250"));
+
+        // And survives into the next transaction
+        client.command("MAIL FROM:<test@example.com>").await;
+        client.command("RCPT TO:<example@example.com>").await;
+        client.command("DATA").await;
+        assert!(client.command("Again\r\n.").await.starts_with("250"));
+
+        for _ in 0..2 {
+            let mail = session.transactions.recv().await.unwrap();
+            let account = mail.authenticated.unwrap();
+            assert_eq!(account.get_primary_address(), "test@example.com");
         }
-    } else {
-        buffer
-            .get_mut()
-            .write_all(&message_formatter("501 Syntax error in parameters"))
-            .await?;
     }
-    Ok(())
+
+    #[tokio::test]
+    async fn unauthenticated_mail_has_no_identity() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command("MAIL FROM:<test@example.com>").await;
+        client.command("RCPT TO:<example@example.com>").await;
+        client.command("DATA").await;
+        assert!(client.command("Hi\r\n.").await.starts_with("250"));
+        assert!(
+            session
+                .transactions
+                .recv()
+                .await
+                .unwrap()
+                .authenticated
+                .is_none()
+        );
+    }
 }
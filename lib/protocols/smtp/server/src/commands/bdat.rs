@@ -0,0 +1,292 @@
+use eemail_lib_shared::{BareLfPolicy, SMTPPortConfiguration};
+use log::debug;
+use tokio::sync::mpsc;
+
+use crate::{
+    BodyType, Filtering, Mail, Session, check_sequence,
+    commands::data::{accept, normalize_line_endings},
+    connection::Connection,
+    reply::{Reply, Status},
+    state::Event,
+};
+
+// RFC 3030, the chunk follows the command straight away and is exactly `size` bytes with no dot stuffing
+pub async fn handle(
+    session: &mut Session,
+    config: &SMTPPortConfiguration,
+    service_config: &eemail_component_configurator::Configuration,
+    connection: &mut Connection,
+    transactions: &mpsc::Sender<Mail>,
+    filtering: &Filtering,
+    cmd: Vec<String>,
+) -> anyhow::Result<()> {
+    let Some((size, last)) = parse(&cmd) else {
+        // Without a size there's no telling where the chunk ends, so there's nothing else we can do
+        connection.reply(Reply::new(
+            Status::SyntaxError,
+            "Syntax error, expected BDAT <size> [LAST]",
+        ));
+        return Ok(());
+    };
+
+    // A chunk that could never fit isn't worth reading past, the client is told and the connection closed
+    if size > config.max_message_size {
+        debug!(
+            "Rejecting BDAT chunk of {} bytes, over {} bytes",
+            size, config.max_message_size
+        );
+        connection.reply(Reply::new(
+            Status::MessageTooLarge,
+            "Message size exceeds fixed maximum message size",
+        ));
+        connection.close().await?;
+        return Ok(());
+    }
+
+    // The chunk is on the wire whatever happens, it has to be read past before the next command
+    let Some(next) = check_sequence(session, Event::Bdat, connection).await? else {
+        connection.discard(size).await?;
+        return Ok(());
+    };
+
+    if size
+        > config
+            .max_message_size
+            .saturating_sub(session.mail.data.len())
+    {
+        debug!(
+            "Rejecting BDAT chunk, over {} bytes",
+            config.max_message_size
+        );
+        connection.discard(size).await?;
+        session.reset_transaction();
+        session.state = session.state.transition(Event::Reset).unwrap();
+        connection.reply(Reply::new(
+            Status::MessageTooLarge,
+            "Message size exceeds fixed maximum message size",
+        ));
+        return Ok(());
+    }
+
+    if !connection
+        .read_exact_into(&mut session.mail.data, size)
+        .await?
+    {
+        debug!("Client went away during BDAT");
+        return Ok(());
+    }
+    session.state = next;
+
+    if !last {
+        connection.reply(Reply::new(Status::Ok, format!("{} octets received", size)));
+        return Ok(());
+    }
+
+    let mut mail = std::mem::take(&mut session.mail);
+    session.reset_transaction();
+    session.state = session.state.transition(Event::DataDone).unwrap();
+
+    // Binary bodies are taken as is, anything else still has to follow the line ending rules
+    if mail.body != BodyType::BinaryMime {
+        let (data, found_bare) = normalize_line_endings(&mail.data);
+        if found_bare && config.bare_lf_policy == BareLfPolicy::Reject {
+            debug!("Rejecting message with a bare CR or LF");
+            connection.reply(Reply::new(
+                Status::InvalidLineEnding,
+                "Bare CR or LF not allowed in message data",
+            ));
+            return Ok(());
+        }
+        mail.data = data;
+    }
+
+    accept(
+        mail,
+        config,
+        service_config,
+        connection,
+        transactions,
+        filtering,
+    )
+    .await
+}
+
+// `BDAT <size> [LAST]`
+fn parse(cmd: &[String]) -> Option<(usize, bool)> {
+    let size = cmd.get(1)?;
+    if !size.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let size = size.parse().ok()?;
+
+    match cmd.get(2..)? {
+        [] => Some((size, false)),
+        [last] if last.eq_ignore_ascii_case("LAST") => Some((size, true)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_shared::BareLfPolicy;
+
+    use super::parse;
+    use crate::{BodyType, test_utils::*};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    async fn start_transaction(session: &mut TestSession, mail: &str) {
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(client.command(mail).await.starts_with("250"));
+        assert!(
+            client
+                .command("RCPT TO:<test@example.com>")
+                .await
+                .starts_with("250")
+        );
+    }
+
+    #[test]
+    fn bdat_parses_arguments() {
+        assert_eq!(parse(&args("BDAT 100")), Some((100, false)));
+        assert_eq!(parse(&args("BDAT 0 last")), Some((0, true)));
+        assert_eq!(parse(&args("BDAT")), None);
+        assert_eq!(parse(&args("BDAT -1")), None);
+        assert_eq!(parse(&args("BDAT 10 FIRST")), None);
+        assert_eq!(parse(&args("BDAT 10 LAST LAST")), None);
+    }
+
+    #[tokio::test]
+    async fn bdat_joins_chunks() {
+        let mut session = start(port_config(), service_config()).await;
+        start_transaction(&mut session, "MAIL FROM:<a@example.org>").await;
+        let client = &mut session.client;
+
+        // A line that's just a dot means nothing to BDAT
+        client.send("BDAT 9\r\nHello\r\n.\r").await;
+        assert!(client.reply().await.starts_with("250 2.0.0 9 octets"));
+        client.send("BDAT 8 LAST\r\n\nWorld\r\n").await;
+        assert!(client.reply().await.starts_with("250"));
+
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.data, b"Hello\r\n.\r\nWorld\r\n");
+    }
+
+    #[tokio::test]
+    async fn bdat_keeps_binary_bodies_intact() {
+        let mut session = start(port_config(), service_config()).await;
+        start_transaction(&mut session, "MAIL FROM:<a@example.org> BODY=BINARYMIME").await;
+        let client = &mut session.client;
+
+        let mut chunk = b"BDAT 6 LAST\r\n".to_vec();
+        chunk.extend_from_slice(&[0x00, b'\n', 0xff, b'\r', b'.', 0x80]);
+        client.send_bytes(&chunk).await;
+        assert!(client.reply().await.starts_with("250"));
+
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.body, BodyType::BinaryMime);
+        assert_eq!(mail.data, [0x00, b'\n', 0xff, b'\r', b'.', 0x80]);
+    }
+
+    #[tokio::test]
+    async fn bdat_applies_bare_lf_policy_to_text_bodies() {
+        let mut session = start(port_config(), service_config()).await;
+        start_transaction(&mut session, "MAIL FROM:<a@example.org>").await;
+        let client = &mut session.client;
+        client.send("BDAT 4 LAST\r\na\nb\n").await;
+        assert!(client.reply().await.starts_with("554 5.6.0"));
+
+        let mut config = port_config();
+        config.bare_lf_policy = BareLfPolicy::Normalize;
+        let mut session = start(config, service_config()).await;
+        start_transaction(&mut session, "MAIL FROM:<a@example.org>").await;
+        let client = &mut session.client;
+        client.send("BDAT 4 LAST\r\na\nb\n").await;
+        assert!(client.reply().await.starts_with("250"));
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.data, b"a\r\nb\r\n");
+    }
+
+    #[tokio::test]
+    async fn bdat_pipelines_with_the_envelope() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client
+            .send(concat!(
+                "MAIL FROM:<a@example.org>\r\n",
+                "RCPT TO:<test@example.com>\r\n",
+                "BDAT 5\r\nHello",
+                "BDAT 3 LAST\r\n!\r\n",
+                "NOOP\r\n",
+            ))
+            .await;
+
+        let mut replies = String::new();
+        while replies.lines().count() < 5 {
+            replies.push_str(&client.reply().await);
+        }
+        let codes: Vec<&str> = replies.lines().map(|line| &line[..3]).collect();
+        assert_eq!(codes, ["250", "250", "250", "250", "250"]);
+
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.data, b"Hello!\r\n");
+    }
+
+    #[tokio::test]
+    async fn bdat_rejects_oversized_messages() {
+        let mut session = start(port_config(), service_config()).await;
+        start_transaction(&mut session, "MAIL FROM:<a@example.org>").await;
+        let client = &mut session.client;
+
+        let mut chunk = b"BDAT 1000\r\n".to_vec();
+        chunk.extend_from_slice(&[b'a'; 1000]);
+        client.send_bytes(&chunk).await;
+        assert!(client.reply().await.starts_with("250"));
+
+        let mut chunk = b"BDAT 100 LAST\r\n".to_vec();
+        chunk.extend_from_slice(&[b'a'; 100]);
+        client.send_bytes(&chunk).await;
+        assert!(client.reply().await.starts_with("552 5.3.4"));
+
+        // The transaction is gone, but the session carries on in sync
+        client.send("BDAT 2 LAST\r\nhi").await;
+        assert!(client.reply().await.starts_with("503"));
+        assert!(client.command("NOOP").await.starts_with("250"));
+        drop(session.client);
+        session.handle.await.unwrap().unwrap();
+        assert!(session.transactions.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn bdat_rejects_chunks_that_could_never_fit() {
+        let mut session = start(port_config(), service_config()).await;
+        start_transaction(&mut session, "MAIL FROM:<a@example.org>").await;
+        let client = &mut session.client;
+        client.send("BDAT 1\r\na").await;
+        assert!(client.reply().await.starts_with("250"));
+
+        // Added to what's already there this would wrap around, it must not be read at all
+        client.send(&format!("BDAT {}\r\n", usize::MAX)).await;
+        assert!(client.reply().await.starts_with("552 5.3.4"));
+        session.handle.await.unwrap().unwrap();
+        assert!(session.transactions.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn bdat_and_data_do_not_mix() {
+        let mut session = start(port_config(), service_config()).await;
+        start_transaction(&mut session, "MAIL FROM:<a@example.org>").await;
+        let client = &mut session.client;
+        client.send("BDAT 2\r\nhi").await;
+        assert!(client.reply().await.starts_with("250"));
+        assert!(client.command("DATA").await.starts_with("503"));
+
+        assert!(client.command("RSET").await.starts_with("250"));
+        start_transaction(&mut session, "MAIL FROM:<a@example.org> BODY=BINARYMIME").await;
+        assert!(session.client.command("DATA").await.starts_with("503"));
+    }
+}
@@ -1,19 +1,563 @@
-use tokio::io::{AsyncWriteExt, BufReader};
+use eemail_lib_shared::{BareLfPolicy, SMTPPortConfiguration};
+use log::debug;
+use tokio::sync::mpsc;
+use uuid::Uuid;
 
-use crate::{Mail, SmtpStream, message_formatter};
+use crate::{
+    BodyType, Filtering, Mail, Session, check_sequence,
+    connection::{Connection, DATA_TIMEOUT},
+    filter,
+    headers::{header_values, mailbox_addresses},
+    reply::{Reply, Status},
+    state::Event,
+};
 
-pub async fn handle(mail: &mut Mail, buffer: &mut BufReader<SmtpStream>) -> anyhow::Result<()> {
-    // Validations
-    if mail.from.is_empty() || mail.to.is_empty() {
-        buffer
-            .get_mut()
-            .write_all(&message_formatter("503 Bad Sequence of commands"))
+// How much message data is read in one go, longer lines just arrive over several chunks
+const DATA_CHUNK: u64 = 8 * 1024;
+
+// How the message data ended, everything up to the end of data marker has been read in every case but `Disconnected`
+#[derive(Debug, PartialEq, Eq)]
+enum Body {
+    Complete(Vec<u8>),
+    TooLarge,
+    BareLineEnding,
+    Disconnected,
+}
+
+pub async fn handle(
+    session: &mut Session,
+    config: &SMTPPortConfiguration,
+    service_config: &eemail_component_configurator::Configuration,
+    connection: &mut Connection,
+    transactions: &mpsc::Sender<Mail>,
+    filtering: &Filtering,
+) -> anyhow::Result<()> {
+    // Only move into DATA once there's a sender and at least one recipient
+    let Some(next) = check_sequence(session, Event::Data, connection).await? else {
+        return Ok(());
+    };
+
+    // RFC 3030, binary content can't survive the dot stuffing and line handling of DATA
+    if session.mail.body == BodyType::BinaryMime {
+        connection.reply(Reply::new(
+            Status::BadSequence,
+            "BODY=BINARYMIME requires BDAT",
+        ));
+        return Ok(());
+    }
+    session.state = next;
+    connection.reply(Reply::new(
+        Status::StartMailInput,
+        "End data with <CR><LF>.<CR><LF>",
+    ));
+    connection.flush().await?;
+
+    let body = read_body(connection, config).await?;
+    debug!("Finished Receving Data from connection");
+
+    let mut mail = std::mem::take(&mut session.mail);
+    session.reset_transaction();
+    session.state = session.state.transition(Event::DataDone).unwrap();
+
+    match body {
+        Body::Complete(data) => {
+            mail.data = data;
+            accept(
+                mail,
+                config,
+                service_config,
+                connection,
+                transactions,
+                filtering,
+            )
             .await?;
+        }
+        Body::TooLarge => {
+            debug!("Rejecting message over {} bytes", config.max_message_size);
+            connection.reply(Reply::new(
+                Status::MessageTooLarge,
+                "Message size exceeds fixed maximum message size",
+            ));
+        }
+        Body::BareLineEnding => {
+            debug!("Rejecting message with a bare CR or LF");
+            connection.reply(Reply::new(
+                Status::InvalidLineEnding,
+                "Bare CR or LF not allowed in message data",
+            ));
+        }
+        Body::Disconnected => debug!("Client went away during DATA"),
     }
-    mail.sending_data = true;
-    buffer
-        .get_mut()
-        .write_all(&message_formatter("354 End data with <CR><LF>.<CR><LF>"))
-        .await?;
     Ok(())
 }
+
+// Hands a finished message over to be delivered, shared with BDAT
+pub(crate) async fn accept(
+    mut mail: Mail,
+    config: &SMTPPortConfiguration,
+    service_config: &eemail_component_configurator::Configuration,
+    connection: &mut Connection,
+    transactions: &mpsc::Sender<Mail>,
+    filtering: &Filtering,
+) -> anyhow::Result<()> {
+    if config.check_header_from
+        && let Some(account) = &mail.authenticated
+    {
+        let from = header_values(&mail.data, "From");
+        let addresses: Vec<String> = from.iter().flat_map(|v| mailbox_addresses(v)).collect();
+        if addresses.is_empty()
+            || addresses
+                .iter()
+                .any(|address| !service_config.may_send_as(account, address))
+        {
+            debug!("Rejecting message with From: {:?}", from);
+            connection.reply(Reply::new(
+                Status::PolicyRejected,
+                "From header address not owned by user",
+            ));
+            return Ok(());
+        }
+    }
+
+    debug!("FROM: {}", mail.from);
+    debug!("TO: {:#?}", mail.to);
+    debug!("DATA: {}", String::from_utf8_lossy(&mail.data));
+
+    mail.id = Uuid::now_v7().as_urn().to_string().replace("urn:uuid:", ""); // maybe I should explore v5 uuid's using the message body as the data, not sure
+    debug!("Given message ID: {}", mail.id);
+
+    if config.filtering_enabled
+        && let Some(reply) = filter::check(&mut mail, filtering, service_config).await
+    {
+        connection.reply(reply);
+        return Ok(());
+    }
+
+    transactions.send(mail).await?;
+    connection.reply(Reply::new(Status::Ok, "Message accepted"));
+    Ok(())
+}
+
+// Reads message data up to a strict <CR><LF>.<CR><LF> as described in RFC 5321 section 4.5.2, undoing the dot stuffing on the way
+async fn read_body(
+    connection: &mut Connection,
+    config: &SMTPPortConfiguration,
+) -> anyhow::Result<Body> {
+    let mut body = Vec::new();
+    let mut chunk = Vec::new();
+
+    // Only a line that follows a proper CRLF can end the message, anything else is how SMTP smuggling works
+    let mut after_crlf = true;
+    let mut at_line_start = true;
+    // A long line can be split between its CR and LF, in which case the LF arrives as a chunk of its own
+    let mut ended_with_cr = false;
+    let mut pending_cr = false;
+    let mut too_large = false;
+    let mut bare_line_ending = false;
+
+    loop {
+        chunk.clear();
+        if connection
+            .read_chunk(&mut chunk, DATA_CHUNK, DATA_TIMEOUT)
+            .await?
+            == 0
+        {
+            return Ok(Body::Disconnected);
+        }
+
+        if after_crlf && chunk == b".\r\n" {
+            break;
+        }
+
+        let mut content = chunk.as_slice();
+        if at_line_start && content.starts_with(b".") {
+            content = &content[1..];
+        }
+
+        if !too_large && !bare_line_ending {
+            let found_bare = push_normalized(&mut body, content, &mut pending_cr);
+            if found_bare && config.bare_lf_policy == BareLfPolicy::Reject {
+                bare_line_ending = true;
+                body = Vec::new();
+            } else if body.len() > config.max_message_size {
+                // Drop what we have straight away rather than holding onto it until the end
+                too_large = true;
+                body = Vec::new();
+            }
+        }
+
+        at_line_start = chunk.ends_with(b"\n");
+        after_crlf = chunk.ends_with(b"\r\n") || (ended_with_cr && chunk == b"\n");
+        ended_with_cr = chunk.ends_with(b"\r");
+    }
+
+    if bare_line_ending {
+        Ok(Body::BareLineEnding)
+    } else if too_large {
+        Ok(Body::TooLarge)
+    } else {
+        Ok(Body::Complete(body))
+    }
+}
+
+// Normalizes a complete message in one go, a CR right at the end counts as bare too
+pub(crate) fn normalize_line_endings(input: &[u8]) -> (Vec<u8>, bool) {
+    let mut output = Vec::with_capacity(input.len());
+    let mut pending_cr = false;
+    let mut found_bare = push_normalized(&mut output, input, &mut pending_cr);
+    if pending_cr {
+        output.extend_from_slice(b"\r\n");
+        found_bare = true;
+    }
+    (output, found_bare)
+}
+
+// Copies `input` into `output` with every line ending as CRLF, returning whether a bare CR or LF had to be fixed up
+// A CR at the very end of `input` is carried in `pending_cr`, as the LF may be in the next chunk
+fn push_normalized(output: &mut Vec<u8>, input: &[u8], pending_cr: &mut bool) -> bool {
+    let mut found_bare = false;
+
+    for &byte in input {
+        if *pending_cr {
+            *pending_cr = false;
+            output.extend_from_slice(b"\r\n");
+            if byte == b'\n' {
+                continue;
+            }
+            found_bare = true;
+        }
+
+        match byte {
+            b'\r' => *pending_cr = true,
+            b'\n' => {
+                found_bare = true;
+                output.extend_from_slice(b"\r\n");
+            }
+            _ => output.push(byte),
+        }
+    }
+
+    found_bare
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use eemail_component_configurator::DmarcConfiguration;
+    use eemail_lib_dmarc::{Policy, Report};
+    use eemail_lib_dns::StaticResolver;
+    use eemail_lib_shared::{BareLfPolicy, FilterAction};
+    use tokio::sync::mpsc;
+
+    use super::{DATA_CHUNK, normalize_line_endings as normalized, push_normalized};
+    use crate::{Filtering, Mail, test_utils::*};
+
+    #[test]
+    fn data_keeps_crlf_line_endings() {
+        assert_eq!(normalized(b"a\r\nb\r\n"), (b"a\r\nb\r\n".to_vec(), false));
+    }
+
+    #[test]
+    fn data_normalizes_bare_line_endings() {
+        assert_eq!(normalized(b"a\nb\r\n"), (b"a\r\nb\r\n".to_vec(), true));
+        assert_eq!(normalized(b"a\rb\r\n"), (b"a\r\nb\r\n".to_vec(), true));
+    }
+
+    #[test]
+    fn data_treats_trailing_cr_as_bare() {
+        assert_eq!(normalized(b"a\r"), (b"a\r\n".to_vec(), true));
+    }
+
+    #[test]
+    fn data_carries_cr_between_chunks() {
+        let mut output = Vec::new();
+        let mut pending_cr = false;
+        assert!(!push_normalized(&mut output, b"abc\r", &mut pending_cr));
+        assert!(pending_cr);
+        assert!(!push_normalized(&mut output, b"\n", &mut pending_cr));
+        assert_eq!(output, b"abc\r\n");
+    }
+
+    async fn send_data(policy: BareLfPolicy, data: &str) -> (TestSession, Vec<String>) {
+        let mut config = port_config();
+        config.bare_lf_policy = policy;
+        let mut session = start(config, service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command("MAIL FROM:<a@example.org>").await;
+        client.command("RCPT TO:<test@example.com>").await;
+        assert!(client.command("DATA").await.starts_with("354"));
+        client.send(data).await;
+        client.send("QUIT\r\n").await;
+
+        let mut replies = Vec::new();
+        loop {
+            let reply = client.reply().await;
+            if reply.is_empty() {
+                break;
+            }
+            let done = reply.starts_with("221");
+            replies.push(reply);
+            if done {
+                break;
+            }
+        }
+        (session, replies)
+    }
+
+    #[tokio::test]
+    async fn data_removes_dot_stuffing() {
+        let (mut session, replies) = send_data(
+            BareLfPolicy::Reject,
+            "..leading dot\r\n...\r\nnot. the end\r\n.\r\n",
+        )
+        .await;
+        assert!(replies[0].starts_with("250"));
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.data, b".leading dot\r\n..\r\nnot. the end\r\n");
+    }
+
+    #[tokio::test]
+    async fn data_only_ends_on_crlf_dot_crlf() {
+        let (mut session, replies) = send_data(
+            BareLfPolicy::Normalize,
+            "one\r\n.\nstill data\r\n. \r\nmore\r\n.\r\n",
+        )
+        .await;
+        assert_eq!(replies.len(), 2);
+        assert!(replies[0].starts_with("250"));
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.data, b"one\r\n\r\nstill data\r\n \r\nmore\r\n");
+    }
+
+    #[tokio::test]
+    async fn data_rejects_bare_lf_when_configured() {
+        let (mut session, replies) = send_data(BareLfPolicy::Reject, "one\nTwo\r\n.\r\n").await;
+        assert!(replies[0].starts_with("554 5.6.0"));
+        assert!(replies[1].starts_with("221"));
+        drop(session.client);
+        session.handle.await.unwrap().unwrap();
+        assert!(session.transactions.recv().await.is_none());
+    }
+
+    // The classic smuggling payload, a second transaction hidden behind <LF>.<CR><LF> inside the first message
+    #[tokio::test]
+    async fn data_does_not_allow_smuggled_transactions() {
+        let payload = concat!(
+            "Subject: first\r\n\r\nHello\n.\r\n",
+            "MAIL FROM:<admin@example.com>\r\n",
+            "RCPT TO:<test@example.com>\r\n",
+            "DATA\r\n",
+            "Subject: smuggled\r\n\r\n",
+            "\r\n.\r\n",
+        );
+
+        for policy in [BareLfPolicy::Reject, BareLfPolicy::Normalize] {
+            let (mut session, replies) = send_data(policy, payload).await;
+            // One reply for the one message, then the QUIT
+            assert_eq!(replies.len(), 2);
+            assert!(replies[1].starts_with("221"));
+            drop(session.client);
+            session.handle.await.unwrap().unwrap();
+
+            match policy {
+                BareLfPolicy::Reject => assert!(replies[0].starts_with("554")),
+                BareLfPolicy::Normalize => {
+                    assert!(replies[0].starts_with("250"));
+                    let mail = session.transactions.recv().await.unwrap();
+                    assert_eq!(mail.from, "a@example.org");
+                    assert!(
+                        String::from_utf8_lossy(&mail.data)
+                            .contains("MAIL FROM:<admin@example.com>\r\n")
+                    );
+                }
+            }
+            assert!(session.transactions.recv().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn data_does_not_end_on_bare_cr_dot() {
+        let (mut session, replies) =
+            send_data(BareLfPolicy::Normalize, "Hello\r.\rworld\r\n.\r\n").await;
+        assert_eq!(replies.len(), 2);
+        assert!(replies[0].starts_with("250"));
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.data, b"Hello\r\n.\r\nworld\r\n");
+    }
+
+    #[tokio::test]
+    async fn data_ends_after_a_line_split_between_cr_and_lf() {
+        let mut config = port_config();
+        config.max_message_size = 16 * 1024;
+        let mut session = start(config, service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command("MAIL FROM:<a@example.org>").await;
+        client.command("RCPT TO:<test@example.com>").await;
+        assert!(client.command("DATA").await.starts_with("354"));
+
+        // The CR is the last byte of one chunk and the LF comes on its own in the next
+        let line = "a".repeat(DATA_CHUNK as usize - 1);
+        client.send(&format!("{}\r\n", line)).await;
+        assert!(client.command(".").await.starts_with("250"));
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.data, format!("{}\r\n", line).into_bytes());
+    }
+
+    async fn send_with_header_from(check_header_from: bool, from: &str) -> String {
+        let mut config = port_config();
+        config.check_header_from = check_header_from;
+        let mut session = start_authenticated(config, service_config()).await;
+        let client = &mut session.client;
+        client.command("MAIL FROM:<test@example.com>").await;
+        client.command("RCPT TO:<example@example.com>").await;
+        client.command("DATA").await;
+        client
+            .command(&format!("From: {}\r\nSubject: hi\r\n\r\nHi\r\n.", from))
+            .await
+    }
+
+    #[tokio::test]
+    async fn data_checks_header_from_when_configured() {
+        for (from, allowed) in [
+            ("Test <test@example.com>", true),
+            ("\"Someone, else\" <hi@example.com>", true),
+            ("Boss <boss@example.org>", false),
+            ("test@example.com, boss@example.org", false),
+        ] {
+            let reply = send_with_header_from(true, from).await;
+            match allowed {
+                true => assert!(reply.starts_with("250"), "{}: {}", from, reply),
+                false => assert!(reply.starts_with("550 5.7.1"), "{}: {}", from, reply),
+            }
+        }
+
+        assert!(
+            send_with_header_from(false, "Boss <boss@example.org>")
+                .await
+                .starts_with("250")
+        );
+    }
+
+    #[tokio::test]
+    async fn filtering_listeners_record_authentication_results() {
+        for filtering_enabled in [true, false] {
+            let mut config = port_config();
+            config.filtering_enabled = filtering_enabled;
+            let mut session = start(config, service_config()).await;
+            let client = &mut session.client;
+            client.command("EHLO client.example").await;
+            client.command("MAIL FROM:<a@example.org>").await;
+            client.command("RCPT TO:<example@example.com>").await;
+            client.command("DATA").await;
+            assert!(
+                client
+                    .command("From: a@example.org\r\n\r\nHi\r\n.")
+                    .await
+                    .starts_with("250")
+            );
+
+            let mail = session.transactions.recv().await.unwrap();
+            let data = String::from_utf8(mail.data).unwrap();
+            assert_eq!(
+                data.starts_with(
+                    "Authentication-Results: mail.example.com;\r\n\
+                     \tspf=none smtp.helo=client.example;\r\n\
+                     \tspf=none smtp.mailfrom=\"a@example.org\";\r\n\
+                     \tdkim=none;\r\n\
+                     \tarc=none;\r\n\
+                     \tdmarc=none header.from=example.org\r\n"
+                ),
+                filtering_enabled
+            );
+            assert!(data.ends_with("From: a@example.org\r\n\r\nHi\r\n"));
+        }
+    }
+
+    // Mail from `from` on a filtering listener, with DMARC results going to the returned receiver
+    async fn send_with_dmarc(
+        service_config: eemail_component_configurator::Configuration,
+        from: &str,
+    ) -> (String, Option<Mail>, mpsc::Receiver<Report>) {
+        let resolver = StaticResolver::default()
+            .txt(
+                "_dmarc.example.org",
+                "v=DMARC1; p=reject; rua=mailto:dmarc@example.org",
+            )
+            .txt("_dmarc.example.net", "v=DMARC1; p=quarantine");
+        let (sender, reports) = mpsc::channel(4);
+        let filtering = Filtering {
+            resolver: Arc::new(resolver),
+            reports: Some(sender),
+        };
+        let mut config = port_config();
+        config.filtering_enabled = true;
+        let mut session = start_with_filtering(config, service_config, filtering).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command(&format!("MAIL FROM:<{}>", from)).await;
+        client.command("RCPT TO:<example@example.com>").await;
+        client.command("DATA").await;
+        let reply = client
+            .command(&format!("From: {}\r\n\r\nHi\r\n.", from))
+            .await;
+        client.command("QUIT").await;
+        drop(session.client);
+        session.handle.await.unwrap().unwrap();
+        (reply, session.transactions.recv().await, reports)
+    }
+
+    #[tokio::test]
+    async fn filtering_listeners_apply_dmarc_policies() {
+        // Nothing passed SPF or DKIM for either domain, so both fail
+        let (reply, mail, mut reports) = send_with_dmarc(service_config(), "a@example.org").await;
+        assert!(reply.starts_with("550 5.7.1"), "{}", reply);
+        assert!(mail.is_none());
+        let report = reports.recv().await.unwrap();
+        assert_eq!(report.domain, "example.org");
+        assert_eq!(report.rows[0].disposition, Policy::Reject);
+        assert_eq!(report.rows[0].source_ip.to_string(), "127.0.0.1");
+        assert_eq!(report.rows[0].envelope_from.as_deref(), Some("example.org"));
+        assert_eq!(report.rows[0].reason, None);
+
+        let (reply, mail, mut reports) = send_with_dmarc(service_config(), "a@example.net").await;
+        assert!(reply.starts_with("250"), "{}", reply);
+        let data = String::from_utf8(mail.unwrap().data).unwrap();
+        assert!(data.starts_with("X-Spam-Flag: YES\r\n"));
+        assert!(data.contains(
+            "\tdkim=none;\r\n\
+             \tarc=none;\r\n\
+             \tdmarc=fail (p=quarantine dis=quarantine) header.from=example.net\r\n"
+        ));
+        // No rua=, so nothing to report
+        assert!(reports.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dmarc_actions_are_configurable() {
+        let mut service_config = service_config();
+        service_config.dmarc = Some(DmarcConfiguration {
+            reject: Some(FilterAction::Tag),
+            quarantine: Some(FilterAction::Accept),
+            ..Default::default()
+        });
+
+        let (reply, mail, mut reports) =
+            send_with_dmarc(service_config.clone(), "a@example.org").await;
+        assert!(reply.starts_with("250"), "{}", reply);
+        let data = String::from_utf8(mail.unwrap().data).unwrap();
+        assert!(data.starts_with("X-Spam-Flag: YES\r\n"));
+        assert!(data.contains("dmarc=fail (p=reject dis=quarantine) header.from=example.org"));
+        let report = reports.recv().await.unwrap();
+        assert_eq!(report.rows[0].disposition, Policy::Quarantine);
+        assert_eq!(report.rows[0].reason.as_deref(), Some("local_policy"));
+
+        let (reply, mail, _) = send_with_dmarc(service_config, "a@example.net").await;
+        assert!(reply.starts_with("250"), "{}", reply);
+        let data = String::from_utf8(mail.unwrap().data).unwrap();
+        assert!(data.starts_with("Authentication-Results:"));
+        assert!(data.contains("dmarc=fail (p=quarantine dis=none) header.from=example.net"));
+    }
+}
@@ -1,42 +1,88 @@
 use eemail_lib_shared::SMTPPortConfiguration;
 use log::debug;
-use tokio::io::{AsyncWriteExt, BufReader};
 
-use crate::{Mail, SmtpStream};
+use crate::{
+    Session, check_sequence,
+    connection::Connection,
+    reply::{Reply, Status},
+    state::Event,
+};
 
 pub async fn handle(
-    mail: &Mail,
+    session: &mut Session,
     config: &SMTPPortConfiguration,
-    buffer: &mut BufReader<SmtpStream>,
+    connection: &mut Connection,
+    cmd: Vec<String>,
 ) -> anyhow::Result<()> {
-    let mut extension_strs: Vec<String> = vec![
-        "250-Localhost".to_string(),
-        "250-PIPELINING".to_string(),
-        format!("250-Size {}", 10 * 1024 * 1024),
-    ];
+    // A new EHLO throws away any transaction that was in progress
+    let Some(next) = check_sequence(session, Event::Helo, connection).await? else {
+        return Ok(());
+    };
+    session.state = next;
+    session.reset_transaction();
+    session.set_helo(cmd.get(1));
+
+    let mut reply = Reply::new(Status::Greeting, "Localhost")
+        .line("PIPELINING")
+        .line(format!("SIZE {}", config.max_message_size))
+        .line("ENHANCEDSTATUSCODES")
+        .line("8BITMIME")
+        .line("SMTPUTF8")
+        .line("CHUNKING")
+        .line("BINARYMIME")
+        .line("DSN");
 
     // Only offer STARTTLS if TLS hasn't been established yet
-    if !mail.has_tlsd {
-        extension_strs.push("250-STARTTLS".to_string());
+    if !session.has_tlsd {
+        reply = reply.line("STARTTLS");
     }
 
     // Only offer AUTH if enabled, TLS is active
-    if config.auth_enabled && mail.has_tlsd {
-        extension_strs.push("250-AUTH PLAIN".to_string());
-    }
-
-    // The last one in the array needs to be "250 " not "250-"
-    if let Some(last) = extension_strs.last_mut() {
-        *last = last.replacen("250-", "250 ", 1);
+    if config.auth_enabled && session.has_tlsd {
+        reply = reply.line(format!("AUTH {}", eemail_lib_sasl::MECHANISMS.join(" ")));
     }
 
-    debug!("Sending EHLO Response {:#?}", extension_strs);
-    let mut joined_extensions = extension_strs.join("\r\n");
-    joined_extensions.push_str("\r\n");
-    buffer
-        .get_mut()
-        .write_all(joined_extensions.as_bytes())
-        .await?;
+    debug!("Sending EHLO Response {:#?}", reply);
+    connection.reply(reply);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::*;
+
+    #[tokio::test]
+    async fn ehlo_advertises_extensions() {
+        let mut session = start(port_config(), service_config()).await;
+        let reply = session.client.command("EHLO client.example").await;
+        assert!(reply.starts_with("250-Localhost\r\n"));
+        assert!(reply.contains("250-ENHANCEDSTATUSCODES\r\n"));
+        assert!(reply.contains("250-CHUNKING\r\n"));
+        assert!(reply.contains("250-BINARYMIME\r\n"));
+        assert!(reply.contains("250-DSN\r\n"));
+        assert!(reply.ends_with("250 STARTTLS\r\n"));
+    }
+
+    #[tokio::test]
+    async fn replies_carry_enhanced_codes() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert_eq!(
+            client.command("MAIL FROM:<a@example.org>").await,
+            "250 2.1.0 OK\r\n"
+        );
+        assert_eq!(
+            client.command("RCPT TO:<test@example.com>").await,
+            "250 2.1.5 OK\r\n"
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org>")
+                .await
+                .starts_with("503 5.5.1 ")
+        );
+        assert!(client.command("BOGUS").await.starts_with("502 5.5.1 "));
+    }
+}
@@ -0,0 +1,22 @@
+use crate::{
+    Session, check_sequence,
+    connection::Connection,
+    reply::{Reply, Status},
+    state::Event,
+};
+
+pub async fn handle(
+    session: &mut Session,
+    connection: &mut Connection,
+    cmd: Vec<String>,
+) -> anyhow::Result<()> {
+    // HELO is the pre-ESMTP greeting, so no extensions get listed
+    let Some(next) = check_sequence(session, Event::Helo, connection).await? else {
+        return Ok(());
+    };
+    session.state = next;
+    session.reset_transaction();
+    session.set_helo(cmd.get(1));
+    connection.reply(Reply::new(Status::Greeting, "Localhost"));
+    Ok(())
+}
@@ -1,36 +1,610 @@
+use eemail_lib_shared::{Dsn, Ret, SMTPPortConfiguration, decode_xtext};
 use log::debug;
-use tokio::io::{AsyncWriteExt, BufReader};
 
-use crate::{Mail, SmtpStream, message_formatter};
+use crate::{
+    BodyType, Filtering, Session, check_sequence,
+    connection::Connection,
+    envelope::{self, Path},
+    filter,
+    reply::{Reply, Status},
+    state::Event,
+};
 
 pub async fn handle(
-    mail: &mut Mail,
-    buffer: &mut BufReader<SmtpStream>,
+    session: &mut Session,
+    config: &SMTPPortConfiguration,
+    service_config: &eemail_component_configurator::Configuration,
+    connection: &mut Connection,
+    filtering: &Filtering,
     cmd: Vec<String>,
 ) -> anyhow::Result<()> {
-    mail.in_mail = true;
-    if let Some(second) = cmd.get(1) {
-        // Carefully strip out the FROM header, this caters to Thunderbirds specific behaviour so some leeway is allowed
-        let second = second
-            .strip_prefix("FROM:")
-            .unwrap_or_else(|| second)
-            .to_string();
-        let second = second
-            .strip_prefix("<")
-            .unwrap_or_else(|| &second)
-            .strip_suffix(">")
-            .unwrap_or_else(|| &second)
-            .to_string();
-
-        debug!("Stripped FROM header to {}", second);
-
-        buffer
-            .get_mut()
-            .write_all(&message_formatter("250 OK"))
-            .await?;
-
-        mail.from = second;
-        debug!("Responded to MAIL FROM");
+    let Some(next) = check_sequence(session, Event::Mail, connection).await? else {
+        return Ok(());
+    };
+
+    // RCPT, DATA and BDAT all need a MAIL first, so checking the listener policy here covers them too
+    if config.require_tls && !session.has_tlsd {
+        connection.reply(Reply::new(
+            Status::TlsRequired,
+            "Must issue a STARTTLS command first",
+        ));
+        return Ok(());
+    }
+    if config.require_auth && session.authenticated.is_none() {
+        connection.reply(Reply::new(Status::AuthRequired, "Authentication required"));
+        return Ok(());
+    }
+
+    let Some(path) = cmd
+        .get(1..)
+        .filter(|args| !args.is_empty())
+        .and_then(|args| envelope::parse(args, "FROM:"))
+    else {
+        connection.reply(Reply::new(
+            Status::SyntaxError,
+            "Syntax error in parameters",
+        ));
+        return Ok(());
+    };
+    debug!("Stripped FROM header to {}", path.address);
+
+    let (body, smtputf8, dsn) = match check_parameters(&path, config) {
+        Ok(parameters) => parameters,
+        Err(reply) => {
+            connection.reply(reply);
+            return Ok(());
+        }
+    };
+
+    // RFC 6531 3.6, UTF-8 addresses are only allowed once the client has asked for SMTPUTF8
+    if !smtputf8 && !path.address.is_ascii() {
+        connection.reply(Reply::new(
+            Status::AddressNotPermitted,
+            "Non-ASCII addresses require SMTPUTF8",
+        ));
+        return Ok(());
+    }
+
+    // RFC 6409 section 6.1, a logged in user can only send as themselves (or who they've been allowed to)
+    if let Some(account) = &session.authenticated
+        && !path.address.is_empty()
+        && !service_config.may_send_as(account, &path.address)
+    {
+        debug!(
+            "{} may not send as {}",
+            account.clone().get_primary_address(),
+            path.address
+        );
+        connection.reply(Reply::new(
+            Status::SenderNotAllowed,
+            "Sender address not owned by user",
+        ));
+        return Ok(());
     }
+
+    // Our own users and trusted relays have already been vouched for, SPF is for everyone else
+    let exempt = session.authenticated.is_some()
+        || session
+            .remote_ip
+            .is_some_and(|ip| service_config.is_trusted_relay(ip));
+    if config.filtering_enabled
+        && !exempt
+        && let Some(reply) =
+            filter::check_sender(session, &path.address, &*filtering.resolver, service_config).await
+    {
+        connection.reply(reply);
+        return Ok(());
+    }
+
+    connection.reply(Reply::new(Status::SenderOk, "OK"));
+
+    session.mail.from = path.address;
+    session.mail.body = body;
+    session.mail.smtputf8 = smtputf8;
+    session.mail.dsn = dsn;
+    session.mail.authenticated = session.authenticated.clone();
+    session.mail.client_ip = session.remote_ip;
+    session.state = next;
+    debug!("Responded to MAIL FROM");
     Ok(())
 }
+
+// Goes through the ESMTP parameters, only the ones for extensions we advertise are allowed
+fn check_parameters(
+    path: &Path,
+    config: &SMTPPortConfiguration,
+) -> Result<(BodyType, bool, Dsn), Reply> {
+    let mut body = BodyType::default();
+    let mut smtputf8 = false;
+    let mut dsn = Dsn::default();
+
+    for (name, value) in &path.parameters {
+        match name.as_str() {
+            // RFC 1870, refuse up front if the client has told us the message is too big
+            "SIZE" => match value.as_deref().map(str::parse::<usize>) {
+                Some(Ok(size)) if size > config.max_message_size => {
+                    debug!(
+                        "Declared size {} is over the limit of {}",
+                        size, config.max_message_size
+                    );
+                    return Err(Reply::new(
+                        Status::MessageTooLarge,
+                        "Message size exceeds fixed maximum message size",
+                    ));
+                }
+                Some(Ok(_)) => {}
+                _ => {
+                    return Err(Reply::new(
+                        Status::SyntaxError,
+                        "Syntax error in SIZE parameter",
+                    ));
+                }
+            },
+            // RFC 6152 and RFC 3030, the data is kept as bytes either way but relaying needs to know
+            "BODY" => match value.as_deref().map(str::to_ascii_uppercase).as_deref() {
+                Some("7BIT") => body = BodyType::SevenBit,
+                Some("8BITMIME") => body = BodyType::EightBitMime,
+                Some("BINARYMIME") => body = BodyType::BinaryMime,
+                _ => {
+                    return Err(Reply::new(
+                        Status::SyntaxError,
+                        "Syntax error in BODY parameter",
+                    ));
+                }
+            },
+            // RFC 6531, takes no value
+            "SMTPUTF8" => match value {
+                None => smtputf8 = true,
+                Some(_) => {
+                    return Err(Reply::new(
+                        Status::SyntaxError,
+                        "SMTPUTF8 does not take a value",
+                    ));
+                }
+            },
+            // RFC 3461 4.3, each can only be given once
+            "RET" if dsn.ret.is_none() => match value.as_deref().and_then(Ret::parse) {
+                Some(ret) => dsn.ret = Some(ret),
+                None => {
+                    return Err(Reply::new(
+                        Status::SyntaxError,
+                        "Syntax error in RET parameter",
+                    ));
+                }
+            },
+            "ENVID" if dsn.envid.is_none() => match value.as_deref().and_then(parse_envid) {
+                Some(envid) => dsn.envid = Some(envid),
+                None => {
+                    return Err(Reply::new(
+                        Status::SyntaxError,
+                        "Syntax error in ENVID parameter",
+                    ));
+                }
+            },
+            "RET" | "ENVID" => {
+                return Err(Reply::new(
+                    Status::SyntaxError,
+                    "Duplicate MAIL FROM parameter",
+                ));
+            }
+            _ => {
+                debug!("Unrecognised MAIL FROM parameter {}", name);
+                return Err(Reply::new(
+                    Status::ParameterNotRecognised,
+                    "MAIL FROM parameters not recognized or not implemented",
+                ));
+            }
+        }
+    }
+
+    Ok((body, smtputf8, dsn))
+}
+
+// RFC 3461 4.4, xtext of at most 100 characters that has to decode to printable ASCII
+fn parse_envid(value: &str) -> Option<String> {
+    if value.len() > 100 {
+        return None;
+    }
+    decode_xtext(value).filter(|envid| envid.bytes().all(|byte| (b' '..=b'~').contains(&byte)))
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_component_configurator::{SmtpConfiguration, SpfConfiguration};
+    use eemail_lib_dns::StaticResolver;
+    use eemail_lib_shared::{FilterAction, Ret};
+
+    use crate::{BodyType, test_utils::*};
+
+    // Senders for each SPF result a client on 127.0.0.1 can get
+    fn spf_zone() -> StaticResolver {
+        StaticResolver::default()
+            .txt("example.org", "v=spf1 ip4:127.0.0.0/8 -all")
+            .txt("example.net", "v=spf1 -all exp=why.example.net")
+            .txt("why.example.net", "%{c} is not one of our servers")
+            .txt("softfail.example", "v=spf1 ~all")
+            .txt("bad-helo.example", "v=spf1 -all")
+            .fail("broken.example")
+    }
+
+    async fn spf_session(
+        filtering_enabled: bool,
+        service_config: eemail_component_configurator::Configuration,
+    ) -> TestSession {
+        let mut config = port_config();
+        config.filtering_enabled = filtering_enabled;
+        start_with_resolver(config, service_config, spf_zone()).await
+    }
+
+    #[tokio::test]
+    async fn mail_rejects_declared_size_over_limit() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        assert!(
+            client
+                .command("EHLO client.example")
+                .await
+                .contains("250-SIZE 1024\r\n")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org> SIZE=1025")
+                .await
+                .starts_with("552")
+        );
+        assert!(
+            client
+                .command("RCPT TO:<test@example.com>")
+                .await
+                .starts_with("503")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org> SIZE=1024")
+                .await
+                .starts_with("250")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_rejects_malformed_size() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org> SIZE=lots")
+                .await
+                .starts_with("501")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org> SIZE")
+                .await
+                .starts_with("501")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_accepts_body_parameter() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        assert!(
+            client
+                .command("EHLO client.example")
+                .await
+                .contains("250-8BITMIME\r\n")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org> BODY=8BITMIME")
+                .await
+                .starts_with("250")
+        );
+        client.command("RSET").await;
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org> BODY=7bit")
+                .await
+                .starts_with("250")
+        );
+        client.command("RSET").await;
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org> BODY=16BIT")
+                .await
+                .starts_with("501")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_rejects_unknown_parameters() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org> FOO=BAR")
+                .await
+                .starts_with("555 5.5.4")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_keeps_8bit_body_intact() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client
+            .command("MAIL FROM:<a@example.org> BODY=8BITMIME")
+            .await;
+        client.command("RCPT TO:<test@example.com>").await;
+        assert!(client.command("DATA").await.starts_with("354"));
+
+        // "Grüße" in Latin-1, which isn't valid UTF-8
+        client
+            .send_bytes(b"Subject: Gr\xfc\xdfe\r\n\r\n\xe9t\xe9\r\n.\r\n")
+            .await;
+        assert!(client.reply().await.starts_with("250"));
+
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.body, BodyType::EightBitMime);
+        assert_eq!(mail.data, b"Subject: Gr\xfc\xdfe\r\n\r\n\xe9t\xe9\r\n");
+    }
+
+    #[tokio::test]
+    async fn mail_accepts_utf8_addresses_with_smtputf8() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        assert!(
+            client
+                .command("EHLO client.example")
+                .await
+                .contains("250-SMTPUTF8\r\n")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<jörg@bücher.example> SMTPUTF8")
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            client
+                .command("RCPT TO:<jörg@bücher.example>")
+                .await
+                .starts_with("250")
+        );
+        client.command("DATA").await;
+        assert!(
+            client
+                .command("Subject: Grüße\r\n\r\nHallo\r\n.")
+                .await
+                .starts_with("250")
+        );
+
+        let mail = session.transactions.recv().await.unwrap();
+        assert!(mail.smtputf8);
+        assert_eq!(mail.from, "jörg@bücher.example");
+        assert_eq!(mail.to, ["jörg@bücher.example"]);
+    }
+
+    #[tokio::test]
+    async fn mail_rejects_utf8_addresses_without_smtputf8() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(
+            client
+                .command("MAIL FROM:<jörg@bücher.example>")
+                .await
+                .starts_with("553 5.6.7")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org> SMTPUTF8=yes")
+                .await
+                .starts_with("501")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org>")
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            client
+                .command("RCPT TO:<jörg@bücher.example>")
+                .await
+                .starts_with("553 5.6.7")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_from_must_belong_to_the_authenticated_user() {
+        let mut session = start_authenticated(port_config(), service_config()).await;
+        let client = &mut session.client;
+        for (sender, allowed) in [
+            ("test@example.com", true),
+            ("jörg@bücher.example", true),
+            // Through send_as
+            ("hi@example.com", true),
+            ("", true),
+            ("someone@example.org", false),
+        ] {
+            let reply = client
+                .command(&format!("MAIL FROM:<{}> SMTPUTF8", sender))
+                .await;
+            if allowed {
+                assert!(reply.starts_with("250"), "{}: {}", sender, reply);
+                client.command("RSET").await;
+            } else {
+                assert!(reply.starts_with("553 5.7.1"), "{}: {}", sender, reply);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mail_accepts_dsn_parameters() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org> RET=HDRS ENVID=QQ314159+2Bx")
+                .await
+                .starts_with("250")
+        );
+        client.command("RCPT TO:<test@example.com>").await;
+        client.command("DATA").await;
+        client.command("Subject: hi\r\n\r\nHi\r\n.").await;
+
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.dsn.ret, Some(Ret::Headers));
+        assert_eq!(mail.dsn.envid.as_deref(), Some("QQ314159+x"));
+    }
+
+    #[tokio::test]
+    async fn mail_rejects_bad_dsn_parameters() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        for parameters in [
+            "RET=BODY",
+            "RET",
+            "RET=FULL RET=HDRS",
+            "ENVID=a+2",
+            "ENVID=a+0Ab",
+            "ENVID=a ENVID=b",
+        ] {
+            assert!(
+                client
+                    .command(&format!("MAIL FROM:<a@example.org> {}", parameters))
+                    .await
+                    .starts_with("501"),
+                "{}",
+                parameters
+            );
+        }
+        assert!(
+            client
+                .command(&format!(
+                    "MAIL FROM:<a@example.org> ENVID={}",
+                    "x".repeat(101)
+                ))
+                .await
+                .starts_with("501")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_checks_spf_on_filtering_listeners() {
+        let mut session = spf_session(true, service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        for (sender, reply) in [
+            ("a@example.org", "250 2.1.0"),
+            (
+                "a@example.net",
+                "550 5.7.23 127.0.0.1 is not one of our servers",
+            ),
+            // Softfail is only tagged and lookup failures let through, unless configured otherwise
+            ("a@softfail.example", "250 2.1.0"),
+            ("a@broken.example", "250 2.1.0"),
+        ] {
+            let received = client.command(&format!("MAIL FROM:<{}>", sender)).await;
+            assert!(received.starts_with(reply), "{}: {}", sender, received);
+            client.command("RSET").await;
+        }
+
+        // The HELO identity is checked too
+        client.command("EHLO bad-helo.example").await;
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org>")
+                .await
+                .starts_with("550 5.7.23 ")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_spf_actions_are_configurable() {
+        let mut service_config = service_config();
+        service_config.spf = Some(SpfConfiguration {
+            fail: Some(FilterAction::Accept),
+            temperror: Some(FilterAction::Reject),
+            ..Default::default()
+        });
+        let mut session = spf_session(true, service_config).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(
+            client
+                .command("MAIL FROM:<a@broken.example>")
+                .await
+                .starts_with("451 4.7.24 ")
+        );
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.net>")
+                .await
+                .starts_with("250")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_skips_spf_when_not_needed() {
+        let mut session = spf_session(false, service_config()).await;
+        session.client.command("EHLO client.example").await;
+        assert!(
+            session
+                .client
+                .command("MAIL FROM:<a@example.net>")
+                .await
+                .starts_with("250")
+        );
+
+        let mut service_config = service_config();
+        service_config.smtp = Some(SmtpConfiguration {
+            relay_networks: Some(vec!["127.0.0.0/8".parse().unwrap()]),
+            ..Default::default()
+        });
+        let mut session = spf_session(true, service_config).await;
+        session.client.command("EHLO client.example").await;
+        assert!(
+            session
+                .client
+                .command("MAIL FROM:<a@example.net>")
+                .await
+                .starts_with("250")
+        );
+    }
+
+    #[tokio::test]
+    async fn mail_spf_results_reach_the_message() {
+        let mut session = spf_session(true, service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command("MAIL FROM:<a@softfail.example>").await;
+        client.command("RCPT TO:<example@example.com>").await;
+        client.command("DATA").await;
+        assert!(
+            client
+                .command("Subject: hi\r\n\r\nHi\r\n.")
+                .await
+                .starts_with("250")
+        );
+
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.authentication.tags, ["spf=softfail"]);
+        assert_eq!(
+            String::from_utf8(mail.data).unwrap(),
+            "X-Spam-Flag: YES\r\n\
+             Authentication-Results: mail.example.com;\r\n\
+             \tspf=none smtp.helo=client.example;\r\n\
+             \tspf=softfail smtp.mailfrom=\"a@softfail.example\";\r\n\
+             \tdkim=none;\r\n\
+             \tarc=none\r\n\
+             Subject: hi\r\n\r\nHi\r\n"
+        );
+    }
+}
@@ -1,7 +1,12 @@
 pub mod auth;
+pub mod bdat;
 pub mod data;
 pub mod ehlo;
+pub mod helo;
 pub mod mail;
+pub mod noop;
 pub mod quit;
 pub mod rcpt;
+pub mod rset;
 pub mod starttls;
+pub mod vrfy;
@@ -0,0 +1,9 @@
+use crate::{
+    connection::Connection,
+    reply::{Reply, Status},
+};
+
+pub async fn handle(connection: &mut Connection) -> anyhow::Result<()> {
+    connection.reply(Reply::new(Status::Ok, "OK"));
+    Ok(())
+}
@@ -1,12 +1,11 @@
-use tokio::io::{AsyncWriteExt, BufReader};
+use crate::{
+    connection::Connection,
+    reply::{Reply, Status},
+};
 
-use crate::{SmtpStream, message_formatter};
-
-pub async fn handle(buffer: &mut BufReader<SmtpStream>) -> anyhow::Result<()> {
-    buffer
-        .get_mut()
-        .write_all(&message_formatter("221 Bye"))
-        .await?;
-    buffer.get_mut().shutdown().await?;
+// RFC 5321 4.1.1.10, nothing after QUIT is read, even if it was pipelined
+pub async fn handle(connection: &mut Connection) -> anyhow::Result<()> {
+    connection.reply(Reply::new(Status::ServiceClosing, "Bye"));
+    connection.close().await?;
     Ok(())
 }
@@ -1,33 +1,338 @@
+use eemail_lib_shared::{Notify, RecipientDsn, decode_xtext};
 use log::debug;
-use tokio::io::{AsyncWriteExt, BufReader};
 
-use crate::{Mail, SmtpStream, message_formatter};
+use crate::{
+    Session, check_sequence,
+    connection::Connection,
+    envelope::{self, Path},
+    reply::{Reply, Status},
+    state::Event,
+};
 
 pub async fn handle(
-    mail: &mut Mail,
-    buffer: &mut BufReader<SmtpStream>,
+    session: &mut Session,
+    service_config: &eemail_component_configurator::Configuration,
+    connection: &mut Connection,
     cmd: Vec<String>,
 ) -> anyhow::Result<()> {
-    if let Some(second) = cmd.get(1) {
-        // Carefully strip out the FROM header, this caters to Thunderbirds specific behaviour so some leeway is allowed
-        let second = second
-            .strip_prefix("TO:")
-            .unwrap_or_else(|| second)
-            .to_string();
-        let second = second
-            .strip_prefix("<")
-            .unwrap_or_else(|| &second)
-            .strip_suffix(">")
-            .unwrap_or_else(|| &second)
-            .to_string();
-
-        debug!("Stripped TO header to {}", second);
-        mail.to.push(second);
-        buffer
-            .get_mut()
-            .write_all(&message_formatter("250 OK"))
-            .await?;
-        debug!("Responded to RCPT TO");
+    let Some(next) = check_sequence(session, Event::Rcpt, connection).await? else {
+        return Ok(());
+    };
+
+    let Some(path) = cmd
+        .get(1..)
+        .filter(|args| !args.is_empty())
+        .and_then(|args| envelope::parse(args, "TO:"))
+        .filter(|path| !path.address.is_empty())
+    else {
+        connection.reply(Reply::new(
+            Status::SyntaxError,
+            "Syntax error in parameters",
+        ));
+        return Ok(());
+    };
+
+    debug!("Stripped TO header to {}", path.address);
+
+    let dsn = match check_parameters(&path) {
+        Ok(dsn) => dsn,
+        Err(reply) => {
+            connection.reply(reply);
+            return Ok(());
+        }
+    };
+
+    if !session.mail.smtputf8 && !path.address.is_ascii() {
+        connection.reply(Reply::new(
+            Status::AddressNotPermitted,
+            "Non-ASCII addresses require SMTPUTF8",
+        ));
+        return Ok(());
+    }
+
+    let local = match path.address.rsplit_once('@') {
+        Some((_, domain)) => service_config.is_local_domain(domain),
+        // RFC 5321 4.1.1.3, the one address that's fine without a domain
+        None if path.address.eq_ignore_ascii_case("postmaster") => true,
+        None => {
+            connection.reply(Reply::new(
+                Status::SyntaxError,
+                "Recipient must include a domain",
+            ));
+            return Ok(());
+        }
+    };
+
+    if local {
+        // Anything accepted here has to end up in a mailbox, so unknown users are refused now rather than dropped later
+        if service_config.get_recipient(&path.address).is_none() {
+            debug!("Rejecting unknown local recipient {}", path.address);
+            connection.reply(Reply::new(Status::MailboxUnavailable, "No such user here"));
+            return Ok(());
+        }
+    } else if !may_relay(session, service_config) {
+        debug!("Refusing to relay to {}", path.address);
+        connection.reply(Reply::new(Status::RelayDenied, "Relay access denied"));
+        return Ok(());
     }
+
+    if dsn != RecipientDsn::default() {
+        session
+            .mail
+            .dsn
+            .recipients
+            .insert(path.address.clone(), dsn);
+    }
+    session.mail.to.push(path.address);
+    session.state = next;
+    connection.reply(Reply::new(Status::RecipientOk, "OK"));
+    debug!("Responded to RCPT TO");
     Ok(())
 }
+
+// RFC 3461 4.1 and 4.2, the only parameters we take on RCPT and each only once
+fn check_parameters(path: &Path) -> Result<RecipientDsn, Reply> {
+    let mut dsn = RecipientDsn::default();
+
+    for (name, value) in &path.parameters {
+        match name.as_str() {
+            "NOTIFY" if dsn.notify.is_none() => match value.as_deref().and_then(Notify::parse) {
+                Some(notify) => dsn.notify = Some(notify),
+                None => {
+                    return Err(Reply::new(
+                        Status::SyntaxError,
+                        "Syntax error in NOTIFY parameter",
+                    ));
+                }
+            },
+            "ORCPT" if dsn.orcpt.is_none() => match value.as_deref().and_then(parse_orcpt) {
+                Some(orcpt) => dsn.orcpt = Some(orcpt),
+                None => {
+                    return Err(Reply::new(
+                        Status::SyntaxError,
+                        "Syntax error in ORCPT parameter",
+                    ));
+                }
+            },
+            "NOTIFY" | "ORCPT" => {
+                return Err(Reply::new(
+                    Status::SyntaxError,
+                    "Duplicate RCPT TO parameter",
+                ));
+            }
+            _ => {
+                debug!("Unrecognised RCPT TO parameter {}", name);
+                return Err(Reply::new(
+                    Status::ParameterNotRecognised,
+                    "RCPT TO parameters not recognized or not implemented",
+                ));
+            }
+        }
+    }
+
+    Ok(dsn)
+}
+
+// "addr-type;xtext", kept with the address decoded so it can go straight into a report
+fn parse_orcpt(value: &str) -> Option<String> {
+    if value.len() > 500 {
+        return None;
+    }
+    let (address_type, address) = value.split_once(';')?;
+    if address_type.is_empty()
+        || !address_type
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return None;
+    }
+    let address = decode_xtext(address).filter(|address| !address.is_empty())?;
+    Some(format!("{};{}", address_type, address))
+}
+
+// Mail for other domains is only taken from authenticated users or the configured trusted networks
+fn may_relay(
+    session: &Session,
+    service_config: &eemail_component_configurator::Configuration,
+) -> bool {
+    session.authenticated.is_some()
+        || session
+            .remote_ip
+            .is_some_and(|ip| service_config.is_trusted_relay(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_component_configurator::SmtpConfiguration;
+    use eemail_lib_shared::{Notify, RecipientDsn};
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    use crate::test_utils::*;
+
+    async fn rcpt<S: AsyncRead + AsyncWrite + Unpin>(
+        client: &mut TestClient<S>,
+        to: &str,
+    ) -> String {
+        client.command(&format!("RCPT TO:<{}>", to)).await
+    }
+
+    #[tokio::test]
+    async fn rcpt_rejects_unknown_local_users() {
+        let mut session = start(port_config(), service_config()).await;
+        session.client.command("EHLO client.example").await;
+        session.client.command("MAIL FROM:<a@example.org>").await;
+        assert!(
+            rcpt(&mut session.client, "nobody@example.com")
+                .await
+                .starts_with("550 5.1.1")
+        );
+        assert!(
+            rcpt(&mut session.client, "test@EXAMPLE.com")
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            rcpt(&mut session.client, "hi@example.com")
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            rcpt(&mut session.client, "example@example.net")
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            rcpt(&mut session.client, "Postmaster")
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            rcpt(&mut session.client, "postmaster@example.net")
+                .await
+                .starts_with("250")
+        );
+        assert!(rcpt(&mut session.client, "nobody").await.starts_with("501"));
+    }
+
+    #[tokio::test]
+    async fn rcpt_refuses_to_relay() {
+        let mut session = start(port_config(), service_config()).await;
+        session.client.command("EHLO client.example").await;
+        session.client.command("MAIL FROM:<a@example.org>").await;
+        assert!(
+            rcpt(&mut session.client, "someone@example.org")
+                .await
+                .starts_with("554 5.7.1")
+        );
+
+        // Nothing was accepted, so the transaction can't go on to DATA
+        assert!(
+            session
+                .client
+                .command("DATA")
+                .await
+                .starts_with("554 5.5.1")
+        );
+    }
+
+    #[tokio::test]
+    async fn rcpt_relays_for_authenticated_users() {
+        let mut session = start_authenticated(port_config(), service_config()).await;
+        session.client.command("MAIL FROM:<test@example.com>").await;
+        assert!(
+            rcpt(&mut session.client, "someone@example.org")
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            rcpt(&mut session.client, "nobody@example.com")
+                .await
+                .starts_with("550 5.1.1")
+        );
+    }
+
+    #[tokio::test]
+    async fn rcpt_relays_for_trusted_networks() {
+        let mut service_config = service_config();
+        service_config.smtp = Some(SmtpConfiguration {
+            relay_networks: Some(vec!["127.0.0.0/8".parse().unwrap()]),
+            ..Default::default()
+        });
+        let mut session = start(port_config(), service_config).await;
+        session.client.command("EHLO client.example").await;
+        session.client.command("MAIL FROM:<a@example.org>").await;
+        assert!(
+            rcpt(&mut session.client, "someone@example.org")
+                .await
+                .starts_with("250")
+        );
+    }
+
+    #[tokio::test]
+    async fn rcpt_accepts_dsn_parameters() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command("MAIL FROM:<a@example.org>").await;
+        assert!(
+            client
+                .command("RCPT TO:<test@example.com> NOTIFY=SUCCESS,FAILURE ORCPT=rfc822;Test+2Bdsn@example.com")
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            client
+                .command("RCPT TO:<hi@example.com> NOTIFY=NEVER")
+                .await
+                .starts_with("250")
+        );
+        assert!(rcpt(client, "example@example.net").await.starts_with("250"));
+        client.command("DATA").await;
+        client.command("Subject: hi\r\n\r\nHi\r\n.").await;
+
+        let mail = session.transactions.recv().await.unwrap();
+        let test = mail.dsn.recipient("test@example.com");
+        assert_eq!(
+            test.notify,
+            Some(Notify {
+                success: true,
+                failure: true,
+                delay: false,
+            })
+        );
+        assert_eq!(test.orcpt.as_deref(), Some("rfc822;Test+dsn@example.com"));
+        assert_eq!(
+            mail.dsn.recipient("hi@example.com").notify,
+            Some(Notify::NEVER)
+        );
+        assert_eq!(
+            mail.dsn.recipient("example@example.net"),
+            RecipientDsn::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn rcpt_rejects_bad_parameters() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command("MAIL FROM:<a@example.org>").await;
+        for (parameters, reply) in [
+            ("NOTIFY=NEVER,DELAY", "501"),
+            ("NOTIFY", "501"),
+            ("NOTIFY=DELAY NOTIFY=FAILURE", "501"),
+            ("ORCPT=test@example.com", "501"),
+            ("ORCPT=;test@example.com", "501"),
+            ("FOO=BAR", "555 5.5.4"),
+        ] {
+            assert!(
+                client
+                    .command(&format!("RCPT TO:<test@example.com> {}", parameters))
+                    .await
+                    .starts_with(reply),
+                "{}",
+                parameters
+            );
+        }
+    }
+}
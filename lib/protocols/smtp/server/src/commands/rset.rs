@@ -0,0 +1,16 @@
+use crate::{
+    Session, check_sequence,
+    connection::Connection,
+    reply::{Reply, Status},
+    state::Event,
+};
+
+pub async fn handle(session: &mut Session, connection: &mut Connection) -> anyhow::Result<()> {
+    let Some(next) = check_sequence(session, Event::Reset, connection).await? else {
+        return Ok(());
+    };
+    session.state = next;
+    session.reset_transaction();
+    connection.reply(Reply::new(Status::Ok, "OK"));
+    Ok(())
+}
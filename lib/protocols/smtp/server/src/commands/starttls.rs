@@ -1,44 +1,123 @@
-use tokio::io::{AsyncWriteExt, BufReader};
+use log::warn;
 use tokio_rustls::TlsAcceptor;
 
-use crate::{Mail, SmtpStream, message_formatter};
+use crate::{
+    Session, check_sequence,
+    connection::Connection,
+    reply::{Reply, Status},
+    state::Event,
+};
 
 pub async fn handle(
-    mail: &mut Mail,
-    buffer: &mut BufReader<SmtpStream>,
+    session: &mut Session,
+    connection: &mut Connection,
     acceptor: &TlsAcceptor,
 ) -> anyhow::Result<()> {
-    if mail.has_tlsd {
-        buffer
-            .get_mut()
-            .write_all(&message_formatter("454 TLS Already Active"))
-            .await?;
+    if session.has_tlsd {
+        connection.reply(Reply::new(Status::BadSequence, "TLS already active"));
         return Ok(());
     }
+    let Some(next) = check_sequence(session, Event::StartTls, connection).await? else {
+        return Ok(());
+    };
 
-    buffer
-        .get_mut()
-        .write_all(&message_formatter("220 Ready to start TLS"))
-        .await?;
-    buffer.get_mut().flush().await?;
+    // RFC 3207 section 4.2 (and CVE-2011-0411), STARTTLS has to be the last command in a pipeline.
+    // Anything after it was sent in plaintext, so it's refused rather than being run once TLS is up
+    if connection.has_buffered_input() {
+        warn!("Client pipelined data after STARTTLS, refusing the upgrade");
+        connection.reply(Reply::new(
+            Status::BadSequence,
+            "STARTTLS must be the last command in a pipeline",
+        ));
+        return Ok(());
+    }
 
-    mail.has_tlsd = true;
+    connection.reply(Reply::new(Status::ServiceReady, "Ready to start TLS"));
+    connection.flush().await?;
 
-    let old_buffer: BufReader<SmtpStream> = unsafe { std::ptr::read(buffer as *const _) };
+    // Everything learnt before the handshake is thrown away, even if it fails
+    session.state = next;
+    session.reset_transaction();
+    connection.start_tls(acceptor).await?;
+    session.has_tlsd = true;
 
-    let inner_stream = old_buffer.into_inner();
-    let plain_stream = match inner_stream {
-        SmtpStream::Plain(s) => s,
-        SmtpStream::Tls(_) => unreachable!("Already checked has_tlsd"),
-    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::test_utils::*;
 
-    let tls_stream = acceptor.accept(plain_stream).await?;
-    let upgraded_stream = SmtpStream::Tls(tls_stream);
+    #[tokio::test]
+    async fn starttls_upgrades_the_session() {
+        let mut config = port_config();
+        config.auth_enabled = true;
+        let mut session = start(config, service_config()).await;
+        let client = &mut session.client;
+        let reply = client.command("EHLO client.example").await;
+        assert!(reply.contains("STARTTLS"));
+        assert!(!reply.contains("AUTH"));
+        assert!(client.command("STARTTLS").await.starts_with("220"));
 
-    // Write the new buffer back
-    unsafe {
-        std::ptr::write(buffer as *mut _, BufReader::new(upgraded_stream));
+        let mut client = session.client.start_tls().await;
+        let reply = client.command("EHLO client.example").await;
+        assert!(!reply.contains("STARTTLS"));
+        assert!(reply.contains("AUTH PLAIN"));
+        assert!(client.command("STARTTLS").await.starts_with("503"));
+        assert!(client.command("QUIT").await.starts_with("221"));
+        client.reader.get_mut().shutdown().await.unwrap();
+        session.handle.await.unwrap().unwrap();
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn starttls_requires_a_new_ehlo() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command("STARTTLS").await;
+
+        let mut client = session.client.start_tls().await;
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org>")
+                .await
+                .starts_with("503")
+        );
+    }
+
+    // CVE-2011-0411, a command smuggled in behind STARTTLS must not run as if it came over TLS
+    #[tokio::test]
+    async fn starttls_refuses_pipelined_commands() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client
+            .send("STARTTLS\r\nMAIL FROM:<injected@example.org>\r\n")
+            .await;
+        assert!(client.reply().await.starts_with("503 5.5.1"));
+
+        // The smuggled command is still answered, but in plaintext and with no upgrade having happened
+        assert!(client.reply().await.starts_with("250"));
+        assert!(client.command("RSET").await.starts_with("250"));
+        assert!(
+            client
+                .command("EHLO client.example")
+                .await
+                .contains("STARTTLS")
+        );
+    }
+
+    #[tokio::test]
+    async fn starttls_handshake_failure_ends_the_session() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(client.command("STARTTLS").await.starts_with("220"));
+        client.send("this is not a ClientHello\r\n").await;
+
+        assert!(session.handle.await.unwrap().is_err());
+        assert!(session.transactions.recv().await.is_none());
+    }
 }
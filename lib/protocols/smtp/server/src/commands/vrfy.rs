@@ -0,0 +1,101 @@
+use eemail_lib_shared::{SMTPPortConfiguration, VrfyPolicy};
+use log::debug;
+
+use crate::{
+    connection::Connection,
+    reply::{Reply, Status},
+};
+
+// Handles both VRFY and EXPN, aliases always expand to exactly one account so the answers are the same
+pub async fn handle(
+    config: &SMTPPortConfiguration,
+    service_config: &eemail_component_configurator::Configuration,
+    connection: &mut Connection,
+    cmd: Vec<String>,
+) -> anyhow::Result<()> {
+    let reply = match config.vrfy_policy {
+        VrfyPolicy::Disabled => Reply::new(Status::NotImplemented, "Command not implemented"),
+        VrfyPolicy::Ambiguous => Reply::new(
+            Status::CannotVerify,
+            "Cannot VRFY user, but will accept message and attempt delivery",
+        ),
+        VrfyPolicy::Enabled => match cmd.get(1) {
+            Some(address) => {
+                let address = address
+                    .strip_prefix("<")
+                    .and_then(|address| address.strip_suffix(">"))
+                    .unwrap_or(address)
+                    .to_string();
+                debug!("Looking up {} for VRFY", address);
+
+                match service_config.clone().get_user_from_alias(&address) {
+                    Some(account) => Reply::new(
+                        Status::RecipientOk,
+                        format!("<{}>", account.get_primary_address()),
+                    ),
+                    None => Reply::new(Status::MailboxUnavailable, "Mailbox unavailable"),
+                }
+            }
+            None => Reply::new(Status::SyntaxError, "Syntax error in parameters"),
+        },
+    };
+
+    connection.reply(reply);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_shared::VrfyPolicy;
+
+    use crate::test_utils::*;
+
+    async fn vrfy(policy: VrfyPolicy, command: &str) -> String {
+        let mut config = port_config();
+        config.vrfy_policy = policy;
+        let mut session = start(config, service_config()).await;
+        session.client.command(command).await
+    }
+
+    #[tokio::test]
+    async fn vrfy_disabled_is_not_implemented() {
+        assert!(
+            vrfy(VrfyPolicy::Disabled, "VRFY <test@example.com>")
+                .await
+                .starts_with("502")
+        );
+        assert!(
+            vrfy(VrfyPolicy::Disabled, "EXPN <test@example.com>")
+                .await
+                .starts_with("502")
+        );
+    }
+
+    #[tokio::test]
+    async fn vrfy_ambiguous_never_confirms() {
+        assert!(
+            vrfy(VrfyPolicy::Ambiguous, "VRFY <test@example.com>")
+                .await
+                .starts_with("252")
+        );
+        assert!(
+            vrfy(VrfyPolicy::Ambiguous, "VRFY <nobody@example.com>")
+                .await
+                .starts_with("252")
+        );
+    }
+
+    #[tokio::test]
+    async fn vrfy_enabled_resolves_aliases() {
+        assert_eq!(
+            vrfy(VrfyPolicy::Enabled, "VRFY <hi@example.com>").await,
+            "250 2.1.5 <example@example.com>\r\n"
+        );
+        assert!(
+            vrfy(VrfyPolicy::Enabled, "EXPN nobody@example.com")
+                .await
+                .starts_with("550")
+        );
+        assert!(vrfy(VrfyPolicy::Enabled, "VRFY").await.starts_with("501"));
+    }
+}
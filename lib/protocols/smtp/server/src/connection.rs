@@ -0,0 +1,196 @@
+use anyhow::bail;
+use log::{debug, warn};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    SmtpStream,
+    reply::{Reply, Status},
+};
+
+// Once this much output is queued it gets written regardless, so a long pipeline can't grow it forever
+const MAX_PENDING_OUTPUT: usize = 16 * 1024;
+
+// RFC 5321 4.5.3.2, how long a client gets to send the next command, and to send each block of message data.
+// A client that goes quiet any longer is told so and disconnected, rather than holding its slot forever
+pub(crate) const COMMAND_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+pub(crate) const DATA_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+// The client socket plus the replies that haven't been written yet, RFC 2920 lets us batch them up
+pub(crate) struct Connection {
+    reader: BufReader<SmtpStream>,
+    pending: Vec<u8>,
+}
+
+impl Connection {
+    pub fn new(stream: SmtpStream) -> Self {
+        Self {
+            reader: BufReader::new(stream),
+            pending: Vec::new(),
+        }
+    }
+
+    // Queues a reply, it goes out on the next flush
+    pub fn reply(&mut self, reply: Reply) {
+        self.pending.extend_from_slice(&reply.to_bytes());
+    }
+
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        debug!("Flushing {} bytes of replies", self.pending.len());
+        let stream = self.reader.get_mut();
+        stream.write_all(&self.pending).await?;
+        stream.flush().await?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    // Whether the client has already sent more than we've processed, i.e. it's pipelining
+    pub fn has_buffered_input(&self) -> bool {
+        !self.reader.buffer().is_empty()
+    }
+
+    // Flushes unless there are more pipelined commands waiting to be answered in the same batch
+    pub async fn flush_if_idle(&mut self) -> anyhow::Result<()> {
+        if !self.has_buffered_input() || self.pending.len() >= MAX_PENDING_OUTPUT {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    // Reads up to and including the next LF, giving up after `limit` bytes. 0 if the client went away or timed out
+    pub async fn read_chunk(
+        &mut self,
+        chunk: &mut Vec<u8>,
+        limit: u64,
+        wait: Duration,
+    ) -> anyhow::Result<usize> {
+        match timeout(
+            wait,
+            (&mut self.reader).take(limit).read_until(b'\n', chunk),
+        )
+        .await
+        {
+            Ok(read) => Ok(read?),
+            Err(_) => {
+                self.timed_out().await?;
+                Ok(0)
+            }
+        }
+    }
+
+    // Reads exactly `length` bytes onto the end of `buffer`, false if the client went away or timed out first
+    pub async fn read_exact_into(
+        &mut self,
+        buffer: &mut Vec<u8>,
+        length: usize,
+    ) -> anyhow::Result<bool> {
+        let mut remaining = length;
+        while remaining > 0 {
+            let available = self.fill(DATA_TIMEOUT).await?;
+            if available == 0 {
+                return Ok(false);
+            }
+            let taken = available.min(remaining);
+            buffer.extend_from_slice(&self.reader.buffer()[..taken]);
+            self.reader.consume(taken);
+            remaining -= taken;
+        }
+        Ok(true)
+    }
+
+    // Reads and drops `length` bytes without holding onto them
+    pub async fn discard(&mut self, length: usize) -> anyhow::Result<()> {
+        let mut remaining = length;
+        while remaining > 0 {
+            let available = self.fill(DATA_TIMEOUT).await?;
+            if available == 0 {
+                return Ok(());
+            }
+            let taken = available.min(remaining);
+            self.reader.consume(taken);
+            remaining -= taken;
+        }
+        Ok(())
+    }
+
+    // Throws away the rest of an overlong line, up to and including its LF
+    pub async fn discard_line(&mut self) -> anyhow::Result<()> {
+        loop {
+            if self.fill(COMMAND_TIMEOUT).await? == 0 {
+                return Ok(());
+            }
+            let buffer = self.reader.buffer();
+            match buffer.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    self.reader.consume(end + 1);
+                    return Ok(());
+                }
+                None => {
+                    let length = buffer.len();
+                    self.reader.consume(length);
+                }
+            }
+        }
+    }
+
+    // How much input is buffered once there's any at all, 0 if the client went away or timed out
+    async fn fill(&mut self, wait: Duration) -> anyhow::Result<usize> {
+        match timeout(wait, self.reader.fill_buf()).await {
+            Ok(buffer) => Ok(buffer?.len()),
+            Err(_) => {
+                self.timed_out().await?;
+                Ok(0)
+            }
+        }
+    }
+
+    async fn timed_out(&mut self) -> anyhow::Result<()> {
+        warn!("Client sent nothing for too long, closing the connection");
+        self.reply(Reply::new(
+            Status::ServiceUnavailable,
+            "Timeout waiting for client input",
+        ));
+        self.close().await
+    }
+
+    // Swaps the plaintext socket for a TLS one, if the handshake fails the connection is left closed
+    pub async fn start_tls(&mut self, acceptor: &TlsAcceptor) -> anyhow::Result<()> {
+        // Anything still buffered was sent in plaintext and must never be read as if it came over TLS
+        if self.has_buffered_input() {
+            bail!("Plaintext data buffered before the TLS handshake");
+        }
+
+        let reader = std::mem::replace(&mut self.reader, BufReader::new(SmtpStream::Closed));
+        let SmtpStream::Plain(stream) = reader.into_inner() else {
+            bail!("TLS is already active");
+        };
+
+        let Ok(stream) = timeout(COMMAND_TIMEOUT, acceptor.accept(stream)).await else {
+            bail!("TLS handshake timed out");
+        };
+        let stream = stream?;
+        self.reader = BufReader::new(SmtpStream::Tls(Box::new(stream)));
+        Ok(())
+    }
+
+    pub async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.flush().await?;
+        self.reader.get_mut().shutdown().await?;
+        Ok(())
+    }
+
+    // Sends what's pending and drops the socket, for when the client can't be kept in sync.
+    // Every read after this finds the connection closed, so the session ends
+    pub async fn close(&mut self) -> anyhow::Result<()> {
+        self.shutdown().await?;
+        self.reader = BufReader::new(SmtpStream::Closed);
+        Ok(())
+    }
+}
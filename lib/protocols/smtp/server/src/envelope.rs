@@ -0,0 +1,97 @@
+// Parsing for the MAIL FROM/RCPT TO arguments, including any ESMTP parameters after the path
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Path {
+    pub address: String,
+    pub parameters: Vec<(String, Option<String>)>,
+}
+
+// `args` is everything after the verb, `keyword` is "FROM:" or "TO:"
+pub fn parse(args: &[String], keyword: &str) -> Option<Path> {
+    let joined = args.join(" ");
+
+    // Carefully strip out the keyword, this caters to Thunderbirds specific behaviour so some leeway is allowed
+    let rest = match joined.get(..keyword.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(keyword) => &joined[keyword.len()..],
+        _ => joined.as_str(),
+    }
+    .trim_start();
+
+    let (address, rest) = if let Some(bracketed) = rest.strip_prefix("<") {
+        let end = bracketed.find('>')?;
+        (&bracketed[..end], &bracketed[end + 1..])
+    } else {
+        match rest.find(char::is_whitespace) {
+            Some(end) => (&rest[..end], &rest[end..]),
+            None => (rest, ""),
+        }
+    };
+
+    let parameters = rest
+        .split_whitespace()
+        .map(|parameter| match parameter.split_once('=') {
+            Some((key, value)) => (key.to_ascii_uppercase(), Some(value.to_string())),
+            None => (parameter.to_ascii_uppercase(), None),
+        })
+        .collect();
+
+    Some(Path {
+        address: address.to_string(),
+        parameters,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn envelope_parses_bracketed_path() {
+        let path = parse(&args("FROM:<me@example.com>"), "FROM:").unwrap();
+        assert_eq!(path.address, "me@example.com");
+        assert!(path.parameters.is_empty());
+    }
+
+    #[test]
+    fn envelope_parses_loose_paths() {
+        assert_eq!(
+            parse(&args("FROM: <me@example.com>"), "FROM:")
+                .unwrap()
+                .address,
+            "me@example.com"
+        );
+        assert_eq!(
+            parse(&args("to:me@example.com"), "TO:").unwrap().address,
+            "me@example.com"
+        );
+    }
+
+    #[test]
+    fn envelope_parses_null_path() {
+        assert_eq!(parse(&args("FROM:<>"), "FROM:").unwrap().address, "");
+    }
+
+    #[test]
+    fn envelope_parses_parameters() {
+        let path = parse(
+            &args("FROM:<me@example.com> size=1024 BODY=8BITMIME"),
+            "FROM:",
+        )
+        .unwrap();
+        assert_eq!(
+            path.parameters,
+            vec![
+                ("SIZE".to_string(), Some("1024".to_string())),
+                ("BODY".to_string(), Some("8BITMIME".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn envelope_rejects_unterminated_path() {
+        assert!(parse(&args("FROM:<me@example.com"), "FROM:").is_none());
+    }
+}
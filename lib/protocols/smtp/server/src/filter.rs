@@ -0,0 +1,595 @@
+// Checks on mail arriving at a filtering listener, recorded in an Authentication-Results header (RFC 8601)
+use log::{debug, error, warn};
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+
+use eemail_component_configurator::{Configuration, DmarcConfiguration, SpfConfiguration};
+use eemail_lib_dkim::{Chain, ChainStatus, Outcome as DkimOutcome, Verification};
+use eemail_lib_dmarc::{
+    DkimResult, Identifiers, Outcome as DmarcOutcome, Policy, Report, Row, SpfResult,
+};
+use eemail_lib_dns::Resolver;
+use eemail_lib_shared::FilterAction;
+use eemail_lib_spf::{Evaluation, Outcome, Query};
+
+use crate::{
+    Mail, Session,
+    headers::{header_values, mailbox_addresses, remove_headers},
+    reply::{Reply, Status},
+};
+
+// How much of b= goes in header.b, RFC 6008 says 8 is almost always enough to tell signatures apart
+const SIGNATURE_PREFIX: usize = 8;
+
+// What filtering listeners need from outside the session
+#[derive(Clone)]
+pub struct Filtering {
+    pub resolver: Arc<dyn Resolver>,
+    // Where DMARC results go to be counted in aggregate reports, None if nobody is sending them
+    pub reports: Option<mpsc::Sender<Report>>,
+}
+
+// What the checks found, for whatever decides what to do with the mail afterwards
+#[derive(Debug, Clone, Default)]
+pub struct Authentication {
+    // RFC 7208 2.3, None when the client greeted us with an address literal
+    pub spf_helo: Option<SpfCheck>,
+    // RFC 7208 2.4
+    pub spf_mail_from: Option<SpfCheck>,
+    // One per DKIM-Signature, top to bottom
+    pub dkim: Vec<Verification>,
+    // RFC 8617, the ARC chain the mail arrived with. Mail is only sealed on its way out again if this was checked
+    pub arc: Option<Chain>,
+    // RFC 7489, one per domain in the From: header
+    pub dmarc: Vec<DmarcCheck>,
+    // Results whose configured action was to tag the mail, e.g. "spf=softfail"
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpfCheck {
+    // The address that was checked, postmaster@<HELO name> for the HELO and null senders
+    pub identity: String,
+    pub evaluation: Evaluation,
+}
+
+#[derive(Debug, Clone)]
+pub struct DmarcCheck {
+    pub evaluation: eemail_lib_dmarc::Evaluation,
+    // What was actually done with the mail, after pct= and local policy
+    pub disposition: Policy,
+}
+
+impl SpfCheck {
+    async fn run(resolver: &dyn Resolver, query: Query) -> Self {
+        let evaluation = eemail_lib_spf::check_host(resolver, &query).await;
+        debug!(
+            "SPF for {} from {}: {:?}",
+            query.sender, query.ip, evaluation
+        );
+        Self {
+            identity: query.sender,
+            evaluation,
+        }
+    }
+
+    fn domain(&self) -> &str {
+        self.identity
+            .rsplit_once('@')
+            .map_or(self.identity.as_str(), |(_, domain)| domain)
+    }
+}
+
+// Checks the HELO and MAIL FROM identities with SPF, at MAIL so anything refused never gets to send its data
+// The reply is Some when the configured action for one of the results is to reject
+pub(crate) async fn check_sender(
+    session: &mut Session,
+    sender: &str,
+    resolver: &dyn Resolver,
+    service_config: &Configuration,
+) -> Option<Reply> {
+    let ip = session.remote_ip?;
+    let helo = session.helo.clone().unwrap_or_default();
+    let receiver = &service_config.fqdn;
+
+    if session.spf_helo.is_none() && !helo.starts_with('[') {
+        let query = Query::helo(ip, &helo, receiver);
+        session.spf_helo = Some(SpfCheck::run(resolver, query).await);
+    }
+    let mail_from = SpfCheck::run(resolver, Query::mail_from(ip, &helo, sender, receiver)).await;
+
+    let mut tags = Vec::new();
+    for check in session.spf_helo.iter().chain([&mail_from]) {
+        let outcome = check.evaluation.outcome;
+        match spf_action(service_config.spf.as_ref(), outcome) {
+            FilterAction::Accept => {}
+            FilterAction::Tag => tags.push(format!("spf={}", outcome.name())),
+            FilterAction::Reject => return Some(spf_reply(check)),
+        }
+    }
+
+    let authentication = &mut session.mail.authentication;
+    authentication.spf_helo = session.spf_helo.clone();
+    authentication.spf_mail_from = Some(mail_from);
+    authentication.tags = tags;
+    None
+}
+
+// Results left out of the config reject on fail, tag on softfail and accept anything else
+fn spf_action(config: Option<&SpfConfiguration>, outcome: Outcome) -> FilterAction {
+    let configured = config.and_then(|config| match outcome {
+        Outcome::None => config.none,
+        Outcome::Neutral => config.neutral,
+        Outcome::Pass => None,
+        Outcome::SoftFail => config.softfail,
+        Outcome::Fail => config.fail,
+        Outcome::TempError => config.temperror,
+        Outcome::PermError => config.permerror,
+    });
+    configured.unwrap_or(match outcome {
+        Outcome::Fail => FilterAction::Reject,
+        Outcome::SoftFail => FilterAction::Tag,
+        _ => FilterAction::Accept,
+    })
+}
+
+// RFC 7208 8.4, a fail gives the domain's own explanation if it has one
+fn spf_reply(check: &SpfCheck) -> Reply {
+    let domain = check.domain();
+    match check.evaluation.outcome {
+        Outcome::TempError => Reply::new(
+            Status::SpfTempError,
+            format!("Temporary error checking the SPF record of {}", domain),
+        ),
+        Outcome::PermError => Reply::new(
+            Status::SpfPermError,
+            format!("The SPF record of {} is invalid", domain),
+        ),
+        _ => Reply::new(
+            Status::SpfFailed,
+            check
+                .evaluation
+                .explanation
+                .clone()
+                .unwrap_or_else(|| format!("Not allowed to send mail for {} by SPF", domain)),
+        ),
+    }
+}
+
+// DKIM and DMARC, once the whole message is in. The reply is Some when DMARC and local policy say to reject it
+pub(crate) async fn check(
+    mail: &mut Mail,
+    filtering: &Filtering,
+    service_config: &Configuration,
+) -> Option<Reply> {
+    // The authserv-id, the name the results are reported under
+    let hostname = &service_config.fqdn;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    mail.authentication.dkim =
+        eemail_lib_dkim::verify(&*filtering.resolver, &mail.data, now.as_secs()).await;
+    for verification in &mail.authentication.dkim {
+        debug!(
+            "DKIM signature from {} on message {}: {:?}",
+            verification.domain, mail.id, verification.outcome
+        );
+    }
+    let chain =
+        eemail_lib_dkim::validate_chain(&*filtering.resolver, &mail.data, now.as_secs()).await;
+    debug!("ARC chain on message {}: {:?}", mail.id, chain);
+    mail.authentication.arc = Some(chain);
+
+    // Same as for SPF, mail from our own users and trusted relays isn't held to anyone's policy
+    let exempt = mail.authenticated.is_some()
+        || mail
+            .client_ip
+            .is_some_and(|ip| service_config.is_trusted_relay(ip));
+    if !exempt {
+        let reply = check_dmarc(mail, filtering, service_config, now.as_secs(), roll()).await;
+        if reply.is_some() {
+            return reply;
+        }
+    }
+
+    // RFC 8601 5, results already claiming to be ours can only be forged
+    let data = remove_headers(&mail.data, "Authentication-Results", |value| {
+        authserv_id(value).eq_ignore_ascii_case(hostname)
+    });
+    let mut header = String::new();
+    if !mail.authentication.tags.is_empty() {
+        debug!(
+            "Tagging message {} for {}",
+            mail.id,
+            mail.authentication.tags.join(", ")
+        );
+        header.push_str("X-Spam-Flag: YES\r\n");
+    }
+    header.push_str(&format!(
+        "Authentication-Results: {}\r\n",
+        mail.authentication.results(hostname)
+    ));
+    let mut header = header.into_bytes();
+    header.extend(data);
+    mail.data = header;
+    None
+}
+
+// RFC 7489 6.6, every domain in the From: header is checked and the strictest result wins.
+// `roll` is below 100 and picks whether pct= applies the policy
+async fn check_dmarc(
+    mail: &mut Mail,
+    filtering: &Filtering,
+    service_config: &Configuration,
+    now: u64,
+    roll: u8,
+) -> Option<Reply> {
+    let mut domains: Vec<String> = header_values(&mail.data, "From")
+        .iter()
+        .flat_map(|value| mailbox_addresses(value))
+        .filter_map(|address| {
+            address
+                .rsplit_once('@')
+                .map(|(_, domain)| domain.to_ascii_lowercase())
+        })
+        .collect();
+    domains.sort();
+    domains.dedup();
+
+    let authentication = &mail.authentication;
+    let spf = authentication
+        .spf_mail_from
+        .as_ref()
+        .filter(|check| check.evaluation.outcome == Outcome::Pass);
+    let passed = Identifiers {
+        spf: spf.map(SpfCheck::domain),
+        dkim: authentication
+            .dkim
+            .iter()
+            .filter(|verification| verification.outcome == DkimOutcome::Pass)
+            .map(|verification| verification.domain.as_str())
+            .collect(),
+    };
+
+    let mut checks = Vec::new();
+    let mut reply = None;
+    for domain in domains {
+        let evaluation = eemail_lib_dmarc::evaluate(&*filtering.resolver, &domain, &passed).await;
+        debug!(
+            "DMARC for {} on message {}: {:?}",
+            domain, mail.id, evaluation
+        );
+        let policy = evaluation.disposition(roll);
+        let action = dmarc_action(service_config.dmarc.as_ref(), policy);
+        let disposition = match action {
+            FilterAction::Accept => Policy::None,
+            FilterAction::Tag => Policy::Quarantine,
+            FilterAction::Reject => Policy::Reject,
+        };
+        if action == FilterAction::Reject && reply.is_none() {
+            reply = Some(Reply::new(
+                Status::PolicyRejected,
+                format!("Rejected by the DMARC policy of {}", evaluation.domain),
+            ));
+        }
+
+        if let (Some(reports), Some(source_ip)) = (&filtering.reports, mail.client_ip)
+            && let Some(mut report) = Report::new(&evaluation, now)
+        {
+            // RFC 7489 7.2.1, why the mail wasn't treated the way the domain asked
+            let reason = if disposition != policy {
+                Some("local_policy")
+            } else if evaluation.outcome == DmarcOutcome::Fail && policy != evaluation.policy() {
+                Some("sampled_out")
+            } else {
+                None
+            };
+            report.add(Row {
+                source_ip,
+                count: 1,
+                disposition,
+                dkim_aligned: evaluation.dkim_aligned,
+                spf_aligned: evaluation.spf_aligned,
+                reason: reason.map(str::to_string),
+                header_from: evaluation.domain.clone(),
+                envelope_from: authentication
+                    .spf_mail_from
+                    .as_ref()
+                    .map(|check| check.domain().to_string()),
+                dkim: authentication
+                    .dkim
+                    .iter()
+                    .filter(|verification| !verification.domain.is_empty())
+                    .map(|verification| DkimResult {
+                        domain: verification.domain.clone(),
+                        selector: verification.selector.clone(),
+                        result: verification.outcome.name().to_string(),
+                    })
+                    .collect(),
+                spf: authentication
+                    .spf_mail_from
+                    .iter()
+                    .map(|check| SpfResult {
+                        domain: check.domain().to_string(),
+                        // A null sender was checked as postmaster@<HELO name>
+                        scope: if mail.from.is_empty() {
+                            "helo"
+                        } else {
+                            "mfrom"
+                        }
+                        .to_string(),
+                        result: check.evaluation.outcome.name().to_string(),
+                    })
+                    .collect(),
+            });
+            if reports.try_send(report).is_err() {
+                warn!(
+                    "DMARC reports are backed up, message {} won't be counted for {}",
+                    mail.id, domain
+                );
+            }
+        }
+        checks.push(DmarcCheck {
+            evaluation,
+            disposition,
+        });
+    }
+
+    if checks
+        .iter()
+        .any(|check| check.disposition == Policy::Quarantine)
+    {
+        mail.authentication.tags.push("dmarc=fail".to_string());
+    }
+    mail.authentication.dmarc = checks;
+    reply
+}
+
+// RFC 7489 6.6.4, the 0-99 a domain's pct= is checked against
+fn roll() -> u8 {
+    match getrandom::u32() {
+        Ok(random) => (random % 100) as u8,
+        Err(e) => {
+            // Failing open, as if sampled out, is safer than holding everyone's mail to a policy they only partly asked for
+            error!("The OS random number generator failed: {}", e);
+            99
+        }
+    }
+}
+
+// Domains asking to reject get rejected and those asking to quarantine get tagged, unless configured otherwise
+fn dmarc_action(config: Option<&DmarcConfiguration>, policy: Policy) -> FilterAction {
+    match policy {
+        Policy::None => FilterAction::Accept,
+        Policy::Quarantine => config
+            .and_then(|config| config.quarantine)
+            .unwrap_or(FilterAction::Tag),
+        Policy::Reject => config
+            .and_then(|config| config.reject)
+            .unwrap_or(FilterAction::Reject),
+    }
+}
+
+// The first thing in the value, before any version or the first result
+fn authserv_id(value: &str) -> &str {
+    value
+        .split(';')
+        .next()
+        .and_then(|id| id.split_whitespace().next())
+        .unwrap_or_default()
+}
+
+impl Authentication {
+    // The Authentication-Results value, `hostname` is the authserv-id. ARC-Authentication-Results repeats it when we seal
+    pub fn results(&self, hostname: &str) -> String {
+        let mut results = Vec::new();
+        // RFC 8601 2.7.2, the HELO identity is reported as just the name
+        if let Some(check) = &self.spf_helo {
+            results.push(spf_result(check, "smtp.helo", check.domain()));
+        }
+        if let Some(check) = &self.spf_mail_from {
+            results.push(spf_result(check, "smtp.mailfrom", &check.identity));
+        }
+        let dkim = results.len();
+        for verification in &self.dkim {
+            let mut result = format!("dkim={}", verification.outcome.name());
+            if let Some(reason) = verification.outcome.reason() {
+                result.push_str(&format!(" reason={}", quote(reason)));
+            }
+            if !verification.domain.is_empty() {
+                result.push_str(&format!(" header.d={}", verification.domain));
+            }
+            if let Some(identity) = &verification.identity {
+                result.push_str(&format!(" header.i={}", quote(identity)));
+            }
+            if !verification.selector.is_empty() {
+                result.push_str(&format!(" header.s={}", quote(&verification.selector)));
+            }
+            if !verification.signature.is_empty() {
+                let prefix: String = verification
+                    .signature
+                    .chars()
+                    .take(SIGNATURE_PREFIX)
+                    .collect();
+                result.push_str(&format!(" header.b={}", quote(&prefix)));
+            }
+            results.push(result);
+        }
+        if results.len() == dkim {
+            results.push("dkim=none".to_string());
+        }
+        if let Some(chain) = &self.arc {
+            let mut result = format!("arc={}", chain.status.name());
+            if let Some(reason) = &chain.reason {
+                result.push_str(&format!(" reason={}", quote(reason)));
+            }
+            if let (ChainStatus::Pass, Some(sealer)) = (chain.status, &chain.sealer) {
+                result.push_str(&format!(" (i={} d={})", chain.instance, sealer));
+            }
+            results.push(result);
+        }
+        for check in &self.dmarc {
+            let evaluation = &check.evaluation;
+            let mut result = format!("dmarc={}", evaluation.outcome.name());
+            if let Some(reason) = &evaluation.reason {
+                result.push_str(&format!(" reason={}", quote(reason)));
+            }
+            // The policy and what we did about it go in a comment, as plenty of other receivers do
+            if evaluation.record.is_some() {
+                result.push_str(&format!(
+                    " (p={} dis={})",
+                    evaluation.policy().name(),
+                    check.disposition.name()
+                ));
+            }
+            result.push_str(&format!(" header.from={}", evaluation.domain));
+            results.push(result);
+        }
+
+        format!("{};\r\n\t{}", hostname, results.join(";\r\n\t"))
+    }
+}
+
+fn spf_result(check: &SpfCheck, property: &str, value: &str) -> String {
+    let mut result = format!("spf={}", check.evaluation.outcome.name());
+    if let Some(reason) = &check.evaluation.reason {
+        result.push_str(&format!(" reason={}", quote(reason)));
+    }
+    result.push_str(&format!(" {}={}", property, quote(value)));
+    result
+}
+
+// Values are MIME tokens (RFC 2045 5.1), anything with specials in it has to be a quoted string
+fn quote(value: &str) -> String {
+    let token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_dkim::Outcome;
+    use eemail_lib_dns::StaticResolver;
+    use eemail_lib_spf::Outcome as SpfOutcome;
+
+    use super::*;
+    use crate::test_utils::{filtering, service_config};
+
+    fn verification(outcome: Outcome) -> Verification {
+        Verification {
+            domain: "example.org".to_string(),
+            selector: "2024".to_string(),
+            identity: Some("@example.org".to_string()),
+            signature: "ab/cdEFghIJ+kl==".to_string(),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn results_header_lists_every_result() {
+        let authentication = Authentication {
+            spf_helo: Some(SpfCheck {
+                identity: "postmaster@mx.example.org".to_string(),
+                evaluation: Evaluation {
+                    outcome: SpfOutcome::PermError,
+                    reason: Some("more than 10 DNS lookups".to_string()),
+                    explanation: None,
+                },
+            }),
+            dkim: vec![
+                verification(Outcome::Pass),
+                verification(Outcome::Fail("body hash did not verify".to_string())),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            authentication.results("mail.example.com"),
+            "mail.example.com;\r\n\
+             \tspf=permerror reason=\"more than 10 DNS lookups\" smtp.helo=mx.example.org;\r\n\
+             \tdkim=pass header.d=example.org header.i=\"@example.org\" header.s=2024 header.b=\"ab/cdEFg\";\r\n\
+             \tdkim=fail reason=\"body hash did not verify\" header.d=example.org header.i=\"@example.org\" header.s=2024 header.b=\"ab/cdEFg\""
+        );
+        assert_eq!(
+            Authentication::default().results("mail.example.com"),
+            "mail.example.com;\r\n\tdkim=none"
+        );
+        let authentication = Authentication {
+            arc: Some(Chain {
+                status: ChainStatus::Pass,
+                instance: 2,
+                sealer: Some("example.net".to_string()),
+                reason: None,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            authentication.results("mail.example.com"),
+            "mail.example.com;\r\n\tdkim=none;\r\n\tarc=pass (i=2 d=example.net)"
+        );
+    }
+
+    #[tokio::test]
+    async fn forged_results_are_replaced() {
+        let mut mail = Mail {
+            data: b"Authentication-Results: MAIL.example.com; dkim=pass\r\n\
+                    Authentication-Results: mx.example.org 1; dkim=pass\r\n\
+                    From: a@example.org\r\n\r\nHi\r\n"
+                .to_vec(),
+            ..Default::default()
+        };
+        let reply = check(
+            &mut mail,
+            &filtering(StaticResolver::default()),
+            &service_config(),
+        )
+        .await;
+        assert!(reply.is_none());
+        assert_eq!(
+            String::from_utf8(mail.data).unwrap(),
+            "Authentication-Results: mail.example.com;\r\n\tdkim=none;\r\n\tarc=none;\r\n\tdmarc=none header.from=example.org\r\n\
+             Authentication-Results: mx.example.org 1; dkim=pass\r\n\
+             From: a@example.org\r\n\r\nHi\r\n"
+        );
+        assert!(mail.authentication.dkim.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dmarc_percentage_is_reported() {
+        let resolver = StaticResolver::default().txt(
+            "_dmarc.example.org",
+            "v=DMARC1; p=reject; pct=50; rua=mailto:dmarc@example.org",
+        );
+        let (sender, mut reports) = mpsc::channel(4);
+        let filtering = Filtering {
+            resolver: Arc::new(resolver),
+            reports: Some(sender),
+        };
+        let mail = || Mail {
+            client_ip: Some("192.0.2.1".parse().unwrap()),
+            data: b"From: a@example.org\r\n\r\nHi\r\n".to_vec(),
+            ..Default::default()
+        };
+
+        let mut sampled = mail();
+        let reply = check_dmarc(&mut sampled, &filtering, &service_config(), 1000, 60).await;
+        assert!(reply.is_none());
+        assert_eq!(sampled.authentication.tags, ["dmarc=fail"]);
+        let row = &reports.recv().await.unwrap().rows[0];
+        assert_eq!(row.disposition, Policy::Quarantine);
+        assert_eq!(row.reason.as_deref(), Some("sampled_out"));
+
+        let mut applied = mail();
+        let reply = check_dmarc(&mut applied, &filtering, &service_config(), 1000, 10).await;
+        assert!(reply.is_some());
+        assert_eq!(reports.recv().await.unwrap().rows[0].reason, None);
+    }
+}
@@ -0,0 +1,197 @@
+// Just enough RFC 5322 header parsing for the checks made on incoming mail
+
+// Every value of the header `name`, unfolded, in the order they appear
+pub fn header_values(data: &[u8], name: &str) -> Vec<String> {
+    let end = header_end(data);
+
+    let mut values: Vec<String> = Vec::new();
+    let mut in_header = false;
+    for line in data[..end].split(|&b| b == b'\n') {
+        let line = String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line));
+
+        if line.starts_with([' ', '\t']) {
+            // A folded continuation of whatever header came before
+            if in_header && let Some(value) = values.last_mut() {
+                value.push_str(&line);
+            }
+            continue;
+        }
+
+        in_header = false;
+        if let Some((field, value)) = line.split_once(':')
+            && field.trim_end().eq_ignore_ascii_case(name)
+        {
+            in_header = true;
+            values.push(value.to_string());
+        }
+    }
+
+    values
+        .iter()
+        .map(|value| value.trim().to_string())
+        .collect()
+}
+
+// `data` without the `name` headers whose unfolded value `remove` picks out, everything else is kept byte for byte
+pub fn remove_headers(data: &[u8], name: &str, remove: impl Fn(&str) -> bool) -> Vec<u8> {
+    let end = header_end(data);
+    let mut kept = Vec::with_capacity(data.len());
+
+    // Each field with its folded lines, as it appears in `data`
+    let mut fields: Vec<&[u8]> = Vec::new();
+    let mut start = 0;
+    let mut position = 0;
+    for line in data[..end].split_inclusive(|&b| b == b'\n') {
+        if position > 0 && !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            fields.push(&data[start..position]);
+            start = position;
+        }
+        position += line.len();
+    }
+    if start < end {
+        fields.push(&data[start..end]);
+    }
+
+    for field in fields {
+        let unfolded: Vec<u8> = field
+            .iter()
+            .copied()
+            .filter(|&b| b != b'\r' && b != b'\n')
+            .collect();
+        let unfolded = String::from_utf8_lossy(&unfolded);
+        let matches = unfolded.split_once(':').is_some_and(|(field, value)| {
+            field.trim_end().eq_ignore_ascii_case(name) && remove(value.trim())
+        });
+        if !matches {
+            kept.extend_from_slice(field);
+        }
+    }
+    kept.extend_from_slice(&data[end..]);
+    kept
+}
+
+// Where the header section stops, just after the CRLF of its last line
+fn header_end(data: &[u8]) -> usize {
+    data.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 2)
+        .unwrap_or(data.len())
+}
+
+// The addresses in a mailbox list, e.g. `"Doe, Jane" <jane@example.com>, bob@example.com (Bob)`
+pub fn mailbox_addresses(value: &str) -> Vec<String> {
+    let mut addresses = Vec::new();
+    let mut outside = String::new();
+    let mut angle: Option<String> = None;
+    let mut in_angle = false;
+    let mut quoted = false;
+    let mut comment_depth = 0usize;
+    let mut escaped = false;
+
+    let mut finish = |outside: &mut String, angle: &mut Option<String>| {
+        let address = angle.take().unwrap_or_else(|| outside.clone());
+        let address = address.trim();
+        if !address.is_empty() {
+            addresses.push(address.to_string());
+        }
+        outside.clear();
+    };
+
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else {
+            match c {
+                '\\' if quoted || comment_depth > 0 => {
+                    escaped = true;
+                    continue;
+                }
+                '(' if !quoted => {
+                    comment_depth += 1;
+                    continue;
+                }
+                ')' if !quoted && comment_depth > 0 => {
+                    comment_depth -= 1;
+                    continue;
+                }
+                _ if comment_depth > 0 => continue,
+                '"' => quoted = !quoted,
+                '<' if !quoted => {
+                    in_angle = true;
+                    angle = Some(String::new());
+                    continue;
+                }
+                '>' if !quoted && in_angle => {
+                    in_angle = false;
+                    continue;
+                }
+                ',' if !quoted && !in_angle => {
+                    finish(&mut outside, &mut angle);
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        if comment_depth > 0 {
+            continue;
+        }
+        match (&mut angle, in_angle) {
+            (Some(angle), true) => angle.push(c),
+            _ => outside.push(c),
+        }
+    }
+    finish(&mut outside, &mut angle);
+
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_are_unfolded_and_case_insensitive() {
+        let data = b"Subject: one\r\nfrom: Jane\r\n <jane@example.com>\r\nTo: bob@example.com\r\n\r\nFrom: body@example.com\r\n";
+        assert_eq!(header_values(data, "From"), ["Jane <jane@example.com>"]);
+        assert_eq!(header_values(data, "subject"), ["one"]);
+        assert!(header_values(data, "Cc").is_empty());
+    }
+
+    #[test]
+    fn headers_can_repeat() {
+        let data = b"Received: a\r\nReceived: b\r\n\tc\r\n\r\n";
+        assert_eq!(header_values(data, "Received"), ["a", "b\tc"]);
+    }
+
+    #[test]
+    fn remove_headers_only_drops_what_it_is_asked_to() {
+        let data = b"Authentication-Results: mail.example.com;\r\n dkim=pass\r\nSubject: hi\r\n\
+            Authentication-Results: other.example; spf=pass\r\n\r\n\
+            Authentication-Results: mail.example.com; body\r\n";
+        let kept = remove_headers(data, "authentication-results", |value| {
+            value.starts_with("mail.example.com;")
+        });
+        assert_eq!(
+            kept,
+            b"Subject: hi\r\nAuthentication-Results: other.example; spf=pass\r\n\r\n\
+              Authentication-Results: mail.example.com; body\r\n"
+        );
+        assert_eq!(remove_headers(data, "Received", |_| true), data);
+    }
+
+    #[test]
+    fn mailbox_addresses_handles_names_and_comments() {
+        assert_eq!(
+            mailbox_addresses(
+                r#""Doe, Jane" <jane@example.com>, bob@example.com (Bob, really), <c@example.com>"#
+            ),
+            ["jane@example.com", "bob@example.com", "c@example.com"]
+        );
+        assert_eq!(
+            mailbox_addresses(r#""a \" <b@example.com>" <real@example.com>"#),
+            ["real@example.com"]
+        );
+        assert!(mailbox_addresses("").is_empty());
+    }
+}
@@ -1,20 +1,50 @@
 use log::{debug, error, warn};
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+    io::{AsyncRead, AsyncWrite, ReadBuf},
     net::TcpStream,
+    sync::mpsc,
+    time::timeout,
 };
 use tokio_rustls::TlsAcceptor;
-use uuid::Uuid;
 
-use eemail_lib_shared::SMTPPortConfiguration;
+use eemail_component_configurator::Account;
+pub use eemail_lib_shared::BodyType;
+use eemail_lib_shared::{Dsn, SMTPPortConfiguration};
+
+pub use crate::filter::{Authentication, DmarcCheck, Filtering, SpfCheck};
+
+use crate::{
+    connection::{COMMAND_TIMEOUT, Connection},
+    reply::{Reply, Status},
+    state::{Event, SessionState},
+};
+
+// RFC 2920 section 3.1, plus AUTH and STARTTLS which also wait on the server
+const SYNC_COMMANDS: [&str; 9] = [
+    "EHLO", "HELO", "DATA", "VRFY", "EXPN", "NOOP", "QUIT", "AUTH", "STARTTLS",
+];
+
+// RFC 5321 allows 512, but AUTH initial responses can be much longer (RFC 4954 suggests 12288)
+const MAX_COMMAND_LINE: u64 = 16 * 1024;
 
 mod commands;
+mod connection;
+mod envelope;
+mod filter;
+pub mod headers;
+pub mod reply;
+mod state;
+#[cfg(test)]
+mod test_utils;
 
 enum SmtpStream {
     Plain(TcpStream),
-    Tls(tokio_rustls::server::TlsStream<TcpStream>),
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+    // Left behind while the socket is being upgraded, and for good if the handshake fails
+    Closed,
 }
 
 impl AsyncRead for SmtpStream {
@@ -26,6 +56,7 @@ impl AsyncRead for SmtpStream {
         match &mut *self {
             SmtpStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
             SmtpStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            SmtpStream::Closed => Poll::Ready(Ok(())),
         }
     }
 }
@@ -39,6 +70,7 @@ impl AsyncWrite for SmtpStream {
         match &mut *self {
             SmtpStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
             SmtpStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            SmtpStream::Closed => Poll::Ready(Err(std::io::ErrorKind::NotConnected.into())),
         }
     }
 
@@ -46,6 +78,7 @@ impl AsyncWrite for SmtpStream {
         match &mut *self {
             SmtpStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
             SmtpStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            SmtpStream::Closed => Poll::Ready(Ok(())),
         }
     }
 
@@ -53,6 +86,7 @@ impl AsyncWrite for SmtpStream {
         match &mut *self {
             SmtpStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
             SmtpStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            SmtpStream::Closed => Poll::Ready(Ok(())),
         }
     }
 }
@@ -62,101 +96,585 @@ pub struct Mail {
     pub id: String,
     pub from: String,
     pub to: Vec<String>,
-    pub data: String,
+    pub body: BodyType,
+    // Set by the SMTPUTF8 parameter (RFC 6531), relaying has to go to a server that offers it too
+    pub smtputf8: bool,
+    // The account the session had logged in as when MAIL was sent, None for unauthenticated mail
+    pub authenticated: Option<Account>,
+    // RFC 3461, the RET/ENVID from MAIL and NOTIFY/ORCPT from each RCPT
+    pub dsn: Dsn,
+    // What the checks on a filtering listener found, left empty anywhere else
+    pub authentication: Authentication,
+    // Where the client sending it connected from
+    pub client_ip: Option<IpAddr>,
+    // The raw message, exactly as received (minus the SMTP transparency dots)
+    pub data: Vec<u8>,
+}
+
+// Everything that lives for the whole connection, the mail transaction is reset after every message
+#[derive(Default)]
+struct Session {
+    mail: Mail,
+    state: SessionState,
+
+    // Set by a successful AUTH, and kept until the connection closes
+    authenticated: Option<Account>,
+
+    // Where the client is connecting from, used to allow relaying from trusted networks
+    remote_ip: Option<IpAddr>,
+
+    // The name given in the last HELO/EHLO, and what SPF made of it on a filtering listener (only checked once)
+    helo: Option<String>,
+    spf_helo: Option<SpfCheck>,
 
     // Boolean checks
-    sending_data: bool,
-    in_mail: bool,
-    has_authed: bool,
     has_tlsd: bool,
 }
 
+impl Session {
+    // Drops the current envelope, as done by RSET, HELO/EHLO and after a message is accepted
+    fn reset_transaction(&mut self) {
+        self.mail = Mail::default();
+    }
+
+    // A new greeting may name a different host, so its SPF result has to be worked out again
+    fn set_helo(&mut self, name: Option<&String>) {
+        self.helo = name.cloned();
+        self.spf_helo = None;
+    }
+}
+
+// Checks a command against the session state, if it's out of sequence the reply is sent here and None is returned
+async fn check_sequence(
+    session: &Session,
+    event: Event,
+    connection: &mut Connection,
+) -> anyhow::Result<Option<SessionState>> {
+    match session.state.transition(event) {
+        Ok(next) => Ok(Some(next)),
+        Err(reply) => {
+            debug!(
+                "Rejected {:?} while in state {:?}: {:?}",
+                event, session.state, reply
+            );
+            connection.reply(reply);
+            Ok(None)
+        }
+    }
+}
+
+// Runs a session until the client goes away, every completed transaction is sent down `transactions` as soon as its DATA finishes
+// `filtering` is only used on filtering listeners
 pub async fn handle_smtp(
     stream: TcpStream,
     config: SMTPPortConfiguration,
     acceptor: TlsAcceptor,
     service_config: eemail_component_configurator::Configuration,
-) -> anyhow::Result<Mail> {
-    let mut stream = SmtpStream::Plain(stream);
-    let mut line = String::new();
+    transactions: mpsc::Sender<Mail>,
+    filtering: Filtering,
+) -> anyhow::Result<()> {
+    let mut session = Session {
+        remote_ip: stream.peer_addr().ok().map(|addr| addr.ip()),
+        ..Default::default()
+    };
 
-    stream
-        .write_all(&message_formatter("220 Server Ready"))
-        .await?;
-    debug!("Sent Ready");
+    // RFC 8314, on an implicit TLS port the handshake comes before any SMTP at all
+    let stream = if config.implicit_tls {
+        session.has_tlsd = true;
+        let Ok(stream) = timeout(COMMAND_TIMEOUT, acceptor.accept(stream)).await else {
+            anyhow::bail!("TLS handshake timed out");
+        };
+        SmtpStream::Tls(Box::new(stream?))
+    } else {
+        SmtpStream::Plain(stream)
+    };
 
-    let mut mail = Mail::default();
-    let mut reader = BufReader::new(stream);
+    let mut connection = Connection::new(stream);
+    let mut line = Vec::new();
+
+    connection.reply(Reply::new(Status::ServiceReady, "Server Ready"));
+    connection.flush().await?;
+    debug!("Sent Ready");
 
     loop {
+        // RFC 2920, replies are batched up until we've caught up with everything the client has pipelined
+        connection.flush_if_idle().await?;
+
         line.clear();
-        let bytes = reader.read_line(&mut line).await?;
+        // Never buffer more than a single line can legitimately need, so a client can't just stream bytes at us
+        let bytes = connection
+            .read_chunk(&mut line, MAX_COMMAND_LINE, COMMAND_TIMEOUT)
+            .await?;
         if bytes == 0 {
             break;
         }
 
-        if !mail.sending_data {
-            let cmd: Vec<String> = line
-                .trim_end()
-                .split_whitespace()
-                .map(str::to_string)
-                .collect();
-            if let Some(first) = cmd.get(0) {
-                if let Some(second) = cmd.get(1) {
-                    debug!("Received Command: {} with data: {}", first, second);
-                } else {
-                    debug!("Received Command: {}", first)
+        if !line.ends_with(b"\n") && bytes as u64 == MAX_COMMAND_LINE {
+            warn!(
+                "Command line over {} bytes, discarding it",
+                MAX_COMMAND_LINE
+            );
+            connection.discard_line().await?;
+            connection.reply(Reply::new(Status::LineTooLong, "Line too long"));
+            continue;
+        }
+
+        // Commands are always text, only message data can be arbitrary bytes
+        let Ok(line) = std::str::from_utf8(&line) else {
+            warn!("Command line isn't valid UTF-8");
+            connection.reply(Reply::new(
+                Status::CommandUnrecognised,
+                "Invalid characters in command",
+            ));
+            continue;
+        };
+
+        let cmd: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+        if let Some(first) = cmd.first() {
+            if let Some(second) = cmd.get(1) {
+                debug!("Received Command: {} with data: {}", first, second);
+            } else {
+                debug!("Received Command: {}", first)
+            }
+            let verb = first.to_ascii_uppercase();
+            match verb.as_str() {
+                "EHLO" => {
+                    commands::ehlo::handle(&mut session, &config, &mut connection, cmd).await?
                 }
-                match first.as_str() {
-                    "EHLO" => commands::ehlo::handle(&mail, &config, &mut reader).await?,
-                    "AUTH" => {
-                        commands::auth::handle(&mut mail, &service_config, &mut reader, cmd).await?
-                    }
-                    "MAIL" => commands::mail::handle(&mut mail, &mut reader, cmd).await?,
-                    "RCPT" => commands::rcpt::handle(&mut mail, &mut reader, cmd).await?,
-                    "DATA" => commands::data::handle(&mut mail, &mut reader).await?,
-                    "QUIT" => commands::quit::handle(&mut reader).await?,
-                    "STARTTLS" => {
-                        commands::starttls::handle(&mut mail, &mut reader, &acceptor).await?
-                    }
-                    _ => {
-                        warn!("Unrecognised Command {}", first);
-                        reader
-                            .get_mut()
-                            .write_all(&message_formatter("502 Command not implemented"))
-                            .await?;
-                    }
+                "HELO" => commands::helo::handle(&mut session, &mut connection, cmd).await?,
+                "AUTH" => {
+                    commands::auth::handle(
+                        &mut session,
+                        &config,
+                        &service_config,
+                        &mut connection,
+                        cmd,
+                    )
+                    .await?
+                }
+                "MAIL" => {
+                    commands::mail::handle(
+                        &mut session,
+                        &config,
+                        &service_config,
+                        &mut connection,
+                        &filtering,
+                        cmd,
+                    )
+                    .await?
+                }
+                "RCPT" => {
+                    commands::rcpt::handle(&mut session, &service_config, &mut connection, cmd)
+                        .await?
+                }
+                "DATA" => {
+                    commands::data::handle(
+                        &mut session,
+                        &config,
+                        &service_config,
+                        &mut connection,
+                        &transactions,
+                        &filtering,
+                    )
+                    .await?
+                }
+                "BDAT" => {
+                    commands::bdat::handle(
+                        &mut session,
+                        &config,
+                        &service_config,
+                        &mut connection,
+                        &transactions,
+                        &filtering,
+                        cmd,
+                    )
+                    .await?
+                }
+                "RSET" => commands::rset::handle(&mut session, &mut connection).await?,
+                "NOOP" => commands::noop::handle(&mut connection).await?,
+                "VRFY" | "EXPN" => {
+                    commands::vrfy::handle(&config, &service_config, &mut connection, cmd).await?
+                }
+                "QUIT" => commands::quit::handle(&mut connection).await?,
+                "STARTTLS" => {
+                    commands::starttls::handle(&mut session, &mut connection, &acceptor).await?
+                }
+                _ => {
+                    warn!("Unrecognised Command {}", first);
+                    connection.reply(Reply::new(
+                        Status::NotImplemented,
+                        "Command not implemented",
+                    ));
                 }
-            } else {
-                error!("Failed to split command (string: {:?}", cmd);
-                continue;
             }
-        } else {
-            // We're receiving DATA - check if it's the end marker
-            if line.trim_end() == "." {
-                mail.sending_data = false;
-                debug!("Finished Receving Data from connection");
-                reader
-                    .get_mut()
-                    .write_all(&message_formatter("250 Message accepted"))
-                    .await?;
-            } else {
-                mail.data.push_str(line.replace("\r\n", "\n").as_str());
+
+            // These commands end a pipelined group, the client waits on their reply before sending anything else
+            if SYNC_COMMANDS.contains(&verb.as_str()) {
+                connection.flush().await?;
             }
+        } else {
+            error!("Failed to split command (string: {:?}", cmd);
+            continue;
         }
     }
-    debug!("FROM: {}", mail.from);
-    debug!("TO: {:#?}", mail.to);
-    debug!("DATA: {}", mail.data);
-
-    mail.id = Uuid::now_v7().as_urn().to_string().replace("urn:uuid:", ""); // maybe I should explore v5 uuid's using the message body as the data, not sure
-    debug!("Given message ID: {}", mail.id);
 
-    Ok(mail)
+    Ok(())
 }
 
-fn message_formatter(string: &'static str) -> Vec<u8> {
-    let formatted = format!("{}\r\n", string);
-    debug!("Sending Message {formatted}");
-    formatted.into_bytes()
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, BufReader},
+        time::Instant,
+    };
+
+    use crate::{
+        connection::{COMMAND_TIMEOUT, DATA_TIMEOUT},
+        test_utils::*,
+    };
+
+    async fn send_message(session: &mut TestSession, from: &str, to: &str, body: &str) {
+        let client = &mut session.client;
+        assert!(
+            client
+                .command(&format!("MAIL FROM:<{}>", from))
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            client
+                .command(&format!("RCPT TO:<{}>", to))
+                .await
+                .starts_with("250")
+        );
+        assert!(client.command("DATA").await.starts_with("354"));
+        client.send(body).await;
+        assert!(client.command(".").await.starts_with("250"));
+    }
+
+    #[tokio::test]
+    async fn session_yields_one_mail_per_transaction() {
+        let mut session = start(port_config(), service_config()).await;
+        session.client.command("EHLO client.example").await;
+
+        send_message(
+            &mut session,
+            "a@example.org",
+            "example@example.com",
+            "First\r\n",
+        )
+        .await;
+        send_message(
+            &mut session,
+            "b@example.org",
+            "test@example.com",
+            "Second\r\n",
+        )
+        .await;
+
+        let first = session.transactions.recv().await.unwrap();
+        let second = session.transactions.recv().await.unwrap();
+        assert_eq!(first.from, "a@example.org");
+        assert_eq!(first.to, ["example@example.com"]);
+        assert_eq!(first.data, b"First\r\n");
+        assert_eq!(second.from, "b@example.org");
+        assert_eq!(second.to, ["test@example.com"]);
+        assert_eq!(second.data, b"Second\r\n");
+        assert_ne!(first.id, second.id);
+    }
+
+    #[tokio::test]
+    async fn rset_discards_envelope() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command("MAIL FROM:<a@example.org>").await;
+        client.command("RCPT TO:<test@example.com>").await;
+        assert!(client.command("RSET").await.starts_with("250"));
+
+        send_message(
+            &mut session,
+            "b@example.org",
+            "example@example.com",
+            "Hi\r\n",
+        )
+        .await;
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.from, "b@example.org");
+        assert_eq!(mail.to, ["example@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn noop_and_helo_are_answered() {
+        let mut session = start(port_config(), service_config()).await;
+        assert!(
+            session
+                .client
+                .command("HELO client.example")
+                .await
+                .starts_with("250 ")
+        );
+        assert!(session.client.command("NOOP").await.starts_with("250"));
+        assert!(session.client.command("noop").await.starts_with("250"));
+    }
+
+    #[tokio::test]
+    async fn session_rejects_mail_before_ehlo() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org>")
+                .await
+                .starts_with("503")
+        );
+        assert!(
+            client
+                .command("RCPT TO:<test@example.com>")
+                .await
+                .starts_with("503")
+        );
+    }
+
+    #[tokio::test]
+    async fn session_does_not_enter_data_without_envelope() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(client.command("DATA").await.starts_with("503"));
+
+        client.command("MAIL FROM:<a@example.org>").await;
+        assert!(client.command("DATA").await.starts_with("554"));
+
+        // The body lines must still be treated as commands, and the dot must not finish a message
+        assert!(client.command("Subject: hi").await.starts_with("502"));
+        assert!(client.command(".").await.starts_with("502"));
+        assert!(client.command("QUIT").await.starts_with("221"));
+        drop(session.client);
+        session.handle.await.unwrap().unwrap();
+        assert!(session.transactions.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn session_rejects_empty_mail_and_rcpt() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        assert!(client.command("MAIL").await.starts_with("501"));
+        assert!(
+            client
+                .command("RCPT TO:<test@example.com>")
+                .await
+                .starts_with("503")
+        );
+        client.command("MAIL FROM:<a@example.org>").await;
+        assert!(client.command("RCPT").await.starts_with("501"));
+        assert!(client.command("DATA").await.starts_with("554"));
+    }
+
+    #[tokio::test]
+    async fn session_rejects_data_over_size_limit() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command("MAIL FROM:<a@example.org>").await;
+        client.command("RCPT TO:<test@example.com>").await;
+        assert!(client.command("DATA").await.starts_with("354"));
+        for _ in 0..64 {
+            client
+                .send("0123456789012345678901234567890123456789\r\n")
+                .await;
+        }
+        assert!(client.command(".").await.starts_with("552"));
+
+        // The session carries on and the next message goes through
+        send_message(
+            &mut session,
+            "b@example.org",
+            "test@example.com",
+            "Small\r\n",
+        )
+        .await;
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.from, "b@example.org");
+    }
+
+    #[tokio::test]
+    async fn session_rejects_overlong_command_lines() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        let line = format!("EHLO {}", "a".repeat(32 * 1024));
+        assert!(client.command(&line).await.starts_with("500"));
+        assert!(client.command("NOOP").await.starts_with("250"));
+    }
+
+    #[tokio::test]
+    async fn session_answers_pipelined_envelope_in_order() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+
+        client
+            .send(concat!(
+                "MAIL FROM:<a@example.org>\r\n",
+                "RCPT TO:<example@example.com>\r\n",
+                "RCPT TO:<broken@example.com\r\n",
+                "RCPT TO:<test@example.com>\r\n",
+                "DATA\r\n",
+            ))
+            .await;
+        assert!(client.reply().await.starts_with("250 2.1.0"));
+        assert!(client.reply().await.starts_with("250 2.1.5"));
+        assert!(client.reply().await.starts_with("501"));
+        assert!(client.reply().await.starts_with("250 2.1.5"));
+        assert!(client.reply().await.starts_with("354"));
+
+        client
+            .send("Subject: pipelined\r\n\r\nHi\r\n.\r\nQUIT\r\n")
+            .await;
+        assert!(client.reply().await.starts_with("250"));
+        assert!(client.reply().await.starts_with("221"));
+
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.to, ["example@example.com", "test@example.com"]);
+        assert_eq!(mail.data, b"Subject: pipelined\r\n\r\nHi\r\n");
+    }
+
+    #[tokio::test]
+    async fn session_does_not_accept_data_after_rejected_pipeline() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+
+        // The MAIL is rejected, so everything after it has to fail too and the body must never become a message
+        client
+            .send(concat!(
+                "MAIL FROM:<a@example.org> SIZE=4096\r\n",
+                "RCPT TO:<test@example.com>\r\n",
+                "DATA\r\n",
+                "Subject: smuggled\r\n",
+                ".\r\n",
+                "NOOP\r\n",
+            ))
+            .await;
+        assert!(client.reply().await.starts_with("552"));
+        assert!(client.reply().await.starts_with("503"));
+        assert!(client.reply().await.starts_with("503"));
+        assert!(client.reply().await.starts_with("502"));
+        assert!(client.reply().await.starts_with("502"));
+        assert!(client.reply().await.starts_with("250"));
+
+        assert!(client.command("QUIT").await.starts_with("221"));
+        drop(session.client);
+        session.handle.await.unwrap().unwrap();
+        assert!(session.transactions.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn session_rejects_commands_that_are_not_utf8() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.send_bytes(b"EHLO \xff\xfe\r\n").await;
+        assert!(client.reply().await.starts_with("500 5.5.2"));
+        assert!(client.command("NOOP").await.starts_with("250"));
+    }
+
+    #[tokio::test]
+    async fn implicit_tls_session_starts_encrypted() {
+        let mut config = port_config();
+        config.auth_enabled = true;
+        let mut session = start_implicit_tls(config, service_config()).await;
+        let client = &mut session.client;
+
+        let reply = client.command("EHLO client.example").await;
+        assert!(!reply.contains("STARTTLS"));
+        assert!(reply.contains("AUTH PLAIN"));
+        assert!(client.command("STARTTLS").await.starts_with("503"));
+
+        assert!(
+            client
+                .command("MAIL FROM:<a@example.org>")
+                .await
+                .starts_with("250")
+        );
+        assert!(
+            client
+                .command("RCPT TO:<test@example.com>")
+                .await
+                .starts_with("250")
+        );
+        assert!(client.command("DATA").await.starts_with("354"));
+        assert!(client.command("Hello\r\n.").await.starts_with("250"));
+        assert!(client.command("QUIT").await.starts_with("221"));
+
+        let mail = session.transactions.recv().await.unwrap();
+        assert_eq!(mail.data, b"Hello\r\n");
+    }
+
+    #[tokio::test]
+    async fn implicit_tls_session_never_greets_in_plaintext() {
+        let mut config = port_config();
+        config.implicit_tls = true;
+        let (socket, _transactions, handle) =
+            serve(config, service_config(), filtering(Default::default())).await;
+        let mut client = TestClient {
+            reader: BufReader::new(socket),
+        };
+        client.send("EHLO client.example\r\n").await;
+
+        // The server only ever sees garbage where the ClientHello should be
+        assert!(handle.await.unwrap().is_err());
+        let mut received = Vec::new();
+        // Whatever came back (if anything) before the socket closed, it wasn't SMTP
+        let _ = client.reader.read_to_end(&mut received).await;
+        assert!(!received.starts_with(b"220"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_clients_are_disconnected() {
+        let mut session = start(port_config(), service_config()).await;
+        let started = Instant::now();
+        assert!(session.client.reply().await.starts_with("421 4.3.2"));
+        assert!(started.elapsed() >= COMMAND_TIMEOUT);
+        assert!(session.client.reply().await.is_empty());
+        session.handle.await.unwrap().unwrap();
+
+        // Message data gets less time, and the message is dropped
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client.command("MAIL FROM:<a@example.org>").await;
+        client.command("RCPT TO:<test@example.com>").await;
+        assert!(client.command("DATA").await.starts_with("354"));
+        let started = Instant::now();
+        assert!(client.reply().await.starts_with("421 4.3.2"));
+        assert!(started.elapsed() >= DATA_TIMEOUT);
+        assert!(started.elapsed() < COMMAND_TIMEOUT);
+        session.handle.await.unwrap().unwrap();
+        assert!(session.transactions.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn nothing_after_quit_is_run() {
+        let mut session = start(port_config(), service_config()).await;
+        let client = &mut session.client;
+        client.command("EHLO client.example").await;
+        client
+            .send("QUIT\r\nMAIL FROM:<a@example.org>\r\nRCPT TO:<test@example.com>\r\n")
+            .await;
+        assert!(client.reply().await.starts_with("221"));
+        assert!(client.reply().await.is_empty());
+        // The client never hung up, the session ends anyway
+        session.handle.await.unwrap().unwrap();
+        assert!(session.transactions.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn session_ends_cleanly_on_quit() {
+        let mut session = start(port_config(), service_config()).await;
+        assert!(session.client.command("QUIT").await.starts_with("221"));
+        drop(session.client);
+        session.handle.await.unwrap().unwrap();
+        assert!(session.transactions.recv().await.is_none());
+    }
 }
@@ -0,0 +1,240 @@
+use log::debug;
+
+// Every reply the server sends, paired with its RFC 3463 enhanced status code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    ServiceReady,
+    ServiceClosing,
+    AuthSucceeded,
+    // Replies to HELO/EHLO, which never carry an enhanced code
+    Greeting,
+    Ok,
+    SenderOk,
+    RecipientOk,
+    CannotVerify,
+    AuthChallenge,
+    StartMailInput,
+    ServiceUnavailable,
+    TlsUnavailable,
+    LineTooLong,
+    CommandUnrecognised,
+    SyntaxError,
+    AuthCancelled,
+    AuthResponseInvalid,
+    NotImplemented,
+    BadSequence,
+    ParameterNotImplemented,
+    TlsRequired,
+    AuthRequired,
+    AuthFailed,
+    EncryptionRequired,
+    MailboxUnavailable,
+    PolicyRejected,
+    MessageTooLarge,
+    AddressNotPermitted,
+    SenderNotAllowed,
+    NoValidRecipients,
+    RelayDenied,
+    InvalidLineEnding,
+    ParameterNotRecognised,
+    // RFC 7372 3.2, refused because of the sender's SPF record
+    SpfFailed,
+    SpfTempError,
+    SpfPermError,
+}
+
+impl Status {
+    pub fn code(self) -> u16 {
+        match self {
+            Status::ServiceReady => 220,
+            Status::ServiceClosing => 221,
+            Status::AuthSucceeded => 235,
+            Status::Greeting | Status::Ok | Status::SenderOk | Status::RecipientOk => 250,
+            Status::CannotVerify => 252,
+            Status::AuthChallenge => 334,
+            Status::StartMailInput => 354,
+            Status::ServiceUnavailable => 421,
+            Status::SpfTempError => 451,
+            Status::TlsUnavailable => 454,
+            Status::LineTooLong | Status::CommandUnrecognised => 500,
+            Status::SyntaxError | Status::AuthCancelled | Status::AuthResponseInvalid => 501,
+            Status::NotImplemented => 502,
+            Status::BadSequence => 503,
+            Status::ParameterNotImplemented => 504,
+            Status::TlsRequired | Status::AuthRequired => 530,
+            Status::AuthFailed => 535,
+            Status::EncryptionRequired => 538,
+            Status::MailboxUnavailable
+            | Status::PolicyRejected
+            | Status::SpfFailed
+            | Status::SpfPermError => 550,
+            Status::MessageTooLarge => 552,
+            Status::AddressNotPermitted | Status::SenderNotAllowed => 553,
+            Status::NoValidRecipients | Status::RelayDenied | Status::InvalidLineEnding => 554,
+            Status::ParameterNotRecognised => 555,
+        }
+    }
+
+    // RFC 2034 leaves the greeting, HELO/EHLO and intermediate (3xx) replies without one
+    pub fn enhanced(self) -> Option<&'static str> {
+        match self {
+            Status::ServiceReady
+            | Status::Greeting
+            | Status::AuthChallenge
+            | Status::StartMailInput => None,
+            Status::ServiceClosing | Status::Ok | Status::CannotVerify => Some("2.0.0"),
+            Status::AuthSucceeded => Some("2.7.0"),
+            Status::SenderOk => Some("2.1.0"),
+            Status::RecipientOk => Some("2.1.5"),
+            Status::ServiceUnavailable => Some("4.3.2"),
+            Status::TlsUnavailable => Some("4.7.0"),
+            Status::LineTooLong | Status::CommandUnrecognised | Status::AuthResponseInvalid => {
+                Some("5.5.2")
+            }
+            Status::AuthCancelled => Some("5.0.0"),
+            Status::SyntaxError
+            | Status::ParameterNotImplemented
+            | Status::ParameterNotRecognised => Some("5.5.4"),
+            Status::NotImplemented | Status::BadSequence | Status::NoValidRecipients => {
+                Some("5.5.1")
+            }
+            Status::TlsRequired | Status::AuthRequired => Some("5.7.0"),
+            Status::AuthFailed => Some("5.7.8"),
+            Status::EncryptionRequired => Some("5.7.11"),
+            Status::MailboxUnavailable => Some("5.1.1"),
+            Status::MessageTooLarge => Some("5.3.4"),
+            Status::InvalidLineEnding => Some("5.6.0"),
+            Status::AddressNotPermitted => Some("5.6.7"),
+            Status::PolicyRejected | Status::SenderNotAllowed | Status::RelayDenied => {
+                Some("5.7.1")
+            }
+            Status::SpfFailed => Some("5.7.23"),
+            Status::SpfTempError => Some("4.7.24"),
+            Status::SpfPermError => Some("5.7.24"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    status: Status,
+    lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn new(status: Status, text: impl Into<String>) -> Self {
+        Self {
+            status,
+            lines: vec![text.into()],
+        }
+    }
+
+    // Adds another line, turning this into a multiline reply
+    pub fn line(mut self, text: impl Into<String>) -> Self {
+        self.lines.push(text.into());
+        self
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let code = self.status.code();
+        let mut formatted = String::new();
+
+        for (index, text) in self.lines.iter().enumerate() {
+            let separator = if index + 1 == self.lines.len() {
+                ' '
+            } else {
+                '-'
+            };
+            match self.status.enhanced() {
+                Some(enhanced) => {
+                    formatted.push_str(&format!("{code}{separator}{enhanced} {text}\r\n"))
+                }
+                None => formatted.push_str(&format!("{code}{separator}{text}\r\n")),
+            }
+        }
+
+        debug!("Sending Message {formatted}");
+        formatted.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_includes_enhanced_code() {
+        assert_eq!(
+            Reply::new(Status::AuthFailed, "Authentication failed").to_bytes(),
+            b"535 5.7.8 Authentication failed\r\n"
+        );
+        assert_eq!(
+            Reply::new(Status::MessageTooLarge, "Too big").to_bytes(),
+            b"552 5.3.4 Too big\r\n"
+        );
+    }
+
+    #[test]
+    fn reply_leaves_greetings_bare() {
+        assert_eq!(
+            Reply::new(Status::ServiceReady, "Server Ready").to_bytes(),
+            b"220 Server Ready\r\n"
+        );
+        assert_eq!(
+            Reply::new(Status::StartMailInput, "Go ahead").to_bytes(),
+            b"354 Go ahead\r\n"
+        );
+    }
+
+    #[test]
+    fn reply_formats_multiline() {
+        assert_eq!(
+            Reply::new(Status::Greeting, "Localhost")
+                .line("PIPELINING")
+                .line("SIZE 1024")
+                .to_bytes(),
+            b"250-Localhost\r\n250-PIPELINING\r\n250 SIZE 1024\r\n"
+        );
+        assert_eq!(
+            Reply::new(Status::Ok, "first").line("second").to_bytes(),
+            b"250-2.0.0 first\r\n250 2.0.0 second\r\n"
+        );
+    }
+
+    #[test]
+    fn reply_codes_match_their_class() {
+        for status in [
+            Status::ServiceClosing,
+            Status::AuthSucceeded,
+            Status::Ok,
+            Status::SenderOk,
+            Status::RecipientOk,
+            Status::CannotVerify,
+            Status::ServiceUnavailable,
+            Status::TlsUnavailable,
+            Status::LineTooLong,
+            Status::CommandUnrecognised,
+            Status::SyntaxError,
+            Status::NotImplemented,
+            Status::BadSequence,
+            Status::ParameterNotImplemented,
+            Status::AuthFailed,
+            Status::MailboxUnavailable,
+            Status::MessageTooLarge,
+            Status::NoValidRecipients,
+            Status::InvalidLineEnding,
+            Status::ParameterNotRecognised,
+            Status::SpfFailed,
+            Status::SpfTempError,
+            Status::SpfPermError,
+        ] {
+            let class = status.code() / 100;
+            let enhanced = status.enhanced().unwrap();
+            assert_eq!(enhanced[..1].parse::<u16>().unwrap(), class);
+        }
+    }
+}
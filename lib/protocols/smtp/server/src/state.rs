@@ -0,0 +1,295 @@
+use crate::reply::{Reply, Status};
+
+// The RFC 5321 command sequence, every handler checks its command against this before doing anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SessionState {
+    // Greeting sent, waiting on HELO/EHLO
+    #[default]
+    Connected,
+    // HELO/EHLO done, no transaction in progress
+    Greeted,
+    // MAIL FROM accepted, waiting on the first RCPT
+    Mail,
+    // At least one RCPT accepted
+    Rcpt,
+    // Between the 354 and the end of data marker
+    Data,
+    // Some BDAT chunks received, waiting on the one marked LAST (RFC 3030)
+    Chunking,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Helo,
+    Auth,
+    StartTls,
+    Mail,
+    Rcpt,
+    Data,
+    Bdat,
+    DataDone,
+    Reset,
+}
+
+impl SessionState {
+    // Works out the state after `event`, or the reply to send back if the command is out of sequence
+    pub fn transition(self, event: Event) -> Result<SessionState, Reply> {
+        use SessionState::*;
+
+        match (event, self) {
+            (Event::DataDone, Data | Chunking) => Ok(Greeted),
+            (_, Data) => Err(Reply::new(Status::BadSequence, "Bad sequence of commands")),
+
+            (Event::Helo, _) => Ok(Greeted),
+
+            (Event::Auth | Event::StartTls, Connected) => Err(Reply::new(
+                Status::BadSequence,
+                "Bad sequence of commands, send EHLO first",
+            )),
+            (Event::Auth | Event::StartTls, Mail | Rcpt | Chunking) => Err(Reply::new(
+                Status::BadSequence,
+                "Bad sequence of commands, not allowed during a mail transaction",
+            )),
+            (Event::Auth, Greeted) => Ok(Greeted),
+            // Everything learnt before STARTTLS is discarded, so the client has to greet again
+            (Event::StartTls, Greeted) => Ok(Connected),
+
+            (Event::Mail, Connected) => Err(Reply::new(
+                Status::BadSequence,
+                "Bad sequence of commands, send HELO/EHLO first",
+            )),
+            (Event::Mail, Greeted) => Ok(Mail),
+            (Event::Mail, Mail | Rcpt | Chunking) => Err(Reply::new(
+                Status::BadSequence,
+                "Bad sequence of commands, nested MAIL command",
+            )),
+
+            (Event::Rcpt, Chunking) => Err(Reply::new(
+                Status::BadSequence,
+                "Bad sequence of commands, RCPT not allowed during BDAT",
+            )),
+            (Event::Rcpt, Mail | Rcpt) => Ok(Rcpt),
+            (Event::Rcpt, _) => Err(Reply::new(
+                Status::BadSequence,
+                "Bad sequence of commands, need MAIL before RCPT",
+            )),
+
+            (Event::Data, Rcpt) => Ok(Data),
+            (Event::Data, Chunking) => Err(Reply::new(
+                Status::BadSequence,
+                "Bad sequence of commands, DATA not allowed during BDAT",
+            )),
+            (Event::Data, Mail) => {
+                Err(Reply::new(Status::NoValidRecipients, "No valid recipients"))
+            }
+            (Event::Data, _) => Err(Reply::new(
+                Status::BadSequence,
+                "Bad sequence of commands, need MAIL before DATA",
+            )),
+
+            (Event::Bdat, Rcpt | Chunking) => Ok(Chunking),
+            (Event::Bdat, Mail) => {
+                Err(Reply::new(Status::NoValidRecipients, "No valid recipients"))
+            }
+            (Event::Bdat, _) => Err(Reply::new(
+                Status::BadSequence,
+                "Bad sequence of commands, need MAIL before BDAT",
+            )),
+
+            (Event::DataDone, _) => {
+                Err(Reply::new(Status::BadSequence, "Bad sequence of commands"))
+            }
+
+            (Event::Reset, Connected) => Ok(Connected),
+            (Event::Reset, _) => Ok(Greeted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(state: SessionState, event: Event) -> Status {
+        state.transition(event).unwrap_err().status()
+    }
+
+    #[test]
+    fn state_follows_a_full_transaction() {
+        let state = SessionState::default();
+        let state = state.transition(Event::Helo).unwrap();
+        assert_eq!(state, SessionState::Greeted);
+        let state = state.transition(Event::Mail).unwrap();
+        assert_eq!(state, SessionState::Mail);
+        let state = state.transition(Event::Rcpt).unwrap();
+        let state = state.transition(Event::Rcpt).unwrap();
+        assert_eq!(state, SessionState::Rcpt);
+        let state = state.transition(Event::Data).unwrap();
+        assert_eq!(state, SessionState::Data);
+        assert_eq!(
+            state.transition(Event::DataDone).unwrap(),
+            SessionState::Greeted
+        );
+    }
+
+    #[test]
+    fn state_follows_a_chunked_transaction() {
+        let state = SessionState::Rcpt.transition(Event::Bdat).unwrap();
+        assert_eq!(state, SessionState::Chunking);
+        let state = state.transition(Event::Bdat).unwrap();
+        assert_eq!(state, SessionState::Chunking);
+        assert_eq!(
+            state.transition(Event::DataDone).unwrap(),
+            SessionState::Greeted
+        );
+    }
+
+    #[test]
+    fn state_only_allows_bdat_reset_and_helo_while_chunking() {
+        for event in [
+            Event::Auth,
+            Event::StartTls,
+            Event::Mail,
+            Event::Rcpt,
+            Event::Data,
+        ] {
+            assert_eq!(rejected(SessionState::Chunking, event), Status::BadSequence);
+        }
+        for event in [Event::Helo, Event::Reset] {
+            assert_eq!(
+                SessionState::Chunking.transition(event).unwrap(),
+                SessionState::Greeted
+            );
+        }
+    }
+
+    #[test]
+    fn state_rejects_bdat_without_recipients() {
+        assert_eq!(
+            rejected(SessionState::Greeted, Event::Bdat),
+            Status::BadSequence
+        );
+        assert_eq!(
+            rejected(SessionState::Mail, Event::Bdat),
+            Status::NoValidRecipients
+        );
+    }
+
+    #[test]
+    fn state_rejects_mail_before_helo() {
+        assert_eq!(
+            rejected(SessionState::Connected, Event::Mail),
+            Status::BadSequence
+        );
+    }
+
+    #[test]
+    fn state_rejects_nested_mail() {
+        assert_eq!(
+            rejected(SessionState::Mail, Event::Mail),
+            Status::BadSequence
+        );
+        assert_eq!(
+            rejected(SessionState::Rcpt, Event::Mail),
+            Status::BadSequence
+        );
+    }
+
+    #[test]
+    fn state_rejects_rcpt_without_mail() {
+        assert_eq!(
+            rejected(SessionState::Connected, Event::Rcpt),
+            Status::BadSequence
+        );
+        assert_eq!(
+            rejected(SessionState::Greeted, Event::Rcpt),
+            Status::BadSequence
+        );
+    }
+
+    #[test]
+    fn state_rejects_data_without_mail() {
+        assert_eq!(
+            rejected(SessionState::Connected, Event::Data),
+            Status::BadSequence
+        );
+        assert_eq!(
+            rejected(SessionState::Greeted, Event::Data),
+            Status::BadSequence
+        );
+    }
+
+    #[test]
+    fn state_rejects_data_without_recipients() {
+        assert_eq!(
+            rejected(SessionState::Mail, Event::Data),
+            Status::NoValidRecipients
+        );
+    }
+
+    #[test]
+    fn state_rejects_commands_during_data() {
+        for event in [
+            Event::Helo,
+            Event::Auth,
+            Event::StartTls,
+            Event::Mail,
+            Event::Rcpt,
+            Event::Data,
+            Event::Bdat,
+            Event::Reset,
+        ] {
+            assert_eq!(rejected(SessionState::Data, event), Status::BadSequence);
+        }
+    }
+
+    #[test]
+    fn state_rejects_end_of_data_outside_data() {
+        for state in [
+            SessionState::Connected,
+            SessionState::Greeted,
+            SessionState::Mail,
+            SessionState::Rcpt,
+        ] {
+            assert_eq!(rejected(state, Event::DataDone), Status::BadSequence);
+        }
+    }
+
+    #[test]
+    fn state_rejects_auth_and_starttls_outside_greeted() {
+        for event in [Event::Auth, Event::StartTls] {
+            assert_eq!(
+                rejected(SessionState::Connected, event),
+                Status::BadSequence
+            );
+            assert_eq!(rejected(SessionState::Mail, event), Status::BadSequence);
+            assert_eq!(rejected(SessionState::Rcpt, event), Status::BadSequence);
+        }
+    }
+
+    #[test]
+    fn state_starttls_requires_a_new_greeting() {
+        assert_eq!(
+            SessionState::Greeted.transition(Event::StartTls).unwrap(),
+            SessionState::Connected
+        );
+    }
+
+    #[test]
+    fn state_reset_and_helo_abort_transactions() {
+        for state in [SessionState::Mail, SessionState::Rcpt] {
+            assert_eq!(
+                state.transition(Event::Reset).unwrap(),
+                SessionState::Greeted
+            );
+            assert_eq!(
+                state.transition(Event::Helo).unwrap(),
+                SessionState::Greeted
+            );
+        }
+        assert_eq!(
+            SessionState::Connected.transition(Event::Reset).unwrap(),
+            SessionState::Connected
+        );
+    }
+}
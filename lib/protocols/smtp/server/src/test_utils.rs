@@ -0,0 +1,243 @@
+// Shared harness for the session tests, runs `handle_smtp` against a real loopback socket
+use std::sync::Arc;
+
+use base64::prelude::*;
+use eemail_lib_dns::StaticResolver;
+use eemail_lib_shared::{BareLfPolicy, SMTPPortConfiguration, VrfyPolicy};
+use rustls::{ClientConfig, RootCertStore, ServerConfig, pki_types::ServerName};
+use rustls_pemfile::{certs, private_key};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector, client::TlsStream};
+
+use crate::{Filtering, Mail, handle_smtp};
+
+const CERT: &[u8] = include_bytes!("../../../../../tests/certs/cert.pem");
+const KEY: &[u8] = include_bytes!("../../../../../tests/certs/key.pem");
+const CA: &[u8] = include_bytes!("../../../../../tests/certs/ca.pem");
+
+pub fn port_config() -> SMTPPortConfiguration {
+    SMTPPortConfiguration {
+        auth_enabled: false,
+        filtering_enabled: false,
+        implicit_tls: false,
+        port: 0,
+        max_connections: 1,
+        max_connections_per_ip: 1,
+        vrfy_policy: VrfyPolicy::Disabled,
+        max_message_size: 1024,
+        bare_lf_policy: BareLfPolicy::Reject,
+        require_tls: false,
+        require_auth: false,
+        check_header_from: false,
+    }
+}
+
+pub fn service_config() -> eemail_component_configurator::Configuration {
+    eemail_component_configurator::Configuration::parse_from_string(
+        include_str!("../../../../../tests/config.toml").to_string(),
+    )
+    .unwrap()
+}
+
+pub fn acceptor() -> TlsAcceptor {
+    let cert_chain = certs(&mut &CERT[..]).collect::<Result<_, _>>().unwrap();
+    let key = private_key(&mut &KEY[..]).unwrap().unwrap();
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+// Trusts the test CA that signed `CERT`
+pub fn connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(&mut &CA[..]) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+pub struct TestSession<S = TcpStream> {
+    pub client: TestClient<S>,
+    pub transactions: mpsc::Receiver<Mail>,
+    pub handle: JoinHandle<anyhow::Result<()>>,
+}
+
+// `resolver` answers any DNS lookups, and DMARC results aren't reported anywhere
+pub fn filtering(resolver: StaticResolver) -> Filtering {
+    Filtering {
+        resolver: Arc::new(resolver),
+        reports: None,
+    }
+}
+
+// Runs `handle_smtp` for the next connection to the returned socket
+pub async fn serve(
+    config: SMTPPortConfiguration,
+    service_config: eemail_component_configurator::Configuration,
+    filtering: Filtering,
+) -> (
+    TcpStream,
+    mpsc::Receiver<Mail>,
+    JoinHandle<anyhow::Result<()>>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (sender, transactions) = mpsc::channel(16);
+
+    let handle = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await?;
+        handle_smtp(
+            socket,
+            config,
+            acceptor(),
+            service_config,
+            sender,
+            filtering,
+        )
+        .await
+    });
+
+    (
+        TcpStream::connect(addr).await.unwrap(),
+        transactions,
+        handle,
+    )
+}
+
+// Starts a session and swallows the 220 greeting
+pub async fn start(
+    config: SMTPPortConfiguration,
+    service_config: eemail_component_configurator::Configuration,
+) -> TestSession {
+    start_with_resolver(config, service_config, StaticResolver::default()).await
+}
+
+pub async fn start_with_resolver(
+    config: SMTPPortConfiguration,
+    service_config: eemail_component_configurator::Configuration,
+    resolver: StaticResolver,
+) -> TestSession {
+    start_with_filtering(config, service_config, filtering(resolver)).await
+}
+
+pub async fn start_with_filtering(
+    config: SMTPPortConfiguration,
+    service_config: eemail_component_configurator::Configuration,
+    filtering: Filtering,
+) -> TestSession {
+    let (socket, transactions, handle) = serve(config, service_config, filtering).await;
+    let mut client = TestClient {
+        reader: BufReader::new(socket),
+    };
+    assert!(client.reply().await.starts_with("220"));
+
+    TestSession {
+        client,
+        transactions,
+        handle,
+    }
+}
+
+// Same as `start`, but the handshake is done before the greeting is read
+pub async fn start_implicit_tls(
+    mut config: SMTPPortConfiguration,
+    service_config: eemail_component_configurator::Configuration,
+) -> TestSession<TlsStream<TcpStream>> {
+    config.implicit_tls = true;
+    let (socket, transactions, handle) =
+        serve(config, service_config, filtering(StaticResolver::default())).await;
+    let stream = connector()
+        .connect(ServerName::try_from("localhost").unwrap(), socket)
+        .await
+        .unwrap();
+    let mut client = TestClient {
+        reader: BufReader::new(stream),
+    };
+    assert!(client.reply().await.starts_with("220"));
+
+    TestSession {
+        client,
+        transactions,
+        handle,
+    }
+}
+
+// An implicit TLS session already logged in as test@example.com, who has send_as for example@example.com
+pub async fn start_authenticated(
+    mut config: SMTPPortConfiguration,
+    service_config: eemail_component_configurator::Configuration,
+) -> TestSession<TlsStream<TcpStream>> {
+    config.auth_enabled = true;
+    let mut session = start_implicit_tls(config, service_config).await;
+    let client = &mut session.client;
+    client.command("EHLO client.example").await;
+    let credentials = BASE64_STANDARD.encode("\0test@example.com\0password");
+    assert!(
+        client
+            .command(&format!("AUTH PLAIN {}", credentials))
+            .await
+            .starts_with("235")
+    );
+    session
+}
+
+pub struct TestClient<S = TcpStream> {
+    pub reader: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TestClient<S> {
+    pub async fn send(&mut self, data: &str) {
+        self.send_bytes(data.as_bytes()).await;
+    }
+
+    pub async fn send_bytes(&mut self, data: &[u8]) {
+        self.reader.get_mut().write_all(data).await.unwrap();
+    }
+
+    // Reads a whole (possibly multiline) reply, returning every line joined by CRLF
+    pub async fn reply(&mut self) -> String {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await.unwrap() == 0 {
+                return reply;
+            }
+            reply.push_str(&line);
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return reply;
+            }
+        }
+    }
+
+    pub async fn command(&mut self, command: &str) -> String {
+        self.send(&format!("{}\r\n", command)).await;
+        self.reply().await
+    }
+}
+
+impl TestClient {
+    // Does the client side of the handshake, to be called once the 220 for STARTTLS has been read
+    pub async fn start_tls(self) -> TestClient<TlsStream<TcpStream>> {
+        assert!(self.reader.buffer().is_empty());
+        let stream = connector()
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                self.reader.into_inner(),
+            )
+            .await
+            .unwrap();
+        TestClient {
+            reader: BufReader::new(stream),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// SASL (RFC 4422) mechanisms, kept out of the SMTP server so IMAP and POP3 can use the same ones
+mod login;
+mod plain;
+mod scram;
+#[cfg(test)]
+mod test_utils;
+
+pub use scram::ScramCredentials;
+
+// Everything `mechanism` knows about, in the order they should be advertised
+pub const MECHANISMS: [&str; 3] = ["PLAIN", "LOGIN", "SCRAM-SHA-256"];
+
+// Where the mechanisms look accounts up, usernames are any of the account's addresses. The configurator implements it
+pub trait CredentialStore {
+    // For the mechanisms that are given the password itself, PLAIN and LOGIN
+    fn verify_password(&self, username: &str, password: &str) -> bool;
+    fn scram_sha256(&self, username: &str) -> Option<ScramCredentials>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    // Whose credentials were checked
+    pub authcid: String,
+    // Who the client asked to act as, if anyone
+    pub authzid: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    // The client's response doesn't follow the mechanism
+    Malformed,
+    InvalidCredentials,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    // Has to go to the client, its response is passed to the next `step`
+    Challenge(Vec<u8>),
+    Success(Identity),
+    Failure(Error),
+}
+
+pub trait Mechanism: Send {
+    // `response` is the decoded client data, None only for the first step when there was no initial response
+    fn step(&mut self, store: &dyn CredentialStore, response: Option<&[u8]>) -> Step;
+}
+
+// Case insensitive, as mechanism names are
+pub fn mechanism(name: &str) -> Option<Box<dyn Mechanism>> {
+    match name.to_ascii_uppercase().as_str() {
+        "PLAIN" => Some(Box::new(plain::Plain)),
+        "LOGIN" => Some(Box::<login::Login>::default()),
+        "SCRAM-SHA-256" => Some(Box::<scram::Scram>::default()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mechanism_names_are_case_insensitive() {
+        for name in MECHANISMS {
+            assert!(mechanism(name).is_some());
+            assert!(mechanism(&name.to_ascii_lowercase()).is_some());
+        }
+        assert!(mechanism("CRAM-MD5").is_none());
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{CredentialStore, Error, Identity, Mechanism, Step};
+
+// The old draft-murchison-sasl-login mechanism, the username and password are asked for one at a time
+#[derive(Default)]
+pub(crate) struct Login {
+    username: Option<String>,
+}
+
+impl Mechanism for Login {
+    fn step(&mut self, store: &dyn CredentialStore, response: Option<&[u8]>) -> Step {
+        // Some clients send the username as the initial response, most wait to be asked
+        let Some(response) = response else {
+            return Step::Challenge(b"Username:".to_vec());
+        };
+        let Ok(response) = std::str::from_utf8(response) else {
+            return Step::Failure(Error::Malformed);
+        };
+
+        match self.username.take() {
+            None if response.is_empty() => Step::Failure(Error::Malformed),
+            None => {
+                self.username = Some(response.to_string());
+                Step::Challenge(b"Password:".to_vec())
+            }
+            Some(username) if store.verify_password(&username, response) => {
+                Step::Success(Identity {
+                    authcid: username,
+                    authzid: None,
+                })
+            }
+            Some(_) => Step::Failure(Error::InvalidCredentials),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn login_asks_for_username_then_password() {
+        let mut login = Login::default();
+        assert_eq!(
+            login.step(&TestStore, None),
+            Step::Challenge(b"Username:".to_vec())
+        );
+        assert_eq!(
+            login.step(&TestStore, Some(USERNAME.as_bytes())),
+            Step::Challenge(b"Password:".to_vec())
+        );
+        assert_eq!(
+            login.step(&TestStore, Some(PASSWORD.as_bytes())),
+            Step::Success(Identity {
+                authcid: USERNAME.to_string(),
+                authzid: None,
+            })
+        );
+    }
+
+    #[test]
+    fn login_takes_username_as_initial_response() {
+        let mut login = Login::default();
+        assert_eq!(
+            login.step(&TestStore, Some(USERNAME.as_bytes())),
+            Step::Challenge(b"Password:".to_vec())
+        );
+        assert_eq!(
+            login.step(&TestStore, Some(b"wrong")),
+            Step::Failure(Error::InvalidCredentials)
+        );
+    }
+
+    #[test]
+    fn login_rejects_empty_username() {
+        assert_eq!(
+            Login::default().step(&TestStore, Some(b"")),
+            Step::Failure(Error::Malformed)
+        );
+    }
+}
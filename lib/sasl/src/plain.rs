@@ -0,0 +1,94 @@
+use crate::{CredentialStore, Error, Identity, Mechanism, Step};
+
+// RFC 4616, a single `authzid NUL authcid NUL password` message
+pub(crate) struct Plain;
+
+impl Mechanism for Plain {
+    fn step(&mut self, store: &dyn CredentialStore, response: Option<&[u8]>) -> Step {
+        // Without an initial response the client is asked for it with an empty challenge
+        let Some(response) = response else {
+            return Step::Challenge(Vec::new());
+        };
+
+        let mut parts = response.split(|&b| b == 0).map(std::str::from_utf8);
+        let (Some(Ok(authzid)), Some(Ok(authcid)), Some(Ok(password)), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Step::Failure(Error::Malformed);
+        };
+        if authcid.is_empty() {
+            return Step::Failure(Error::Malformed);
+        }
+
+        if !store.verify_password(authcid, password) {
+            return Step::Failure(Error::InvalidCredentials);
+        }
+
+        Step::Success(Identity {
+            authcid: authcid.to_string(),
+            authzid: (!authzid.is_empty()).then(|| authzid.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn plain_accepts_initial_response() {
+        assert_eq!(
+            Plain.step(&TestStore, Some(b"\0user@example.com\0password")),
+            Step::Success(Identity {
+                authcid: USERNAME.to_string(),
+                authzid: None,
+            })
+        );
+    }
+
+    #[test]
+    fn plain_asks_for_missing_initial_response() {
+        let mut plain = Plain;
+        assert_eq!(plain.step(&TestStore, None), Step::Challenge(Vec::new()));
+        assert!(matches!(
+            plain.step(&TestStore, Some(b"\0user@example.com\0password")),
+            Step::Success(_)
+        ));
+    }
+
+    #[test]
+    fn plain_passes_authzid_through() {
+        assert_eq!(
+            Plain.step(
+                &TestStore,
+                Some(b"other@example.com\0user@example.com\0password")
+            ),
+            Step::Success(Identity {
+                authcid: USERNAME.to_string(),
+                authzid: Some("other@example.com".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn plain_rejects_bad_responses() {
+        for response in [
+            &b""[..],
+            b"user@example.com",
+            b"\0user@example.com",
+            b"\0\0password",
+            b"\0user@example.com\0password\0extra",
+            b"\0user@example.com\0\xff",
+        ] {
+            assert_eq!(
+                Plain.step(&TestStore, Some(response)),
+                Step::Failure(Error::Malformed)
+            );
+        }
+        assert_eq!(
+            Plain.step(&TestStore, Some(b"\0user@example.com\0wrong")),
+            Step::Failure(Error::InvalidCredentials)
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use std::{fmt, sync::OnceLock};
+
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::{CredentialStore, Error, Identity, Mechanism, Step};
+
+// RFC 7677 asks for at least this many
+const DEFAULT_ITERATIONS: u32 = 4096;
+
+// What the server keeps for SCRAM-SHA-256, the password itself can't be recovered from it (RFC 5802 section 3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramCredentials {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: [u8; 32],
+    pub server_key: [u8; 32],
+}
+
+impl ScramCredentials {
+    pub fn new(password: &str, salt: &[u8], iterations: u32) -> Self {
+        let mut salted_password = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut salted_password);
+        let client_key = hmac(&salted_password, b"Client Key");
+
+        Self {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: Sha256::digest(client_key).into(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+
+    // With a fresh salt, for setting up an account
+    pub fn generate(password: &str) -> Self {
+        Self::new(password, &random::<16>(), DEFAULT_ITERATIONS)
+    }
+
+    // RFC 5803, SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>
+    pub fn parse(value: &str) -> Option<Self> {
+        let (parameters, keys) = value.strip_prefix("SCRAM-SHA-256$")?.split_once('$')?;
+        let (iterations, salt) = parameters.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+
+        Some(Self {
+            iterations: iterations.parse().ok()?,
+            salt: BASE64_STANDARD.decode(salt).ok()?,
+            stored_key: BASE64_STANDARD.decode(stored_key).ok()?.try_into().ok()?,
+            server_key: BASE64_STANDARD.decode(server_key).ok()?.try_into().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for ScramCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SCRAM-SHA-256${}:{}${}:{}",
+            self.iterations,
+            BASE64_STANDARD.encode(&self.salt),
+            BASE64_STANDARD.encode(self.stored_key),
+            BASE64_STANDARD.encode(self.server_key)
+        )
+    }
+}
+
+// RFC 5802/7677 without channel binding, which we never advertise
+#[derive(Default)]
+pub(crate) struct Scram {
+    state: State,
+}
+
+#[derive(Default)]
+enum State {
+    #[default]
+    Start,
+    // server-first sent, waiting on client-final
+    ServerFirst(Exchange),
+    // server-final sent, waiting on the empty response that ends it (SMTP can't carry it in the 235)
+    ServerFinal(Identity),
+    Done,
+}
+
+struct Exchange {
+    identity: Identity,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+    // None for an unknown user, who still gets as far as the proof so they look like a wrong password
+    credentials: Option<ScramCredentials>,
+}
+
+struct ClientFirst {
+    gs2_header: String,
+    authzid: Option<String>,
+    bare: String,
+    username: String,
+    nonce: String,
+}
+
+impl Mechanism for Scram {
+    fn step(&mut self, store: &dyn CredentialStore, response: Option<&[u8]>) -> Step {
+        match (std::mem::replace(&mut self.state, State::Done), response) {
+            (State::Start, None) => {
+                self.state = State::Start;
+                Step::Challenge(Vec::new())
+            }
+            (State::Start, Some(response)) => {
+                let server_nonce = BASE64_STANDARD.encode(random::<18>());
+                self.client_first(store, response, &server_nonce)
+            }
+            (State::ServerFirst(exchange), Some(response)) => self.client_final(exchange, response),
+            (State::ServerFinal(identity), Some(b"")) => Step::Success(identity),
+            _ => Step::Failure(Error::Malformed),
+        }
+    }
+}
+
+impl Scram {
+    fn client_first(
+        &mut self,
+        store: &dyn CredentialStore,
+        response: &[u8],
+        server_nonce: &str,
+    ) -> Step {
+        let Some(client_first) = parse_client_first(response) else {
+            return Step::Failure(Error::Malformed);
+        };
+
+        let credentials = store.scram_sha256(&client_first.username);
+        let (salt, iterations) = match &credentials {
+            Some(credentials) => (credentials.salt.clone(), credentials.iterations),
+            None => (fake_salt(&client_first.username), DEFAULT_ITERATIONS),
+        };
+
+        let nonce = format!("{}{}", client_first.nonce, server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            BASE64_STANDARD.encode(salt),
+            iterations
+        );
+
+        self.state = State::ServerFirst(Exchange {
+            identity: Identity {
+                authcid: client_first.username,
+                authzid: client_first.authzid,
+            },
+            gs2_header: client_first.gs2_header,
+            client_first_bare: client_first.bare,
+            server_first: server_first.clone(),
+            nonce,
+            credentials,
+        });
+        Step::Challenge(server_first.into_bytes())
+    }
+
+    fn client_final(&mut self, exchange: Exchange, response: &[u8]) -> Step {
+        let Ok(message) = std::str::from_utf8(response) else {
+            return Step::Failure(Error::Malformed);
+        };
+        let Some((without_proof, proof)) = message.rsplit_once(",p=") else {
+            return Step::Failure(Error::Malformed);
+        };
+
+        let mut attributes = without_proof.split(',');
+        let channel_binding = attributes.next().and_then(|a| a.strip_prefix("c="));
+        let nonce = attributes.next().and_then(|a| a.strip_prefix("r="));
+        if channel_binding != Some(&BASE64_STANDARD.encode(&exchange.gs2_header))
+            || nonce != Some(&exchange.nonce)
+        {
+            return Step::Failure(Error::Malformed);
+        }
+
+        let Some(proof) = BASE64_STANDARD
+            .decode(proof)
+            .ok()
+            .filter(|proof| proof.len() == 32)
+        else {
+            return Step::Failure(Error::Malformed);
+        };
+
+        let Some(credentials) = exchange.credentials else {
+            return Step::Failure(Error::InvalidCredentials);
+        };
+
+        let auth_message = format!(
+            "{},{},{}",
+            exchange.client_first_bare, exchange.server_first, without_proof
+        );
+        let client_signature = hmac(&credentials.stored_key, auth_message.as_bytes());
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(client_signature)
+            .map(|(a, b)| a ^ b)
+            .collect();
+        if !bool::from(
+            Sha256::digest(client_key)
+                .as_slice()
+                .ct_eq(&credentials.stored_key),
+        ) {
+            return Step::Failure(Error::InvalidCredentials);
+        }
+
+        let server_signature = hmac(&credentials.server_key, auth_message.as_bytes());
+        self.state = State::ServerFinal(exchange.identity);
+        Step::Challenge(format!("v={}", BASE64_STANDARD.encode(server_signature)).into_bytes())
+    }
+}
+
+// gs2-header "," client-first-message-bare, e.g. "n,,n=user,r=fyko+d2lbbFgONRv9qkxdawL"
+fn parse_client_first(response: &[u8]) -> Option<ClientFirst> {
+    let message = std::str::from_utf8(response).ok()?;
+    let mut parts = message.splitn(3, ',');
+    let (channel_binding, authzid, bare) = (parts.next()?, parts.next()?, parts.next()?);
+
+    // "p=" asks for channel binding, "y" means the client supports it but thinks we don't, which is right
+    if channel_binding != "n" && channel_binding != "y" {
+        return None;
+    }
+    let gs2_header = format!("{},{},", channel_binding, authzid);
+    let authzid = match authzid {
+        "" => None,
+        authzid => Some(decode_saslname(authzid.strip_prefix("a=")?)?),
+    };
+
+    let mut attributes = bare.split(',');
+    let username = decode_saslname(attributes.next()?.strip_prefix("n=")?)?;
+    let nonce = attributes.next()?.strip_prefix("r=")?;
+    if username.is_empty() || nonce.is_empty() || !nonce.bytes().all(|b| b.is_ascii_graphic()) {
+        return None;
+    }
+
+    Some(ClientFirst {
+        gs2_header,
+        authzid,
+        bare: bare.to_string(),
+        username,
+        nonce: nonce.to_string(),
+    })
+}
+
+// Commas and equals signs in names are sent as =2C and =3D, any other = is an error
+fn decode_saslname(name: &str) -> Option<String> {
+    let mut decoded = String::new();
+    let mut rest = name;
+    while let Some(index) = rest.find('=') {
+        decoded.push_str(&rest[..index]);
+        match rest.get(index..index + 3)? {
+            "=2C" => decoded.push(','),
+            "=3D" => decoded.push('='),
+            _ => return None,
+        }
+        rest = &rest[index + 3..];
+    }
+    decoded.push_str(rest);
+    Some(decoded)
+}
+
+// Stays the same for a given name while the process runs, so retrying doesn't show the account is missing
+fn fake_salt(username: &str) -> Vec<u8> {
+    static SECRET: OnceLock<[u8; 32]> = OnceLock::new();
+    hmac(SECRET.get_or_init(random::<32>), username.as_bytes())[..16].to_vec()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+// Nothing here can carry on safely without randomness, so running out is fatal
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    getrandom::fill(&mut bytes).expect("the OS random number generator failed");
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    // The example exchange from RFC 7677 section 3
+    struct RfcStore;
+
+    impl CredentialStore for RfcStore {
+        fn verify_password(&self, _: &str, _: &str) -> bool {
+            false
+        }
+
+        fn scram_sha256(&self, username: &str) -> Option<ScramCredentials> {
+            let salt = BASE64_STANDARD.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+            (username == "user").then(|| ScramCredentials::new("pencil", &salt, 4096))
+        }
+    }
+
+    const SERVER_NONCE: &str = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    const CLIENT_FINAL: &[u8] = b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+
+    fn challenge(step: Step) -> String {
+        match step {
+            Step::Challenge(challenge) => String::from_utf8(challenge).unwrap(),
+            step => panic!("expected a challenge, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn scram_follows_rfc_7677_example() {
+        let mut scram = Scram::default();
+        assert_eq!(scram.step(&RfcStore, None), Step::Challenge(Vec::new()));
+
+        let server_first =
+            scram.client_first(&RfcStore, b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO", SERVER_NONCE);
+        assert_eq!(
+            challenge(server_first),
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+        );
+
+        assert_eq!(
+            challenge(scram.step(&RfcStore, Some(CLIENT_FINAL))),
+            "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+        );
+        assert_eq!(
+            scram.step(&RfcStore, Some(b"")),
+            Step::Success(Identity {
+                authcid: "user".to_string(),
+                authzid: None,
+            })
+        );
+    }
+
+    #[test]
+    fn scram_rejects_wrong_proof() {
+        let mut scram = Scram::default();
+        scram.client_first(&RfcStore, b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO", SERVER_NONCE);
+        let wrong = String::from_utf8(CLIENT_FINAL.to_vec())
+            .unwrap()
+            .replace("p=dHzb", "p=dHzc");
+        assert_eq!(
+            scram.step(&RfcStore, Some(wrong.as_bytes())),
+            Step::Failure(Error::InvalidCredentials)
+        );
+    }
+
+    #[test]
+    fn scram_rejects_tampered_nonce() {
+        let mut scram = Scram::default();
+        scram.client_first(&RfcStore, b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO", "different");
+        assert_eq!(
+            scram.step(&RfcStore, Some(CLIENT_FINAL)),
+            Step::Failure(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn scram_hides_unknown_users() {
+        let salt = |username: &str| {
+            let mut scram = Scram::default();
+            let server_first = challenge(scram.step(
+                &RfcStore,
+                Some(format!("n,,n={},r=abcdef", username).as_bytes()),
+            ));
+            server_first.split(',').nth(1).unwrap().to_string()
+        };
+
+        // Same salt every time, just like a real account
+        assert_eq!(salt("nobody"), salt("nobody"));
+        assert_ne!(salt("nobody"), salt("somebody"));
+    }
+
+    #[test]
+    fn scram_refuses_channel_binding() {
+        let mut scram = Scram::default();
+        assert_eq!(
+            scram.step(&RfcStore, Some(b"p=tls-unique,,n=user,r=abcdef")),
+            Step::Failure(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn scram_decodes_names() {
+        assert_eq!(decode_saslname("a=2Cb=3Dc").unwrap(), "a,b=c");
+        assert!(decode_saslname("a=b").is_none());
+        assert!(decode_saslname("a=2").is_none());
+
+        let client_first = parse_client_first(b"n,a=admin=40x,n=user,r=abc");
+        assert!(client_first.is_none());
+        let client_first = parse_client_first(b"y,a=other@example.com,n=user,r=abc").unwrap();
+        assert_eq!(client_first.authzid.unwrap(), "other@example.com");
+        assert_eq!(client_first.gs2_header, "y,a=other@example.com,");
+    }
+
+    #[test]
+    fn scram_credentials_round_trip() {
+        let credentials = ScramCredentials::generate(PASSWORD);
+        assert_eq!(credentials.iterations, DEFAULT_ITERATIONS);
+        assert_eq!(
+            ScramCredentials::parse(&credentials.to_string()),
+            Some(credentials)
+        );
+        assert!(ScramCredentials::parse("SCRAM-SHA-1$4096:AAAA$AAAA:AAAA").is_none());
+
+        // The value from tests/config.toml
+        let salt: Vec<u8> = (0..16).collect();
+        assert_eq!(
+            ScramCredentials::new(PASSWORD, &salt, 4096).to_string(),
+            "SCRAM-SHA-256$4096:AAECAwQFBgcICQoLDA0ODw==$4PSH04DiBM59z6mw0gs6x1r6+duXYQ+R0KwGZr+W5/o=:IgPInY95tTazYxnARISZb/eTxuX/JRwWgrM9ByaOUIk="
+        );
+    }
+
+    #[test]
+    fn scram_works_with_stored_credentials() {
+        let mut scram = Scram::default();
+        let server_first =
+            challenge(scram.step(&TestStore, Some(b"n,,n=user@example.com,r=abcdef")));
+        assert!(server_first.starts_with("r=abcdef"));
+        assert!(server_first.ends_with(",i=4096"));
+    }
+}
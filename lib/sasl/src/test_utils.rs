@@ -0,0 +1,17 @@
+// An in-memory credential store, "user@example.com" with the password "password"
+use crate::{CredentialStore, ScramCredentials};
+
+pub const USERNAME: &str = "user@example.com";
+pub const PASSWORD: &str = "password";
+
+pub struct TestStore;
+
+impl CredentialStore for TestStore {
+    fn verify_password(&self, username: &str, password: &str) -> bool {
+        username == USERNAME && password == PASSWORD
+    }
+
+    fn scram_sha256(&self, username: &str) -> Option<ScramCredentials> {
+        (username == USERNAME).then(|| ScramCredentials::new(PASSWORD, &[7; 16], 4096))
+    }
+}
@@ -0,0 +1,189 @@
+// RFC 3461, what a sender asked to be told about their mail
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt};
+
+// The RET parameter from MAIL FROM, how much of the message goes back with a failure report
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Ret {
+    Full,
+    Headers,
+}
+
+impl Ret {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_uppercase().as_str() {
+            "FULL" => Some(Ret::Full),
+            "HDRS" => Some(Ret::Headers),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Ret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ret::Full => write!(f, "FULL"),
+            Ret::Headers => write!(f, "HDRS"),
+        }
+    }
+}
+
+// The NOTIFY parameter from RCPT TO, NEVER is all of these unset
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Notify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool,
+}
+
+// RFC 3461 4.1, a recipient without NOTIFY hears about failures and delays
+impl Default for Notify {
+    fn default() -> Self {
+        Self {
+            success: false,
+            failure: true,
+            delay: true,
+        }
+    }
+}
+
+impl Notify {
+    pub const NEVER: Notify = Notify {
+        success: false,
+        failure: false,
+        delay: false,
+    };
+
+    // NEVER has to be on its own, the others can be given in any combination
+    pub fn parse(value: &str) -> Option<Self> {
+        if value.eq_ignore_ascii_case("NEVER") {
+            return Some(Self::NEVER);
+        }
+
+        let mut notify = Self::NEVER;
+        for keyword in value.split(',') {
+            match keyword.to_ascii_uppercase().as_str() {
+                "SUCCESS" if !notify.success => notify.success = true,
+                "FAILURE" if !notify.failure => notify.failure = true,
+                "DELAY" if !notify.delay => notify.delay = true,
+                _ => return None,
+            }
+        }
+        Some(notify)
+    }
+}
+
+impl fmt::Display for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keywords: Vec<&str> = [
+            (self.success, "SUCCESS"),
+            (self.failure, "FAILURE"),
+            (self.delay, "DELAY"),
+        ]
+        .into_iter()
+        .filter_map(|(set, keyword)| set.then_some(keyword))
+        .collect();
+
+        if keywords.is_empty() {
+            write!(f, "NEVER")
+        } else {
+            write!(f, "{}", keywords.join(","))
+        }
+    }
+}
+
+// The parameters given with one RCPT TO, None where the client left them out
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecipientDsn {
+    pub notify: Option<Notify>,
+    // The address type and the address as the client first gave it, e.g. "rfc822;me@example.com", already decoded
+    pub orcpt: Option<String>,
+}
+
+// Everything given for one transaction, recipients are keyed by the address in their RCPT TO
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dsn {
+    pub ret: Option<Ret>,
+    // Already decoded from xtext
+    pub envid: Option<String>,
+    pub recipients: HashMap<String, RecipientDsn>,
+}
+
+impl Dsn {
+    pub fn recipient(&self, address: &str) -> RecipientDsn {
+        self.recipients.get(address).cloned().unwrap_or_default()
+    }
+}
+
+// RFC 3461 4, "+" followed by two uppercase hex digits for anything that isn't printable ASCII, "+" or "="
+pub fn decode_xtext(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => {
+                let high = hex_digit(input.next()?)?;
+                let low = hex_digit(input.next()?)?;
+                bytes.push(high << 4 | low);
+            }
+            b'=' => return None,
+            b'!'..=b'~' => bytes.push(byte),
+            _ => return None,
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// Only uppercase is allowed in xtext
+fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+pub fn encode_xtext(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'!'..=b'~' if byte != b'+' && byte != b'=' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("+{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xtext_round_trips() {
+        assert_eq!(encode_xtext("a+b=c d"), "a+2Bb+3Dc+20d");
+        assert_eq!(decode_xtext("a+2Bb+3Dc+20d").unwrap(), "a+b=c d");
+        assert_eq!(decode_xtext(&encode_xtext("jörg")).unwrap(), "jörg");
+        assert!(decode_xtext("a+2b").is_none());
+        assert!(decode_xtext("a+2").is_none());
+        assert!(decode_xtext("a=b").is_none());
+    }
+
+    #[test]
+    fn notify_parses_keywords() {
+        assert_eq!(Notify::parse("never"), Some(Notify::NEVER));
+        assert_eq!(
+            Notify::parse("SUCCESS,delay"),
+            Some(Notify {
+                success: true,
+                failure: false,
+                delay: true,
+            })
+        );
+        assert!(Notify::parse("NEVER,FAILURE").is_none());
+        assert!(Notify::parse("FAILURE,FAILURE").is_none());
+        assert!(Notify::parse("").is_none());
+        assert_eq!(Notify::default().to_string(), "FAILURE,DELAY");
+        assert_eq!(Notify::NEVER.to_string(), "NEVER");
+    }
+}
@@ -1,7 +1,77 @@
+use serde::{Deserialize, Serialize};
+
+pub use crate::dsn::{Dsn, Notify, RecipientDsn, Ret, decode_xtext, encode_xtext};
+
+mod dsn;
+
 #[derive(Clone, Copy)]
 pub struct SMTPPortConfiguration {
     pub auth_enabled: bool,
     pub filtering_enabled: bool,
     pub implicit_tls: bool,
     pub port: u16,
+
+    // Connection limits, enforced by the listener before a session is started
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+
+    pub vrfy_policy: VrfyPolicy,
+
+    // RFC 1870, in bytes
+    pub max_message_size: usize,
+
+    pub bare_lf_policy: BareLfPolicy,
+
+    // Refuse mail until the session is encrypted/authenticated, with a 530
+    pub require_tls: bool,
+    pub require_auth: bool,
+
+    // Hold the From: header of authenticated mail to the same rules as MAIL FROM
+    pub check_header_from: bool,
+}
+
+// How VRFY and EXPN are answered, most servers turn these off as they leak which mailboxes exist
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VrfyPolicy {
+    // 502, as if the command didn't exist
+    #[default]
+    Disabled,
+    // 252, neither confirms nor denies the mailbox
+    Ambiguous,
+    // 250/550, answers truthfully from the configured accounts
+    Enabled,
+}
+
+// What to do with message data that has a CR or LF on its own, rather than as a CRLF pair
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BareLfPolicy {
+    // Fail the whole message once the end of data is reached
+    Reject,
+    // Turn them into CRLF, the end of data marker still has to be a strict CRLF.CRLF
+    #[default]
+    Normalize,
+}
+
+// What a filtering listener does with mail that a check didn't like, ordered from mildest to harshest
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    Accept,
+    // Accept it, but with an X-Spam-Flag header so the mailbox can file it as junk
+    Tag,
+    // Refuse it during the SMTP transaction
+    Reject,
+}
+
+// The BODY= parameter from MAIL FROM, RFC 6152
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyType {
+    #[default]
+    SevenBit,
+    EightBitMime,
+    // RFC 3030, only allowed with BDAT
+    BinaryMime,
 }
@@ -0,0 +1,719 @@
+// SPF (RFC 7208), whether a host may send mail using a domain in MAIL FROM or HELO
+use std::{
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use eemail_lib_dns::{Error as DnsError, Resolver};
+
+use crate::{
+    macros::Context,
+    record::{Mechanism, Record},
+};
+
+mod macros;
+mod record;
+
+// RFC 7208 4.6.4, across the whole check including every include and redirect
+const MAX_LOOKUPS: usize = 10;
+// Lookups that find nothing, past this the record is likely being used to flood someone's DNS
+const MAX_VOID_LOOKUPS: usize = 2;
+// Per mx mechanism, and how many PTR names get checked for ptr and %{p}
+const MAX_NAMES: usize = 10;
+// RFC 7208 7.3, longer expansions lose labels off the left
+const MAX_DOMAIN_LENGTH: usize = 253;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    // No record, or the domain can't have one
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    // A DNS lookup failed, worth trying again later
+    TempError,
+    // The record is broken or needs too many lookups
+    PermError,
+}
+
+impl Outcome {
+    // As it appears in Authentication-Results and the config
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::None => "none",
+            Outcome::Neutral => "neutral",
+            Outcome::Pass => "pass",
+            Outcome::Fail => "fail",
+            Outcome::SoftFail => "softfail",
+            Outcome::TempError => "temperror",
+            Outcome::PermError => "permerror",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Evaluation {
+    pub outcome: Outcome,
+    // What went wrong, for temperror and permerror
+    pub reason: Option<String>,
+    // From the exp= of the record that failed the check (RFC 7208 6.2), meant for the sender
+    pub explanation: Option<String>,
+}
+
+impl Evaluation {
+    fn new(outcome: Outcome) -> Self {
+        Self {
+            outcome,
+            reason: None,
+            explanation: None,
+        }
+    }
+}
+
+// What check_host() is asked about, bar the domain, which is taken from `sender`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub ip: IpAddr,
+    // Always has a local part, postmaster if the identity had none
+    pub sender: String,
+    pub helo: String,
+    // Our own name, for %{r} in explanations
+    pub receiver: String,
+}
+
+impl Query {
+    // RFC 7208 2.4, the null reverse path is checked as postmaster@<helo>
+    pub fn mail_from(ip: IpAddr, helo: &str, sender: &str, receiver: &str) -> Self {
+        let sender = match sender {
+            "" => format!("postmaster@{}", helo),
+            sender if !sender.contains('@') => format!("postmaster@{}", sender),
+            sender => sender.to_string(),
+        };
+        Self {
+            ip,
+            sender,
+            helo: helo.to_string(),
+            receiver: receiver.to_string(),
+        }
+    }
+
+    // RFC 7208 2.3
+    pub fn helo(ip: IpAddr, helo: &str, receiver: &str) -> Self {
+        Self::mail_from(ip, helo, "", receiver)
+    }
+
+    pub fn domain(&self) -> &str {
+        self.sender
+            .rsplit_once('@')
+            .map_or(self.sender.as_str(), |(_, domain)| domain)
+    }
+}
+
+// RFC 7208 4, check_host() for the domain of `query.sender`
+pub async fn check_host(resolver: &dyn Resolver, query: &Query) -> Evaluation {
+    let mut evaluator = Evaluator {
+        resolver,
+        query,
+        // RFC 7208 5, an IPv4 client that reached us over IPv6 is still checked as IPv4
+        ip: match query.ip {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(query.ip, IpAddr::V4),
+            ip => ip,
+        },
+        lookups: 0,
+        void_lookups: 0,
+        now: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+    match evaluator.check_host(query.domain().to_string()).await {
+        Ok(evaluation) => evaluation,
+        Err(Abort(outcome, reason)) => Evaluation {
+            outcome,
+            reason: Some(reason),
+            explanation: None,
+        },
+    }
+}
+
+// Stops the whole check with a temperror or permerror
+struct Abort(Outcome, String);
+
+impl Abort {
+    fn temporary(reason: impl Into<String>) -> Self {
+        Abort(Outcome::TempError, reason.into())
+    }
+
+    fn permanent(reason: impl Into<String>) -> Self {
+        Abort(Outcome::PermError, reason.into())
+    }
+}
+
+struct Evaluator<'a> {
+    resolver: &'a dyn Resolver,
+    query: &'a Query,
+    ip: IpAddr,
+    lookups: usize,
+    void_lookups: usize,
+    now: u64,
+}
+
+impl<'a> Evaluator<'a> {
+    // Boxed as include and redirect come back round to it
+    fn check_host(
+        &mut self,
+        domain: String,
+    ) -> Pin<Box<dyn Future<Output = Result<Evaluation, Abort>> + Send + '_>> {
+        Box::pin(async move {
+            // RFC 7208 4.3
+            if !valid_domain(&domain) {
+                return Ok(Evaluation::new(Outcome::None));
+            }
+            let records = match self.resolver.txt(&domain).await {
+                Ok(records) => records,
+                Err(DnsError::NoSuchDomain) => return Ok(Evaluation::new(Outcome::None)),
+                Err(DnsError::Temporary(reason)) => {
+                    return Err(Abort::temporary(format!("{}: {}", domain, reason)));
+                }
+            };
+            let record = match record::select(&records) {
+                Ok(Some(record)) => record::parse(record)
+                    .map_err(|reason| Abort::permanent(format!("{}: {}", domain, reason)))?,
+                Ok(None) => return Ok(Evaluation::new(Outcome::None)),
+                Err(reason) => return Err(Abort::permanent(format!("{}: {}", domain, reason))),
+            };
+
+            self.evaluate(&domain, &record).await
+        })
+    }
+
+    // RFC 7208 4.6
+    async fn evaluate(&mut self, domain: &str, record: &Record) -> Result<Evaluation, Abort> {
+        for (qualifier, mechanism) in &record.directives {
+            if self.matches(domain, mechanism).await? {
+                let mut evaluation = Evaluation::new(*qualifier);
+                if *qualifier == Outcome::Fail
+                    && let Some(explanation) = &record.explanation
+                {
+                    evaluation.explanation = self.explain(domain, explanation).await;
+                }
+                return Ok(evaluation);
+            }
+        }
+
+        // RFC 7208 6.1, only reached when nothing matched, so an `all` anywhere means it's ignored
+        let Some(redirect) = &record.redirect else {
+            return Ok(Evaluation::new(Outcome::Neutral));
+        };
+        self.count_lookup()?;
+        let target = self.domain_spec(redirect, domain).await?;
+        let evaluation = self.check_host(target.clone()).await?;
+        if evaluation.outcome == Outcome::None {
+            return Err(Abort::permanent(format!(
+                "redirect to {} which has no SPF record",
+                target
+            )));
+        }
+        Ok(evaluation)
+    }
+
+    // RFC 7208 5
+    async fn matches(&mut self, domain: &str, mechanism: &Mechanism) -> Result<bool, Abort> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip(network, prefix) => Ok(in_network(self.ip, *network, *prefix)),
+            Mechanism::Include(spec) => {
+                self.count_lookup()?;
+                let target = self.domain_spec(spec, domain).await?;
+                let evaluation = self.check_host(target.clone()).await?;
+                match evaluation.outcome {
+                    Outcome::Pass => Ok(true),
+                    Outcome::Fail | Outcome::SoftFail | Outcome::Neutral => Ok(false),
+                    Outcome::None => Err(Abort::permanent(format!(
+                        "include of {} which has no SPF record",
+                        target
+                    ))),
+                    // Any error from inside the include has already stopped the check
+                    Outcome::TempError | Outcome::PermError => Ok(false),
+                }
+            }
+            Mechanism::A(spec, v4, v6) => {
+                self.count_lookup()?;
+                let target = self.target(spec, domain).await?;
+                let addresses = self.addresses(&target, true).await?;
+                Ok(addresses
+                    .into_iter()
+                    .any(|address| self.in_dual_network(address, *v4, *v6)))
+            }
+            Mechanism::Mx(spec, v4, v6) => {
+                self.count_lookup()?;
+                let target = self.target(spec, domain).await?;
+                let exchanges = match self.resolver.mx(&target).await {
+                    Ok(exchanges) => exchanges,
+                    Err(DnsError::NoSuchDomain) => Vec::new(),
+                    Err(DnsError::Temporary(reason)) => {
+                        return Err(Abort::temporary(format!("{}: {}", target, reason)));
+                    }
+                };
+                if exchanges.is_empty() {
+                    self.count_void()?;
+                }
+                if exchanges.len() > MAX_NAMES {
+                    return Err(Abort::permanent(format!(
+                        "{} has more than {} MX records",
+                        target, MAX_NAMES
+                    )));
+                }
+                for exchange in exchanges {
+                    let exchange = exchange.exchange.trim_end_matches('.');
+                    if exchange.is_empty() {
+                        continue;
+                    }
+                    let addresses = self.addresses(exchange, false).await?;
+                    if addresses
+                        .into_iter()
+                        .any(|address| self.in_dual_network(address, *v4, *v6))
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(spec) => {
+                self.count_lookup()?;
+                let target = self.target(spec, domain).await?;
+                Ok(self
+                    .validated_names()
+                    .await
+                    .iter()
+                    .any(|name| is_within(name, &target)))
+            }
+            Mechanism::Exists(spec) => {
+                self.count_lookup()?;
+                let target = self.domain_spec(spec, domain).await?;
+                // Always an A lookup, whatever the client's address is
+                let addresses = self.addresses(&target, true).await?;
+                Ok(addresses.iter().any(IpAddr::is_ipv4))
+            }
+        }
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Abort> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(Abort::permanent(format!(
+                "more than {} DNS lookups",
+                MAX_LOOKUPS
+            )));
+        }
+        Ok(())
+    }
+
+    fn count_void(&mut self) -> Result<(), Abort> {
+        self.void_lookups += 1;
+        if self.void_lookups > MAX_VOID_LOOKUPS {
+            return Err(Abort::permanent(format!(
+                "more than {} lookups found nothing",
+                MAX_VOID_LOOKUPS
+            )));
+        }
+        Ok(())
+    }
+
+    // `void` says whether finding no addresses counts towards MAX_VOID_LOOKUPS
+    async fn addresses(&mut self, name: &str, void: bool) -> Result<Vec<IpAddr>, Abort> {
+        let addresses = match self.resolver.ip(name).await {
+            Ok(addresses) => addresses,
+            Err(DnsError::NoSuchDomain) => Vec::new(),
+            Err(DnsError::Temporary(reason)) => {
+                return Err(Abort::temporary(format!("{}: {}", name, reason)));
+            }
+        };
+        if void && addresses.is_empty() {
+            self.count_void()?;
+        }
+        Ok(addresses)
+    }
+
+    fn in_dual_network(&self, address: IpAddr, v4: u8, v6: u8) -> bool {
+        let prefix = if address.is_ipv4() { v4 } else { v6 };
+        in_network(self.ip, address, prefix)
+    }
+
+    // RFC 7208 5.5, names from the PTR records that lead back to the client's address
+    async fn validated_names(&self) -> Vec<String> {
+        let names = self.resolver.ptr(self.ip).await.unwrap_or_default();
+        let mut validated = Vec::new();
+        for name in names.into_iter().take(MAX_NAMES) {
+            if let Ok(addresses) = self.resolver.ip(&name).await
+                && addresses.contains(&self.ip)
+            {
+                validated.push(name);
+            }
+        }
+        validated
+    }
+
+    // a, mx and ptr check the current domain unless they say otherwise
+    async fn target(&self, spec: &Option<String>, domain: &str) -> Result<String, Abort> {
+        match spec {
+            Some(spec) => self.domain_spec(spec, domain).await,
+            None => Ok(domain.to_string()),
+        }
+    }
+
+    async fn domain_spec(&self, spec: &str, domain: &str) -> Result<String, Abort> {
+        let mut expanded = self
+            .expand(spec, domain, false)
+            .await
+            .map_err(Abort::permanent)?;
+        while expanded.len() > MAX_DOMAIN_LENGTH {
+            match expanded.split_once('.') {
+                Some((_, rest)) => expanded = rest.to_string(),
+                None => break,
+            }
+        }
+        Ok(expanded)
+    }
+
+    async fn expand(&self, spec: &str, domain: &str, explanation: bool) -> Result<String, String> {
+        // RFC 7208 7.3, p is the validated name in `domain` if there is one, any validated name otherwise
+        let validated = if macros::uses_validated(spec) {
+            let names = self.validated_names().await;
+            names
+                .iter()
+                .find(|name| is_within(name, domain))
+                .or(names.first())
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_string())
+        } else {
+            String::new()
+        };
+        let context = Context {
+            sender: &self.query.sender,
+            domain,
+            ip: self.ip,
+            helo: &self.query.helo,
+            receiver: &self.query.receiver,
+            validated: &validated,
+            now: self.now,
+        };
+        macros::expand(spec, &context, explanation)
+    }
+
+    // RFC 7208 6.2, any trouble getting the explanation just means there isn't one
+    async fn explain(&self, domain: &str, spec: &str) -> Option<String> {
+        let target = self.domain_spec(spec, domain).await.ok()?;
+        let records = self.resolver.txt(&target).await.ok()?;
+        let [record] = &records[..] else {
+            return None;
+        };
+        self.expand(record, domain, true).await.ok()
+    }
+}
+
+// RFC 7208 4.3, a name that can't be looked up at all has no record
+fn valid_domain(domain: &str) -> bool {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    let labels: Vec<&str> = domain.split('.').collect();
+    domain.len() <= MAX_DOMAIN_LENGTH
+        && labels.len() > 1
+        && labels
+            .iter()
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+fn is_within(name: &str, domain: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    let domain = domain.trim_end_matches('.');
+    name.eq_ignore_ascii_case(domain)
+        || name
+            .to_ascii_lowercase()
+            .ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eemail_lib_dns::StaticResolver;
+
+    use super::*;
+
+    // The zone from RFC 7208 appendix A, the SPF record at example.com is left to each test
+    fn zone() -> StaticResolver {
+        StaticResolver::default()
+            .ip("example.com", "192.0.2.10".parse().unwrap())
+            .ip("example.com", "192.0.2.11".parse().unwrap())
+            .ip("amy.example.com", "192.0.2.65".parse().unwrap())
+            .ip("bob.example.com", "192.0.2.66".parse().unwrap())
+            .ip("mail-a.example.com", "192.0.2.129".parse().unwrap())
+            .ip("mail-b.example.com", "192.0.2.130".parse().unwrap())
+            .ip("mail-c.example.org", "192.0.2.140".parse().unwrap())
+            .mx("example.com", 10, "mail-a.example.com")
+            .mx("example.com", 20, "mail-b.example.com")
+            .mx("example.org", 10, "mail-c.example.org")
+            .ptr("192.0.2.10".parse().unwrap(), "example.com")
+            .ptr("192.0.2.11".parse().unwrap(), "example.com")
+            .ptr("192.0.2.65".parse().unwrap(), "amy.example.com")
+            .ptr("192.0.2.66".parse().unwrap(), "bob.example.com")
+            .ptr("192.0.2.129".parse().unwrap(), "mail-a.example.com")
+            .ptr("192.0.2.130".parse().unwrap(), "mail-b.example.com")
+            .ptr("192.0.2.140".parse().unwrap(), "mail-c.example.org")
+            .ptr("10.0.0.4".parse().unwrap(), "bob.example.com")
+    }
+
+    async fn outcome(resolver: &StaticResolver, ip: &str) -> Outcome {
+        let query = Query::mail_from(
+            ip.parse().unwrap(),
+            "client.example.net",
+            "sender@example.com",
+            "mail.example.net",
+        );
+        check_host(resolver, &query).await.outcome
+    }
+
+    // RFC 7208 A.1 to A.4, each record with addresses it should and shouldn't pass
+    #[tokio::test]
+    async fn rfc_examples() {
+        for (record, passes, fails) in [
+            ("v=spf1 +all", &["192.0.2.1"][..], &[][..]),
+            (
+                "v=spf1 a -all",
+                &["192.0.2.10", "192.0.2.11"],
+                &["192.0.2.65"],
+            ),
+            ("v=spf1 a:example.org -all", &[], &["192.0.2.10"]),
+            (
+                "v=spf1 mx -all",
+                &["192.0.2.129", "192.0.2.130"],
+                &["192.0.2.140"],
+            ),
+            (
+                "v=spf1 mx:example.org -all",
+                &["192.0.2.140"],
+                &["192.0.2.129"],
+            ),
+            (
+                "v=spf1 mx mx:example.org -all",
+                &["192.0.2.129", "192.0.2.140"],
+                &["192.0.2.10"],
+            ),
+            (
+                "v=spf1 mx/30 mx:example.org/30 -all",
+                &["192.0.2.131", "192.0.2.143"],
+                &["192.0.2.10"],
+            ),
+            (
+                "v=spf1 ptr -all",
+                &["192.0.2.10", "192.0.2.65"],
+                &["192.0.2.140", "10.0.0.4"],
+            ),
+            (
+                "v=spf1 ip4:192.0.2.128/28 -all",
+                &["192.0.2.129", "192.0.2.143"],
+                &["192.0.2.65", "192.0.2.10"],
+            ),
+        ] {
+            let resolver = zone().txt("example.com", record);
+            for ip in passes {
+                assert_eq!(
+                    outcome(&resolver, ip).await,
+                    Outcome::Pass,
+                    "{} {}",
+                    record,
+                    ip
+                );
+            }
+            for ip in fails {
+                assert_eq!(
+                    outcome(&resolver, ip).await,
+                    Outcome::Fail,
+                    "{} {}",
+                    record,
+                    ip
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn qualifiers_and_defaults() {
+        let resolver = zone().txt("example.com", "v=spf1 ?a ~mx");
+        assert_eq!(outcome(&resolver, "192.0.2.10").await, Outcome::Neutral);
+        assert_eq!(outcome(&resolver, "192.0.2.129").await, Outcome::SoftFail);
+        // Nothing matched and there's no redirect
+        assert_eq!(outcome(&resolver, "192.0.2.200").await, Outcome::Neutral);
+
+        let ipv6 = zone().txt("example.com", "v=spf1 ip6:2001:db8::/32 -all");
+        assert_eq!(outcome(&ipv6, "2001:db8::1").await, Outcome::Pass);
+        assert_eq!(outcome(&ipv6, "2001:db9::1").await, Outcome::Fail);
+        let mapped = zone().txt("example.com", "v=spf1 ip4:192.0.2.1 -all");
+        assert_eq!(outcome(&mapped, "::ffff:192.0.2.1").await, Outcome::Pass);
+    }
+
+    #[tokio::test]
+    async fn missing_and_broken_records() {
+        assert_eq!(outcome(&zone(), "192.0.2.10").await, Outcome::None);
+        assert_eq!(
+            outcome(&StaticResolver::default(), "192.0.2.10").await,
+            Outcome::None
+        );
+        let two = zone()
+            .txt("example.com", "v=spf1 -all")
+            .txt("example.com", "v=spf1 +all");
+        assert_eq!(outcome(&two, "192.0.2.10").await, Outcome::PermError);
+        let broken = zone().txt("example.com", "v=spf1 a:");
+        assert_eq!(outcome(&broken, "192.0.2.10").await, Outcome::PermError);
+        let failing = zone().fail("example.com");
+        assert_eq!(outcome(&failing, "192.0.2.10").await, Outcome::TempError);
+    }
+
+    #[tokio::test]
+    async fn include_and_redirect() {
+        let resolver = zone()
+            .txt("example.com", "v=spf1 include:_spf.example.org -all")
+            .txt("_spf.example.org", "v=spf1 ip4:192.0.2.1 ~all");
+        assert_eq!(outcome(&resolver, "192.0.2.1").await, Outcome::Pass);
+        // The include's ~all is just a non match, the outer -all decides
+        assert_eq!(outcome(&resolver, "192.0.2.2").await, Outcome::Fail);
+
+        let redirected = zone()
+            .txt(
+                "example.com",
+                "v=spf1 ip4:192.0.2.1 redirect=_spf.example.org",
+            )
+            .txt("_spf.example.org", "v=spf1 ip4:192.0.2.2 ~all");
+        assert_eq!(outcome(&redirected, "192.0.2.1").await, Outcome::Pass);
+        assert_eq!(outcome(&redirected, "192.0.2.2").await, Outcome::Pass);
+        assert_eq!(outcome(&redirected, "192.0.2.3").await, Outcome::SoftFail);
+
+        let dangling = zone().txt("example.com", "v=spf1 include:nothing.example.org -all");
+        assert_eq!(outcome(&dangling, "192.0.2.1").await, Outcome::PermError);
+        let redirect_to_nothing = zone().txt("example.com", "v=spf1 redirect=nothing.example.org");
+        assert_eq!(
+            outcome(&redirect_to_nothing, "192.0.2.1").await,
+            Outcome::PermError
+        );
+        // all wins over redirect, wherever it is
+        let ignored = zone().txt("example.com", "v=spf1 redirect=nothing.example.org -all");
+        assert_eq!(outcome(&ignored, "192.0.2.1").await, Outcome::Fail);
+    }
+
+    #[tokio::test]
+    async fn lookup_limits_are_enforced() {
+        let mut resolver = zone().txt(
+            "example.com",
+            "v=spf1 include:l1.example.org include:l2.example.org -all",
+        );
+        for n in 1..=10 {
+            resolver = resolver.txt(
+                &format!("l{}.example.org", n),
+                &format!(
+                    "v=spf1 a:example.com mx:example.com include:l{}.example.org",
+                    n + 2
+                ),
+            );
+        }
+        let query = Query::mail_from(
+            "192.0.2.200".parse().unwrap(),
+            "client.example.net",
+            "sender@example.com",
+            "mail.example.net",
+        );
+        let evaluation = check_host(&resolver, &query).await;
+        assert_eq!(evaluation.outcome, Outcome::PermError);
+        assert_eq!(
+            evaluation.reason.as_deref(),
+            Some("more than 10 DNS lookups")
+        );
+
+        let void = zone().txt(
+            "example.com",
+            "v=spf1 a:none1.example.org a:none2.example.org a:none3.example.org -all",
+        );
+        let evaluation = check_host(&void, &query).await;
+        assert_eq!(evaluation.outcome, Outcome::PermError);
+        assert_eq!(
+            evaluation.reason.as_deref(),
+            Some("more than 2 lookups found nothing")
+        );
+    }
+
+    #[tokio::test]
+    async fn macros_and_explanations() {
+        let resolver = zone()
+            .txt(
+                "example.com",
+                "v=spf1 exists:%{l}.%{ir}.allow.example.com -all exp=why.%{d}",
+            )
+            .ip(
+                "sender.1.2.0.192.allow.example.com",
+                "127.0.0.2".parse().unwrap(),
+            )
+            .txt("why.example.com", "%{c} may not send as %{s}");
+        assert_eq!(outcome(&resolver, "192.0.2.1").await, Outcome::Pass);
+
+        let query = Query::mail_from(
+            "192.0.2.5".parse().unwrap(),
+            "client.example.net",
+            "sender@example.com",
+            "mail.example.net",
+        );
+        let evaluation = check_host(&resolver, &query).await;
+        assert_eq!(evaluation.outcome, Outcome::Fail);
+        assert_eq!(
+            evaluation.explanation.as_deref(),
+            Some("192.0.2.5 may not send as sender@example.com")
+        );
+
+        let validated = zone()
+            .txt("example.com", "v=spf1 exists:%{p}.ok.example.com -all")
+            .ip(
+                "amy.example.com.ok.example.com",
+                "127.0.0.2".parse().unwrap(),
+            );
+        assert_eq!(outcome(&validated, "192.0.2.65").await, Outcome::Pass);
+        assert_eq!(outcome(&validated, "192.0.2.66").await, Outcome::Fail);
+    }
+
+    #[test]
+    fn identities_always_have_a_local_part() {
+        let ip = "192.0.2.1".parse().unwrap();
+        let helo = Query::helo(ip, "mx.example.org", "mail.example.net");
+        assert_eq!(helo.sender, "postmaster@mx.example.org");
+        assert_eq!(helo.domain(), "mx.example.org");
+        let null = Query::mail_from(ip, "mx.example.org", "", "mail.example.net");
+        assert_eq!(null, helo);
+        let bare = Query::mail_from(ip, "mx.example.org", "example.com", "mail.example.net");
+        assert_eq!(bare.sender, "postmaster@example.com");
+    }
+
+    #[test]
+    fn networks_match_on_their_prefix() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert!(in_network(ip("192.0.2.129"), ip("192.0.2.128"), 28));
+        assert!(!in_network(ip("192.0.2.145"), ip("192.0.2.128"), 28));
+        assert!(in_network(ip("10.0.0.1"), ip("192.0.2.128"), 0));
+        assert!(in_network(ip("2001:db8::1"), ip("2001:db8::"), 32));
+        assert!(!in_network(ip("192.0.2.1"), ip("::"), 0));
+        assert!(!valid_domain("localhost"));
+        assert!(!valid_domain("a..example"));
+        assert!(valid_domain("example.com."));
+    }
+}
@@ -0,0 +1,237 @@
+// RFC 7208 7, the %{...} macros in domain specs and explanations
+use std::net::IpAddr;
+
+// The characters a macro can be split on, `.` when none are given
+const DELIMITERS: &str = ".-+,/_=";
+
+// What the macro letters stand for during one check
+pub(crate) struct Context<'a> {
+    // s, always with a local part by now
+    pub sender: &'a str,
+    // d, the domain whose record is being evaluated
+    pub domain: &'a str,
+    pub ip: IpAddr,
+    pub helo: &'a str,
+    pub receiver: &'a str,
+    // p, only looked up by the caller when the string asks for it
+    pub validated: &'a str,
+    pub now: u64,
+}
+
+// Explanations (RFC 7208 6.2) can also use c, r and t, and may have spaces in them
+pub(crate) fn expand(spec: &str, context: &Context, explanation: bool) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut chars = spec.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            let allowed = ('\x21'..='\x7e').contains(&c) || (c == ' ' && explanation);
+            if !allowed {
+                return Err(format!("invalid character {:?} in {}", c, spec));
+            }
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some('_') => expanded.push(' '),
+            Some('-') => expanded.push_str("%20"),
+            Some('{') => {
+                let mut body = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => body.push(c),
+                        None => return Err(format!("unterminated macro in {}", spec)),
+                    }
+                }
+                expanded.push_str(&value(&body, context, explanation)?);
+            }
+            _ => return Err(format!("stray % in {}", spec)),
+        }
+    }
+    Ok(expanded)
+}
+
+// Whether `spec` needs %{p}, so the PTR lookups are only made when they're used
+pub(crate) fn uses_validated(spec: &str) -> bool {
+    spec.contains("%{p") || spec.contains("%{P")
+}
+
+// One macro, `body` is what was between the braces: a letter, then *DIGIT ["r"] *delimiter
+fn value(body: &str, context: &Context, explanation: bool) -> Result<String, String> {
+    let mut chars = body.chars();
+    let letter = chars.next().ok_or_else(|| "empty macro".to_string())?;
+    let (local_part, sender_domain) = context
+        .sender
+        .rsplit_once('@')
+        .unwrap_or(("postmaster", context.sender));
+
+    let value = match letter.to_ascii_lowercase() {
+        's' => context.sender.to_string(),
+        'l' => local_part.to_string(),
+        'o' => sender_domain.to_string(),
+        'd' => context.domain.to_string(),
+        'i' => dotted(context.ip),
+        'p' => context.validated.to_string(),
+        'v' if context.ip.is_ipv4() => "in-addr".to_string(),
+        'v' => "ip6".to_string(),
+        'h' => context.helo.to_string(),
+        'c' if explanation => context.ip.to_string(),
+        'r' if explanation => context.receiver.to_string(),
+        't' if explanation => context.now.to_string(),
+        _ => return Err(format!("unknown macro letter {}", letter)),
+    };
+
+    let rest = chars.as_str();
+    let (digits, rest) = rest.split_at(
+        rest.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len()),
+    );
+    let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+        Some(delimiters) => (true, delimiters),
+        None => (false, rest),
+    };
+    if !delimiters.chars().all(|c| DELIMITERS.contains(c)) {
+        return Err(format!("invalid macro {{{}}}", body));
+    }
+    let delimiters = if delimiters.is_empty() {
+        "."
+    } else {
+        delimiters
+    };
+
+    let mut parts: Vec<&str> = value.split(|c| delimiters.contains(c)).collect();
+    if reverse {
+        parts.reverse();
+    }
+    if !digits.is_empty() {
+        let keep: usize = match digits.parse() {
+            Ok(0) | Err(_) => return Err(format!("invalid macro {{{}}}", body)),
+            Ok(keep) => keep,
+        };
+        if keep < parts.len() {
+            parts = parts.split_off(parts.len() - keep);
+        }
+    }
+
+    let value = parts.join(".");
+    Ok(if letter.is_ascii_uppercase() {
+        escape(&value)
+    } else {
+        value
+    })
+}
+
+// i, IPv6 addresses are written out as dot separated nibbles
+fn dotted(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0xf])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
+
+// Uppercase macro letters are URL escaped, everything but the RFC 3986 unreserved characters
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(ip: &str) -> Context<'static> {
+        Context {
+            sender: "strong-bad@email.example.com",
+            domain: "email.example.com",
+            ip: ip.parse().unwrap(),
+            helo: "mx.example.org",
+            receiver: "mail.example.net",
+            validated: "unknown",
+            now: 1000,
+        }
+    }
+
+    // RFC 7208 7.4
+    #[test]
+    fn macros_expand_like_the_rfc_examples() {
+        let ipv6 = context("2001:db8::cb01");
+        let context = context("192.0.2.3");
+        for (spec, expanded) in [
+            ("%{s}", "strong-bad@email.example.com"),
+            ("%{o}", "email.example.com"),
+            ("%{d}", "email.example.com"),
+            ("%{d4}", "email.example.com"),
+            ("%{d3}", "email.example.com"),
+            ("%{d2}", "example.com"),
+            ("%{d1}", "com"),
+            ("%{dr}", "com.example.email"),
+            ("%{d2r}", "example.email"),
+            ("%{l}", "strong-bad"),
+            ("%{l-}", "strong.bad"),
+            ("%{lr}", "strong-bad"),
+            ("%{lr-}", "bad.strong"),
+            ("%{l1r-}", "strong"),
+            (
+                "%{ir}.%{v}._spf.%{d2}",
+                "3.2.0.192.in-addr._spf.example.com",
+            ),
+            ("%{lr-}.lp._spf.%{d2}", "bad.strong.lp._spf.example.com"),
+            (
+                "%{lr-}.lp.%{ir}.%{v}._spf.%{d2}",
+                "bad.strong.lp.3.2.0.192.in-addr._spf.example.com",
+            ),
+            (
+                "%{ir}.%{v}.%{l1r-}.lp._spf.%{d2}",
+                "3.2.0.192.in-addr.strong.lp._spf.example.com",
+            ),
+            (
+                "%{d2}.trusted-domains.example.net",
+                "example.com.trusted-domains.example.net",
+            ),
+        ] {
+            assert_eq!(expand(spec, &context, false).unwrap(), expanded, "{}", spec);
+        }
+
+        assert_eq!(
+            expand("%{ir}.%{v}._spf.%{d2}", &ipv6, false).unwrap(),
+            "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com"
+        );
+    }
+
+    #[test]
+    fn explanations_have_more_letters() {
+        let context = context("192.0.2.3");
+        assert_eq!(
+            expand("%{c} is not allowed by %{r} at %{t}%_%%%-", &context, true).unwrap(),
+            "192.0.2.3 is not allowed by mail.example.net at 1000 %%20"
+        );
+        assert!(expand("%{c}", &context, false).is_err());
+        assert!(expand("a b", &context, false).is_err());
+        assert_eq!(
+            expand("%{S}", &context, false).unwrap(),
+            "strong-bad%40email.example.com"
+        );
+    }
+
+    #[test]
+    fn malformed_macros_are_errors() {
+        let context = context("192.0.2.3");
+        for spec in ["%{d0}", "%{x}", "%{d", "%a", "%{d2r*}", "%{}"] {
+            assert!(expand(spec, &context, false).is_err(), "{}", spec);
+        }
+    }
+}
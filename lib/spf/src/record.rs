@@ -0,0 +1,262 @@
+// RFC 7208 4.5 and 12, picking out a domain's SPF record and parsing it
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::{Outcome, macros::Context};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Mechanism {
+    All,
+    Include(String),
+    // Optional domain spec, then the IPv4 and IPv6 prefix lengths
+    A(Option<String>, u8, u8),
+    Mx(Option<String>, u8, u8),
+    Ptr(Option<String>),
+    Ip(IpAddr, u8),
+    Exists(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    // Tried in order, the first to match decides the result
+    pub directives: Vec<(Outcome, Mechanism)>,
+    pub redirect: Option<String>,
+    pub explanation: Option<String>,
+}
+
+// The one record out of all the TXT records at a name. Ok(None) when there isn't one, an error when there's more
+pub(crate) fn select(records: &[String]) -> Result<Option<&str>, String> {
+    let mut spf = records.iter().filter(|record| {
+        record
+            .get(..6)
+            .is_some_and(|version| version.eq_ignore_ascii_case("v=spf1"))
+            && record[6..].chars().next().is_none_or(|c| c == ' ')
+    });
+    match (spf.next(), spf.next()) {
+        (None, _) => Ok(None),
+        (Some(record), None) => Ok(Some(record)),
+        (Some(_), Some(_)) => Err("more than one SPF record".to_string()),
+    }
+}
+
+// Any syntax error anywhere makes the whole record a permerror, even in terms that would never be reached
+pub(crate) fn parse(record: &str) -> Result<Record, String> {
+    let mut parsed = Record {
+        directives: Vec::new(),
+        redirect: None,
+        explanation: None,
+    };
+
+    for term in record.split(' ').skip(1).filter(|term| !term.is_empty()) {
+        if let Some((name, value)) = modifier(term) {
+            check_macros(value)?;
+            let slot = match name.to_ascii_lowercase().as_str() {
+                "redirect" => &mut parsed.redirect,
+                "exp" => &mut parsed.explanation,
+                // RFC 7208 6, unknown modifiers are ignored
+                _ => continue,
+            };
+            if slot.replace(value.to_string()).is_some() {
+                return Err(format!("more than one {}= modifier", name));
+            }
+            continue;
+        }
+
+        let (qualifier, rest) = match term.chars().next() {
+            Some('+') => (Outcome::Pass, &term[1..]),
+            Some('-') => (Outcome::Fail, &term[1..]),
+            Some('~') => (Outcome::SoftFail, &term[1..]),
+            Some('?') => (Outcome::Neutral, &term[1..]),
+            _ => (Outcome::Pass, term),
+        };
+        parsed.directives.push((qualifier, mechanism(rest)?));
+    }
+    Ok(parsed)
+}
+
+// name=value, where the name is ALPHA *( ALPHA / DIGIT / "-" / "_" / "." )
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    let mut chars = name.chars();
+    (chars.next()?.is_ascii_alphabetic()
+        && chars.all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)))
+    .then_some((name, value))
+}
+
+fn mechanism(term: &str) -> Result<Mechanism, String> {
+    let split = term.find([':', '/']).unwrap_or(term.len());
+    let (name, rest) = term.split_at(split);
+    // The domain spec after a colon, which some mechanisms must have and others may
+    let argument = rest.strip_prefix(':');
+
+    let mechanism = match name.to_ascii_lowercase().as_str() {
+        "all" if rest.is_empty() => Mechanism::All,
+        "include" => Mechanism::Include(domain_spec(argument)?),
+        "exists" => Mechanism::Exists(domain_spec(argument)?),
+        "ptr" => Mechanism::Ptr(argument.map(|spec| domain_spec(Some(spec))).transpose()?),
+        "a" | "mx" => {
+            let (spec, v4, v6) = dual_cidr(argument.unwrap_or(rest))?;
+            let spec = match argument {
+                Some(_) => Some(domain_spec(Some(spec))?),
+                None if spec.is_empty() => None,
+                None => return Err(format!("invalid mechanism {}", term)),
+            };
+            if name.eq_ignore_ascii_case("a") {
+                Mechanism::A(spec, v4, v6)
+            } else {
+                Mechanism::Mx(spec, v4, v6)
+            }
+        }
+        "ip4" => {
+            let (address, prefix) = network(argument, 32, term)?;
+            let address: Ipv4Addr = address
+                .parse()
+                .map_err(|_| format!("invalid address in {}", term))?;
+            Mechanism::Ip(IpAddr::V4(address), prefix)
+        }
+        "ip6" => {
+            let (address, prefix) = network(argument, 128, term)?;
+            let address: Ipv6Addr = address
+                .parse()
+                .map_err(|_| format!("invalid address in {}", term))?;
+            Mechanism::Ip(IpAddr::V6(address), prefix)
+        }
+        _ => return Err(format!("unknown mechanism {}", term)),
+    };
+    Ok(mechanism)
+}
+
+fn domain_spec(spec: Option<&str>) -> Result<String, String> {
+    match spec {
+        Some(spec) if !spec.is_empty() => {
+            check_macros(spec)?;
+            Ok(spec.to_string())
+        }
+        _ => Err("missing domain".to_string()),
+    }
+}
+
+// Expanding against made up values is enough to find any syntax errors
+fn check_macros(spec: &str) -> Result<(), String> {
+    let context = Context {
+        sender: "postmaster@example.com",
+        domain: "example.com",
+        ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        helo: "example.com",
+        receiver: "example.com",
+        validated: "example.com",
+        now: 0,
+    };
+    crate::macros::expand(spec, &context, false).map(|_| ())
+}
+
+// [ "/" ip4-cidr-length ] [ "//" ip6-cidr-length ] at the end of an a or mx
+fn dual_cidr(spec: &str) -> Result<(&str, u8, u8), String> {
+    let (spec, v6) = match spec.rsplit_once("//") {
+        Some((rest, length)) => (rest, prefix_length(length, 128)?),
+        None => (spec, 128),
+    };
+    let (spec, v4) = match spec.rsplit_once('/') {
+        Some((rest, length)) if length.bytes().all(|b| b.is_ascii_digit()) => {
+            (rest, prefix_length(length, 32)?)
+        }
+        _ => (spec, 32),
+    };
+    Ok((spec, v4, v6))
+}
+
+// ip4:/ip6: take an address, with an optional prefix length
+fn network<'a>(argument: Option<&'a str>, max: u8, term: &str) -> Result<(&'a str, u8), String> {
+    let argument = argument.ok_or_else(|| format!("missing address in {}", term))?;
+    match argument.split_once('/') {
+        Some((address, length)) => Ok((address, prefix_length(length, max)?)),
+        None => Ok((argument, max)),
+    }
+}
+
+// No leading zeros and no more than the address has bits
+fn prefix_length(length: &str, max: u8) -> Result<u8, String> {
+    let valid = !length.is_empty()
+        && length.bytes().all(|b| b.is_ascii_digit())
+        && (length == "0" || !length.starts_with('0'));
+    match length.parse::<u8>() {
+        Ok(length) if valid && length <= max => Ok(length),
+        _ => Err(format!("invalid prefix length /{}", length)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_spf_record_is_allowed() {
+        let records = |records: &[&str]| -> Vec<String> {
+            records.iter().map(|record| record.to_string()).collect()
+        };
+        assert_eq!(
+            select(&records(&["google-site-verification=abc", "v=spf1 -all"])),
+            Ok(Some("v=spf1 -all"))
+        );
+        assert_eq!(select(&records(&["V=SPF1"])), Ok(Some("V=SPF1")));
+        assert_eq!(select(&records(&["v=spf10 -all"])), Ok(None));
+        assert!(select(&records(&["v=spf1 a", "v=spf1 mx"])).is_err());
+    }
+
+    #[test]
+    fn records_parse_into_directives_and_modifiers() {
+        let record = parse(
+            "v=spf1 a mx/30 -a:%{d}.example.org/24//64 ~ip4:192.0.2.0/24 ?ip6:2001:db8::/32 \
+             ptr include:_spf.example.com exists:%{ir}.bl.example  redirect=_spf.example.net exp=explain.%{d} x-unknown=1",
+        )
+        .unwrap();
+        assert_eq!(
+            record.directives,
+            [
+                (Outcome::Pass, Mechanism::A(None, 32, 128)),
+                (Outcome::Pass, Mechanism::Mx(None, 30, 128)),
+                (
+                    Outcome::Fail,
+                    Mechanism::A(Some("%{d}.example.org".to_string()), 24, 64)
+                ),
+                (
+                    Outcome::SoftFail,
+                    Mechanism::Ip("192.0.2.0".parse().unwrap(), 24)
+                ),
+                (
+                    Outcome::Neutral,
+                    Mechanism::Ip("2001:db8::".parse().unwrap(), 32)
+                ),
+                (Outcome::Pass, Mechanism::Ptr(None)),
+                (
+                    Outcome::Pass,
+                    Mechanism::Include("_spf.example.com".to_string())
+                ),
+                (
+                    Outcome::Pass,
+                    Mechanism::Exists("%{ir}.bl.example".to_string())
+                ),
+            ]
+        );
+        assert_eq!(record.redirect.as_deref(), Some("_spf.example.net"));
+        assert_eq!(record.explanation.as_deref(), Some("explain.%{d}"));
+    }
+
+    #[test]
+    fn syntax_errors_anywhere_are_rejected() {
+        for record in [
+            "v=spf1 include",
+            "v=spf1 ip4:192.0.2.0/33",
+            "v=spf1 ip4:192.0.2.300",
+            "v=spf1 ip6:192.0.2.1",
+            "v=spf1 a/024",
+            "v=spf1 a:",
+            "v=spf1 all:example.com",
+            "v=spf1 foo:example.com",
+            "v=spf1 exists:%{x}",
+            "v=spf1 redirect=a.example redirect=b.example",
+            "v=spf1 -all exp=%{q}",
+        ] {
+            assert!(parse(record).is_err(), "{}", record);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::{fs::OpenOptions, io::Write};
+
+use eemail_lib_dkim::{Algorithm, SigningKey};
+
+const USAGE: &str = "usage: eemail dkim-keygen <domain> <selector> [rsa|ed25519]";
+
+// TXT records are made of strings of at most 255 characters each
+const TXT_STRING_LENGTH: usize = 255;
+
+// Makes a new signing key in ./<selector>.<domain>.pem and prints the DNS record and config to go with it
+pub fn keygen(args: &[String]) -> Result<(), String> {
+    let (domain, selector, algorithm) = match args {
+        [domain, selector] => (domain, selector, Algorithm::RsaSha256),
+        [domain, selector, algorithm] => {
+            let algorithm = match algorithm.as_str() {
+                "rsa" => Algorithm::RsaSha256,
+                "ed25519" => Algorithm::Ed25519Sha256,
+                _ => return Err(USAGE.to_string()),
+            };
+            (domain, selector, algorithm)
+        }
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let key = SigningKey::generate(algorithm).map_err(|e| e.to_string())?;
+    let pem = key.to_pem().map_err(|e| e.to_string())?;
+    let record = key.dns_record().map_err(|e| e.to_string())?;
+
+    // Never overwrite a key, one that's already published would stop verifying
+    let path = format!("{}.{}.pem", selector, domain);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+
+    let strings: Vec<String> = record
+        .as_bytes()
+        .chunks(TXT_STRING_LENGTH)
+        .map(|chunk| format!("\"{}\"", String::from_utf8_lossy(chunk)))
+        .collect();
+
+    println!("Wrote the {} private key to {}\n", algorithm.name(), path);
+    println!("Publish this TXT record:\n");
+    println!(
+        "{}._domainkey.{}. IN TXT ( {} )\n",
+        selector,
+        domain,
+        strings.join(" ")
+    );
+    println!("And add this to config.toml:\n");
+    println!("[[dkim.keys]]");
+    println!("domain = \"{}\"", domain);
+    println!("selector = \"{}\"", selector);
+    println!("private_key = \"{}\"", path);
+    Ok(())
+}
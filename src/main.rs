@@ -1,7 +1,24 @@
 use log::{error, info};
 
+mod dkim;
+mod scram;
+
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("dkim-keygen") => Some(dkim::keygen(&args[2..])),
+        Some("scram-password") => Some(scram::password(&args[2..])),
+        _ => None,
+    };
+    if let Some(result) = result {
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     dotenv::dotenv().ok();
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
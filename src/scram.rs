@@ -0,0 +1,29 @@
+use std::io::BufRead;
+
+use eemail_lib_sasl::ScramCredentials;
+
+const USAGE: &str = "usage: eemail scram-password < password";
+
+// Reads a password from stdin, so it stays out of the shell history, and prints the scram_sha256 value to store for it
+pub fn password(args: &[String]) -> Result<(), String> {
+    if !args.is_empty() {
+        return Err(USAGE.to_string());
+    }
+
+    let mut password = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|e| format!("Failed to read the password: {}", e))?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(USAGE.to_string());
+    }
+
+    println!("Add this to the account in config.toml:\n");
+    println!(
+        "scram_sha256 = \"{}\"",
+        ScramCredentials::generate(password)
+    );
+    Ok(())
+}